@@ -0,0 +1,240 @@
+//! Who may read a room's history over HTTP, from the room's `visibility`
+//! setting. Members authenticate with their account as
+//! `Authorization: Basic <base64 of name:password>`, and the admin token reads
+//! every room, as does a room token with the `read` scope. Crawlers are told
+//! not to index anything but public, listed rooms.
+
+use actix::SystemService;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::accounts::Accounts;
+use crate::admin;
+use crate::message::{Authenticate, GetRoomSettings, IsRoomMember};
+use crate::server::WsChatServer;
+use crate::settings::{RoomFlag, Visibility};
+use crate::tokens::{self, Scope};
+
+/// `name` and `password` from `Authorization: Basic ...`
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let encoded = req
+        .headers()
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+
+    Some((name.to_owned(), password.to_owned()))
+}
+
+/// Whether the request may read the room's history, answering with the
+/// response to send instead if not. Otherwise resolves to whether the history
+/// may be indexed.
+pub async fn authorize_history(
+    req: &HttpRequest,
+    room_name: &str,
+) -> Result<bool, HttpResponse> {
+    let settings = WsChatServer::from_registry()
+        .send(GetRoomSettings(room_name.to_owned()))
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .unwrap_or_default();
+    let indexable = settings.visibility == Visibility::Public
+        && !settings.has_flag(RoomFlag::Unlisted);
+
+    if admin::authorize(req.headers()).is_ok()
+        || tokens::grant(req, room_name, Scope::Read).await.is_some()
+    {
+        return Ok(indexable);
+    }
+
+    match settings.visibility {
+        Visibility::Public => Ok(indexable),
+        // as if it didn't exist
+        Visibility::Private => Err(HttpResponse::NotFound().finish()),
+        Visibility::Members => {
+            let account = authenticate(req).await?;
+            if !is_member(&account, room_name).await? {
+                return Err(HttpResponse::Forbidden().finish());
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Whether the account belongs to the room, see `IsRoomMember`
+async fn is_member(account: &str, room_name: &str) -> Result<bool, HttpResponse> {
+    WsChatServer::from_registry()
+        .send(IsRoomMember {
+            room_name: room_name.to_owned(),
+            account: account.to_owned(),
+        })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())
+}
+
+/// The account the request's `Authorization: Basic ...` logs in as,
+/// answering with the response to send instead if it doesn't
+pub async fn authenticate(req: &HttpRequest) -> Result<String, HttpResponse> {
+    let (name, password) = basic_credentials(req).ok_or_else(challenge)?;
+
+    match Accounts::from_registry()
+        .send(Authenticate(name.clone(), password))
+        .await
+    {
+        Ok(Ok(())) => Ok(name),
+        Ok(Err(_)) => Err(challenge()),
+        Err(_) => Err(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+fn challenge() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header("WWW-Authenticate", "Basic realm=\"chat\"")
+        .finish()
+}
+
+/// Response for history `authorize_history` allowed, marked `noindex` unless
+/// it may be indexed
+pub fn history_response(indexable: bool) -> HttpResponseBuilder {
+    let mut res = HttpResponse::Ok();
+    if !indexable {
+        res.header("X-Robots-Tag", "noindex, nofollow");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::message::{IssueRoomToken, Register};
+    use crate::testing::{run, TestClient};
+    use crate::tokens::RoomTokens;
+
+    const PASSWORD: &str = "correct horse";
+
+    /// A client logged in as a new account `name`
+    async fn log_in(name: &str) -> TestClient {
+        Accounts::from_registry()
+            .send(Register {
+                name: name.to_owned(),
+                email: format!("{}@example.com", name),
+                password: PASSWORD.to_owned(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut client = TestClient::connect(&[]);
+        client.texts().await;
+        client
+            .ask_for(&format!("/login {} {}", name, PASSWORD), "logged in as")
+            .await;
+        client
+    }
+
+    fn basic(name: &str) -> HttpRequest {
+        let credentials = base64::encode(format!("{}:{}", name, PASSWORD));
+        TestRequest::default()
+            .header("Authorization", format!("Basic {}", credentials))
+            .to_http_request()
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .header("Authorization", format!("Bearer {}", token))
+            .to_http_request()
+    }
+
+    /// What `authorize_history` answers for room `r`: whether it may be
+    /// indexed, or the status refusing it
+    async fn history(req: HttpRequest) -> Result<bool, StatusCode> {
+        authorize_history(&req, "r")
+            .await
+            .map_err(|res| res.status())
+    }
+
+    #[test]
+    fn test_authorize_history() {
+        run(async {
+            std::env::set_var("ADMIN_TOKEN", "admin-s3cret");
+
+            // alice owns r, bob and carol joined it and carol was banned,
+            // dave never joined
+            let mut alice = log_in("alice").await;
+            alice.ask_for("/join r", "alice joined r").await;
+            let mut bob = log_in("bob").await;
+            bob.ask_for("/join r", "bob joined r").await;
+            let mut carol = log_in("carol").await;
+            carol.ask_for("/join r", "carol joined r").await;
+            alice.ask_for("/ban carol", "carol is banned from r").await;
+            let _dave = log_in("dave").await;
+
+            let (_, token) = RoomTokens::from_registry()
+                .send(IssueRoomToken {
+                    room_name: "r".to_owned(),
+                    label: "archiver".to_owned(),
+                    scopes: BTreeSet::from([Scope::Read]),
+                })
+                .await
+                .unwrap();
+
+            let anonymous = || TestRequest::default().to_http_request();
+            let admin = || bearer("admin-s3cret");
+            let read_token = || bearer(&token);
+
+            assert_eq!(history(anonymous()).await, Ok(true));
+            assert_eq!(history(basic("dave")).await, Ok(true));
+            assert_eq!(history(basic("bob")).await, Ok(true));
+            assert_eq!(history(basic("carol")).await, Ok(true));
+            assert_eq!(history(admin()).await, Ok(true));
+            assert_eq!(history(read_token()).await, Ok(true));
+
+            alice
+                .ask_for("/settings visibility members", "settings changed")
+                .await;
+            assert_eq!(history(anonymous()).await, Err(StatusCode::UNAUTHORIZED));
+            assert_eq!(history(basic("dave")).await, Err(StatusCode::FORBIDDEN));
+            assert_eq!(history(basic("bob")).await, Ok(false));
+            assert_eq!(history(basic("carol")).await, Err(StatusCode::FORBIDDEN));
+            assert_eq!(history(admin()).await, Ok(false));
+            assert_eq!(history(read_token()).await, Ok(false));
+
+            alice
+                .ask_for("/settings visibility private", "settings changed")
+                .await;
+            assert_eq!(history(anonymous()).await, Err(StatusCode::NOT_FOUND));
+            assert_eq!(history(basic("dave")).await, Err(StatusCode::NOT_FOUND));
+            assert_eq!(history(basic("bob")).await, Err(StatusCode::NOT_FOUND));
+            assert_eq!(history(basic("carol")).await, Err(StatusCode::NOT_FOUND));
+            assert_eq!(history(admin()).await, Ok(false));
+            assert_eq!(history(read_token()).await, Ok(false));
+        });
+    }
+
+    #[test]
+    fn test_marking_read_is_not_membership() {
+        run(async {
+            let mut alice = log_in("alice").await;
+            alice.ask_for("/join r", "alice joined r").await;
+            alice
+                .ask_for("/settings visibility members", "settings changed")
+                .await;
+
+            let mut dave = log_in("dave").await;
+            assert_eq!(
+                dave.ask_for("/read r", "!!!").await,
+                "!!! you aren't a member of this room"
+            );
+            assert_eq!(history(basic("dave")).await, Err(StatusCode::FORBIDDEN));
+        });
+    }
+}
@@ -0,0 +1,934 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use log::{debug, info, warn};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::config;
+use crate::mail::Mailer;
+use crate::message::{
+    Authenticate, ConfirmPasswordReset, CountMessage, DetachLogin, EndSession,
+    FilterHit, GetNotifyLevel, GetTrust, ListSessions, Login, Logout, Posted,
+    QueueMention, Register, Report, RequestPasswordReset, ResumeLogin,
+    SendPasswordReset, SendVerification, SetNotifyLevel, SignedOut, VerifyEmail,
+};
+use crate::session::unix_millis;
+use crate::trust::{self, AccountStanding, Standing, TrustRecord};
+
+/// How long an email verification link stays valid
+const VERIFICATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a password reset link stays valid
+const PASSWORD_RESET_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Characters a password needs at least
+const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum AccountError {
+    NameTaken,
+    /// the email is verified for another account already
+    EmailTaken,
+    InvalidCredentials,
+    InvalidToken,
+    NoSuchSession,
+    /// a password shorter than `MIN_PASSWORD_LEN`
+    WeakPassword,
+    Internal,
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::NameTaken => write!(f, "account name is already taken"),
+            AccountError::EmailTaken => write!(f, "email is already in use"),
+            AccountError::InvalidCredentials => write!(f, "invalid name or password"),
+            AccountError::InvalidToken => write!(f, "invalid or expired token"),
+            AccountError::NoSuchSession => write!(f, "no such session"),
+            AccountError::WeakPassword => write!(
+                f,
+                "password must have at least {} characters",
+                MIN_PASSWORD_LEN
+            ),
+            AccountError::Internal => write!(f, "internal account error"),
+        }
+    }
+}
+
+/// How many sessions may be logged in as one account at a time, the config's
+/// `sessions_per_account`: `unlimited` (the default), a number, or `single`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionPolicy {
+    Unlimited,
+    /// the oldest session is signed out to make room for a new one
+    Limit(usize),
+    /// like `Limit(1)`, but the previous session is told it signed in
+    /// elsewhere
+    Single,
+}
+
+impl FromStr for SessionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unlimited" => Ok(SessionPolicy::Unlimited),
+            "single" => Ok(SessionPolicy::Single),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "expected unlimited, single or a number of sessions, not {:?}",
+                    s
+                )),
+                Ok(n) => Ok(SessionPolicy::Limit(n)),
+            },
+        }
+    }
+}
+
+/// A number in the config file, or `"unlimited"` or `"single"`
+impl<'de> Deserialize<'de> for SessionPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Policy {
+            Sessions(usize),
+            Name(String),
+        }
+
+        let policy = match Policy::deserialize(deserializer)? {
+            Policy::Sessions(sessions) => sessions.to_string(),
+            Policy::Name(name) => name,
+        };
+        policy.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A session logged in as an account, as `/sessions` lists it
+#[derive(Clone, Debug, Serialize)]
+pub struct Device {
+    /// the login's id
+    pub id: u64,
+    /// the client's `User-Agent`
+    pub device: Option<String>,
+    /// unix milliseconds
+    pub since: u64,
+}
+
+struct LoggedIn {
+    device: Device,
+    session: Recipient<SignedOut>,
+}
+
+/// The login of a session that lost its client, see `DetachLogin`
+struct Detached {
+    account: String,
+    device: Device,
+    since: Instant,
+}
+
+/// Which messages of a room an account is notified of while it is offline
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NotifyLevel {
+    All,
+    #[default]
+    Mentions,
+    Nothing,
+}
+
+impl FromStr for NotifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotifyLevel::All),
+            "mentions" => Ok(NotifyLevel::Mentions),
+            "off" => Ok(NotifyLevel::Nothing),
+            _ => Err(format!("expected all, mentions or off, not {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for NotifyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyLevel::All => write!(f, "all"),
+            NotifyLevel::Mentions => write!(f, "mentions"),
+            NotifyLevel::Nothing => write!(f, "off"),
+        }
+    }
+}
+
+struct Account {
+    email: String,
+    /// set once the owner followed the link sent by `SendVerification`
+    email_verified: bool,
+    password_hash: String,
+    /// websocket sessions currently logged in as this account, oldest first
+    sessions: Vec<LoggedIn>,
+    /// per room, `NotifyLevel::Mentions` where unset
+    notifications: HashMap<String, NotifyLevel>,
+    /// unix milliseconds
+    created: u64,
+    /// chat messages sent while logged in
+    messages: u64,
+}
+
+impl Account {
+    fn notify_level(&self, room_name: &str) -> NotifyLevel {
+        self.notifications
+            .get(room_name)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Registered users, keyed by account name
+#[derive(Default)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+    /// id of the latest login
+    last_login: u64,
+    /// pending email verification tokens: token -> (account name, expiry)
+    verifications: HashMap<String, (String, Instant)>,
+    /// pending password resets: sha256(token) -> (account name, expiry)
+    password_resets: HashMap<String, (String, Instant)>,
+    /// logins waiting for their session to be resumed: sha256(resume token)
+    /// -> login
+    detached: HashMap<String, Detached>,
+    /// reports and filter hits by client name, whether or not it's an account
+    trust: HashMap<String, TrustRecord>,
+}
+
+/// Random hex string for use in emailed links
+pub fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Reset tokens are only kept hashed, so a leaked account store can't be
+/// used to take over accounts
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Refuses passwords shorter than `MIN_PASSWORD_LEN`
+fn hash_password(password: &str) -> Result<String, AccountError> {
+    if password.trim().chars().count() < MIN_PASSWORD_LEN {
+        return Err(AccountError::WeakPassword);
+    }
+
+    let salt = rand::random::<[u8; 16]>();
+
+    argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default()).map_err(
+        |err| {
+            warn!("hash_password() - {}", err);
+            AccountError::Internal
+        },
+    )
+}
+
+/// Tells a session its login is gone, which closes it
+fn sign_out(logged_in: LoggedIn, reason: &str) {
+    let _ = logged_in.session.do_send(SignedOut(reason.to_owned()));
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+}
+
+impl Actor for Accounts {
+    type Context = Context<Self>;
+}
+
+impl Handler<Register> for Accounts {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: Register, _ctx: &mut Self::Context) -> Self::Result {
+        let Register {
+            name,
+            email,
+            password,
+        } = msg;
+
+        let token = self.register(&name, &email, &password)?;
+        Mailer::from_registry().do_send(SendVerification { email, name, token });
+        Ok(())
+    }
+}
+
+impl Handler<VerifyEmail> for Accounts {
+    type Result = Result<String, AccountError>;
+
+    fn handle(&mut self, msg: VerifyEmail, _ctx: &mut Self::Context) -> Self::Result {
+        self.verify_email(&msg.0)
+    }
+}
+
+impl Handler<RequestPasswordReset> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: RequestPasswordReset, _ctx: &mut Self::Context) {
+        let RequestPasswordReset(email) = msg;
+
+        if let Some((name, token)) = self.request_password_reset(&email) {
+            Mailer::from_registry().do_send(SendPasswordReset { email, name, token });
+        }
+    }
+}
+
+impl Handler<ConfirmPasswordReset> for Accounts {
+    type Result = Result<(), AccountError>;
+
+    fn handle(
+        &mut self,
+        msg: ConfirmPasswordReset,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let ConfirmPasswordReset { token, password } = msg;
+        self.confirm_password_reset(&token, &password)
+    }
+}
+
+impl Accounts {
+    /// Adds an account with an unverified email, resolves to the token that
+    /// verifies it
+    fn register(
+        &mut self,
+        name: &str,
+        email: &str,
+        password: &str,
+    ) -> Result<String, AccountError> {
+        if self.accounts.contains_key(name) {
+            return Err(AccountError::NameTaken);
+        }
+        if self.email_taken(email, name) {
+            return Err(AccountError::EmailTaken);
+        }
+
+        let account = Account {
+            email: email.to_owned(),
+            email_verified: false,
+            password_hash: hash_password(password)?,
+            sessions: Vec::new(),
+            notifications: HashMap::new(),
+            created: unix_millis() as u64,
+            messages: 0,
+        };
+
+        let token = random_token();
+        self.verifications.insert(
+            token.clone(),
+            (name.to_owned(), Instant::now() + VERIFICATION_TTL),
+        );
+
+        debug!("Accounts::register() - registered account {}", name);
+        self.accounts.insert(name.to_owned(), account);
+        Ok(token)
+    }
+
+    /// Resolves to the name of the account whose email is now verified
+    fn verify_email(&mut self, token: &str) -> Result<String, AccountError> {
+        let now = Instant::now();
+        self.verifications.retain(|_, (_, expires)| *expires > now);
+
+        let (name, _) = self
+            .verifications
+            .remove(token)
+            .ok_or(AccountError::InvalidToken)?;
+
+        // two accounts may have registered with it before either verified it
+        let email = match self.accounts.get(&name) {
+            Some(account) => account.email.clone(),
+            None => return Err(AccountError::InvalidToken),
+        };
+        if self.email_taken(&email, &name) {
+            return Err(AccountError::EmailTaken);
+        }
+
+        let account = self
+            .accounts
+            .get_mut(&name)
+            .ok_or(AccountError::InvalidToken)?;
+        account.email_verified = true;
+
+        debug!("Accounts::verify_email() - verified email for {}", &name);
+        Ok(name)
+    }
+
+    /// The account that verified `email` and a new reset token for it, to be
+    /// mailed there, `None` if no account did
+    fn request_password_reset(&mut self, email: &str) -> Option<(String, String)> {
+        let name = self
+            .accounts
+            .iter()
+            .find(|(_, account)| account.email_verified && account.email == email)
+            .map(|(name, _)| name.clone());
+
+        let name = match name {
+            Some(name) => name,
+            None => {
+                debug!("Accounts::request_password_reset() - no verified account");
+                return None;
+            }
+        };
+
+        // only the newest link for an account is usable
+        let now = Instant::now();
+        self.password_resets
+            .retain(|_, (owner, expires)| *owner != name && *expires > now);
+
+        let token = random_token();
+        self.password_resets
+            .insert(hash_token(&token), (name.clone(), now + PASSWORD_RESET_TTL));
+
+        Some((name, token))
+    }
+
+    fn confirm_password_reset(
+        &mut self,
+        token: &str,
+        password: &str,
+    ) -> Result<(), AccountError> {
+        // before the token is used up, so it can be tried again with a
+        // longer one
+        let password_hash = hash_password(password)?;
+
+        let (name, expires) = self
+            .password_resets
+            .remove(&hash_token(token))
+            .ok_or(AccountError::InvalidToken)?;
+
+        if expires <= Instant::now() {
+            return Err(AccountError::InvalidToken);
+        }
+
+        let account = self
+            .accounts
+            .get_mut(&name)
+            .ok_or(AccountError::InvalidToken)?;
+        account.password_hash = password_hash;
+
+        // whoever knew the old password is signed out everywhere, and can't
+        // resume a login either
+        for logged_in in account.sessions.drain(..) {
+            sign_out(logged_in, "signed out, the password was reset");
+        }
+        self.detached.retain(|_, detached| detached.account != name);
+
+        debug!(
+            "Accounts::confirm_password_reset() - password reset for {}",
+            &name
+        );
+        Ok(())
+    }
+
+    /// Whether an account other than `name` verified `email`, which then
+    /// belongs to it alone, so a password reset goes to one account only
+    fn email_taken(&self, email: &str, name: &str) -> bool {
+        self.accounts.iter().any(|(other, account)| {
+            other != name && account.email_verified && account.email == email
+        })
+    }
+
+    fn logout(&mut self, name: &str, login: u64) {
+        if let Some(account) = self.accounts.get_mut(name) {
+            account
+                .sessions
+                .retain(|logged_in| logged_in.device.id != login);
+        }
+    }
+
+    /// Adds a session to the account's, signing the oldest ones out if it now
+    /// has more than `sessions_per_account` allows
+    fn add_session(
+        &mut self,
+        name: &str,
+        logged_in: LoggedIn,
+    ) -> Result<(), AccountError> {
+        let account = self
+            .accounts
+            .get_mut(name)
+            .ok_or(AccountError::InvalidCredentials)?;
+        account.sessions.push(logged_in);
+
+        let (limit, reason) = match config().sessions_per_account {
+            SessionPolicy::Unlimited => return Ok(()),
+            SessionPolicy::Limit(limit) => (
+                limit,
+                format!("signed out, {} allows {} sessions at a time", name, limit),
+            ),
+            SessionPolicy::Single => (1, "signed in elsewhere".to_owned()),
+        };
+
+        while account.sessions.len() > limit {
+            let oldest = account.sessions.remove(0);
+            info!("Accounts - signing out the oldest session of {}", name);
+            sign_out(oldest, &reason);
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<Login> for Accounts {
+    type Result = Result<u64, AccountError>;
+
+    fn handle(&mut self, msg: Login, _ctx: &mut Self::Context) -> Self::Result {
+        let Login {
+            name,
+            password,
+            session,
+            device,
+            previous,
+        } = msg;
+
+        match self.accounts.get(&name) {
+            Some(account) if verify_password(&account.password_hash, &password) => {}
+            _ => return Err(AccountError::InvalidCredentials),
+        }
+
+        if let Some((previous, login)) = previous {
+            self.logout(&previous, login);
+        }
+
+        self.last_login += 1;
+        let login = self.last_login;
+        self.add_session(
+            &name,
+            LoggedIn {
+                device: Device {
+                    id: login,
+                    device,
+                    since: unix_millis() as u64,
+                },
+                session,
+            },
+        )?;
+
+        Ok(login)
+    }
+}
+
+impl Handler<DetachLogin> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: DetachLogin, _ctx: &mut Self::Context) {
+        let DetachLogin {
+            account,
+            login,
+            token,
+        } = msg;
+
+        let now = Instant::now();
+        self.detached.retain(|_, detached| {
+            now.duration_since(detached.since) < config().resume_grace()
+        });
+
+        let sessions = match self.accounts.get_mut(&account) {
+            Some(account) => &mut account.sessions,
+            None => return,
+        };
+        if let Some(i) = sessions
+            .iter()
+            .position(|logged_in| logged_in.device.id == login)
+        {
+            let device = sessions.remove(i).device;
+            self.detached.insert(
+                hash_token(&token),
+                Detached {
+                    account,
+                    device,
+                    since: now,
+                },
+            );
+        }
+    }
+}
+
+/// Keeps the login's id, so it is the same session in `/sessions`
+impl Handler<ResumeLogin> for Accounts {
+    type Result = Result<(String, u64), AccountError>;
+
+    fn handle(&mut self, msg: ResumeLogin, _ctx: &mut Self::Context) -> Self::Result {
+        let ResumeLogin {
+            token,
+            session,
+            device,
+        } = msg;
+
+        let Detached {
+            account,
+            device: mut resumed,
+            since,
+        } = self
+            .detached
+            .remove(&hash_token(&token))
+            .ok_or(AccountError::InvalidToken)?;
+        if since.elapsed() >= config().resume_grace() {
+            return Err(AccountError::InvalidToken);
+        }
+
+        resumed.device = device;
+        let login = resumed.id;
+        self.add_session(
+            &account,
+            LoggedIn {
+                device: resumed,
+                session,
+            },
+        )?;
+
+        Ok((account, login))
+    }
+}
+
+impl Handler<ListSessions> for Accounts {
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, msg: ListSessions, _ctx: &mut Self::Context) -> Self::Result {
+        let devices = self
+            .accounts
+            .get(&msg.0)
+            .map(|account| {
+                account
+                    .sessions
+                    .iter()
+                    .map(|logged_in| logged_in.device.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        MessageResult(devices)
+    }
+}
+
+impl Handler<EndSession> for Accounts {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: EndSession, _ctx: &mut Self::Context) -> Self::Result {
+        let EndSession { account, login } = msg;
+
+        let sessions = &mut self
+            .accounts
+            .get_mut(&account)
+            .ok_or(AccountError::NoSuchSession)?
+            .sessions;
+        let i = sessions
+            .iter()
+            .position(|logged_in| logged_in.device.id == login)
+            .ok_or(AccountError::NoSuchSession)?;
+
+        info!("Accounts - ending session {} of {}", login, &account);
+        sign_out(sessions.remove(i), "signed out from another session");
+
+        Ok(())
+    }
+}
+
+impl Handler<Authenticate> for Accounts {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: Authenticate, _ctx: &mut Self::Context) -> Self::Result {
+        let Authenticate(name, password) = msg;
+
+        match self.accounts.get(&name) {
+            Some(account) if verify_password(&account.password_hash, &password) => {
+                Ok(())
+            }
+            _ => Err(AccountError::InvalidCredentials),
+        }
+    }
+}
+
+impl Handler<Logout> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: Logout, _ctx: &mut Self::Context) {
+        let Logout(name, login) = msg;
+        self.logout(&name, login);
+    }
+}
+
+impl Handler<Posted> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: Posted, _ctx: &mut Self::Context) {
+        let Posted {
+            room_name,
+            context,
+            mentioned,
+        } = msg;
+
+        // online users see the message in the room, only offline ones with a
+        // confirmed address get mail
+        for (name, account) in &self.accounts {
+            if !account.sessions.is_empty() || !account.email_verified {
+                continue;
+            }
+
+            let is_mentioned = mentioned.contains(name);
+            let notify = match account.notify_level(&room_name) {
+                NotifyLevel::All => true,
+                NotifyLevel::Mentions => is_mentioned,
+                NotifyLevel::Nothing => false,
+            };
+
+            if notify {
+                Mailer::from_registry().do_send(QueueMention {
+                    email: account.email.clone(),
+                    name: name.clone(),
+                    room_name: room_name.clone(),
+                    context: context.clone(),
+                    mentioned: is_mentioned,
+                });
+            }
+        }
+    }
+}
+
+impl Handler<SetNotifyLevel> for Accounts {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: SetNotifyLevel, _ctx: &mut Self::Context) -> Self::Result {
+        let SetNotifyLevel {
+            account,
+            room_name,
+            level,
+        } = msg;
+
+        let account = self
+            .accounts
+            .get_mut(&account)
+            .ok_or(AccountError::InvalidCredentials)?;
+
+        if level == NotifyLevel::default() {
+            account.notifications.remove(&room_name);
+        } else {
+            account.notifications.insert(room_name, level);
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<GetNotifyLevel> for Accounts {
+    type Result = MessageResult<GetNotifyLevel>;
+
+    fn handle(&mut self, msg: GetNotifyLevel, _ctx: &mut Self::Context) -> Self::Result {
+        let GetNotifyLevel(account, room_name) = msg;
+
+        MessageResult(
+            self.accounts
+                .get(&account)
+                .map_or_else(NotifyLevel::default, |account| {
+                    account.notify_level(&room_name)
+                }),
+        )
+    }
+}
+
+impl Handler<GetTrust> for Accounts {
+    type Result = MessageResult<GetTrust>;
+
+    fn handle(&mut self, msg: GetTrust, _ctx: &mut Self::Context) -> Self::Result {
+        let GetTrust { name, account } = msg;
+
+        // only logged in sessions get the account's standing
+        let account = account
+            .filter(|account| *account == name)
+            .and_then(|account| self.accounts.get(&account));
+        let standing = account.map(|account| AccountStanding {
+            created: account.created,
+            email_verified: account.email_verified,
+        });
+
+        MessageResult(Standing {
+            score: trust::score(
+                standing.as_ref(),
+                self.trust.get(&name),
+                unix_millis() as u64,
+            ),
+            account_created: account.map(|account| account.created),
+            account_messages: account.map_or(0, |account| account.messages),
+        })
+    }
+}
+
+impl Handler<CountMessage> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: CountMessage, _ctx: &mut Self::Context) {
+        let CountMessage(name) = msg;
+        if let Some(account) = self.accounts.get_mut(&name) {
+            account.messages += 1;
+        }
+    }
+}
+
+impl Handler<Report> for Accounts {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Report, _ctx: &mut Self::Context) -> Self::Result {
+        let Report {
+            reporter,
+            name,
+            reason,
+        } = msg;
+
+        if reporter == name {
+            return Err("you can't report yourself".to_owned());
+        }
+
+        let record = self.trust.entry(name.clone()).or_default();
+        if !record.reporters.insert(reporter.clone()) {
+            return Err(format!("you already reported {}", name));
+        }
+
+        info!(
+            "Report::handle() - {} reported {}: {}",
+            &reporter, &name, &reason
+        );
+        Ok(())
+    }
+}
+
+impl Handler<FilterHit> for Accounts {
+    type Result = ();
+
+    fn handle(&mut self, msg: FilterHit, _ctx: &mut Self::Context) {
+        let FilterHit(name) = msg;
+        self.trust.entry(name).or_default().filter_hits += 1;
+    }
+}
+
+impl SystemService for Accounts {}
+impl Supervised for Accounts {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse";
+
+    /// Accounts with alice registered and her email verified
+    fn verified_alice() -> Accounts {
+        let mut accounts = Accounts::default();
+        let token = accounts
+            .register("alice", "alice@example.com", PASSWORD)
+            .unwrap();
+        accounts.verify_email(&token).unwrap();
+        accounts
+    }
+
+    fn password_works(accounts: &Accounts, password: &str) -> bool {
+        verify_password(&accounts.accounts["alice"].password_hash, password)
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let mut accounts = verified_alice();
+        assert!(accounts.request_password_reset("bob@example.com").is_none());
+
+        let (name, token) = accounts
+            .request_password_reset("alice@example.com")
+            .unwrap();
+        assert_eq!(name, "alice");
+        // only the hash is kept
+        assert!(!accounts.password_resets.contains_key(&token));
+        assert!(accounts.password_resets.contains_key(&hash_token(&token)));
+
+        // a password too short doesn't use the token up
+        assert!(matches!(
+            accounts.confirm_password_reset(&token, "short"),
+            Err(AccountError::WeakPassword)
+        ));
+        accounts
+            .confirm_password_reset(&token, "battery staple")
+            .unwrap();
+        assert!(!password_works(&accounts, PASSWORD));
+        assert!(password_works(&accounts, "battery staple"));
+
+        // single-use
+        assert!(matches!(
+            accounts.confirm_password_reset(&token, "staple battery"),
+            Err(AccountError::InvalidToken)
+        ));
+
+        let (_, token) = accounts
+            .request_password_reset("alice@example.com")
+            .unwrap();
+        accounts
+            .password_resets
+            .get_mut(&hash_token(&token))
+            .unwrap()
+            .1 = Instant::now();
+        assert!(matches!(
+            accounts.confirm_password_reset(&token, "staple battery"),
+            Err(AccountError::InvalidToken)
+        ));
+        assert!(password_works(&accounts, "battery staple"));
+    }
+
+    #[test]
+    fn test_verify_email() {
+        let mut accounts = Accounts::default();
+        let token = accounts
+            .register("alice", "alice@example.com", PASSWORD)
+            .unwrap();
+
+        // no mail goes to an address until it is verified
+        assert!(accounts
+            .request_password_reset("alice@example.com")
+            .is_none());
+        assert!(matches!(
+            accounts.verify_email("not the token"),
+            Err(AccountError::InvalidToken)
+        ));
+        assert_eq!(accounts.verify_email(&token).unwrap(), "alice");
+        assert!(accounts.accounts["alice"].email_verified);
+        assert!(accounts
+            .request_password_reset("alice@example.com")
+            .is_some());
+        assert!(matches!(
+            accounts.verify_email(&token),
+            Err(AccountError::InvalidToken)
+        ));
+
+        let token = accounts
+            .register("bob", "bob@example.com", PASSWORD)
+            .unwrap();
+        accounts.verifications.get_mut(&token).unwrap().1 = Instant::now();
+        assert!(matches!(
+            accounts.verify_email(&token),
+            Err(AccountError::InvalidToken)
+        ));
+        assert!(!accounts.accounts["bob"].email_verified);
+
+        // the address belongs to alice now
+        assert!(matches!(
+            accounts.register("mallory", "alice@example.com", PASSWORD),
+            Err(AccountError::EmailTaken)
+        ));
+
+        // of two accounts registered with one address, the first to verify
+        // it gets it
+        let carol = accounts
+            .register("carol", "carol@example.com", PASSWORD)
+            .unwrap();
+        let dave = accounts
+            .register("dave", "carol@example.com", PASSWORD)
+            .unwrap();
+        assert_eq!(accounts.verify_email(&carol).unwrap(), "carol");
+        assert!(matches!(
+            accounts.verify_email(&dave),
+            Err(AccountError::EmailTaken)
+        ));
+        let (name, _) = accounts
+            .request_password_reset("carol@example.com")
+            .unwrap();
+        assert_eq!(name, "carol");
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::BTreeSet;
+
+use actix::SystemService;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::HeaderMap;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures::future::{ready, Either, Ready};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::blocklist::{self, BlockRule};
+use crate::config;
+use crate::import::{parse_archive, parse_authors, MAX_ARCHIVE_SIZE};
+use crate::journal::Journal;
+use crate::latency;
+use crate::message::{
+    AddWebhook, Announce, DeleteRoom, EnableWebhook, GetLoad, GetRoomSettings,
+    ImportEvents, IssueRoomToken, ListPresence, ListRoomTokens, ListRooms,
+    ListTemplates, ListWebhooks, PutTemplate, RemoveTemplate, RemoveWebhook,
+    RevokeRoomToken, UpdateRoomSettings, WebhookDeliveries,
+};
+use crate::server::{parse_page_token, RoomError, WsChatServer, ROOM_PAGE_SIZE};
+use crate::settings::parse_patch;
+use crate::templates::Templates;
+use crate::tokens::{RoomTokens, Scope, MAX_LABEL_LEN};
+use crate::watch::{self, WatchRule};
+use crate::webhooks::Webhooks;
+
+#[derive(Deserialize)]
+struct WebhookForm {
+    url: String,
+    room_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RoomsQuery {
+    /// the previous page's `next` token
+    after: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastForm {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    label: String,
+    scopes: BTreeSet<Scope>,
+}
+
+/// The admin API is only served if `ADMIN_TOKEN` is set, and requires it as
+/// `Authorization: Bearer <token>`
+pub fn authorize(headers: &HeaderMap) -> Result<(), HttpResponse> {
+    let token = match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return Err(HttpResponse::NotFound().finish()),
+    };
+
+    let given = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let matches =
+        given.is_some_and(|given| same_token(given.as_bytes(), token.as_bytes()));
+    if !matches {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+
+    Ok(())
+}
+
+/// Compares the digests rather than the tokens, in time independent of where
+/// they differ or how long they are
+fn same_token(given: &[u8], token: &[u8]) -> bool {
+    let given = Sha256::digest(given);
+    let token = Sha256::digest(token);
+
+    given
+        .iter()
+        .zip(token.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Lets requests into the `/api/admin` scope only if `authorize` does
+pub fn guard<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> Either<Ready<Result<ServiceResponse, Error>>, S::Future>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    match authorize(req.headers()) {
+        Ok(()) => Either::Right(srv.call(req)),
+        Err(res) => Either::Left(ready(Ok(req.into_response(res)))),
+    }
+}
+
+fn mailbox_error(err: actix::MailboxError) -> Error {
+    actix_web::error::ErrorInternalServerError(err)
+}
+
+/// Responds with the new hook, including the secret payloads are signed
+/// with, which isn't shown again
+async fn add_webhook(form: web::Json<WebhookForm>) -> Result<HttpResponse, Error> {
+    let WebhookForm { url, room_name } = form.into_inner();
+
+    let (info, secret) = Webhooks::from_registry()
+        .send(AddWebhook { url, room_name })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "webhook": info,
+        "secret": secret,
+    })))
+}
+
+async fn list_webhooks() -> Result<HttpResponse, Error> {
+    let hooks = Webhooks::from_registry()
+        .send(ListWebhooks)
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Ok().json(hooks))
+}
+
+async fn remove_webhook(id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let removed = Webhooks::from_registry()
+        .send(RemoveWebhook(id.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+/// Re-enables a hook that was disabled after failing repeatedly
+async fn enable_webhook(id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let enabled = Webhooks::from_registry()
+        .send(EnableWebhook(id.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(if enabled {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+async fn webhook_deliveries(id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let deliveries = Webhooks::from_registry()
+        .send(WebhookDeliveries(id.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match deliveries {
+        Some(deliveries) => HttpResponse::Ok().json(deliveries),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// This node's broadcast latency and what it sheds to keep up
+async fn load() -> Result<HttpResponse, Error> {
+    let report = WsChatServer::from_registry()
+        .send(GetLoad)
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// How long sessions on this node wait on the chat server, see `latency`
+async fn round_trips() -> HttpResponse {
+    HttpResponse::Ok().json(latency::report())
+}
+
+/// The rooms on this node, unlisted ones included, a page at a time like
+/// `/list`
+async fn list_rooms(query: web::Query<RoomsQuery>) -> Result<HttpResponse, Error> {
+    let after = match query.into_inner().after {
+        Some(token) => match parse_page_token(&token) {
+            Some(after) => Some(after),
+            None => return Ok(HttpResponse::BadRequest().body("invalid page token")),
+        },
+        None => None,
+    };
+
+    let page = WsChatServer::from_registry()
+        .send(ListRooms {
+            after,
+            limit: ROOM_PAGE_SIZE,
+            unlisted: true,
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// A room's clients on this node, with their names and how long they have
+/// been idle, as `/list-clients` shows them. Clients whose session didn't
+/// answer in time, e.g. while it is disconnected but may resume, have no
+/// name.
+async fn room_clients(room_name: web::Path<String>) -> Result<HttpResponse, Error> {
+    let presence = WsChatServer::from_registry()
+        .send(ListPresence(room_name.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+    let presence = match presence {
+        Some(presence) => presence,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let clients: Vec<Value> = presence
+        .clients
+        .into_iter()
+        .map(|(id, client)| {
+            serde_json::json!({
+                "id": id,
+                "name": client.as_ref().map(|client| &client.name),
+                "idle_secs": client
+                    .and_then(|client| client.idle)
+                    .map(|idle| idle.as_secs()),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "generation": presence.generation,
+        "clients": clients,
+    })))
+}
+
+/// Sends every member an announcement, `{"text":"back in 5 minutes"}`, as a
+/// system frame
+async fn broadcast(
+    room_name: web::Path<String>,
+    form: web::Json<BroadcastForm>,
+) -> Result<HttpResponse, Error> {
+    let text = form.into_inner().text.trim().to_owned();
+    let max_len = config::config().max_message_len;
+    if text.is_empty() || text.len() > max_len {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("text must be 1 to {} bytes", max_len)));
+    }
+
+    let res = WsChatServer::from_registry()
+        .send(Announce {
+            room_name: room_name.into_inner(),
+            text,
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(RoomError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    })
+}
+
+/// Removes every member, on every node, and forgets the room. Its history
+/// stays in the journal.
+async fn delete_room(room_name: web::Path<String>) -> Result<HttpResponse, Error> {
+    let res = WsChatServer::from_registry()
+        .send(DeleteRoom(room_name.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(RoomError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    })
+}
+
+/// This node's copy of a room's settings
+pub async fn room_settings(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if let Err(res) = authorize(req.headers()) {
+        return Ok(res);
+    }
+
+    let settings = WsChatServer::from_registry()
+        .send(GetRoomSettings(room_name.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match settings {
+        Some(settings) => HttpResponse::Ok().json(settings),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// Same settings as `/settings`, e.g. `{"topic":"standup at 10","slow_mode":null}`.
+/// Responds with the new settings, or 202 if the room lives on another node.
+pub async fn update_room_settings(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+    body: web::Json<Map<String, Value>>,
+) -> Result<HttpResponse, Error> {
+    if let Err(res) = authorize(req.headers()) {
+        return Ok(res);
+    }
+
+    let changes = match parse_patch(body.into_inner()) {
+        Ok(changes) => changes,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+
+    let res = WsChatServer::from_registry()
+        .send(UpdateRoomSettings {
+            room_name: room_name.into_inner(),
+            client_id: None,
+            changes,
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match res {
+        Ok(Some(settings)) => HttpResponse::Ok().json(settings),
+        Ok(None) => HttpResponse::Accepted().finish(),
+        Err(RoomError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    })
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// e.g. `U024BE7LH:bob,carol:caroline`, see `import::parse_authors`
+    #[serde(default)]
+    authors: String,
+}
+
+/// Adds an archive of the room's history, or of a Slack channel's, to this
+/// node's journal, see `import`. Responds with how many events were imported,
+/// skipped as not being chat, and dropped for being older than the room
+/// keeps.
+async fn import_history(
+    room_name: web::Path<String>,
+    query: web::Query<ImportQuery>,
+    body: String,
+) -> Result<HttpResponse, Error> {
+    let archive = match parse_authors(&query.authors)
+        .and_then(|authors| parse_archive(&body, &authors))
+    {
+        Ok(archive) => archive,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+    let total = archive.events.len();
+
+    let res = Journal::from_registry()
+        .send(ImportEvents {
+            room_name: room_name.into_inner(),
+            events: archive.events,
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match res {
+        Ok(kept) => HttpResponse::Ok().json(serde_json::json!({
+            "imported": kept,
+            "skipped": archive.skipped,
+            "dropped": total - kept,
+        })),
+        Err(err) => HttpResponse::Conflict().body(err),
+    })
+}
+
+async fn list_templates() -> Result<HttpResponse, Error> {
+    let templates = Templates::from_registry()
+        .send(ListTemplates)
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Ok().json(templates))
+}
+
+/// Body in the `PATCH /api/rooms/{room}` format, as the settings rooms created
+/// from the template start with
+async fn put_template(
+    name: web::Path<String>,
+    body: web::Json<Map<String, Value>>,
+) -> Result<HttpResponse, Error> {
+    let res = Templates::from_registry()
+        .send(PutTemplate {
+            name: name.into_inner(),
+            template: body.into_inner(),
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    })
+}
+
+async fn remove_template(name: web::Path<String>) -> Result<HttpResponse, Error> {
+    let removed = Templates::from_registry()
+        .send(RemoveTemplate(name.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+async fn list_blocklist() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(blocklist::rules()))
+}
+
+/// Body like `{"action":"mute","mute_secs":600}`, applied to messages from
+/// then on
+async fn put_blocked(
+    pattern: web::Path<String>,
+    rule: web::Json<BlockRule>,
+) -> Result<HttpResponse, Error> {
+    Ok(match blocklist::put(&pattern, rule.into_inner()) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    })
+}
+
+async fn remove_blocked(pattern: web::Path<String>) -> Result<HttpResponse, Error> {
+    Ok(if blocklist::remove(&pattern) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+async fn list_watch_rules() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(watch::rules()))
+}
+
+/// Body like `{"pattern":"\\bAKIA[0-9A-Z]{16}\\b","note":"AWS key"}`, checked
+/// from then on
+async fn put_watch_rule(
+    name: web::Path<String>,
+    rule: web::Json<WatchRule>,
+) -> Result<HttpResponse, Error> {
+    Ok(match watch::put(&name, rule.into_inner()) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    })
+}
+
+async fn remove_watch_rule(name: web::Path<String>) -> Result<HttpResponse, Error> {
+    Ok(if watch::remove(&name) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+/// Responds with the new token, which isn't shown again
+async fn issue_token(
+    room_name: web::Path<String>,
+    form: web::Json<TokenForm>,
+) -> Result<HttpResponse, Error> {
+    let TokenForm { label, scopes } = form.into_inner();
+    let label = label.trim().to_owned();
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("label must be 1 to {} bytes", MAX_LABEL_LEN)));
+    }
+    if scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("a token needs at least one scope"));
+    }
+
+    let (info, token) = RoomTokens::from_registry()
+        .send(IssueRoomToken {
+            room_name: room_name.into_inner(),
+            label,
+            scopes,
+        })
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "token": info,
+        "secret": token,
+    })))
+}
+
+async fn list_tokens(room_name: web::Path<String>) -> Result<HttpResponse, Error> {
+    let tokens = RoomTokens::from_registry()
+        .send(ListRoomTokens(room_name.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+async fn revoke_token(id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let revoked = RoomTokens::from_registry()
+        .send(RevokeRoomToken(id.into_inner()))
+        .await
+        .map_err(mailbox_error)?;
+
+    Ok(if revoked {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+/// Routes under `/api/admin`
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/webhooks")
+            .route(web::get().to(list_webhooks))
+            .route(web::post().to(add_webhook)),
+    )
+    .service(web::resource("/webhooks/{id}").route(web::delete().to(remove_webhook)))
+    .service(
+        web::resource("/webhooks/{id}/enable").route(web::post().to(enable_webhook)),
+    )
+    .service(
+        web::resource("/webhooks/{id}/deliveries")
+            .route(web::get().to(webhook_deliveries)),
+    )
+    .service(web::resource("/load").route(web::get().to(load)))
+    .service(web::resource("/latency").route(web::get().to(round_trips)))
+    .service(web::resource("/rooms").route(web::get().to(list_rooms)))
+    .service(web::resource("/rooms/{name}").route(web::delete().to(delete_room)))
+    .service(web::resource("/rooms/{name}/clients").route(web::get().to(room_clients)))
+    .service(web::resource("/rooms/{name}/broadcast").route(web::post().to(broadcast)))
+    .service(web::resource("/templates").route(web::get().to(list_templates)))
+    .service(
+        web::resource("/templates/{name}")
+            .route(web::put().to(put_template))
+            .route(web::delete().to(remove_template)),
+    )
+    .service(web::resource("/blocklist").route(web::get().to(list_blocklist)))
+    .service(
+        web::resource("/blocklist/{pattern}")
+            .route(web::put().to(put_blocked))
+            .route(web::delete().to(remove_blocked)),
+    )
+    .service(web::resource("/watch").route(web::get().to(list_watch_rules)))
+    .service(
+        web::resource("/watch/{name}")
+            .route(web::put().to(put_watch_rule))
+            .route(web::delete().to(remove_watch_rule)),
+    )
+    .service(
+        web::resource("/rooms/{name}/import")
+            .app_data(web::PayloadConfig::new(MAX_ARCHIVE_SIZE))
+            .route(web::post().to(import_history)),
+    )
+    .service(
+        web::resource("/rooms/{name}/tokens")
+            .route(web::get().to(list_tokens))
+            .route(web::post().to(issue_token)),
+    )
+    .service(web::resource("/tokens/{id}").route(web::delete().to(revoke_token)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_token() {
+        assert!(same_token(b"s3cret", b"s3cret"));
+        assert!(!same_token(b"s3cres", b"s3cret"));
+        assert!(!same_token(b"s3cret!", b"s3cret"));
+        assert!(!same_token(b"", b"s3cret"));
+    }
+}
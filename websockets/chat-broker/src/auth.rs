@@ -0,0 +1,206 @@
+//! Signing in on the websocket upgrade. With `JWT_SECRET` set, `/ws/` only
+//! upgrades connections carrying a JWT signed with it (HS256), as
+//! `Authorization: Bearer <jwt>` or, for browsers, which can't set headers on
+//! websockets, `?token=<jwt>`. The session takes its name from the token's
+//! `name` claim, or `sub` without one.
+
+use std::fmt;
+
+use actix_web::{HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::frames::valid_name;
+use crate::session::unix_millis;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Malformed,
+    /// only HS256 is accepted, carries the token's `alg`
+    Algorithm(String),
+    BadSignature,
+    Expired,
+    NotYetValid,
+    /// neither `name` nor `sub` is a usable client name
+    NoName,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "sign in with a bearer token"),
+            AuthError::Malformed => write!(f, "token isn't a JWT"),
+            AuthError::Algorithm(alg) => {
+                write!(f, "token signed with {}, only HS256 is accepted", alg)
+            }
+            AuthError::BadSignature => write!(f, "token signature doesn't match"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::NotYetValid => write!(f, "token isn't valid yet"),
+            AuthError::NoName => write!(f, "token names no one, set sub or name"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    name: Option<String>,
+    /// unix seconds
+    exp: Option<u64>,
+    nbf: Option<u64>,
+}
+
+/// The secret tokens are signed with, `None` if connections needn't sign in
+fn secret() -> Option<String> {
+    std::env::var("JWT_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+/// The token from `Authorization: Bearer ...`, or else the `token` parameter
+fn bearer_token<'a>(req: &'a HttpRequest, query: Option<&'a str>) -> Option<&'a str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or(query)
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, AuthError> {
+    let json = base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| AuthError::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| AuthError::Malformed)
+}
+
+/// The client name a JWT signed with `secret` vouches for at `now`, in unix
+/// seconds
+fn verify(token: &str, secret: &[u8], now: u64) -> Result<String, AuthError> {
+    let mut parts = token.split('.');
+    let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+            (header, claims, signature)
+        }
+        _ => return Err(AuthError::Malformed),
+    };
+
+    let Header { alg } = decode(header)?;
+    if alg != "HS256" {
+        return Err(AuthError::Algorithm(alg));
+    }
+
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| AuthError::Malformed)?;
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes any key size");
+    mac.update(format!("{}.{}", header, claims).as_bytes());
+    mac.verify(&signature)
+        .map_err(|_| AuthError::BadSignature)?;
+
+    let claims: Claims = decode(claims)?;
+    if claims.exp.is_some_and(|exp| exp <= now) {
+        return Err(AuthError::Expired);
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now) {
+        return Err(AuthError::NotYetValid);
+    }
+
+    claims
+        .name
+        .or(claims.sub)
+        .map(|name| name.trim().to_owned())
+        .filter(|name| valid_name(name))
+        .ok_or(AuthError::NoName)
+}
+
+/// The name the upgrade request signs in as, `None` if signing in is off.
+/// Answers with the 401 to send instead if the request doesn't sign in.
+pub fn authenticate(
+    req: &HttpRequest,
+    query_token: Option<&str>,
+) -> Result<Option<String>, HttpResponse> {
+    let secret = match secret() {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let now = unix_millis() as u64 / 1000;
+
+    bearer_token(req, query_token)
+        .ok_or(AuthError::Missing)
+        .and_then(|token| verify(token, secret.as_bytes(), now))
+        .map(Some)
+        .map_err(|err| {
+            HttpResponse::Unauthorized()
+                .header("WWW-Authenticate", "Bearer realm=\"chat\"")
+                .body(err.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(header: &str, claims: &str, secret: &[u8]) -> String {
+        let header = base64::encode_config(header, base64::URL_SAFE_NO_PAD);
+        let claims = base64::encode_config(claims, base64::URL_SAFE_NO_PAD);
+
+        let mut mac = Hmac::<Sha256>::new_varkey(secret).unwrap();
+        mac.update(format!("{}.{}", header, claims).as_bytes());
+        let signature = mac.finalize().into_bytes();
+
+        format!(
+            "{}.{}.{}",
+            header,
+            claims,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let hs256 = r#"{"alg":"HS256","typ":"JWT"}"#;
+        let token = |claims| sign(hs256, claims, b"secret");
+
+        assert_eq!(
+            verify(&token(r#"{"sub":"bob","exp":200}"#), b"secret", 100),
+            Ok("bob".to_owned())
+        );
+        // `name` wins over `sub`
+        assert_eq!(
+            verify(&token(r#"{"sub":"42","name":"alice"}"#), b"secret", 100),
+            Ok("alice".to_owned())
+        );
+
+        assert_eq!(
+            verify(&token(r#"{"sub":"bob"}"#), b"other", 100),
+            Err(AuthError::BadSignature)
+        );
+        assert_eq!(
+            verify(&token(r#"{"sub":"bob","exp":100}"#), b"secret", 100),
+            Err(AuthError::Expired)
+        );
+        assert_eq!(
+            verify(&token(r#"{"sub":"bob","nbf":101}"#), b"secret", 100),
+            Err(AuthError::NotYetValid)
+        );
+        assert_eq!(
+            verify(&token(r#"{"sub":"bob smith"}"#), b"secret", 100),
+            Err(AuthError::NoName)
+        );
+        assert_eq!(
+            verify(
+                &sign(r#"{"alg":"none"}"#, r#"{"sub":"bob"}"#, b"secret"),
+                b"secret",
+                100
+            ),
+            Err(AuthError::Algorithm("none".to_owned()))
+        );
+        assert_eq!(verify("bob", b"secret", 100), Err(AuthError::Malformed));
+    }
+}
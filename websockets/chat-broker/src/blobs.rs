@@ -0,0 +1,301 @@
+//! Attachments and voice notes kept for download, `GET /api/blobs/{id}`, so
+//! rooms are sent a link to them rather than their bytes. Blobs are kept in
+//! memory, or as files in `BLOB_DIR` when it's set, up to
+//! `MAX_BLOB_STORE_SIZE` bytes in all; the oldest are dropped to make room.
+//! Ids are random, so anyone with a link may download it, as with the
+//! bytes themselves once sent.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use actix_web::{web, Error, HttpResponse};
+use bytes::Bytes;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::random_token;
+use crate::config::config;
+
+static STORE: Lazy<Mutex<BlobStore>> = Lazy::new(|| {
+    let dir = std::env::var_os("BLOB_DIR").map(PathBuf::from);
+    let max_size = config().max_blob_store_size;
+    let store = match dir {
+        Some(dir) => BlobStore::open(dir, max_size),
+        None => Ok(BlobStore::new(None, max_size)),
+    };
+
+    Mutex::new(store.unwrap_or_else(|err| {
+        warn!("can't use BLOB_DIR, keeping blobs in memory: {}", err);
+        BlobStore::new(None, max_size)
+    }))
+});
+
+/// Written next to each blob in `BLOB_DIR`, as `{id}.json`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BlobInfo {
+    pub name: String,
+    pub mime: String,
+    pub size: usize,
+}
+
+struct Blob {
+    info: BlobInfo,
+    /// `None` when in `BLOB_DIR`
+    data: Option<Bytes>,
+}
+
+struct BlobStore {
+    dir: Option<PathBuf>,
+    capacity: usize,
+    used: usize,
+    /// oldest first
+    order: VecDeque<String>,
+    blobs: HashMap<String, Blob>,
+}
+
+/// Ids are what `random_token` makes, anything else can't be a blob and
+/// mustn't reach the file system
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn info_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+impl BlobStore {
+    fn new(dir: Option<PathBuf>, capacity: usize) -> Self {
+        BlobStore {
+            dir,
+            capacity,
+            used: 0,
+            order: VecDeque::new(),
+            blobs: HashMap::new(),
+        }
+    }
+
+    /// Picks up the blobs already in `dir`, oldest first
+    fn open(dir: PathBuf, capacity: usize) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id)
+                    if valid_id(id) && path.extension() == Some("json".as_ref()) =>
+                {
+                    id.to_owned()
+                }
+                _ => continue,
+            };
+            let info: BlobInfo =
+                match fs::read(&path).map(|json| serde_json::from_slice(&json)) {
+                    Ok(Ok(info)) => info,
+                    _ => continue,
+                };
+            let modified = fs::metadata(&path)?.modified()?;
+            found.push((modified, id, info));
+        }
+        found.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut store = BlobStore::new(Some(dir), capacity);
+        for (_, id, info) in found {
+            store.make_room(info.size)?;
+            store.used += info.size;
+            store.order.push_back(id.clone());
+            store.blobs.insert(id, Blob { info, data: None });
+        }
+
+        Ok(store)
+    }
+
+    /// Drops the oldest blobs until `size` more bytes fit
+    fn make_room(&mut self, size: usize) -> io::Result<()> {
+        while self.used + size > self.capacity {
+            let id = match self.order.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            if let Some(blob) = self.blobs.remove(&id) {
+                self.used -= blob.info.size;
+            }
+            // gone already if another node sharing the directory dropped it
+            if let Some(dir) = &self.dir {
+                let _ = fs::remove_file(info_path(dir, &id));
+                let _ = fs::remove_file(dir.join(&id));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn put(&mut self, id: String, info: BlobInfo, data: Vec<u8>) -> io::Result<()> {
+        if info.size > self.capacity {
+            return Err(io::Error::other("larger than the whole store"));
+        }
+        self.make_room(info.size)?;
+
+        let data = match &self.dir {
+            Some(dir) => {
+                // the data first, a blob is only listed once it's all there
+                fs::write(dir.join(&id), &data)?;
+                fs::write(info_path(dir, &id), serde_json::to_vec(&info)?)?;
+                None
+            }
+            None => Some(Bytes::from(data)),
+        };
+
+        self.used += info.size;
+        self.order.push_back(id.clone());
+        self.blobs.insert(id, Blob { info, data });
+
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<(BlobInfo, Bytes)>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+
+        match (self.blobs.get(id), &self.dir) {
+            (
+                Some(Blob {
+                    info,
+                    data: Some(data),
+                }),
+                _,
+            ) => Ok(Some((info.clone(), data.clone()))),
+            (Some(Blob { info, data: None }), Some(dir)) => {
+                let data = fs::read(dir.join(id))?;
+                Ok(Some((info.clone(), Bytes::from(data))))
+            }
+            // another node's, when nodes share the directory
+            (None, Some(dir)) => {
+                let info = match fs::read(info_path(dir, id)) {
+                    Ok(json) => serde_json::from_slice(&json)?,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(err),
+                };
+                let data = fs::read(dir.join(id))?;
+                Ok(Some((info, Bytes::from(data))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Keeps a blob, giving the path it can be downloaded from
+pub fn store(name: String, mime: String, data: Vec<u8>) -> io::Result<String> {
+    let id = random_token();
+    let info = BlobInfo {
+        name,
+        mime,
+        size: data.len(),
+    };
+
+    STORE
+        .lock()
+        .expect("blob store poisoned")
+        .put(id.clone(), info, data)?;
+
+    Ok(format!("/api/blobs/{}", id))
+}
+
+/// Types a browser may show in place, everything else is downloaded
+fn shown_inline(mime: &str) -> bool {
+    let mime = mime.to_ascii_lowercase();
+    let media = mime.starts_with("image/")
+        || mime.starts_with("audio/")
+        || mime.starts_with("video/");
+
+    // scripts run from SVGs opened on their own
+    media && !mime.starts_with("image/svg")
+}
+
+/// The file name as it can go in `Content-Disposition`
+fn header_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_control() || !c.is_ascii() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// The blob with the type it was sent with, if it's one browsers show,
+/// and as a download otherwise. 404 for blobs dropped or never kept.
+pub async fn download(id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let blob = STORE
+        .lock()
+        .expect("blob store poisoned")
+        .get(&id)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let (info, data) = match blob {
+        Some(blob) => blob,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let (mime, disposition) = if shown_inline(&info.mime) {
+        (info.mime.as_str(), "inline")
+    } else {
+        ("application/octet-stream", "attachment")
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(mime)
+        .header(
+            "Content-Disposition",
+            format!(
+                "{}; filename=\"{}\"",
+                disposition,
+                header_file_name(&info.name)
+            ),
+        )
+        .header("Cache-Control", "private, max-age=31536000, immutable")
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Content-Security-Policy", "default-src 'none'; sandbox")
+        .body(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(size: usize) -> BlobInfo {
+        BlobInfo {
+            name: "cat.png".to_owned(),
+            mime: "image/png".to_owned(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = BlobStore::new(None, 10);
+        store.put("a1".to_owned(), info(4), vec![1; 4]).unwrap();
+        store.put("b2".to_owned(), info(4), vec![2; 4]).unwrap();
+        assert_eq!(store.get("a1").unwrap().unwrap().1, Bytes::from(vec![1; 4]));
+
+        // the oldest goes to make room
+        store.put("c3".to_owned(), info(4), vec![3; 4]).unwrap();
+        assert!(store.get("a1").unwrap().is_none());
+        assert!(store.get("b2").unwrap().is_some());
+        assert_eq!(store.used, 8);
+
+        assert!(store.put("d4".to_owned(), info(11), vec![4; 11]).is_err());
+        assert!(store.get("../c3").unwrap().is_none());
+
+        assert!(shown_inline("image/PNG"));
+        assert!(!shown_inline("image/svg+xml"));
+        assert!(!shown_inline("text/html"));
+        assert_eq!(header_file_name("a\"b\nc.png"), "a_b_c.png");
+    }
+}
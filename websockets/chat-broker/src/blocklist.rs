@@ -0,0 +1,344 @@
+//! The operator's blocklist: words and phrases checked against everything
+//! posted, in every room, each with what happens to a message containing
+//! it. Patterns are read from the JSON file `BLOCKLIST` names, an object of
+//! rules by pattern, plus the comma-separated `BLOCKED_WORDS` as `drop`
+//! rules; the admin API's changes are saved back to the file. Without the
+//! variable they only live as long as the node.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// How long `mute` rules mute for unless they say otherwise
+const DEFAULT_MUTE: Duration = Duration::from_secs(10 * 60);
+
+const MAX_PATTERN_LEN: usize = 100;
+
+static BLOCKLIST: Lazy<Mutex<Blocklist>> = Lazy::new(|| Mutex::new(Blocklist::load()));
+
+/// What's done about a message matching a pattern, from the mildest; the
+/// strongest of a message's matches is the one taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockAction {
+    /// the match is starred out and the rest posted
+    Mask,
+    /// the message isn't posted and the sender is told what wasn't allowed
+    Warn,
+    /// the message isn't posted and counts against the sender's trust
+    Drop,
+    /// like `Drop`, and the sender is muted in the room
+    Mute,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlockRule {
+    pub action: BlockAction,
+    /// how long a `mute` rule mutes for, 10 minutes if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute_secs: Option<u64>,
+}
+
+/// What to do with a message, see `check`
+#[derive(Debug, PartialEq)]
+pub enum Verdict<'a> {
+    /// post it, as it is unless a `mask` pattern matched
+    Post(Cow<'a, str>),
+    /// refuse it, naming the pattern
+    Warn(String),
+    Drop,
+    Mute(Duration),
+}
+
+#[derive(Default)]
+struct Blocklist {
+    path: Option<PathBuf>,
+    /// by normalized pattern
+    rules: BTreeMap<String, BlockRule>,
+}
+
+/// The pattern as it's kept and compared: lower case, its words separated
+/// by single spaces. A word ending in `*` matches any word starting with it.
+fn normalize(pattern: &str) -> Result<String, String> {
+    let words: Vec<_> = pattern
+        .split(|c: char| !c.is_alphanumeric() && c != '*')
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    let valid = |word: &String| {
+        let stem = word.strip_suffix('*').unwrap_or(word);
+        !stem.is_empty() && !stem.contains('*')
+    };
+    if words.is_empty() || !words.iter().all(valid) {
+        return Err("pattern: expected words, each optionally ending in *".to_owned());
+    }
+
+    let pattern = words.join(" ");
+    if pattern.chars().count() > MAX_PATTERN_LEN {
+        return Err(format!(
+            "pattern: longer than {} characters",
+            MAX_PATTERN_LEN
+        ));
+    }
+
+    Ok(pattern)
+}
+
+/// Byte ranges of the words of `msg`
+fn words(msg: &str) -> Vec<Range<usize>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (at, c) in msg.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(at),
+            (false, Some(from)) => {
+                words.push(from..at);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        words.push(from..msg.len());
+    }
+
+    words
+}
+
+/// Byte ranges of `msg` the normalized `pattern` matches
+fn matches(msg: &str, words: &[Range<usize>], pattern: &str) -> Vec<Range<usize>> {
+    let pattern: Vec<_> = pattern.split(' ').collect();
+    let word_matches = |word: &Range<usize>, pattern: &str| {
+        let word = msg[word.clone()].to_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(stem) => word.starts_with(stem),
+            None => word == pattern,
+        }
+    };
+
+    words
+        .windows(pattern.len())
+        .filter(|window| {
+            window
+                .iter()
+                .zip(&pattern)
+                .all(|(word, pattern)| word_matches(word, pattern))
+        })
+        .map(|window| window[0].start..window[window.len() - 1].end)
+        .collect()
+}
+
+/// `msg` with the letters and digits in `ranges` starred out
+fn mask(msg: &str, ranges: &[Range<usize>]) -> String {
+    msg.char_indices()
+        .map(|(at, c)| {
+            let masked = ranges.iter().any(|range| range.contains(&at));
+            if masked && c.is_alphanumeric() {
+                '*'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+impl Blocklist {
+    fn load() -> Self {
+        let mut blocklist = Blocklist::default();
+
+        let words = std::env::var("BLOCKED_WORDS").unwrap_or_default();
+        for word in words.split(',').filter(|word| !word.trim().is_empty()) {
+            let rule = BlockRule {
+                action: BlockAction::Drop,
+                mute_secs: None,
+            };
+            if let Err(err) = blocklist.put(word, rule) {
+                warn!("Blocklist - ignoring blocked word {}: {}", word, err);
+            }
+        }
+
+        let path = match std::env::var("BLOCKLIST") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return blocklist,
+        };
+        let rules: BTreeMap<String, BlockRule> = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(rules) => rules,
+                Err(err) => {
+                    warn!("Blocklist - ignoring {}: {}", path.display(), err);
+                    return blocklist;
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        for (pattern, rule) in rules {
+            if let Err(err) = blocklist.put(&pattern, rule) {
+                warn!("Blocklist - ignoring pattern {}: {}", pattern, err);
+            }
+        }
+
+        info!(
+            "Blocklist - loaded {} patterns from {}",
+            blocklist.rules.len(),
+            path.display()
+        );
+        blocklist.path = Some(path);
+        blocklist
+    }
+
+    /// Written next to the file and renamed over it, like templates
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+
+        let res = serde_json::to_vec_pretty(&self.rules)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(err) = res {
+            warn!("Blocklist - can't save {}: {}", path.display(), err);
+        }
+    }
+
+    fn put(&mut self, pattern: &str, rule: BlockRule) -> Result<(), String> {
+        if rule.mute_secs == Some(0) {
+            return Err("mute_secs: expected a number from 1".to_owned());
+        }
+        self.rules.insert(normalize(pattern)?, rule);
+
+        Ok(())
+    }
+
+    fn check<'a>(&self, msg: &'a str) -> Verdict<'a> {
+        if self.rules.is_empty() {
+            return Verdict::Post(Cow::Borrowed(msg));
+        }
+
+        let words = words(msg);
+        let mut masked = Vec::new();
+        let mut strongest: Option<(&String, &BlockRule)> = None;
+        for (pattern, rule) in &self.rules {
+            let found = matches(msg, &words, pattern);
+            if found.is_empty() {
+                continue;
+            }
+            if rule.action == BlockAction::Mask {
+                masked.extend(found);
+            }
+
+            let stronger = strongest.is_none_or(|(_, strongest)| {
+                (rule.action, rule.mute_secs) > (strongest.action, strongest.mute_secs)
+            });
+            if stronger {
+                strongest = Some((pattern, rule));
+            }
+        }
+
+        match strongest {
+            None => Verdict::Post(Cow::Borrowed(msg)),
+            Some((pattern, rule)) => match rule.action {
+                BlockAction::Mask => Verdict::Post(Cow::Owned(mask(msg, &masked))),
+                BlockAction::Warn => Verdict::Warn(pattern.clone()),
+                BlockAction::Drop => Verdict::Drop,
+                BlockAction::Mute => Verdict::Mute(
+                    rule.mute_secs.map_or(DEFAULT_MUTE, Duration::from_secs),
+                ),
+            },
+        }
+    }
+}
+
+/// What the blocklist has to say about a message about to be posted
+pub fn check(msg: &str) -> Verdict<'_> {
+    BLOCKLIST.lock().expect("blocklist poisoned").check(msg)
+}
+
+pub fn rules() -> BTreeMap<String, BlockRule> {
+    BLOCKLIST.lock().expect("blocklist poisoned").rules.clone()
+}
+
+/// Adds or replaces the rule for `pattern`
+pub fn put(pattern: &str, rule: BlockRule) -> Result<(), String> {
+    let mut blocklist = BLOCKLIST.lock().expect("blocklist poisoned");
+    blocklist.put(pattern, rule)?;
+    blocklist.save();
+
+    Ok(())
+}
+
+/// Whether there was a rule for `pattern` to remove
+pub fn remove(pattern: &str) -> bool {
+    let mut blocklist = BLOCKLIST.lock().expect("blocklist poisoned");
+    let removed = normalize(pattern)
+        .is_ok_and(|pattern| blocklist.rules.remove(&pattern).is_some());
+    if removed {
+        blocklist.save();
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: BlockAction) -> BlockRule {
+        BlockRule {
+            action,
+            mute_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_blocklist() {
+        assert_eq!(normalize(" Buy  NOW! "), Ok("buy now".to_owned()));
+        assert_eq!(normalize("spam*"), Ok("spam*".to_owned()));
+        assert!(normalize("*").is_err());
+        assert!(normalize("s*am").is_err());
+
+        let mut blocklist = Blocklist::default();
+        assert_eq!(
+            blocklist.check("hello"),
+            Verdict::Post(Cow::Borrowed("hello"))
+        );
+
+        blocklist.put("darn", rule(BlockAction::Mask)).unwrap();
+        blocklist.put("buy now", rule(BlockAction::Warn)).unwrap();
+        blocklist.put("spam*", rule(BlockAction::Drop)).unwrap();
+        assert_eq!(
+            blocklist.check("Darn it, darnit"),
+            Verdict::Post(Cow::Owned("**** it, darnit".to_owned()))
+        );
+        assert_eq!(
+            blocklist.check("darn, BUY now!"),
+            Verdict::Warn("buy now".to_owned())
+        );
+        assert_eq!(
+            blocklist.check("buy something now"),
+            Verdict::Post(Cow::Borrowed("buy something now"))
+        );
+        assert_eq!(blocklist.check("the spammers"), Verdict::Drop);
+
+        let mute = BlockRule {
+            action: BlockAction::Mute,
+            mute_secs: Some(60),
+        };
+        blocklist.put("scam", mute).unwrap();
+        assert_eq!(
+            blocklist.check("spam scam"),
+            Verdict::Mute(Duration::from_secs(60))
+        );
+    }
+}
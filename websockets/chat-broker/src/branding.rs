@@ -0,0 +1,176 @@
+//! White-labelling the bundled web client: the title, logo, colors and welcome
+//! copy of `static/index.html` come from the environment, filled in where the
+//! page says `{{title}}` and the like when the node starts.
+//!
+//! - `BRAND_TITLE`: the page title and heading
+//! - `BRAND_LOGO_URL`: an image shown next to the heading
+//! - `BRAND_ACCENT_COLOR`, `BRAND_BACKGROUND_COLOR`, `BRAND_TEXT_COLOR`: CSS
+//!   colors, e.g. `#0a7cff` or `navy`
+//! - `BRAND_WELCOME`: a paragraph of text above the chat log
+
+use std::fs;
+
+use actix_web::{web, HttpResponse};
+use log::warn;
+
+/// The page, in the directory `Files` serves
+const INDEX: &str = "./static/index.html";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Branding {
+    title: Option<String>,
+    logo_url: Option<String>,
+    accent_color: Option<String>,
+    background_color: Option<String>,
+    text_color: Option<String>,
+    welcome: Option<String>,
+}
+
+/// The rendered page, as served on `/`, `None` if it couldn't be read
+#[derive(Clone)]
+pub struct IndexPage(Option<String>);
+
+fn env(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+/// A hex color or a color name, which can't break out of the style sheet
+fn is_color(value: &str) -> bool {
+    let name = value.strip_prefix('#').unwrap_or(value);
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+impl Branding {
+    /// Colors that aren't are left out, with a warning
+    pub fn from_env() -> Self {
+        let color = |key| {
+            env(key).filter(|value| {
+                let valid = is_color(value);
+                if !valid {
+                    warn!("{} isn't a color, ignoring it: {:?}", key, value);
+                }
+                valid
+            })
+        };
+
+        Branding {
+            title: env("BRAND_TITLE"),
+            logo_url: env("BRAND_LOGO_URL"),
+            accent_color: color("BRAND_ACCENT_COLOR"),
+            background_color: color("BRAND_BACKGROUND_COLOR"),
+            text_color: color("BRAND_TEXT_COLOR"),
+            welcome: env("BRAND_WELCOME"),
+        }
+    }
+
+    /// Reads the page and brands it. Run from elsewhere than the crate's
+    /// directory, the node goes without.
+    pub fn index_page(&self) -> IndexPage {
+        match fs::read_to_string(INDEX) {
+            Ok(page) => IndexPage(Some(self.render(&page))),
+            Err(err) => {
+                warn!("can't read {}, not serving it: {}", INDEX, err);
+                IndexPage(None)
+            }
+        }
+    }
+
+    fn render(&self, page: &str) -> String {
+        let title = self.title.as_deref();
+        let logo = match &self.logo_url {
+            Some(url) => {
+                format!(r#"<img id="logo" src="{}" alt="" />"#, escape_html(url))
+            }
+            None => String::new(),
+        };
+        let welcome = match &self.welcome {
+            Some(welcome) => format!(r#"<p id="welcome">{}</p>"#, escape_html(welcome)),
+            None => String::new(),
+        };
+        let colors = [
+            ("--accent", &self.accent_color, "inherit"),
+            ("--background", &self.background_color, "white"),
+            ("--text", &self.text_color, "black"),
+        ];
+        let colors: String = colors
+            .iter()
+            .map(|(name, color, default)| {
+                format!("{}: {};", name, color.as_deref().unwrap_or(default))
+            })
+            .collect();
+
+        page.replace(
+            "{{title}}",
+            &escape_html(title.unwrap_or("Websocket Chat Broker")),
+        )
+        .replace("{{heading}}", &escape_html(title.unwrap_or("Chat!")))
+        .replace("{{logo}}", &logo)
+        .replace("{{colors}}", &colors)
+        .replace("{{welcome}}", &welcome)
+    }
+}
+
+/// `GET /`
+pub async fn index(page: web::Data<IndexPage>) -> HttpResponse {
+    match &page.0 {
+        Some(page) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(page.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = "<title>{{title}}</title>{{logo}}<h1>{{heading}}</h1>:root { {{colors}} }{{welcome}}";
+
+        assert_eq!(
+            Branding::default().render(page),
+            "<title>Websocket Chat Broker</title><h1>Chat!</h1>:root { --accent: inherit;--background: white;--text: black; }"
+        );
+
+        let branding = Branding {
+            title: Some("Acme <Support>".to_owned()),
+            logo_url: Some("/logo.png?a=1&b=\"2\"".to_owned()),
+            accent_color: Some("#0a7cff".to_owned()),
+            welcome: Some("Ask us anything & we'll answer".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            branding.render(page),
+            "<title>Acme &lt;Support&gt;</title>\
+             <img id=\"logo\" src=\"/logo.png?a=1&amp;b=&quot;2&quot;\" alt=\"\" />\
+             <h1>Acme &lt;Support&gt;</h1>\
+             :root { --accent: #0a7cff;--background: white;--text: black; }\
+             <p id=\"welcome\">Ask us anything &amp; we&#39;ll answer</p>"
+        );
+
+        assert!(is_color("navy"));
+        assert!(is_color("#fff"));
+        assert!(!is_color("red; } body { display: none"));
+        assert!(!is_color("#"));
+    }
+}
@@ -0,0 +1,131 @@
+//! Circuit breakers for the services outside the node: webhook receivers, the
+//! CAPTCHA provider and the SMTP server. After a run of failed or slow calls
+//! the circuit opens and calls fail straight away, so a struggling service
+//! isn't sent more requests to pile up waiting on it. Once the cool-off is
+//! over a single call is let through to probe it, closing the circuit again
+//! if it succeeds.
+
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Failed calls in a row that open the circuit
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long an open circuit refuses calls before probing
+pub const COOL_OFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Circuit {
+    Closed,
+    Open,
+    /// a probe is on its way
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// calls taking longer count as failed, even if they succeed
+    slow: Duration,
+    failures: u32,
+    circuit: Circuit,
+    /// when the circuit last opened or was probed
+    changed: Instant,
+}
+
+impl CircuitBreaker {
+    pub fn new(slow: Duration) -> Self {
+        CircuitBreaker {
+            slow,
+            failures: 0,
+            circuit: Circuit::Closed,
+            changed: Instant::now(),
+        }
+    }
+
+    pub fn circuit(&self) -> Circuit {
+        self.circuit
+    }
+
+    /// Whether a call may be made now. Past the cool-off, the first one asking
+    /// is the probe; a probe that never reports back is given up on after
+    /// another one.
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&mut self, now: Instant) -> bool {
+        if self.circuit == Circuit::Closed {
+            return true;
+        }
+        if now.saturating_duration_since(self.changed) < COOL_OFF {
+            return false;
+        }
+
+        self.circuit = Circuit::HalfOpen;
+        self.changed = now;
+        true
+    }
+
+    /// Counts a call that was allowed. True if this opened the circuit, for
+    /// the caller to log.
+    pub fn record(&mut self, ok: bool, elapsed: Duration) -> bool {
+        self.record_at(ok, elapsed, Instant::now())
+    }
+
+    fn record_at(&mut self, ok: bool, elapsed: Duration, now: Instant) -> bool {
+        if ok && elapsed <= self.slow {
+            self.failures = 0;
+            self.circuit = Circuit::Closed;
+            return false;
+        }
+
+        self.failures += 1;
+        let open =
+            self.circuit == Circuit::HalfOpen || self.failures >= FAILURE_THRESHOLD;
+        if !open || self.circuit == Circuit::Open {
+            return false;
+        }
+
+        self.circuit = Circuit::Open;
+        self.changed = now;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let fast = Duration::from_millis(10);
+        let mut breaker = CircuitBreaker::new(Duration::from_secs(1));
+        let start = breaker.changed;
+
+        for _ in 1..FAILURE_THRESHOLD {
+            assert!(breaker.allow_at(start));
+            assert!(!breaker.record_at(false, fast, start));
+        }
+        // slow calls count as failed too
+        assert!(breaker.record_at(true, Duration::from_secs(2), start));
+        assert_eq!(breaker.circuit(), Circuit::Open);
+        assert!(!breaker.allow_at(start + COOL_OFF / 2));
+
+        // one probe after the cool-off, failing opens it again
+        let later = start + COOL_OFF;
+        assert!(breaker.allow_at(later));
+        assert_eq!(breaker.circuit(), Circuit::HalfOpen);
+        assert!(!breaker.allow_at(later));
+        assert!(breaker.record_at(false, fast, later));
+        assert!(!breaker.allow_at(later + COOL_OFF / 2));
+
+        // a successful probe closes it
+        let later = later + COOL_OFF;
+        assert!(breaker.allow_at(later));
+        assert!(!breaker.record_at(true, fast, later));
+        assert_eq!(breaker.circuit(), Circuit::Closed);
+        assert!(breaker.allow_at(later));
+    }
+}
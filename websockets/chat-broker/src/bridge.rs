@@ -0,0 +1,219 @@
+#[cfg(feature = "redis")]
+use std::env;
+use std::net::SocketAddr;
+
+use actix::io::SinkWrite;
+use actix::prelude::*;
+use actix_broker::BrokerSubscribe;
+use bytes::{Bytes, BytesMut};
+#[cfg(feature = "redis")]
+use futures::channel::mpsc;
+use futures::stream::{SplitSink, StreamExt};
+use log::{info, warn};
+use tokio::net::UdpSocket;
+use tokio_util::codec::BytesCodec;
+use tokio_util::udp::UdpFramed;
+
+use crate::cluster::{BridgeIn, BridgeOut, Envelope};
+use crate::server::WsChatServer;
+
+type SinkItem = (Bytes, SocketAddr);
+type UdpSink = SplitSink<UdpFramed<BytesCodec>, SinkItem>;
+
+/// Simplest possible bridge: JSON envelopes sent as UDP datagrams to a fixed
+/// list of peers. Enabled by setting `BRIDGE_BIND` (e.g. `0.0.0.0:7070`) and
+/// `BRIDGE_PEERS` (comma-separated `host:port` of the other nodes). Datagrams
+/// from anywhere else are dropped, since envelopes are trusted like the node's
+/// own sessions.
+pub struct UdpBridge {
+    sink: SinkWrite<SinkItem, UdpSink>,
+    peers: Vec<SocketAddr>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Datagram(BytesMut, SocketAddr);
+
+impl UdpBridge {
+    /// Starts the bridge if `BRIDGE_BIND` is set
+    pub async fn from_env() -> std::io::Result<Option<Addr<UdpBridge>>> {
+        let bind: SocketAddr = match std::env::var("BRIDGE_BIND") {
+            Ok(bind) => bind.parse().map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+            })?,
+            Err(_) => return Ok(None),
+        };
+
+        let peers = std::env::var("BRIDGE_PEERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|peer| {
+                let peer = peer.trim();
+                if peer.is_empty() {
+                    return None;
+                }
+
+                peer.parse()
+                    .map_err(|err| warn!("UdpBridge - bad peer {:?}: {}", peer, err))
+                    .ok()
+            })
+            .collect::<Vec<SocketAddr>>();
+
+        let sock = UdpSocket::bind(&bind).await?;
+        info!("UdpBridge - listening on {}, peers: {:?}", &bind, &peers);
+
+        let (sink, stream) = UdpFramed::new(sock, BytesCodec::new()).split();
+
+        Ok(Some(UdpBridge::create(|ctx| {
+            ctx.add_stream(stream.filter_map(
+                |item: std::io::Result<(BytesMut, SocketAddr)>| async {
+                    item.map(|(data, sender)| Datagram(data, sender)).ok()
+                },
+            ));
+
+            UdpBridge {
+                sink: SinkWrite::new(sink, ctx),
+                peers,
+            }
+        })))
+    }
+}
+
+impl Actor for UdpBridge {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.subscribe_system_async::<BridgeOut>(ctx);
+    }
+}
+
+impl Handler<BridgeOut> for UdpBridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: BridgeOut, _ctx: &mut Self::Context) {
+        let BridgeOut(envelope) = msg;
+
+        let data = match serde_json::to_vec(&envelope) {
+            Ok(data) => Bytes::from(data),
+            Err(err) => {
+                warn!("UdpBridge - can't serialize envelope: {}", err);
+                return;
+            }
+        };
+
+        for peer in &self.peers {
+            if self.sink.write((data.clone(), *peer)).is_some() {
+                warn!("UdpBridge - send to {} failed", peer);
+            }
+        }
+    }
+}
+
+impl StreamHandler<Datagram> for UdpBridge {
+    fn handle(&mut self, msg: Datagram, _ctx: &mut Self::Context) {
+        let Datagram(data, sender) = msg;
+
+        if !self.peers.contains(&sender) {
+            warn!("UdpBridge - dropping datagram from {}, not a peer", sender);
+            return;
+        }
+
+        match serde_json::from_slice::<Envelope>(&data) {
+            Ok(envelope) => WsChatServer::from_registry().do_send(BridgeIn(envelope)),
+            Err(err) => warn!("UdpBridge - bad datagram from {}: {}", sender, err),
+        }
+    }
+}
+
+impl actix::io::WriteHandler<std::io::Error> for UdpBridge {}
+
+/// Relays envelopes through a Redis pub/sub channel, so any number of nodes
+/// can share one Redis without knowing each other, e.g. behind a load
+/// balancer. Built with the `redis` feature and enabled by setting
+/// `REDIS_URL` (e.g. `redis://127.0.0.1/`), `REDIS_CHANNEL` picks the channel,
+/// `chat-broker` by default. Every node gets its own envelopes back, which
+/// `WsChatServer` drops like any it has seen.
+#[cfg(feature = "redis")]
+pub struct RedisBridge {
+    /// envelopes waiting to be published, in order
+    outbox: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBridge {
+    /// Starts the bridge if `REDIS_URL` is set
+    pub async fn from_env() -> redis::RedisResult<Option<Addr<RedisBridge>>> {
+        let url = match env::var("REDIS_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let channel =
+            env::var("REDIS_CHANNEL").unwrap_or_else(|_| "chat-broker".to_owned());
+
+        let client = redis::Client::open(url.as_str())?;
+        // a subscribed connection can't publish, so there is one for each
+        let mut publisher = client.get_async_connection().await?;
+        let mut subscriber = client.get_async_connection().await?.into_pubsub();
+        subscriber.subscribe(&channel).await?;
+        info!("RedisBridge - relaying through {} on {}", &channel, &url);
+
+        let (outbox, mut queued) = mpsc::unbounded::<Vec<u8>>();
+        let publish_to = channel.clone();
+        actix::spawn(async move {
+            while let Some(data) = queued.next().await {
+                let published: redis::RedisResult<()> = redis::cmd("PUBLISH")
+                    .arg(&publish_to)
+                    .arg(data)
+                    .query_async(&mut publisher)
+                    .await;
+                if let Err(err) = published {
+                    warn!("RedisBridge - publish failed: {}", err);
+                }
+            }
+        });
+
+        actix::spawn(async move {
+            let mut messages = subscriber.on_message();
+            while let Some(msg) = messages.next().await {
+                match serde_json::from_slice::<Envelope>(msg.get_payload_bytes()) {
+                    Ok(envelope) => {
+                        WsChatServer::from_registry().do_send(BridgeIn(envelope))
+                    }
+                    Err(err) => {
+                        warn!("RedisBridge - bad message on {}: {}", &channel, err)
+                    }
+                }
+            }
+            warn!("RedisBridge - subscription to {} ended", &channel);
+        });
+
+        Ok(Some(RedisBridge { outbox }.start()))
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Actor for RedisBridge {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.subscribe_system_async::<BridgeOut>(ctx);
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Handler<BridgeOut> for RedisBridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: BridgeOut, _ctx: &mut Self::Context) {
+        let BridgeOut(envelope) = msg;
+
+        match serde_json::to_vec(&envelope) {
+            Ok(data) => {
+                if self.outbox.unbounded_send(data).is_err() {
+                    warn!("RedisBridge - publisher stopped");
+                }
+            }
+            Err(err) => warn!("RedisBridge - can't serialize envelope: {}", err),
+        }
+    }
+}
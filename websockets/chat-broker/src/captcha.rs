@@ -0,0 +1,133 @@
+//! CAPTCHA challenge for anonymous sessions. With `CAPTCHA_SECRET` set, a
+//! session that isn't logged in has to pass the provider's challenge before
+//! its first message is accepted: the client renders the widget with
+//! `CAPTCHA_SITE_KEY` and relays the token it gets in a `captcha` frame, which
+//! the server checks with the provider's siteverify endpoint.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::breaker::{CircuitBreaker, COOL_OFF};
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks taking longer count against the provider's circuit breaker
+const SLOW_VERIFY: Duration = Duration::from_secs(3);
+
+static BREAKER: Lazy<Mutex<CircuitBreaker>> =
+    Lazy::new(|| Mutex::new(CircuitBreaker::new(SLOW_VERIFY)));
+
+/// Tokens are a couple of kilobytes at most
+pub const MAX_TOKEN_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Provider {
+    Turnstile,
+    HCaptcha,
+}
+
+impl Provider {
+    fn verify_url(self) -> &'static str {
+        match self {
+            Provider::Turnstile => {
+                "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+            }
+            Provider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Turnstile => "turnstile",
+            Provider::HCaptcha => "hcaptcha",
+        }
+    }
+}
+
+pub struct Captcha {
+    pub provider: Provider,
+    /// public, for the client's widget
+    pub site_key: String,
+    secret: String,
+    /// `CAPTCHA_VERIFY_URL`, the provider's siteverify endpoint by default
+    verify_url: String,
+}
+
+/// Configured from `CAPTCHA_PROVIDER` (`turnstile`, the default, or
+/// `hcaptcha`), `CAPTCHA_SITE_KEY` and `CAPTCHA_SECRET`, none without a secret
+pub static CAPTCHA: Lazy<Option<Captcha>> = Lazy::new(|| {
+    let secret = std::env::var("CAPTCHA_SECRET").ok()?;
+
+    let provider = match std::env::var("CAPTCHA_PROVIDER").as_deref() {
+        Ok("hcaptcha") => Provider::HCaptcha,
+        _ => Provider::Turnstile,
+    };
+
+    Some(Captcha {
+        provider,
+        site_key: std::env::var("CAPTCHA_SITE_KEY").unwrap_or_default(),
+        secret,
+        verify_url: std::env::var("CAPTCHA_VERIFY_URL")
+            .unwrap_or_else(|_| provider.verify_url().to_owned()),
+    })
+});
+
+/// The part of the siteverify answer both providers share
+#[derive(Deserialize)]
+struct Verdict {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+impl Captcha {
+    /// Asks the provider whether the token is from a passed challenge. While
+    /// the provider keeps failing it isn't asked, and the client is told to
+    /// come back later.
+    pub async fn verify(&self, token: String) -> Result<(), String> {
+        if !BREAKER.lock().unwrap().allow() {
+            return Err("captcha provider unavailable, try again later".to_owned());
+        }
+
+        let sent = Instant::now();
+        let verdict = self.ask(&token).await;
+        if BREAKER
+            .lock()
+            .unwrap()
+            .record(verdict.is_ok(), sent.elapsed())
+        {
+            warn!(
+                "Captcha - {} keeps failing, not asking it for {}s",
+                self.provider.name(),
+                COOL_OFF.as_secs()
+            );
+        }
+        let verdict = verdict?;
+
+        if verdict.success {
+            Ok(())
+        } else {
+            Err(format!(
+                "captcha failed ({}), try again",
+                verdict.error_codes.join(", ")
+            ))
+        }
+    }
+
+    async fn ask(&self, token: &str) -> Result<Verdict, String> {
+        let mut res = awc::Client::new()
+            .post(&self.verify_url)
+            .timeout(VERIFY_TIMEOUT)
+            .send_form(&[("secret", self.secret.as_str()), ("response", token)])
+            .await
+            .map_err(|err| format!("captcha provider unreachable: {}", err))?;
+
+        res.json()
+            .await
+            .map_err(|err| format!("bad answer from captcha provider: {}", err))
+    }
+}
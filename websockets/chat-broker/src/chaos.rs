@@ -0,0 +1,106 @@
+//! Chaos mode, for trying clients and their reconnect logic against a server
+//! that misbehaves. Only builds with the `chaos` feature turn it on, with any
+//! of:
+//!
+//! - `CHAOS_LATENCY_MS`, and `CHAOS_JITTER_MS` on top at random: how long
+//!   room traffic is held back before it goes out to each client
+//! - `CHAOS_DROP_RATE`: the share of frames from and to clients lost, 0 to 1
+//! - `CHAOS_DISCONNECTS_PER_MIN`: how often each session is cut off, without
+//!   a close frame, on average
+//! - `CHAOS_STALL_MS`: how long at most the chat server blocks on each
+//!   message, stalling every room of the node
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+#[derive(Debug)]
+pub struct Chaos {
+    latency: Duration,
+    jitter: Duration,
+    drop_rate: f64,
+    /// chance of a disconnect each time `disconnects` is asked
+    disconnect_rate: f64,
+    stall: Duration,
+}
+
+/// How often sessions roll for a disconnect
+pub const DISCONNECT_CHECK: Duration = Duration::from_secs(1);
+
+/// The faults to inject, `None` unless built with `chaos` and configured
+pub static CHAOS: Lazy<Option<Chaos>> = Lazy::new(|| {
+    if !cfg!(feature = "chaos") {
+        return None;
+    }
+
+    let env = |key| -> f64 {
+        std::env::var(key)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|value: &f64| *value > 0.0)
+            .unwrap_or(0.0)
+    };
+    let ms = |key| Duration::from_millis(env(key) as u64);
+
+    let chaos = Chaos {
+        latency: ms("CHAOS_LATENCY_MS"),
+        jitter: ms("CHAOS_JITTER_MS"),
+        drop_rate: env("CHAOS_DROP_RATE").min(1.0),
+        disconnect_rate: (env("CHAOS_DISCONNECTS_PER_MIN") / 60.0
+            * DISCONNECT_CHECK.as_secs_f64())
+        .min(1.0),
+        stall: ms("CHAOS_STALL_MS"),
+    };
+    let off = chaos.latency == Duration::from_secs(0)
+        && chaos.jitter == Duration::from_secs(0)
+        && chaos.drop_rate == 0.0
+        && chaos.disconnect_rate == 0.0
+        && chaos.stall == Duration::from_secs(0);
+    if off {
+        return None;
+    }
+
+    warn!("chaos mode is on: {:?}", chaos);
+    Some(chaos)
+});
+
+fn up_to(max: Duration) -> Duration {
+    match max.as_millis() as u64 {
+        0 => Duration::from_secs(0),
+        max => Duration::from_millis(rand::thread_rng().gen_range(0, max + 1)),
+    }
+}
+
+impl Chaos {
+    /// When a frame handed over now should go out, `after` being when the one
+    /// before it does, so frames are held back but not reordered
+    pub fn due(&self, after: Option<Instant>) -> Instant {
+        let due = Instant::now() + self.latency + up_to(self.jitter);
+        after.map_or(due, |after| due.max(after))
+    }
+
+    /// Whether to lose the frame at hand
+    pub fn drops(&self) -> bool {
+        self.drop_rate > 0.0 && rand::random::<f64>() < self.drop_rate
+    }
+
+    /// Whether to cut the session off, asked every `DISCONNECT_CHECK`
+    pub fn disconnects(&self) -> bool {
+        self.disconnect_rate > 0.0 && rand::random::<f64>() < self.disconnect_rate
+    }
+
+    pub fn disconnecting(&self) -> bool {
+        self.disconnect_rate > 0.0
+    }
+
+    /// Blocks the calling thread for a while, on an actor like a slow
+    /// handler would
+    pub fn stall(&self) {
+        let stall = up_to(self.stall);
+        if stall > Duration::from_secs(0) {
+            std::thread::sleep(stall);
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Plumbing for running several chat-broker nodes side by side.
+//!
+//! Nodes don't talk to each other directly. `WsChatServer` issues every
+//! locally sent message as a [`BridgeOut`] on the system broker, and a bridge
+//! actor (Redis pub/sub, NATS, ...) relays it to its peers, which hand it to
+//! their own `WsChatServer` as a [`BridgeIn`]. Every envelope is tagged with
+//! its origin node and a per-node message id, so a message relayed more than
+//! once can be recognised and dropped.
+//!
+//! Which nodes are alive is tracked by `membership::Membership`, and each room
+//! is homed on one of them by a [`HashRing`].
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::prelude::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::drafts::Draft;
+use crate::frames::Ephemeral;
+use crate::hours::OpeningHours;
+use crate::journal::EventKind;
+use crate::migration::SessionState;
+use crate::server::{Question, RoomAccess};
+use crate::settings::{RoomSettings, Setting};
+
+/// A client anywhere in the cluster: (node id, client id)
+pub type ClientRef = (String, usize);
+
+/// Points each node gets on the hash ring, more points spread rooms more evenly
+const VIRTUAL_NODES: usize = 64;
+
+/// Identity of this process in the cluster, from `NODE_ID` or random
+pub static NODE_ID: Lazy<String> = Lazy::new(|| {
+    std::env::var("NODE_ID")
+        .unwrap_or_else(|_| format!("node-{}", hex::encode(rand::random::<[u8; 4]>())))
+});
+
+/// Starts at the boot time in microseconds, so a node restarted with the same
+/// `NODE_ID` carries on above the ids it used before, which peers may still
+/// have in their `DedupeCache`
+static LAST_ENVELOPE_ID: Lazy<AtomicU64> = Lazy::new(|| {
+    let booted = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64);
+
+    AtomicU64::new(booted)
+});
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Payload {
+    /// something that happened in a room, for its members on every node.
+    /// `log` is false for rooms flagged `no_log`, whose events nodes without
+    /// a copy of the settings mustn't journal either.
+    Event {
+        room_name: String,
+        event: EventKind,
+        log: bool,
+    },
+    /// every client name connected to the origin node, announced periodically
+    /// and whenever it changes, with the accounts of those signed in
+    Names {
+        names: Vec<String>,
+        #[serde(default)]
+        accounts: HashMap<String, String>,
+    },
+    /// a client on the origin node claimed `name` on login or resuming, see
+    /// `RegisterName`, so whoever had it here goes without it
+    NameClaimed { name: String },
+    /// sent to `room_name` by the origin node's client `from`
+    Ephemeral {
+        room_name: String,
+        from: String,
+        event: Ephemeral,
+    },
+    /// text for the client named `to`, connected to `to_node`, e.g. a
+    /// private message
+    Whisper {
+        to_node: String,
+        to: String,
+        text: String,
+    },
+    /// a message or attachment sent on the origin node to a room homed on
+    /// `to_node`
+    Forward {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+        event: EventKind,
+    },
+    /// a client of the origin node joined a room homed on `to_node`
+    Join {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+        client_name: String,
+    },
+    /// a room setting changed on the origin node for a room homed on `to_node`
+    Manage {
+        to_node: String,
+        room_name: String,
+        /// `None` for the admin API, which may change any room
+        client_id: Option<usize>,
+        action: RoomAction,
+    },
+    /// the home node of `room_name` let the client see the room's event log,
+    /// which its own node sends from its journal
+    EventLog {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+        count: usize,
+    },
+    /// asks the home node of `room_name` whether the origin node's client
+    /// owns it, before the cross-post `post` goes out to any room
+    CheckOwner {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+        post: u64,
+    },
+    /// the answer to a `CheckOwner`
+    OwnerChecked {
+        to_node: String,
+        room_name: String,
+        post: u64,
+        owner: bool,
+    },
+    /// text for a single client of `to_node`, e.g. a rejected `Forward`
+    Reply {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+        text: String,
+    },
+    /// the home node of `room_name` registered an alias for it
+    Aliased { alias: String, room_name: String },
+    /// the home node of `room_name` replaced its join code, `None` removed it
+    JoinCode {
+        room_name: String,
+        code: Option<String>,
+    },
+    /// the home node of `room_name` changed who may join it
+    Access {
+        room_name: String,
+        access: RoomAccess,
+    },
+    /// the origin node's view of the cluster: node id -> heartbeat count
+    Heartbeat { members: HashMap<String, u64> },
+    /// `room_name` moved to `to_node`, which should take over its state
+    Handoff {
+        to_node: String,
+        room_name: String,
+        owner: ClientRef,
+        archived: bool,
+        hours: Option<OpeningHours>,
+        aliases: Vec<String>,
+        settings: RoomSettings,
+        join_code: Option<String>,
+        questions: Vec<Question>,
+        canned: BTreeMap<String, String>,
+    },
+    /// the home node's settings of `room_name`, for nodes with clients in it
+    RoomSettings {
+        room_name: String,
+        settings: RoomSettings,
+    },
+    /// `room_name` moved to `to_node` and the origin node has clients in it,
+    /// sent in case the previous home died along with the room's state
+    Rehome {
+        to_node: String,
+        room_name: String,
+        client_id: usize,
+    },
+    /// a session of the draining origin node, resumable with `token`
+    Session { token: String, state: SessionState },
+    /// the session for `token` was resumed
+    SessionTaken { token: String },
+    /// an account's draft for a room was saved, or discarded if `None`
+    Draft {
+        account: String,
+        room_name: String,
+        draft: Option<Draft>,
+    },
+}
+
+/// Room settings changes that are applied by the room's home node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomAction {
+    Archive(bool),
+    Hours(Option<OpeningHours>),
+    Alias(String),
+    Stream(StreamAction),
+    Settings(Vec<Setting>),
+    JoinCode(CodeAction),
+    Hand(HandAction),
+    Question(QuestionAction),
+    Moderate(ModAction),
+    /// one copy of an `EventKind::CrossPost`
+    CrossPost(Box<EventKind>),
+    /// an edit or deletion of a cross-post, made in another of its rooms
+    CopyChange(Box<EventKind>),
+    Access(AccessAction),
+    /// saves a canned response, or removes it if `content` is `None`
+    Canned {
+        name: String,
+        content: Option<String>,
+    },
+    /// the room's last events, for its owner
+    EventLog(usize),
+    /// an operator's message to every member, from the admin API
+    Announce(String),
+    /// removes every member and forgets the room, from the admin API
+    Delete,
+    /// replaces the text of a chat message, its author only
+    EditMessage {
+        id: u64,
+        text: String,
+    },
+    /// takes a chat message back, its author only
+    DeleteMessage(u64),
+    /// reacts to a chat message, or takes the reaction back
+    React {
+        id: u64,
+        emoji: String,
+    },
+}
+
+impl RoomAction {
+    pub fn owner_only(&self) -> bool {
+        !matches!(
+            self,
+            RoomAction::Stream(_)
+                | RoomAction::Hand(HandAction::Raise(_))
+                | RoomAction::Hand(HandAction::Lower)
+                | RoomAction::Question(QuestionAction::Ask { .. })
+                | RoomAction::Question(QuestionAction::Upvote(_))
+                | RoomAction::EditMessage { .. }
+                | RoomAction::DeleteMessage(_)
+                | RoomAction::React { .. }
+        )
+    }
+}
+
+/// The speaker queue of moderated rooms. Members raise their hand, the owner
+/// sees the queue and calls on someone, who may then post for a while.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HandAction {
+    /// carries the member's name, so the owner can call on them by it
+    Raise(String),
+    Lower,
+    /// owners only
+    List,
+    /// owners only
+    CallOn(String),
+}
+
+/// Keeping members in line, owners only. Members are named by their client
+/// name, bans also cover the account of that name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ModAction {
+    /// out of the room, free to join again
+    Kick(String),
+    /// out of the room, and refused when joining or posting until unbanned
+    Ban(String),
+    Unban(String),
+    /// may stay but not post, for that many seconds
+    Mute(String, u64),
+    Unmute(String),
+    /// opens a private room for the owner `by` and `with`, see
+    /// `EventKind::Breakout`
+    Breakout {
+        by: String,
+        with: String,
+    },
+}
+
+/// Questions of rooms in Q&A mode, by their id within the room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum QuestionAction {
+    Ask {
+        from: String,
+        text: String,
+    },
+    Upvote(u64),
+    /// owners only
+    Answer(u64),
+}
+
+/// Shows, replaces or removes a room's join code, see `WsChatServer::join_codes`
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum CodeAction {
+    /// creates one if the room has none
+    Show,
+    Rotate,
+    Remove,
+}
+
+/// Changes who may join a room, see `WsChatServer::access`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AccessAction {
+    Open,
+    /// carries the password's hash, see `password_hash`
+    Password(String),
+    /// carries the owner's name, invited from the start so they can come back
+    InviteOnly(String),
+    Invite(String),
+}
+
+/// Live stream announcements, open to every room member. The media itself is
+/// negotiated between the clients through `Signal` frames.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StreamAction {
+    Start {
+        host: String,
+        title: String,
+    },
+    Join(String),
+    Leave(String),
+    /// host or room owner only
+    End(String),
+}
+
+fn ring_hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Consistent hash ring assigning every room a home node. Adding or removing
+/// a node only moves the rooms that hash next to it.
+#[derive(Default)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<'a>(nodes: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut ring = BTreeMap::new();
+
+        for node in nodes {
+            for vnode in 0..VIRTUAL_NODES {
+                ring.insert(ring_hash(&format!("{}#{}", node, vnode)), node.to_owned());
+            }
+        }
+
+        HashRing { ring }
+    }
+
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        self.ring
+            .range(ring_hash(key)..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    /// `NODE_ID` of the node the message was first sent on
+    pub origin: String,
+    /// unique per origin node
+    pub id: u64,
+    pub payload: Payload,
+}
+
+impl Envelope {
+    /// Wraps a payload sent from this node
+    pub fn new(payload: Payload) -> Self {
+        Envelope {
+            origin: NODE_ID.clone(),
+            id: LAST_ENVELOPE_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            payload,
+        }
+    }
+}
+
+/// A local message for bridges to relay to the other nodes
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct BridgeOut(pub Envelope);
+
+/// A message relayed in from another node
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct BridgeIn(pub Envelope);
+
+/// A heartbeat relayed in from `origin`, for `Membership`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Gossip {
+    pub origin: String,
+    pub members: HashMap<String, u64>,
+}
+
+/// The set of live nodes, this one included, changed. Sorted by node id.
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct MembersChanged(pub Vec<String>);
+
+/// Bounded memory of recently seen envelopes, the oldest are forgotten first
+pub struct DedupeCache {
+    capacity: usize,
+    seen: HashSet<(String, u64)>,
+    order: VecDeque<(String, u64)>,
+}
+
+impl DedupeCache {
+    pub fn new(capacity: usize) -> Self {
+        DedupeCache {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records the envelope, returns false if it was already seen
+    pub fn insert(&mut self, envelope: &Envelope) -> bool {
+        let key = (envelope.origin.clone(), envelope.id);
+
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+impl Default for DedupeCache {
+    fn default() -> Self {
+        DedupeCache::new(10_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(origin: &str, id: u64) -> Envelope {
+        Envelope {
+            origin: origin.to_owned(),
+            id,
+            payload: Payload::Event {
+                room_name: "Main".to_owned(),
+                event: EventKind::message("hi".to_owned()),
+                log: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_hash_ring() {
+        let rooms: Vec<String> = (0..200).map(|i| format!("room-{}", i)).collect();
+
+        let two = HashRing::new(vec!["a", "b"]);
+        let three = HashRing::new(vec!["a", "b", "c"]);
+
+        let on_c = rooms
+            .iter()
+            .filter(|room| three.node_for(room) == Some("c"))
+            .count();
+        assert!(on_c > 20 && on_c < 120, "rooms on c: {}", on_c);
+
+        // adding c only moves rooms onto c
+        for room in &rooms {
+            let before = two.node_for(room).unwrap();
+            let after = three.node_for(room).unwrap();
+            assert!(after == before || after == "c");
+        }
+
+        assert_eq!(HashRing::new(vec![]).node_for("Main"), None);
+    }
+
+    #[test]
+    fn test_dedupe_cache() {
+        let mut cache = DedupeCache::new(2);
+
+        assert!(cache.insert(&envelope("a", 1)));
+        assert!(!cache.insert(&envelope("a", 1)));
+        assert!(cache.insert(&envelope("b", 1)));
+
+        // evicts ("a", 1)
+        assert!(cache.insert(&envelope("a", 2)));
+        assert!(cache.insert(&envelope("a", 1)));
+        assert!(!cache.insert(&envelope("a", 2)));
+    }
+}
@@ -0,0 +1,922 @@
+//! Slash commands. Each is a `Command` found by its name or one of its
+//! aliases in `REGISTRY`, which also writes `/help` from the commands'
+//! usage and summary, so a new command is one more entry in `BUILTINS`
+//! calling the session method that does the work.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use actix_web_actors::ws;
+use once_cell::sync::Lazy;
+
+use crate::cluster::{AccessAction, CodeAction, HandAction, ModAction, QuestionAction};
+use crate::journal::{EVENT_LOG_SIZE, MAX_EVENT_LOG_SIZE};
+use crate::message::Reply;
+use crate::scheduler::parse_delay;
+use crate::server::password_hash;
+use crate::session::WsChatSession;
+
+pub type Ctx = ws::WebsocketContext<WsChatSession>;
+
+/// The arguments didn't fit the command, answered with its usage
+#[derive(Debug, PartialEq)]
+pub struct UsageError;
+
+/// A slash command
+pub trait Command: Sync {
+    /// without the slash
+    fn name(&self) -> &'static str;
+
+    /// other names it goes by, without the slash
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// the arguments it takes, e.g. `room [password]`
+    fn usage(&self) -> &'static str;
+
+    /// one line for `/help`
+    fn summary(&self) -> &'static str;
+
+    /// `Err` gets the client the command's usage
+    fn run(
+        &self,
+        session: &mut WsChatSession,
+        args: Args<'_>,
+        ctx: &mut Ctx,
+    ) -> Result<(), UsageError>;
+}
+
+/// The arguments after a command's name, taken a word at a time
+pub struct Args<'a> {
+    rest: &'a str,
+    /// unix milliseconds the command arrived at
+    pub received: u128,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str, received: u128) -> Self {
+        Args {
+            rest: args,
+            received,
+        }
+    }
+
+    /// The next word, `None` once there are no more
+    pub fn word(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (word, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        self.rest = rest;
+        Some(word)
+    }
+
+    pub fn required(&mut self) -> Result<&'a str, UsageError> {
+        self.word().ok_or(UsageError)
+    }
+
+    /// The next word as a `T`
+    pub fn parse<T: FromStr>(&mut self) -> Result<T, UsageError> {
+        self.required()?.parse().map_err(|_| UsageError)
+    }
+
+    /// Everything not taken yet, trimmed, empty if nothing is left
+    pub fn rest(&mut self) -> &'a str {
+        std::mem::take(&mut self.rest).trim()
+    }
+
+    pub fn required_rest(&mut self) -> Result<&'a str, UsageError> {
+        Some(self.rest())
+            .filter(|rest| !rest.is_empty())
+            .ok_or(UsageError)
+    }
+}
+
+type Run = fn(&mut WsChatSession, Args<'_>, &mut Ctx) -> Result<(), UsageError>;
+
+/// A command that's a function, as all the built-in ones are
+pub struct Builtin {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub run: Run,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        self.aliases
+    }
+
+    fn usage(&self) -> &'static str {
+        self.usage
+    }
+
+    fn summary(&self) -> &'static str {
+        self.summary
+    }
+
+    fn run(
+        &self,
+        session: &mut WsChatSession,
+        args: Args<'_>,
+        ctx: &mut Ctx,
+    ) -> Result<(), UsageError> {
+        (self.run)(session, args, ctx)
+    }
+}
+
+/// The commands, in the order `/help` lists them
+pub struct Registry {
+    commands: Vec<&'static dyn Command>,
+    /// index into `commands` by name and alias
+    by_name: HashMap<&'static str, usize>,
+}
+
+impl Registry {
+    pub fn new(commands: Vec<&'static dyn Command>) -> Self {
+        let mut by_name = HashMap::new();
+        for (index, command) in commands.iter().enumerate() {
+            for name in Some(command.name()).iter().chain(command.aliases()) {
+                let taken = by_name.insert(*name, index);
+                assert!(taken.is_none(), "two commands are called /{}", name);
+            }
+        }
+
+        Registry { commands, by_name }
+    }
+
+    /// By name or alias, without the slash
+    pub fn find(&self, name: &str) -> Option<&'static dyn Command> {
+        self.by_name.get(name).map(|index| self.commands[*index])
+    }
+
+    /// The line `/help` has for the command
+    fn help_line(command: &dyn Command) -> String {
+        let mut line = usage(command);
+        for alias in command.aliases() {
+            line.push_str(&format!(", /{}", alias));
+        }
+        format!("{} - {}", line, command.summary())
+    }
+
+    /// Every command or, given a name, just that one
+    pub fn help(&self, name: Option<&str>) -> Option<Vec<String>> {
+        match name {
+            Some(name) => {
+                let command = self.find(name.trim_start_matches('/'))?;
+                Some(vec![Registry::help_line(command)])
+            }
+            None => Some(
+                self.commands
+                    .iter()
+                    .map(|command| Registry::help_line(*command))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// `/name args`, as in usage replies
+fn usage(command: &dyn Command) -> String {
+    match command.usage() {
+        "" => format!("/{}", command.name()),
+        usage => format!("/{} {}", command.name(), usage),
+    }
+}
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(|| {
+    Registry::new(
+        BUILTINS
+            .iter()
+            .map(|command| command as &'static dyn Command)
+            .collect(),
+    )
+});
+
+/// Runs the slash command `msg`, replying with its usage if the arguments
+/// don't fit it
+pub fn dispatch(session: &mut WsChatSession, msg: &str, received: u128, ctx: &mut Ctx) {
+    let line = msg.trim_start_matches('/');
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+
+    match REGISTRY.find(name) {
+        Some(command) => {
+            if command
+                .run(session, Args::new(args, received), ctx)
+                .is_err()
+            {
+                session.reply(ctx, Reply::error(format!("usage: {}", usage(command))));
+            }
+        }
+        None => session.reply(ctx, Reply::error(format!("unknown command: {:?}", msg))),
+    }
+}
+
+fn moderate(
+    session: &mut WsChatSession,
+    mut args: Args<'_>,
+    ctx: &mut Ctx,
+    action: fn(String) -> ModAction,
+) -> Result<(), UsageError> {
+    let name = args.required()?;
+    session.moderate(action(name.to_owned()), ctx);
+    Ok(())
+}
+
+static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "help",
+        aliases: &["?"],
+        usage: "[command]",
+        summary: "list the commands, or show how to use one",
+        run: |session, mut args, ctx| {
+            let name = args.word();
+            match REGISTRY.help(name) {
+                Some(lines) => {
+                    for line in lines {
+                        session.reply(ctx, Reply::notice(line));
+                    }
+                }
+                None => session.reply(
+                    ctx,
+                    Reply::error(format!("unknown command: /{}", name.unwrap_or_default())),
+                ),
+            }
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "list",
+        aliases: &[],
+        usage: "[token]",
+        summary: "list the available rooms, 50 at a time",
+        run: |session, mut args, ctx| {
+            session.list_rooms(args.word(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "create",
+        aliases: &[],
+        usage: "name [--template template]",
+        summary: "create a room that doesn't exist yet and join it",
+        run: |session, mut args, ctx| {
+            session.create_room(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "join",
+        aliases: &["j"],
+        usage: "room [password]",
+        summary: "join a room, creating it if it doesn't exist",
+        run: |session, mut args, ctx| {
+            match args.word() {
+                Some(room_name) => {
+                    let password = Some(args.rest())
+                        .filter(|password| !password.is_empty())
+                        .map(str::to_owned);
+                    session.join_room(room_name, password, ctx);
+                }
+                None => session.reply(ctx, Reply::error("room name is required")),
+            }
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "access",
+        aliases: &[],
+        usage: "open|invite|password secret",
+        summary: "let anyone, only the invited, or those with the password join, room owners only",
+        run: |session, mut args, ctx| {
+            let action = match (args.required()?, args.rest()) {
+                ("open", "") => AccessAction::Open,
+                ("invite", "") => AccessAction::InviteOnly(session.client_name()),
+                ("password", password) if !password.is_empty() => {
+                    AccessAction::Password(password_hash(session.room_name(), password))
+                }
+                _ => return Err(UsageError),
+            };
+            session.access(action, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "invite",
+        aliases: &[],
+        usage: "name",
+        summary: "let someone into an invite only room, room owners only",
+        run: |session, mut args, ctx| {
+            let name = args.required()?;
+            session.access(AccessAction::Invite(name.to_owned()), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "leave",
+        aliases: &["part"],
+        usage: "[room]",
+        summary: "leave a room, the current one by default",
+        run: |session, mut args, ctx| {
+            session.leave_room(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "switch",
+        aliases: &[],
+        usage: "room",
+        summary: "send messages to another room joined, without leaving this one",
+        run: |session, mut args, ctx| {
+            let room_name = args.required()?;
+            session.switch_room(room_name, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "join-code",
+        aliases: &[],
+        usage: "CODE",
+        summary: "join the room a join code belongs to",
+        run: |session, mut args, ctx| {
+            let code = args.required()?;
+            session.join_by_code(code, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "code",
+        aliases: &[],
+        usage: "[new|off]",
+        summary: "show, replace or remove this room's join code, room owners only",
+        run: |session, mut args, ctx| {
+            let action = match args.rest() {
+                "" => CodeAction::Show,
+                "new" => CodeAction::Rotate,
+                "off" => CodeAction::Remove,
+                _ => return Err(UsageError),
+            };
+            session.join_code(action, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "raise-hand",
+        aliases: &[],
+        usage: "",
+        summary: "ask to speak in a moderated room",
+        run: |session, _, ctx| {
+            session.hand(HandAction::Raise(session.client_name()), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "lower-hand",
+        aliases: &[],
+        usage: "",
+        summary: "take back a raised hand",
+        run: |session, _, ctx| {
+            session.hand(HandAction::Lower, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "hands",
+        aliases: &[],
+        usage: "",
+        summary: "list the raised hands, room owners only",
+        run: |session, _, ctx| {
+            session.hand(HandAction::List, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "call-on",
+        aliases: &[],
+        usage: "name",
+        summary: "let a member with a raised hand post for 5 minutes, room owners only",
+        run: |session, mut args, ctx| {
+            let name = args.required()?;
+            session.hand(HandAction::CallOn(name.to_owned()), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "kick",
+        aliases: &[],
+        usage: "name",
+        summary: "remove a member from the room, room owners only",
+        run: |session, args, ctx| moderate(session, args, ctx, ModAction::Kick),
+    },
+    Builtin {
+        name: "ban",
+        aliases: &[],
+        usage: "name",
+        summary: "remove a member and keep them out, room owners only",
+        run: |session, args, ctx| moderate(session, args, ctx, ModAction::Ban),
+    },
+    Builtin {
+        name: "unban",
+        aliases: &[],
+        usage: "name",
+        summary: "let a banned member back in, room owners only",
+        run: |session, args, ctx| moderate(session, args, ctx, ModAction::Unban),
+    },
+    Builtin {
+        name: "mute",
+        aliases: &[],
+        usage: "name 10m",
+        summary: "stop a member posting for a while, room owners only",
+        run: |session, mut args, ctx| {
+            let name = args.required()?;
+            match parse_delay(args.required_rest()?) {
+                Ok(delay) => {
+                    let action = ModAction::Mute(name.to_owned(), delay.as_secs());
+                    session.moderate(action, ctx);
+                }
+                Err(err) => session.reply(ctx, Reply::error(err)),
+            }
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "unmute",
+        aliases: &[],
+        usage: "name",
+        summary: "let a muted member post again, room owners only",
+        run: |session, args, ctx| moderate(session, args, ctx, ModAction::Unmute),
+    },
+    Builtin {
+        name: "breakout",
+        aliases: &[],
+        usage: "name",
+        summary: "take a member aside to a private room, room owners only",
+        run: |session, mut args, ctx| {
+            let action = ModAction::Breakout {
+                by: session.client_name(),
+                with: args.required()?.to_owned(),
+            };
+            session.moderate(action, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "events",
+        aliases: &[],
+        usage: "[n]",
+        summary: "the room's last 20 (or n, up to 200) events besides chat, room owners only",
+        run: |session, mut args, ctx| {
+            let count = match args.word() {
+                None => EVENT_LOG_SIZE,
+                Some(count) => match count.parse() {
+                    Ok(count) if (1..=MAX_EVENT_LOG_SIZE).contains(&count) => count,
+                    _ => return Err(UsageError),
+                },
+            };
+            session.event_log(count, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "ask",
+        aliases: &[],
+        usage: "question",
+        summary: "ask a question in a room in Q&A mode",
+        run: |session, mut args, ctx| {
+            let action = QuestionAction::Ask {
+                from: session.client_name(),
+                text: args.required_rest()?.to_owned(),
+            };
+            session.question(action, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "upvote",
+        aliases: &[],
+        usage: "id",
+        summary: "upvote a question, once per member",
+        run: |session, mut args, ctx| {
+            session.question(QuestionAction::Upvote(args.parse()?), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "answered",
+        aliases: &[],
+        usage: "id",
+        summary: "mark a question as answered, room owners only",
+        run: |session, mut args, ctx| {
+            session.question(QuestionAction::Answer(args.parse()?), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "questions",
+        aliases: &[],
+        usage: "",
+        summary: "list this room's questions, the most upvoted open ones first",
+        run: |session, _, ctx| {
+            session.list_questions(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "schedule",
+        aliases: &[],
+        usage: "15m message",
+        summary: "post a message to this room later, up to 7 days",
+        run: |session, mut args, ctx| {
+            session.schedule(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "scheduled",
+        aliases: &[],
+        usage: "",
+        summary: "list your scheduled messages",
+        run: |session, _, ctx| {
+            session.list_scheduled(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "unschedule",
+        aliases: &[],
+        usage: "id",
+        summary: "cancel a scheduled message",
+        run: |session, mut args, ctx| {
+            session.unschedule(args.parse()?, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "remind",
+        aliases: &[],
+        usage: "me 2h text",
+        summary: "get text whispered back to you later, named clients only",
+        run: |session, mut args, ctx| {
+            session.remind(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "reminders",
+        aliases: &[],
+        usage: "",
+        summary: "list your reminders",
+        run: |session, _, ctx| {
+            session.list_reminders(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "name",
+        aliases: &["nick"],
+        usage: "name",
+        summary: "set your name, unless another client on any node goes by it",
+        run: |session, mut args, ctx| {
+            match args.word() {
+                Some(name) => session.set_name(name.to_owned(), false, ctx),
+                None => session.reply(ctx, Reply::error("name is required")),
+            }
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "msg",
+        aliases: &["pm"],
+        usage: "name message",
+        summary: "send a private message",
+        run: |session, mut args, ctx| {
+            let to = args.required()?;
+            let content = args.required_rest()?;
+            session.private_message(to, content, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "login",
+        aliases: &[],
+        usage: "name password",
+        summary: "log in to a registered account",
+        run: |session, mut args, ctx| {
+            let name = args.required()?;
+            let password = args.required_rest()?;
+            session.login(name, password, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "alias",
+        aliases: &[],
+        usage: "alias room",
+        summary: "make /join alias enter room, room owners only",
+        run: |session, mut args, ctx| {
+            let alias = args.required()?;
+            let room_name = args.required()?;
+            session.add_alias(alias, room_name, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "archive",
+        aliases: &[],
+        usage: "",
+        summary: "freeze this room read-only, room owners only",
+        run: |session, _, ctx| {
+            session.archive_room(true, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "unarchive",
+        aliases: &[],
+        usage: "",
+        summary: "make an archived room writable again, room owners only",
+        run: |session, _, ctx| {
+            session.archive_room(false, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "hours",
+        aliases: &[],
+        usage: "HH:MM-HH:MM|off",
+        summary: "only accept messages in this daily UTC window, room owners only",
+        run: |session, mut args, ctx| {
+            session.set_opening_hours(args.required_rest()?, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "notify",
+        aliases: &[],
+        usage: "all|mentions|off",
+        summary: "choose which messages of this room are emailed to you while away",
+        run: |session, mut args, ctx| {
+            session.notify(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "settings",
+        aliases: &[],
+        usage: "[key value]",
+        summary: "show this room's settings or change one, room owners only",
+        run: |session, mut args, ctx| {
+            session.room_settings(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "list-clients",
+        aliases: &["who"],
+        usage: "",
+        summary: "list the clients in this room on this node",
+        run: |session, _, ctx| {
+            session.list_clients(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "whoami",
+        aliases: &[],
+        usage: "",
+        summary: "get your name, id and room",
+        run: |session, _, ctx| {
+            session.who_am_i(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "trust",
+        aliases: &[],
+        usage: "",
+        summary: "show your trust score and what it lets you do",
+        run: |session, _, ctx| {
+            session.show_trust(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "captcha",
+        aliases: &[],
+        usage: "token",
+        summary: "pass the captcha with the token it gave",
+        run: |session, mut args, ctx| {
+            session.captcha(args.required()?.to_owned(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "team",
+        aliases: &[],
+        usage: "[command]",
+        summary: "list your teams or manage one",
+        run: |session, mut args, ctx| {
+            session.team(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "read",
+        aliases: &[],
+        usage: "[room]",
+        summary: "mark a room read, the current one by default",
+        run: |session, mut args, ctx| {
+            let room_name = match args.word() {
+                Some(room_name) => room_name.to_owned(),
+                None => session.room_name().to_owned(),
+            };
+            session.mark_read(room_name, None, false, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "unread",
+        aliases: &[],
+        usage: "",
+        summary: "count the unread messages of your rooms",
+        run: |session, _, ctx| {
+            session.unread(false, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "typing",
+        aliases: &[],
+        usage: "[off]",
+        summary: "show the room you are typing, for 5 seconds unless sent again",
+        run: |session, mut args, ctx| {
+            session.typing(args.rest() != "off", ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "edit",
+        aliases: &[],
+        usage: "id new text",
+        summary: "change a message you sent to the current room, by the id in its ack",
+        run: |session, mut args, ctx| {
+            let id = args.parse()?;
+            session.edit_message(id, args.required_rest()?, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "delete",
+        aliases: &[],
+        usage: "id",
+        summary: "take back a message you sent to the current room",
+        run: |session, mut args, ctx| {
+            let id = args.parse()?;
+            if !args.rest().is_empty() {
+                return Err(UsageError);
+            }
+            session.delete_message(id, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "react",
+        aliases: &[],
+        usage: "id emoji",
+        summary: "react to a message in the current room, again to take it back",
+        run: |session, mut args, ctx| {
+            let id = args.parse()?;
+            session.react(id, args.required_rest()?, ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "star",
+        aliases: &[],
+        usage: "id",
+        summary: "save a message of a room you are in, logged in users only",
+        run: |session, mut args, ctx| {
+            session.star(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "unstar",
+        aliases: &[],
+        usage: "id",
+        summary: "drop a starred message",
+        run: |session, mut args, ctx| {
+            session.unstar(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "starred",
+        aliases: &[],
+        usage: "",
+        summary: "list your starred messages",
+        run: |session, _, ctx| {
+            session.starred(ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "canned",
+        aliases: &[],
+        usage: "[name|add name text|remove name]",
+        summary: "list or post this room's canned responses, or manage them as its owner",
+        run: |session, mut args, ctx| {
+            session.canned(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "forward",
+        aliases: &[],
+        usage: "id room",
+        summary: "repost a message of a room you are in to another room you are in",
+        run: |session, mut args, ctx| {
+            session.forward(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "xpost",
+        aliases: &[],
+        usage: "#room #room message",
+        summary: "post one announcement to several rooms you own",
+        run: |session, mut args, ctx| {
+            session.cross_post(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "sessions",
+        aliases: &[],
+        usage: "[command]",
+        summary: "list or sign out your account's other sessions",
+        run: |session, mut args, ctx| {
+            session.sessions(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "report",
+        aliases: &[],
+        usage: "name reason",
+        summary: "report an abusive client, logged in users only",
+        run: |session, mut args, ctx| {
+            session.report(args.rest(), ctx);
+            Ok(())
+        },
+    },
+    Builtin {
+        name: "time_sync",
+        aliases: &[],
+        usage: "[client_time]",
+        summary: "get server receive and transmit times for clock sync",
+        run: |session, mut args, ctx| {
+            let client_time = args.word().and_then(|time| time.parse().ok());
+            session.time_sync(client_time, args.received, ctx);
+            Ok(())
+        },
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let mut args = Args::new("  bob  the rest ", 0);
+        assert_eq!(args.word(), Some("bob"));
+        assert_eq!(args.rest(), "the rest");
+        assert_eq!(args.word(), None);
+        assert_eq!(args.required_rest(), Err(UsageError));
+
+        let mut args = Args::new("12 x", 0);
+        assert_eq!(args.parse::<u64>(), Ok(12));
+        assert_eq!(args.parse::<u64>(), Err(UsageError));
+
+        assert_eq!(
+            REGISTRY.find("j").map(|command| command.name()),
+            Some("join")
+        );
+        assert!(REGISTRY.find("frob").is_none());
+        let join = REGISTRY.help(Some("/join")).unwrap();
+        assert_eq!(
+            join,
+            ["/join room [password], /j - join a room, creating it if it doesn't exist"]
+        );
+        assert_eq!(REGISTRY.help(None).unwrap().len(), BUILTINS.len());
+    }
+}
@@ -0,0 +1,406 @@
+//! The node's tunables: where it listens, how big rooms, their history,
+//! messages and attachments get, how often clients are pinged, how fast they
+//! may post and how long the node waits on itself. They come from a
+//! TOML file, `CONFIG_FILE` or else `chat-broker.toml` in the working
+//! directory if there is one, with each setting overridden by its
+//! environment variable if that is set, e.g.
+//!
+//! ```toml
+//! port = 9000
+//! max_room_size = 50
+//! message_rate = 2
+//! ```
+//!
+//! with `PORT=9001` on top. Anything left out keeps its default.
+
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::accounts::SessionPolicy;
+
+/// The file read if `CONFIG_FILE` isn't set, when it exists
+const DEFAULT_FILE: &str = "chat-broker.toml";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `HOST`
+    pub host: String,
+    /// `PORT`
+    pub port: u16,
+    /// most clients in a room at once, counted per node, unless the room's
+    /// own `capacity` says otherwise. `MAX_ROOM_SIZE`, unlimited if unset.
+    pub max_room_size: Option<usize>,
+    /// events the journal keeps per room, and the most a room's `retention`
+    /// may ask for, `HISTORY_DEPTH`
+    pub history_depth: usize,
+    /// how often clients are pinged and get a stats frame,
+    /// `PING_INTERVAL_SECS`
+    pub ping_interval_secs: u64,
+    /// how long a client may go without sending anything, pongs included,
+    /// before its connection is taken for dead, `CLIENT_TIMEOUT_SECS`
+    pub client_timeout_secs: u64,
+    /// how often the node gossips its view of the cluster,
+    /// `GOSSIP_INTERVAL_SECS`
+    pub gossip_interval_secs: u64,
+    /// chat messages a client may send per second, and in a burst,
+    /// `MESSAGE_RATE`
+    pub message_rate: u32,
+    /// messages dropped for going over `message_rate` within a minute that
+    /// get the connection closed, `FLOOD_STRIKES`
+    pub flood_strikes: usize,
+    /// whether clients may have their frames compressed, `COMPRESSION`, off
+    /// unless set as it takes memory for every connection, see `deflate`
+    pub compression: bool,
+    /// whiteboard ops a client may send per second, `DRAW_RATE`
+    pub draw_rate: u32,
+    /// characters a chat message may have, `MAX_MESSAGE_LEN`
+    pub max_message_len: usize,
+    /// bytes an inline attachment may have, `MAX_ATTACHMENT_SIZE`
+    pub max_attachment_size: usize,
+    /// bytes of attachments the node keeps in all, `MAX_BLOB_STORE_SIZE`
+    pub max_blob_store_size: usize,
+    /// how long sessions wait for an answer before telling the client the
+    /// server is busy, `REQUEST_TIMEOUT_SECS`
+    pub request_timeout_secs: u64,
+    /// broadcast latency the server aims to stay under, and requests are
+    /// held to, `LATENCY_BUDGET_MS`
+    pub latency_budget_ms: u64,
+    /// how long a migrated or disconnected session waits for its client to
+    /// reconnect, `RESUME_GRACE_SECS`
+    pub resume_grace_secs: u64,
+    /// how many sessions may be logged in as one account,
+    /// `SESSIONS_PER_ACCOUNT`
+    pub sessions_per_account: SessionPolicy,
+    /// rooms every new session joins, none for a lobby where clients have to
+    /// `/join` one first. `DEFAULT_ROOMS`, comma-separated.
+    pub default_rooms: Vec<String>,
+    /// longest voice note accepted, `MAX_VOICE_NOTE_SECS`
+    pub max_voice_note_secs: u64,
+    /// trust score needed to post links, `TRUST_LINKS`
+    pub trust_links: i64,
+    /// trust score needed to send attachments and voice notes,
+    /// `TRUST_UPLOADS`
+    pub trust_uploads: i64,
+    /// trust score needed to create a room by joining it, `TRUST_CREATE_ROOM`
+    pub trust_create_room: i64,
+    /// how old an account, or a session without one, has to be before it may
+    /// post links and uploads, `NEWCOMER_AGE_SECS`
+    pub newcomer_age_secs: u64,
+    /// how many chat messages it has to have sent before that,
+    /// `NEWCOMER_MESSAGES`
+    pub newcomer_messages: u64,
+    /// how often the mentions queued for a user are mailed to them,
+    /// `MAIL_BATCH_SECS`
+    pub mail_batch_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_owned(),
+            port: 8080,
+            max_room_size: None,
+            history_depth: 1000,
+            ping_interval_secs: 10,
+            client_timeout_secs: 30,
+            gossip_interval_secs: 5,
+            message_rate: 5,
+            flood_strikes: 10,
+            compression: false,
+            draw_rate: 30,
+            max_message_len: 4096,
+            max_attachment_size: 256 * 1024,
+            max_blob_store_size: 64 * 1024 * 1024,
+            request_timeout_secs: 5,
+            latency_budget_ms: 200,
+            resume_grace_secs: 60,
+            sessions_per_account: SessionPolicy::Unlimited,
+            default_rooms: vec!["Main".to_owned()],
+            max_voice_note_secs: 120,
+            trust_links: 10,
+            trust_uploads: 20,
+            trust_create_room: 0,
+            newcomer_age_secs: 600,
+            newcomer_messages: 3,
+            mail_batch_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    /// an environment variable that doesn't parse, and its value
+    Env(&'static str, String),
+    /// a setting that must be positive
+    Zero(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "can't read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "invalid {}: {}", path, err),
+            ConfigError::Env(key, value) => write!(f, "invalid {}: {:?}", key, value),
+            ConfigError::Zero(key) => write!(f, "{} must be more than 0", key),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The node's config, the defaults until `Config::init` ran, as in unit tests
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+fn parse<T: FromStr>(
+    key: &'static str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<Option<T>, ConfigError> {
+    match env(key) {
+        Some(value) if !value.is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Env(key, value)),
+        _ => Ok(None),
+    }
+}
+
+fn set<T: FromStr>(
+    field: &mut T,
+    key: &'static str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    if let Some(value) = parse(key, env)? {
+        *field = value;
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Loads the config for the node, before anything reads it
+    pub fn init() -> Result<&'static Config, ConfigError> {
+        let env = |key: &str| std::env::var(key).ok();
+
+        let (path, required) = match env("CONFIG_FILE") {
+            Some(path) => (path, true),
+            None => (DEFAULT_FILE.to_owned(), false),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(err) if required || err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ConfigError::Read(path, err))
+            }
+            Err(_) => None,
+        };
+
+        let config = Config::load(text.as_deref(), &path, env)?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    fn load(
+        text: Option<&str>,
+        path: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let mut config = match text {
+            Some(text) => toml::from_str(text)
+                .map_err(|err| ConfigError::Parse(path.to_owned(), err))?,
+            None => Config::default(),
+        };
+
+        set(&mut config.host, "HOST", &env)?;
+        set(&mut config.port, "PORT", &env)?;
+        if let Some(size) = parse("MAX_ROOM_SIZE", &env)? {
+            config.max_room_size = Some(size);
+        }
+        set(&mut config.history_depth, "HISTORY_DEPTH", &env)?;
+        set(&mut config.ping_interval_secs, "PING_INTERVAL_SECS", &env)?;
+        set(&mut config.client_timeout_secs, "CLIENT_TIMEOUT_SECS", &env)?;
+        set(
+            &mut config.gossip_interval_secs,
+            "GOSSIP_INTERVAL_SECS",
+            &env,
+        )?;
+        set(&mut config.message_rate, "MESSAGE_RATE", &env)?;
+        set(&mut config.flood_strikes, "FLOOD_STRIKES", &env)?;
+        set(&mut config.compression, "COMPRESSION", &env)?;
+        set(&mut config.draw_rate, "DRAW_RATE", &env)?;
+        set(&mut config.max_message_len, "MAX_MESSAGE_LEN", &env)?;
+        set(&mut config.max_attachment_size, "MAX_ATTACHMENT_SIZE", &env)?;
+        set(&mut config.max_blob_store_size, "MAX_BLOB_STORE_SIZE", &env)?;
+        set(
+            &mut config.request_timeout_secs,
+            "REQUEST_TIMEOUT_SECS",
+            &env,
+        )?;
+        set(&mut config.latency_budget_ms, "LATENCY_BUDGET_MS", &env)?;
+        set(&mut config.resume_grace_secs, "RESUME_GRACE_SECS", &env)?;
+        set(
+            &mut config.sessions_per_account,
+            "SESSIONS_PER_ACCOUNT",
+            &env,
+        )?;
+        // set but empty means no default rooms, unlike the other variables
+        if let Some(rooms) = env("DEFAULT_ROOMS") {
+            config.default_rooms = rooms.split(',').map(str::to_owned).collect();
+        }
+        set(&mut config.max_voice_note_secs, "MAX_VOICE_NOTE_SECS", &env)?;
+        set(&mut config.trust_links, "TRUST_LINKS", &env)?;
+        set(&mut config.trust_uploads, "TRUST_UPLOADS", &env)?;
+        set(&mut config.trust_create_room, "TRUST_CREATE_ROOM", &env)?;
+        set(&mut config.newcomer_age_secs, "NEWCOMER_AGE_SECS", &env)?;
+        set(&mut config.newcomer_messages, "NEWCOMER_MESSAGES", &env)?;
+        set(&mut config.mail_batch_secs, "MAIL_BATCH_SECS", &env)?;
+
+        config.default_rooms = config
+            .default_rooms
+            .iter()
+            .map(|room| room.trim())
+            .filter(|room| !room.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        let positive = [
+            ("max_room_size", config.max_room_size.unwrap_or(1) as u64),
+            ("history_depth", config.history_depth as u64),
+            ("ping_interval_secs", config.ping_interval_secs),
+            ("client_timeout_secs", config.client_timeout_secs),
+            ("gossip_interval_secs", config.gossip_interval_secs),
+            ("message_rate", u64::from(config.message_rate)),
+            ("flood_strikes", config.flood_strikes as u64),
+            ("draw_rate", u64::from(config.draw_rate)),
+            ("max_message_len", config.max_message_len as u64),
+            ("request_timeout_secs", config.request_timeout_secs),
+            ("latency_budget_ms", config.latency_budget_ms),
+            ("max_voice_note_secs", config.max_voice_note_secs),
+            ("mail_batch_secs", config.mail_batch_secs),
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::Zero(key));
+        }
+
+        Ok(config)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn gossip_interval(&self) -> Duration {
+        Duration::from_secs(self.gossip_interval_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn latency_budget(&self) -> Duration {
+        Duration::from_millis(self.latency_budget_ms)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+
+    pub fn max_voice_note_ms(&self) -> u64 {
+        self.max_voice_note_secs.saturating_mul(1000)
+    }
+
+    pub fn newcomer_age(&self) -> Duration {
+        Duration::from_secs(self.newcomer_age_secs)
+    }
+
+    pub fn mail_batch_interval(&self) -> Duration {
+        Duration::from_secs(self.mail_batch_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let none = |_: &str| None;
+        assert_eq!(
+            Config::load(None, DEFAULT_FILE, none).unwrap(),
+            Config::default()
+        );
+
+        let text = "port = 9000\nmax_room_size = 50\nmessage_rate = 2\n\
+            sessions_per_account = 2\n";
+        let env = |key: &str| match key {
+            "PORT" => Some("9001".to_owned()),
+            "FLOOD_STRIKES" => Some("3".to_owned()),
+            "RESUME_GRACE_SECS" => Some("0".to_owned()),
+            "DEFAULT_ROOMS" => Some(" Main, Help,".to_owned()),
+            "TRUST_LINKS" => Some("-5".to_owned()),
+            _ => None,
+        };
+        let config = Config::load(Some(text), "chat.toml", env).unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.max_room_size, Some(50));
+        assert_eq!(config.message_rate, 2);
+        assert_eq!(config.flood_strikes, 3);
+        assert_eq!(config.history_depth, 1000);
+        assert_eq!(config.sessions_per_account, SessionPolicy::Limit(2));
+        assert_eq!(config.resume_grace(), Duration::ZERO);
+        assert_eq!(config.default_rooms, ["Main", "Help"]);
+        assert_eq!(config.trust_links, -5);
+        assert_eq!(config.trust_uploads, 20);
+        let lobby = |key: &str| Some(String::new()).filter(|_| key == "DEFAULT_ROOMS");
+        let config = Config::load(None, DEFAULT_FILE, lobby).unwrap();
+        assert!(config.default_rooms.is_empty());
+        let text = "sessions_per_account = \"single\"";
+        let config = Config::load(Some(text), "chat.toml", none).unwrap();
+        assert_eq!(config.sessions_per_account, SessionPolicy::Single);
+
+        assert!(matches!(
+            Config::load(Some("prot = 9000"), "chat.toml", none),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            Config::load(Some("message_rate = 0"), "chat.toml", none),
+            Err(ConfigError::Zero("message_rate"))
+        ));
+        assert!(matches!(
+            Config::load(Some("mail_batch_secs = 0"), "chat.toml", none),
+            Err(ConfigError::Zero("mail_batch_secs"))
+        ));
+        let env =
+            |key: &str| Some("10m".to_owned()).filter(|_| key == "NEWCOMER_AGE_SECS");
+        assert!(matches!(
+            Config::load(None, DEFAULT_FILE, env),
+            Err(ConfigError::Env("NEWCOMER_AGE_SECS", _))
+        ));
+        assert!(matches!(
+            Config::load(Some("sessions_per_account = 0"), "chat.toml", none),
+            Err(ConfigError::Parse(..))
+        ));
+        let env = |key: &str| Some("lots".to_owned()).filter(|_| key == "MESSAGE_RATE");
+        assert!(matches!(
+            Config::load(None, DEFAULT_FILE, env),
+            Err(ConfigError::Env("MESSAGE_RATE", _))
+        ));
+    }
+}
@@ -0,0 +1,466 @@
+//! Websocket compression, the `permessage-deflate` extension (RFC 7692), for
+//! the big frames: history replays, room lists, catch-ups. With the config's
+//! `compression` on, clients offering it get it; others are served as before.
+//!
+//! The websocket codec of actix-http knows nothing of extensions, so this works
+//! on the bytes around it: frames from the client that are compressed are
+//! inflated into plain ones before the codec parses them, and frames the codec
+//! wrote big enough to be worth it are deflated on their way out. Each
+//! compressed connection keeps a compressor and a decompressor, a few hundred
+//! kilobytes, for as long as it's open.
+
+use std::fmt;
+
+use actix::Actor;
+use actix_web::error::PayloadError;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws::{self, Message, ProtocolError, WebsocketContext};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status,
+};
+use futures::{Stream, StreamExt};
+
+use crate::config::config;
+
+/// Frames smaller than this go out uncompressed, it wouldn't save much
+const MIN_COMPRESSED_LEN: usize = 256;
+
+/// Most bytes a message from a client may take, compressed or inflated, as
+/// much as the codec takes in a frame
+const MAX_MESSAGE_LEN: usize = 65_536;
+
+/// What deflate blocks flushed with `FlushCompress::Sync` end with, left out
+/// of the frames
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+
+/// The parameters agreed on with a client
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Deflate {
+    /// the server compresses each message on its own
+    server_no_context_takeover: bool,
+    /// and so does the client
+    client_no_context_takeover: bool,
+}
+
+impl fmt::Display for Deflate {
+    /// As the response's `Sec-WebSocket-Extensions`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "permessage-deflate")?;
+        if self.server_no_context_takeover {
+            write!(f, "; server_no_context_takeover")?;
+        }
+        if self.client_no_context_takeover {
+            write!(f, "; client_no_context_takeover")?;
+        }
+        Ok(())
+    }
+}
+
+/// The first `permessage-deflate` offer of a `Sec-WebSocket-Extensions`
+/// header that can be taken. Offers asking for a smaller window than the
+/// default one for the server can't, the compressor has no such setting.
+pub fn negotiate(extensions: &str) -> Option<Deflate> {
+    extensions.split(',').find_map(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut deflate = Deflate::default();
+        for param in params {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (key, value) {
+                ("server_no_context_takeover", None) => {
+                    deflate.server_no_context_takeover = true
+                }
+                ("client_no_context_takeover", None) => {
+                    deflate.client_no_context_takeover = true
+                }
+                // inflating takes any window
+                ("client_max_window_bits", _) => {}
+                ("server_max_window_bits", Some("15")) => {}
+                _ => return None,
+            }
+        }
+
+        Some(deflate)
+    })
+}
+
+struct Header {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// bytes of the header
+    len: usize,
+    payload_len: usize,
+}
+
+impl Header {
+    fn frame_len(&self) -> usize {
+        self.len + self.payload_len
+    }
+}
+
+/// The header of the frame `buf` starts with, `None` until all of it is there
+fn parse_header(buf: &[u8]) -> Option<Header> {
+    let (first, second) = (*buf.first()?, *buf.get(1)?);
+
+    let (payload_len, mut len) = match second & 0x7f {
+        126 => (
+            usize::from(u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?])),
+            4,
+        ),
+        127 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(buf.get(2..10)?);
+            (u64::from_be_bytes(bytes) as usize, 10)
+        }
+        payload_len => (usize::from(payload_len), 2),
+    };
+    let mask = match second & 0x80 != 0 {
+        true => {
+            let mut mask = [0; 4];
+            mask.copy_from_slice(buf.get(len..len + 4)?);
+            len += 4;
+            Some(mask)
+        }
+        false => None,
+    };
+
+    Some(Header {
+        fin: first & FIN != 0,
+        rsv1: first & RSV1 != 0,
+        opcode: first & 0x0f,
+        mask,
+        len,
+        payload_len,
+    })
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Writes a whole frame, masked with `mask` if given
+fn write_frame(dst: &mut BytesMut, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+
+    dst.reserve(payload.len() + 14);
+    dst.put_u8(first);
+    match payload.len() {
+        len if len < 126 => dst.put_u8(masked | len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            dst.put_u8(masked | 126);
+            dst.put_u16(len as u16);
+        }
+        len => {
+            dst.put_u8(masked | 127);
+            dst.put_u64(len as u64);
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            dst.put_slice(&mask);
+            let start = dst.len();
+            dst.put_slice(payload);
+            apply_mask(&mut dst[start..], mask);
+        }
+        None => dst.put_slice(payload),
+    }
+}
+
+/// Turns the compressed messages of a client into plain frames
+struct Inflater {
+    decompress: Decompress,
+    reset: bool,
+    buf: BytesMut,
+    /// payload left of a plain frame being passed through
+    passing: usize,
+    /// opcode and payload so far of a compressed message split over frames
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Inflater {
+    fn new(deflate: Deflate) -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            reset: deflate.client_no_context_takeover,
+            buf: BytesMut::new(),
+            passing: 0,
+            message: None,
+        }
+    }
+
+    /// The frames of `chunk` that are complete, plain
+    fn feed(&mut self, chunk: &[u8]) -> Result<Bytes, PayloadError> {
+        self.buf.extend_from_slice(chunk);
+        let mut out = BytesMut::new();
+
+        loop {
+            if self.passing > 0 {
+                let len = self.passing.min(self.buf.len());
+                out.extend_from_slice(&self.buf.split_to(len));
+                self.passing -= len;
+                if self.passing > 0 {
+                    break;
+                }
+                continue;
+            }
+
+            let header = match parse_header(&self.buf) {
+                Some(header) => header,
+                None => break,
+            };
+            let control = header.opcode & 0x08 != 0;
+            if header.rsv1 && (control || header.opcode == 0) {
+                return Err(PayloadError::EncodingCorrupted);
+            }
+            let continued = header.opcode == 0 && self.message.is_some();
+            if !header.rsv1 && (control || !continued) {
+                out.extend_from_slice(&self.buf.split_to(header.len));
+                self.passing = header.payload_len;
+                continue;
+            }
+
+            let compressed = self.message.as_ref().map_or(0, |(_, data)| data.len());
+            if compressed + header.payload_len > MAX_MESSAGE_LEN {
+                return Err(PayloadError::Overflow);
+            }
+            if self.buf.len() < header.frame_len() {
+                break;
+            }
+
+            let mut frame = self.buf.split_to(header.frame_len());
+            frame.advance(header.len);
+            if let Some(mask) = header.mask {
+                apply_mask(&mut frame, mask);
+            }
+            let (opcode, mut data) = self
+                .message
+                .take()
+                .unwrap_or_else(|| (header.opcode, Vec::new()));
+            data.extend_from_slice(&frame);
+            if !header.fin {
+                self.message = Some((opcode, data));
+                continue;
+            }
+
+            let inflated = self.inflate(data)?;
+            write_frame(&mut out, FIN | opcode, Some([0; 4]), &inflated);
+        }
+
+        Ok(out.freeze())
+    }
+
+    fn inflate(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>, PayloadError> {
+        data.extend_from_slice(&TAIL);
+        let mut inflated = Vec::with_capacity(data.len() * 4);
+        let start = self.decompress.total_in();
+
+        loop {
+            let read = (self.decompress.total_in() - start) as usize;
+            if inflated.len() == inflated.capacity() {
+                inflated.reserve(inflated.len());
+            }
+            let status = self
+                .decompress
+                .decompress_vec(&data[read..], &mut inflated, FlushDecompress::Sync)
+                .map_err(|_| PayloadError::EncodingCorrupted)?;
+
+            if inflated.len() > MAX_MESSAGE_LEN {
+                return Err(PayloadError::Overflow);
+            }
+            let read = (self.decompress.total_in() - start) as usize;
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                break;
+            }
+            if read == data.len() && inflated.len() < inflated.capacity() {
+                break;
+            }
+        }
+
+        if self.reset {
+            self.decompress.reset(false);
+        }
+        Ok(inflated)
+    }
+}
+
+/// Compresses the frames the codec wrote that are worth it
+struct Deflater {
+    compress: Compress,
+    reset: bool,
+    buf: BytesMut,
+}
+
+impl Deflater {
+    fn new(deflate: Deflate) -> Self {
+        Deflater {
+            compress: Compress::new(Compression::default(), false),
+            reset: deflate.server_no_context_takeover,
+            buf: BytesMut::new(),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        self.buf.extend_from_slice(chunk);
+        let mut out = BytesMut::new();
+
+        while let Some(header) = parse_header(&self.buf) {
+            if self.buf.len() < header.frame_len() {
+                break;
+            }
+            let frame = self.buf.split_to(header.frame_len());
+
+            // text or binary, in one frame
+            let data = header.opcode == 1 || header.opcode == 2;
+            if !data || !header.fin || header.payload_len < MIN_COMPRESSED_LEN {
+                out.extend_from_slice(&frame);
+                continue;
+            }
+
+            let compressed = self.deflate(&frame[header.len..]);
+            write_frame(&mut out, FIN | RSV1 | header.opcode, None, &compressed);
+        }
+
+        out.freeze()
+    }
+
+    fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            let read = (self.compress.total_in() - start) as usize;
+            if compressed.len() == compressed.capacity() {
+                compressed.reserve(compressed.len());
+            }
+            self.compress
+                .compress_vec(&data[read..], &mut compressed, FlushCompress::Sync)
+                .expect("compressing to a buffer can't fail");
+
+            let read = (self.compress.total_in() - start) as usize;
+            if read == data.len() && compressed.len() < compressed.capacity() {
+                break;
+            }
+        }
+
+        if compressed.ends_with(&TAIL) {
+            compressed.truncate(compressed.len() - TAIL.len());
+        }
+        if self.reset {
+            self.compress.reset();
+        }
+        compressed
+    }
+}
+
+/// Like `ws::start`, compressing frames if the config allows it and the
+/// client offers to
+pub fn start<A, T>(actor: A, req: &HttpRequest, stream: T) -> Result<HttpResponse, Error>
+where
+    A: Actor<Context = WebsocketContext<A>>
+        + actix::StreamHandler<Result<Message, ProtocolError>>,
+    T: Stream<Item = Result<Bytes, PayloadError>> + 'static,
+{
+    let offered = req
+        .headers()
+        .get_all("Sec-WebSocket-Extensions")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let deflate = match config().compression {
+        true => negotiate(&offered),
+        false => None,
+    };
+    let deflate = match deflate {
+        Some(deflate) => deflate,
+        None => return ws::start(actor, req, stream),
+    };
+
+    let mut res = ws::handshake(req)?;
+    res.header("Sec-WebSocket-Extensions", deflate.to_string());
+
+    let mut inflater = Inflater::new(deflate);
+    let mut deflater = Deflater::new(deflate);
+    let stream = stream.map(move |chunk| chunk.and_then(|chunk| inflater.feed(&chunk)));
+    let frames = WebsocketContext::create(actor, stream)
+        .map(move |chunk| chunk.map(|chunk| deflater.feed(&chunk)));
+
+    Ok(res.streaming(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some(Deflate::default())
+        );
+        let deflate = negotiate(
+            "x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=10, \
+             permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+        )
+        .unwrap();
+        assert_eq!(
+            deflate.to_string(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+        assert_eq!(negotiate("permessage-deflate; foo=1"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_compression() {
+        let text = "hello ".repeat(100);
+
+        // the server's frame comes out compressed, the client's short one as is
+        let mut frame = BytesMut::new();
+        write_frame(&mut frame, FIN | 1, None, text.as_bytes());
+        write_frame(&mut frame, FIN | 1, None, b"hi");
+        let mut deflater = Deflater::new(Deflate::default());
+        let (first, second) = frame.split_at(30);
+        let mut out = BytesMut::from(&deflater.feed(first)[..]);
+        out.extend_from_slice(&deflater.feed(second));
+
+        let header = parse_header(&out).unwrap();
+        assert!(header.rsv1);
+        assert!(header.payload_len < text.len() / 10);
+        let compressed = out.split_to(header.frame_len()).split_off(header.len);
+        assert_eq!(&out[..], b"\x81\x02hi");
+
+        // it inflates back, sent by a client, masked and split over two frames
+        let mut frame = BytesMut::new();
+        let (start, end) = compressed.split_at(5);
+        write_frame(&mut frame, RSV1 | 1, Some([1, 2, 3, 4]), start);
+        write_frame(&mut frame, FIN, Some([5, 6, 7, 8]), end);
+        write_frame(&mut frame, FIN | 9, Some([1, 1, 1, 1]), b"ping");
+        let mut inflater = Inflater::new(Deflate::default());
+        let out = inflater.feed(&frame).unwrap();
+
+        let mut expected = BytesMut::new();
+        write_frame(&mut expected, FIN | 1, Some([0; 4]), text.as_bytes());
+        write_frame(&mut expected, FIN | 9, Some([1, 1, 1, 1]), b"ping");
+        assert_eq!(out, expected.freeze());
+
+        let mut frame = BytesMut::new();
+        write_frame(&mut frame, FIN | RSV1 | 1, Some([0; 4]), b"\xff\xff");
+        assert!(inflater.feed(&frame).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::journal::{EventKind, Journal, RoomEvent};
+use crate::message::{DigestRooms, PostDigest, RoomHistory};
+use crate::server::WsChatServer;
+use crate::session::unix_millis;
+
+/// How often rooms are checked for a digest being due
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Participants and questions listed in a digest
+const TOP_COUNT: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Participant {
+    pub name: String,
+    pub messages: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopQuestion {
+    pub id: u64,
+    pub text: String,
+    pub votes: usize,
+}
+
+/// Summary of a room's activity over the last `hours`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Digest {
+    pub hours: u64,
+    /// chat messages and questions
+    pub messages: usize,
+    /// attachments and voice notes
+    pub attachments: usize,
+    /// everyone who posted anything
+    pub participants: usize,
+    pub top_participants: Vec<Participant>,
+    /// the most upvoted questions of a room in Q&A mode
+    pub top_questions: Vec<TopQuestion>,
+}
+
+/// Works out a digest from a room's journaled events
+pub fn summarize(events: &[RoomEvent], hours: u64) -> Digest {
+    let mut digest = Digest {
+        hours,
+        ..Default::default()
+    };
+    let mut posts: HashMap<&str, usize> = HashMap::new();
+    let mut questions: Vec<TopQuestion> = Vec::new();
+
+    for event in events {
+        let author = match &event.kind {
+            // "bob: hi"
+            EventKind::Message { content, .. } => {
+                digest.messages += 1;
+                content.split_once(": ").map(|(name, _)| name)
+            }
+            EventKind::Question { id, from, text } => {
+                digest.messages += 1;
+                questions.push(TopQuestion {
+                    id: *id,
+                    text: text.clone(),
+                    votes: 0,
+                });
+                Some(from.as_str())
+            }
+            EventKind::Forwarded { from, .. } | EventKind::CrossPost { from, .. } => {
+                digest.messages += 1;
+                Some(from.as_str())
+            }
+            EventKind::Attachment { from, .. } | EventKind::VoiceNote { from, .. } => {
+                digest.attachments += 1;
+                Some(from.as_str())
+            }
+            EventKind::QuestionVotes { id, votes } => {
+                if let Some(question) = questions.iter_mut().find(|q| q.id == *id) {
+                    question.votes = *votes;
+                }
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(author) = author {
+            *posts.entry(author).or_default() += 1;
+        }
+    }
+
+    digest.participants = posts.len();
+
+    let mut participants: Vec<Participant> = posts
+        .into_iter()
+        .map(|(name, messages)| Participant {
+            name: name.to_owned(),
+            messages,
+        })
+        .collect();
+    participants.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.name.cmp(&b.name)));
+    participants.truncate(TOP_COUNT);
+    digest.top_participants = participants;
+
+    questions.retain(|question| question.votes > 0);
+    questions.sort_by(|a, b| b.votes.cmp(&a.votes).then(a.id.cmp(&b.id)));
+    questions.truncate(TOP_COUNT);
+    digest.top_questions = questions;
+
+    digest
+}
+
+impl fmt::Display for Digest {
+    /// e.g. `last 24h: 120 messages and 3 attachments from 8 people, most
+    /// active: bob (40), ann (30), cat (12)`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "last {}h: {} messages and {} attachments from {} people",
+            self.hours, self.messages, self.attachments, self.participants
+        )?;
+
+        if !self.top_participants.is_empty() {
+            let names: Vec<String> = self
+                .top_participants
+                .iter()
+                .map(|p| format!("{} ({})", p.name, p.messages))
+                .collect();
+            write!(f, ", most active: {}", names.join(", "))?;
+        }
+
+        if !self.top_questions.is_empty() {
+            let questions: Vec<String> = self
+                .top_questions
+                .iter()
+                .map(|q| format!("[question {}] {} ({} votes)", q.id, q.text, q.votes))
+                .collect();
+            write!(f, ", top questions: {}", questions.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Background job posting a digest to every room with the `digest` setting,
+/// as a `digest` room event, once per period. Runs on every node, but only
+/// for the rooms homed on it, from what the node's journal still holds.
+#[derive(Default)]
+pub struct Digests {
+    /// room -> when its last digest went out, or when it was first seen
+    last_sent: HashMap<String, u64>,
+}
+
+impl Digests {
+    fn check(&mut self, ctx: &mut Context<Self>) {
+        WsChatServer::from_registry()
+            .send(DigestRooms)
+            .into_actor(self)
+            .map(|res, act, ctx| {
+                let rooms = match res {
+                    Ok(rooms) => rooms,
+                    Err(_) => return,
+                };
+                let now = unix_millis() as u64;
+
+                act.last_sent
+                    .retain(|room, _| rooms.iter().any(|(name, _)| name == room));
+
+                for (room_name, hours) in rooms {
+                    let period = hours * 60 * 60 * 1000;
+                    // the first digest covers a whole period
+                    let last_sent =
+                        act.last_sent.entry(room_name.clone()).or_insert(now);
+
+                    if now - *last_sent >= period {
+                        *last_sent = now;
+                        act.send_digest(room_name, hours, now - period, ctx);
+                    }
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn send_digest(
+        &mut self,
+        room_name: String,
+        hours: u64,
+        since: u64,
+        ctx: &mut Context<Self>,
+    ) {
+        let msg = RoomHistory {
+            room_name: room_name.clone(),
+            since,
+        };
+
+        Journal::from_registry()
+            .send(msg)
+            .into_actor(self)
+            .map(move |res, _act, _ctx| {
+                let digest = match res {
+                    Ok(events) => summarize(&events, hours),
+                    Err(_) => return,
+                };
+
+                // quiet rooms get none
+                if digest.messages + digest.attachments > 0 {
+                    WsChatServer::from_registry()
+                        .do_send(PostDigest { room_name, digest });
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Digests {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(CHECK_INTERVAL, |act, ctx| act.check(ctx));
+    }
+}
+
+impl SystemService for Digests {}
+impl Supervised for Digests {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind) -> RoomEvent {
+        RoomEvent {
+            seq: 0,
+            room_name: "Main".to_owned(),
+            time: 0,
+            kind,
+        }
+    }
+
+    fn message(content: &str) -> RoomEvent {
+        event(EventKind::message(content.to_owned()))
+    }
+
+    #[test]
+    fn test_summarize() {
+        let events = vec![
+            message("bob: hi"),
+            message("ann: hello"),
+            message("bob: how are you?"),
+            event(EventKind::Joined {
+                name: "cat".to_owned(),
+            }),
+            event(EventKind::Question {
+                id: 1,
+                from: "cat".to_owned(),
+                text: "lunch?".to_owned(),
+            }),
+            event(EventKind::QuestionVotes { id: 1, votes: 2 }),
+        ];
+
+        let digest = summarize(&events, 24);
+        assert_eq!(digest.messages, 4);
+        assert_eq!(digest.participants, 3);
+        assert_eq!(
+            digest.top_participants[0],
+            Participant {
+                name: "bob".to_owned(),
+                messages: 2
+            }
+        );
+        assert_eq!(digest.top_participants[1].name, "ann");
+        assert_eq!(digest.top_questions[0].votes, 2);
+        assert_eq!(
+            digest.to_string(),
+            "last 24h: 4 messages and 0 attachments from 3 people, most active: \
+             bob (2), ann (1), cat (1), top questions: [question 1] lunch? (2 votes)"
+        );
+    }
+}
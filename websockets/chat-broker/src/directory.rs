@@ -0,0 +1,113 @@
+//! The public directory of rooms, `GET /api/directory`, for sites helping
+//! people find rooms to join: every public room that isn't unlisted, with its
+//! topic, language and how many are in it, a page at a time, or only the
+//! rooms in one language. Like previews, pages are cached on the node and
+//! may be cached by anyone in between for as long.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix::SystemService;
+use actix_web::{web, Error, HttpResponse};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::message::ListDirectory;
+use crate::server::{parse_page_token, WsChatServer, ROOM_PAGE_SIZE};
+use crate::settings::language_tag;
+
+/// How long a page is served from the cache, and may be cached elsewhere
+const DIRECTORY_TTL: Duration = Duration::from_secs(30);
+
+/// Pages cached before the expired ones are dropped, as any token can be
+/// asked for
+const MAX_CACHED: usize = 1_000;
+
+/// (when listed, the page) by the language and token it was asked with,
+/// see `list`
+static CACHE: Lazy<Mutex<HashMap<String, (Instant, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize)]
+pub struct DirectoryQuery {
+    /// continuation token from the page before
+    #[serde(default)]
+    after: Option<String>,
+    /// only rooms in this language, e.g. `pt` for `pt` and `pt-BR` rooms
+    #[serde(default)]
+    language: Option<String>,
+}
+
+fn cached(key: &str, now: Instant) -> Option<String> {
+    let cache = CACHE.lock().expect("directory cache poisoned");
+
+    cache
+        .get(key)
+        .filter(|(listed, _)| now.saturating_duration_since(*listed) < DIRECTORY_TTL)
+        .map(|(_, page)| page.clone())
+}
+
+fn cache(key: String, page: String, now: Instant) {
+    let mut cache = CACHE.lock().expect("directory cache poisoned");
+
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, (listed, _)| {
+            now.saturating_duration_since(*listed) < DIRECTORY_TTL
+        });
+    }
+    if cache.len() < MAX_CACHED {
+        cache.insert(key, (now, page));
+    }
+}
+
+/// A page of the directory, 50 rooms in name order with the token of the next
+/// page in `next`. Counts are of this node's clients, and rooms with none
+/// here aren't listed.
+pub async fn list(query: web::Query<DirectoryQuery>) -> Result<HttpResponse, Error> {
+    let DirectoryQuery { after, language } = query.into_inner();
+    let token = after.unwrap_or_default();
+    let language = match language.as_deref().map(language_tag) {
+        Some(None) => return Ok(HttpResponse::BadRequest().body("invalid language tag")),
+        Some(language) => language,
+        None => None,
+    };
+    let key = format!("{}\n{}", language.as_deref().unwrap_or_default(), token);
+    let now = Instant::now();
+
+    let page = match cached(&key, now) {
+        Some(page) => page,
+        None => {
+            let after = match token.as_str() {
+                "" => None,
+                token => match parse_page_token(token) {
+                    Some(after) => Some(after.name),
+                    None => {
+                        return Ok(HttpResponse::BadRequest().body("invalid page token"))
+                    }
+                },
+            };
+            let page = WsChatServer::from_registry()
+                .send(ListDirectory {
+                    after,
+                    language,
+                    limit: ROOM_PAGE_SIZE,
+                })
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            let page = serde_json::to_string(&page)?;
+
+            cache(key, page.clone(), now);
+            page
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(
+            "Cache-Control",
+            format!("public, max-age={}", DIRECTORY_TTL.as_secs()),
+        )
+        .header("Access-Control-Allow-Origin", "*")
+        .body(page))
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use actix::prelude::*;
+use actix_broker::BrokerIssue;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::{BridgeOut, Envelope, Payload};
+use crate::message::{GetDraft, LoadDrafts, SaveDraft, StoreDraft, SyncDraft};
+use crate::session::unix_millis;
+use crate::storage::store;
+
+/// Rooms an account keeps drafts for, the least recently saved are dropped
+/// beyond that
+const MAX_DRAFTS_PER_ACCOUNT: usize = 50;
+
+/// Characters a draft may have, whatever `max_message_len` allows, as every
+/// draft is stored on every node
+pub const MAX_DRAFT_LEN: usize = 4096;
+
+/// Text typed into a room but not sent yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Draft {
+    pub text: String,
+    /// unix milliseconds
+    pub updated: u64,
+}
+
+/// Unsent messages by account and room, so typing can carry on from another
+/// device or after a reconnect. Every node keeps them all, hearing of the
+/// other nodes' saves over the cluster, and writes them to the store if one
+/// is configured, restoring them from it on starting.
+#[derive(Default)]
+pub struct Drafts {
+    drafts: HashMap<String, HashMap<String, Draft>>,
+}
+
+impl Drafts {
+    /// Replaces the account's draft for the room, or discards it if `None`,
+    /// unless what is kept was saved later. Returns whether it changed.
+    fn keep(&mut self, account: &str, room_name: &str, draft: Option<Draft>) -> bool {
+        let kept = self
+            .drafts
+            .get(account)
+            .and_then(|drafts| drafts.get(room_name))
+            .map(|kept| kept.updated);
+
+        match draft {
+            Some(draft) if kept.is_none_or(|kept| kept <= draft.updated) => {
+                let drafts = self.drafts.entry(account.to_owned()).or_default();
+                drafts.insert(room_name.to_owned(), draft);
+
+                if drafts.len() > MAX_DRAFTS_PER_ACCOUNT {
+                    let oldest = drafts
+                        .iter()
+                        .min_by_key(|(_, draft)| draft.updated)
+                        .map(|(room_name, _)| room_name.clone());
+                    if let Some(oldest) = oldest {
+                        drafts.remove(&oldest);
+                        store_draft(account, &oldest, None);
+                    }
+                }
+                true
+            }
+            None if kept.is_some() => {
+                if let Some(drafts) = self.drafts.get_mut(account) {
+                    drafts.remove(room_name);
+                    if drafts.is_empty() {
+                        self.drafts.remove(account);
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Writes a draft to the store, if there is one
+fn store_draft(account: &str, room_name: &str, draft: Option<Draft>) {
+    if let Some(store) = store() {
+        store.do_send(StoreDraft {
+            account: account.to_owned(),
+            room_name: room_name.to_owned(),
+            draft,
+        });
+    }
+}
+
+impl Actor for Drafts {
+    type Context = Context<Self>;
+
+    /// Picks up the drafts kept before a restart, before taking new ones
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let store = match store() {
+            Some(store) => store,
+            None => return,
+        };
+
+        store
+            .send(LoadDrafts)
+            .into_actor(self)
+            .then(|res, act, _ctx| {
+                match res {
+                    Ok(Ok(drafts)) => {
+                        for (account, room_name, draft) in drafts {
+                            act.keep(&account, &room_name, Some(draft));
+                        }
+                    }
+                    Ok(Err(err)) => warn!("Drafts - restoring failed: {}", err),
+                    Err(err) => warn!("Drafts - restoring failed: {}", err),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Handler<SaveDraft> for Drafts {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveDraft, _ctx: &mut Self::Context) {
+        let SaveDraft {
+            account,
+            room_name,
+            text,
+        } = msg;
+
+        let draft = (!text.is_empty()).then(|| Draft {
+            text,
+            updated: unix_millis() as u64,
+        });
+        if self.keep(&account, &room_name, draft.clone()) {
+            store_draft(&account, &room_name, draft.clone());
+            self.issue_system_async(BridgeOut(Envelope::new(Payload::Draft {
+                account,
+                room_name,
+                draft,
+            })));
+        }
+    }
+}
+
+impl Handler<SyncDraft> for Drafts {
+    type Result = ();
+
+    fn handle(&mut self, msg: SyncDraft, _ctx: &mut Self::Context) {
+        let SyncDraft {
+            account,
+            room_name,
+            draft,
+        } = msg;
+
+        if self.keep(&account, &room_name, draft.clone()) {
+            store_draft(&account, &room_name, draft);
+        }
+    }
+}
+
+impl Handler<GetDraft> for Drafts {
+    type Result = Option<Draft>;
+
+    fn handle(&mut self, msg: GetDraft, _ctx: &mut Self::Context) -> Self::Result {
+        self.drafts
+            .get(&msg.account)
+            .and_then(|drafts| drafts.get(&msg.room_name))
+            .cloned()
+    }
+}
+
+impl SystemService for Drafts {}
+impl Supervised for Drafts {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+
+    fn draft(text: &str, updated: u64) -> Option<Draft> {
+        Some(Draft {
+            text: text.to_owned(),
+            updated,
+        })
+    }
+
+    #[test]
+    fn test_drafts_per_account() {
+        let mut drafts = Drafts::default();
+        for i in 0..MAX_DRAFTS_PER_ACCOUNT as u64 {
+            drafts.keep("alice", &format!("room{}", i), draft("hi", 100 + i));
+        }
+        assert_eq!(drafts.drafts["alice"].len(), MAX_DRAFTS_PER_ACCOUNT);
+        assert!(drafts.drafts["alice"].contains_key("room0"));
+
+        // one more drops the least recently saved
+        drafts.keep("alice", "room0", draft("hi again", 1000));
+        drafts.keep("alice", "one more", draft("hi", 1001));
+        let kept = &drafts.drafts["alice"];
+        assert_eq!(kept.len(), MAX_DRAFTS_PER_ACCOUNT);
+        assert!(kept.contains_key("room0") && kept.contains_key("one more"));
+        assert!(!kept.contains_key("room1"));
+
+        // other accounts have their own
+        drafts.keep("bob", "room1", draft("hey", 1));
+        assert_eq!(drafts.drafts["alice"].len(), MAX_DRAFTS_PER_ACCOUNT);
+    }
+
+    #[test]
+    fn test_sync_draft() {
+        run(async {
+            let drafts = Drafts::default().start();
+            let get = || {
+                drafts.send(GetDraft {
+                    account: "alice".to_owned(),
+                    room_name: "r".to_owned(),
+                })
+            };
+
+            drafts
+                .send(SaveDraft {
+                    account: "alice".to_owned(),
+                    room_name: "r".to_owned(),
+                    text: "typed here".to_owned(),
+                })
+                .await
+                .unwrap();
+            let saved = get().await.unwrap().unwrap();
+
+            // saved later on another node
+            let sync = |draft| SyncDraft {
+                account: "alice".to_owned(),
+                room_name: "r".to_owned(),
+                draft,
+            };
+            drafts
+                .send(sync(draft("typed there", saved.updated + 1)))
+                .await
+                .unwrap();
+            assert_eq!(get().await.unwrap().unwrap().text, "typed there");
+
+            // one saved before that doesn't replace it
+            drafts
+                .send(sync(draft("stale", saved.updated)))
+                .await
+                .unwrap();
+            assert_eq!(get().await.unwrap().unwrap().text, "typed there");
+
+            drafts.send(sync(None)).await.unwrap();
+            assert!(get().await.unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,134 @@
+//! The embeddable widget, `GET /embed/{room}`: a page for an iframe showing a
+//! public room live, and letting visitors chat in it unless `mode=read`. It
+//! joins only that room, whatever `DEFAULT_ROOMS` are, and talks to the page
+//! embedding it with `postMessage`, see the README.
+//!
+//! `EMBED_ORIGINS` (comma-separated) restricts which sites may frame it and
+//! exchange messages with it, any may if unset.
+
+use actix::prelude::*;
+use actix_web::{web, Error, HttpResponse};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::message::GetRoomSettings;
+use crate::server::WsChatServer;
+use crate::settings::Visibility;
+
+/// The widget, its config filled in where it says `{{config}}`
+const PAGE: &str = include_str!("embed.html");
+
+static ORIGINS: Lazy<Vec<String>> = Lazy::new(|| {
+    std::env::var("EMBED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_owned)
+        .collect()
+});
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    #[default]
+    Chat,
+    /// no input, just the room's traffic
+    Read,
+}
+
+#[derive(Deserialize)]
+pub struct EmbedQuery {
+    #[serde(default)]
+    theme: Theme,
+    #[serde(default)]
+    mode: Mode,
+}
+
+/// What the widget's script is started with
+#[derive(Debug, Serialize)]
+struct Config<'a> {
+    room: &'a str,
+    theme: Theme,
+    mode: Mode,
+    /// the origins `postMessage` goes to and is taken from, empty for any
+    origins: &'a [String],
+}
+
+/// The page, with `config` as a script can take it: `</script>` and the like
+/// can't close the tag early
+fn page(config: &Config) -> String {
+    let config = serde_json::to_string(config)
+        .expect("config serializes")
+        .replace('<', "\\u003c");
+
+    PAGE.replace("{{config}}", &config)
+}
+
+/// `GET /embed/{room}?theme=dark&mode=read`, not found for rooms that aren't
+/// public like the ones that don't exist
+pub async fn widget(
+    room_name: web::Path<String>,
+    query: web::Query<EmbedQuery>,
+) -> Result<HttpResponse, Error> {
+    let room_name = room_name.into_inner();
+    let EmbedQuery { theme, mode } = query.into_inner();
+
+    let settings = WsChatServer::from_registry()
+        .send(GetRoomSettings(room_name.clone()))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .unwrap_or_default();
+    if settings.visibility != Visibility::Public {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let frame_ancestors = match ORIGINS.is_empty() {
+        true => "*".to_owned(),
+        false => ORIGINS.join(" "),
+    };
+    let config = Config {
+        room: &room_name,
+        theme,
+        mode,
+        origins: &ORIGINS,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .header(
+            "Content-Security-Policy",
+            format!("frame-ancestors {}", frame_ancestors),
+        )
+        .body(page(&config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_config() {
+        let origins = vec!["https://example.com".to_owned()];
+        let config = Config {
+            room: "</script><b>",
+            theme: Theme::Dark,
+            mode: Mode::Read,
+            origins: &origins,
+        };
+
+        let page = page(&config);
+        assert!(page.contains(
+            r#"{"room":"\u003c/script>\u003cb>","theme":"dark","mode":"read","origins":["https://example.com"]}"#
+        ));
+        assert!(!page.contains("{{config}}"));
+    }
+}
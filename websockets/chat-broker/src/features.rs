@@ -0,0 +1,182 @@
+//! What a client can handle beyond text and system frames, declared in a
+//! `hello` frame. Sessions that never send one get everything, as before
+//! `hello` existed; the others only get frames their client understands,
+//! the rest turned into plain notices or dropped.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::frames::system_frame;
+
+/// Longest list of features a `hello` frame may declare
+pub const MAX_FEATURES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// `attachment` frames
+    Attachments,
+    /// `voice_note` frames
+    VoiceNotes,
+    /// `stream_started`, `stream_viewers`, `stream_ended` and `signal` frames
+    Streams,
+    /// `draw` frames
+    Whiteboard,
+    /// `presence` frames
+    Presence,
+    /// `typing` frames
+    Typing,
+    /// `mention` frames
+    Mentions,
+    /// `reactions` frames
+    Reactions,
+}
+
+const ALL: [Feature; 8] = [
+    Feature::Attachments,
+    Feature::VoiceNotes,
+    Feature::Streams,
+    Feature::Whiteboard,
+    Feature::Presence,
+    Feature::Typing,
+    Feature::Mentions,
+    Feature::Reactions,
+];
+
+impl Feature {
+    fn from_name(name: &str) -> Option<Feature> {
+        serde_json::from_value(Value::String(name.to_owned())).ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Features(BTreeSet<Feature>);
+
+impl Default for Features {
+    fn default() -> Self {
+        Features(ALL.iter().copied().collect())
+    }
+}
+
+impl Features {
+    /// The features both the client and this server support. Names this
+    /// server doesn't know are left out rather than refused, so newer clients
+    /// can still connect.
+    pub fn negotiate(names: &[String]) -> Features {
+        Features(
+            names
+                .iter()
+                .filter_map(|name| Feature::from_name(name))
+                .collect(),
+        )
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.0.contains(&feature)
+    }
+
+    /// The frame as the client should get it, `None` if it shouldn't get it
+    pub fn downgrade<'a>(&self, frame: &'a str) -> Option<Cow<'a, str>> {
+        if self.0.len() == ALL.len() || !frame.starts_with('{') {
+            return Some(Cow::Borrowed(frame));
+        }
+
+        let fields = match serde_json::from_str(frame) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return Some(Cow::Borrowed(frame)),
+        };
+        let field = |name| fields.get(name).and_then(Value::as_str).unwrap_or("");
+
+        let notice = match field("type") {
+            "attachment" if !self.has(Feature::Attachments) => format!(
+                "{} sent an attachment, {} ({})",
+                field("from"),
+                field("name"),
+                field("mime")
+            ),
+            "voice_note" if !self.has(Feature::VoiceNotes) => {
+                if self.has(Feature::Attachments) {
+                    let mut attachment = fields.clone();
+                    attachment.insert("type".to_owned(), "attachment".into());
+                    attachment.insert("name".to_owned(), "voice note".into());
+                    attachment.remove("duration_ms");
+                    return Some(Cow::Owned(Value::Object(attachment).to_string()));
+                }
+
+                let ms = fields
+                    .get("duration_ms")
+                    .and_then(Value::as_u64)
+                    .unwrap_or(0);
+                format!(
+                    "{} sent a voice note ({}s)",
+                    field("from"),
+                    (ms + 500) / 1000
+                )
+            }
+            "stream_started" if !self.has(Feature::Streams) => {
+                format!("{} started a stream: {}", field("host"), field("title"))
+            }
+            "stream_viewers" | "stream_ended" | "signal"
+                if !self.has(Feature::Streams) =>
+            {
+                return None
+            }
+            "draw" if !self.has(Feature::Whiteboard) => return None,
+            "presence" if !self.has(Feature::Presence) => return None,
+            "typing" if !self.has(Feature::Typing) => return None,
+            // the message itself still arrives
+            "mention" if !self.has(Feature::Mentions) => return None,
+            "reactions" if !self.has(Feature::Reactions) => return None,
+            _ => return Some(Cow::Borrowed(frame)),
+        };
+
+        Some(Cow::Owned(system_frame(
+            "downgraded",
+            None,
+            &notice,
+            Map::new(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downgrade() {
+        let attachment = r#"{"type":"attachment","from":"bob","name":"cat.png","mime":"image/png","data":""}"#;
+        let voice_note = r#"{"type":"voice_note","from":"bob","mime":"audio/ogg","duration_ms":4200,"data":""}"#;
+
+        let all = Features::default();
+        assert_eq!(all.downgrade(attachment), Some(Cow::Borrowed(attachment)));
+
+        let features = Features::negotiate(&["attachments".into(), "threads".into()]);
+        assert!(features.has(Feature::Attachments));
+        assert!(!features.has(Feature::VoiceNotes));
+
+        assert_eq!(
+            features.downgrade(attachment),
+            Some(Cow::Borrowed(attachment))
+        );
+        assert!(features
+            .downgrade(voice_note)
+            .unwrap()
+            .contains(r#""type":"attachment""#));
+        assert_eq!(features.downgrade(r#"{"type":"draw","op":{}}"#), None);
+        assert_eq!(
+            features.downgrade("bob: hi"),
+            Some(Cow::Borrowed("bob: hi"))
+        );
+
+        let bare = Features::negotiate(&[]);
+        assert!(bare
+            .downgrade(voice_note)
+            .unwrap()
+            .contains("bob sent a voice note (4s)"));
+    }
+}
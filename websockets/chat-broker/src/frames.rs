@@ -0,0 +1,689 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::captcha::MAX_TOKEN_SIZE;
+use crate::config::config;
+use crate::features::MAX_FEATURES;
+use crate::message::Reply;
+
+/// Most points in one whiteboard stroke, longer strokes are sent in pieces
+const MAX_STROKE_POINTS: usize = 256;
+
+/// Largest presence payload, in bytes of JSON
+const MAX_PRESENCE_SIZE: usize = 1024;
+
+/// Characters in a reaction, enough for flags and joined emoji such as
+/// families
+const MAX_EMOJI_CHARS: usize = 8;
+
+/// Characters in a client name
+pub const MAX_NAME_CHARS: usize = 32;
+
+/// Structured alternative to the slash commands, sent as a JSON text frame,
+/// e.g. `{"type":"message","content":"hi"}`. Unknown types and fields are
+/// rejected rather than ignored.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientFrame {
+    Message {
+        content: String,
+    },
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Name {
+        name: String,
+    },
+    Whisper {
+        to: String,
+        content: String,
+    },
+    TimeSync {
+        client_time: Option<u64>,
+    },
+    /// announces an attachment of `size` bytes, sent as binary frames next
+    AttachmentStart {
+        id: String,
+        name: String,
+        mime: String,
+        size: usize,
+    },
+    /// announces a live stream in the current room
+    StreamStart {
+        title: String,
+    },
+    StreamJoin {
+        stream_id: String,
+    },
+    StreamLeave {
+        stream_id: String,
+    },
+    StreamEnd {
+        stream_id: String,
+    },
+    /// WebRTC signaling data for the client called `to`
+    Signal {
+        to: String,
+        data: Value,
+    },
+    /// like `AttachmentStart`, for an audio clip sent as a voice note
+    VoiceNoteStart {
+        id: String,
+        mime: String,
+        duration_ms: u64,
+        size: usize,
+    },
+    /// all of the attachment's or voice note's binary frames were sent
+    AttachmentEnd {
+        id: String,
+    },
+    /// whiteboard drawing in the current room, relayed to the other members
+    /// but not kept anywhere
+    Draw {
+        op: DrawOp,
+    },
+    /// what the client is up to, e.g. `{"cursor":[120,48],"viewing":"main.rs"}`,
+    /// relayed like `Draw`. Only the latest state matters, so updates sent
+    /// faster than the throttle are merged.
+    Presence {
+        data: Value,
+    },
+    /// token from a passed CAPTCHA challenge, see `captcha`
+    Captcha {
+        token: String,
+    },
+    /// asks for the current room's members, then every change to them
+    Members {},
+    /// a page of the room list, the first one without `after`, then with
+    /// the previous page's `next` token
+    ListRooms {
+        #[serde(default)]
+        after: Option<String>,
+    },
+    /// what the client supports, see `features`, and whether it wants every
+    /// reply as JSON, see `Reply`
+    Hello {
+        features: Vec<String>,
+        #[serde(default)]
+        json: bool,
+    },
+    /// keeps what the user typed into a room so far, an empty `text`
+    /// discards it
+    SaveDraft {
+        room: String,
+        text: String,
+    },
+    GetDraft {
+        room: String,
+    },
+    /// marks the room read up to the journal's `seq`, or up to its latest
+    /// message without one, on every session of the account
+    MarkRead {
+        room: String,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    /// asks for the unread counts of the rooms joined and marked read
+    Unread {},
+    /// the user is typing in the current room, sent again every few seconds
+    /// while they keep at it, or `"typing":false` once they stop
+    Typing {
+        #[serde(default = "typing_default")]
+        typing: bool,
+    },
+    /// reacts to the current room's message `id`, or takes the reaction back
+    /// if the client already reacted with `emoji`
+    React {
+        id: u64,
+        emoji: String,
+    },
+}
+
+fn typing_default() -> bool {
+    true
+}
+
+/// Room traffic too frequent or short-lived for the journal: relayed to the
+/// room's members on every node as it happens and then forgotten, e.g.
+/// `{"type":"presence","room":"Main","from":"bob","data":{...}}`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Ephemeral {
+    Draw {
+        op: DrawOp,
+    },
+    Presence {
+        data: Value,
+    },
+    /// `typing` turns false once the member stops, see `TYPING_EXPIRY`
+    Typing {
+        typing: bool,
+    },
+}
+
+impl Ephemeral {
+    /// The frame shown to the members of `room_name`
+    pub fn frame(&self, room_name: &str, from: &str) -> String {
+        let mut frame = serde_json::to_value(self).unwrap_or_default();
+
+        if let Value::Object(fields) = &mut frame {
+            fields.insert("room".to_owned(), room_name.into());
+            fields.insert("from".to_owned(), from.into());
+        }
+
+        frame.to_string()
+    }
+}
+
+/// Whiteboard operation, e.g.
+/// `{"kind":"stroke","points":[[0,0],[10,5]],"color":"#e33","width":2}`.
+/// Coordinates are up to the clients.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DrawOp {
+    Stroke {
+        points: Vec<[f64; 2]>,
+        color: String,
+        width: f64,
+    },
+    Clear,
+}
+
+/// Why a frame was rejected, sent back as
+/// `{"type":"error","field":"content","error":"..."}`
+#[derive(Debug, PartialEq)]
+pub struct FrameError {
+    /// the offending field, `None` if the frame as a whole is malformed
+    pub field: Option<String>,
+    pub error: String,
+}
+
+impl FrameError {
+    pub fn new(field: Option<&str>, error: impl fmt::Display) -> Self {
+        FrameError {
+            field: field.map(str::to_owned),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Notice from the server rather than from a user, e.g. a join or a change to
+/// a room's settings, sent as
+/// `{"type":"system","kind":"joined","room":"Main","text":"bob joined Main",...}`
+/// with whatever else `fields` holds. `text` is only meant for display, clients
+/// styling or filtering notices should go by `kind`.
+pub fn system_frame(
+    kind: &str,
+    room_name: Option<&str>,
+    text: &str,
+    mut fields: Map<String, Value>,
+) -> String {
+    fields.insert("type".to_owned(), "system".into());
+    fields.insert("kind".to_owned(), kind.into());
+    fields.insert("room".to_owned(), serde_json::json!(room_name));
+    fields.insert("text".to_owned(), text.into());
+
+    Value::Object(fields).to_string()
+}
+
+/// A chat message as room members get it, e.g.
+/// `{"type":"message","id":1604000000000001,"posted":1604000000000,"from":"bob","content":"hi"}`
+/// for the line `bob: hi`. Sessions that didn't ask for JSON get the line
+/// back, see `plain_message`.
+pub fn message_frame(line: &str, id: u64, posted: u64) -> String {
+    let (from, content) = match line.split_once(": ") {
+        Some((from, content)) => (Some(from), content),
+        None => (None, line),
+    };
+
+    serde_json::json!({
+        "type": "message",
+        "id": id,
+        "posted": posted,
+        "from": from,
+        "content": content,
+    })
+    .to_string()
+}
+
+/// Sent to a member named as `@name` in a chat message, besides the message
+/// itself, e.g.
+/// `{"type":"mention","room":"Main","id":1604000000000001,"posted":1604000000000,"from":"bob","content":"@alice hi"}`.
+/// It isn't held back by `/notify off`, which only concerns emails.
+pub fn mention_frame(room_name: &str, line: &str, id: u64, posted: u64) -> String {
+    let (from, content) = match line.split_once(": ") {
+        Some((from, content)) => (Some(from), content),
+        None => (None, line),
+    };
+
+    serde_json::json!({
+        "type": "mention",
+        "room": room_name,
+        "id": id,
+        "posted": posted,
+        "from": from,
+        "content": content,
+    })
+    .to_string()
+}
+
+/// The chat line a `message_frame` was made from, `None` for other frames
+pub fn plain_message(frame: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Line<'a> {
+        #[serde(rename = "type")]
+        kind: &'a str,
+        from: Option<Cow<'a, str>>,
+        content: Cow<'a, str>,
+    }
+
+    if !frame.starts_with('{') {
+        return None;
+    }
+    let line: Line = serde_json::from_str(frame).ok()?;
+    if line.kind != "message" {
+        return None;
+    }
+
+    Some(match line.from {
+        Some(from) => format!("{}: {}", from, line.content),
+        None => line.content.into_owned(),
+    })
+}
+
+/// The id of a `message_frame`, `None` for other frames
+pub fn message_id(frame: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Message<'a> {
+        #[serde(rename = "type")]
+        kind: &'a str,
+        id: u64,
+    }
+
+    if !frame.starts_with('{') {
+        return None;
+    }
+    let message: Message = serde_json::from_str(frame).ok()?;
+    Some(message.id).filter(|_| message.kind == "message")
+}
+
+/// How a frame is shown to a session in text mode: chat lines and replies
+/// as their text, see `Reply::text`, and other frames as they are
+pub fn plain_text(frame: &str) -> Cow<'_, str> {
+    if let Some(line) = plain_message(frame) {
+        return Cow::Owned(line);
+    }
+    match serde_json::from_str::<Reply>(frame) {
+        Ok(reply) if frame.starts_with('{') => Cow::Owned(reply.text()),
+        _ => Cow::Borrowed(frame),
+    }
+}
+
+fn check_len(field: &str, value: &str, max: usize) -> Result<(), FrameError> {
+    if value.chars().count() > max {
+        return Err(FrameError::new(
+            Some(field),
+            format!("longer than {} characters", max),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_size(size: usize) -> Result<(), FrameError> {
+    if size > config().max_attachment_size {
+        return Err(FrameError::new(
+            Some("size"),
+            format!("larger than {} bytes", config().max_attachment_size),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A reaction is a few characters that aren't letters, digits or blanks,
+/// so it can't spell out a message
+pub fn valid_emoji(emoji: &str) -> bool {
+    let count = emoji.chars().count();
+
+    (1..=MAX_EMOJI_CHARS).contains(&count)
+        && emoji
+            .chars()
+            .all(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+/// A client name is one short word without `:`, since chat lines are told
+/// apart from their author by the first `": "`, see `EventKind::author`
+pub fn valid_name(name: &str) -> bool {
+    let count = name.chars().count();
+
+    (1..=MAX_NAME_CHARS).contains(&count)
+        && name
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && c != ':')
+}
+
+fn check_not_empty(field: &str, value: &str) -> Result<(), FrameError> {
+    if value.trim().is_empty() {
+        return Err(FrameError::new(Some(field), "must not be empty"));
+    }
+
+    Ok(())
+}
+
+fn parse_with_limit(text: &str, max_len: usize) -> Result<ClientFrame, FrameError> {
+    let mut object: Map<String, Value> =
+        serde_json::from_str(text).map_err(|err| FrameError::new(None, err))?;
+
+    let kind = match object.remove("type") {
+        Some(Value::String(kind)) => kind,
+        Some(_) => return Err(FrameError::new(Some("type"), "expected a string")),
+        None => return Err(FrameError::new(Some("type"), "missing field")),
+    };
+
+    // internally tagged enums lose track of the field paths in errors, so
+    // deserialize as {"<type>": {fields}} instead
+    let mut tagged = Map::new();
+    tagged.insert(kind, Value::Object(object));
+
+    let frame: ClientFrame = serde_path_to_error::deserialize(Value::Object(tagged))
+        .map_err(|err| {
+            // e.g. "message.content", the leading type isn't part of the field.
+            // "." is an unknown type, a bare type a missing field.
+            let path = err.path().to_string();
+            let field = match path.split_once('.') {
+                Some(("", _)) => Some("type".to_owned()),
+                Some((_, field)) => Some(field.to_owned()),
+                None => None,
+            };
+
+            FrameError {
+                field,
+                error: err.into_inner().to_string(),
+            }
+        })?;
+
+    match &frame {
+        ClientFrame::Message { content } => check_len("content", content, max_len)?,
+        ClientFrame::Join { room, .. }
+        | ClientFrame::GetDraft { room }
+        | ClientFrame::MarkRead { room, .. } => check_not_empty("room", room)?,
+        ClientFrame::SaveDraft { room, text } => {
+            check_not_empty("room", room)?;
+            check_len("text", text, max_len)?;
+        }
+        ClientFrame::Name { name } => check_not_empty("name", name)?,
+        ClientFrame::Whisper { to, content } => {
+            check_not_empty("to", to)?;
+            check_len("content", content, max_len)?;
+        }
+        ClientFrame::TimeSync { .. } => {}
+        ClientFrame::AttachmentStart { id, name, size, .. } => {
+            check_not_empty("id", id)?;
+            check_not_empty("name", name)?;
+            check_size(*size)?;
+        }
+        ClientFrame::VoiceNoteStart {
+            id,
+            mime,
+            duration_ms,
+            size,
+        } => {
+            check_not_empty("id", id)?;
+            check_size(*size)?;
+
+            if !mime.starts_with("audio/") {
+                return Err(FrameError::new(Some("mime"), "must be an audio/ type"));
+            }
+
+            let max_ms = config().max_voice_note_ms();
+            if *duration_ms == 0 || *duration_ms > max_ms {
+                return Err(FrameError::new(
+                    Some("duration_ms"),
+                    format!("must be between 1 and {}", max_ms),
+                ));
+            }
+        }
+        ClientFrame::AttachmentEnd { .. } => {}
+        ClientFrame::StreamStart { title } => check_len("title", title, max_len)?,
+        ClientFrame::StreamJoin { .. }
+        | ClientFrame::StreamLeave { .. }
+        | ClientFrame::StreamEnd { .. }
+        | ClientFrame::Members {}
+        | ClientFrame::ListRooms { .. }
+        | ClientFrame::Unread {}
+        | ClientFrame::Typing { .. } => {}
+        ClientFrame::Signal { to, .. } => check_not_empty("to", to)?,
+        ClientFrame::React { emoji, .. } => {
+            if !valid_emoji(emoji) {
+                return Err(FrameError::new(
+                    Some("emoji"),
+                    format!("expected an emoji of up to {} characters", MAX_EMOJI_CHARS),
+                ));
+            }
+        }
+        ClientFrame::Draw { op } => match op {
+            DrawOp::Stroke {
+                points,
+                color,
+                width,
+            } => {
+                if points.is_empty() || points.len() > MAX_STROKE_POINTS {
+                    return Err(FrameError::new(
+                        Some("op.points"),
+                        format!("must hold 1 to {} points", MAX_STROKE_POINTS),
+                    ));
+                }
+                check_len("op.color", color, 32)?;
+                if !(*width > 0.0 && *width <= 100.0) {
+                    return Err(FrameError::new(
+                        Some("op.width"),
+                        "must be above 0 and at most 100",
+                    ));
+                }
+            }
+            DrawOp::Clear => {}
+        },
+        ClientFrame::Presence { data } => {
+            if data.to_string().len() > MAX_PRESENCE_SIZE {
+                return Err(FrameError::new(
+                    Some("data"),
+                    format!("larger than {} bytes", MAX_PRESENCE_SIZE),
+                ));
+            }
+        }
+        ClientFrame::Hello { features, .. } => {
+            if features.len() > MAX_FEATURES {
+                return Err(FrameError::new(
+                    Some("features"),
+                    format!("more than {} features", MAX_FEATURES),
+                ));
+            }
+            for feature in features {
+                check_len("features", feature, 64)?;
+            }
+        }
+        ClientFrame::Captcha { token } => {
+            check_not_empty("token", token)?;
+            if token.len() > MAX_TOKEN_SIZE {
+                return Err(FrameError::new(
+                    Some("token"),
+                    format!("longer than {} bytes", MAX_TOKEN_SIZE),
+                ));
+            }
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Whether a text frame is meant as a JSON frame, an object with a `type`, so
+/// chat lines that merely start with `{` are posted as they are
+pub fn is_frame(text: &str) -> bool {
+    #[derive(Deserialize)]
+    struct Typed {
+        #[serde(rename = "type")]
+        _kind: IgnoredAny,
+    }
+
+    text.starts_with('{') && serde_json::from_str::<Typed>(text).is_ok()
+}
+
+/// Parses and validates a JSON frame
+pub fn parse_frame(text: &str) -> Result<ClientFrame, FrameError> {
+    parse_with_limit(text, config().max_message_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_of(text: &str) -> Option<String> {
+        parse_with_limit(text, 5).unwrap_err().field
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        assert_eq!(
+            parse_with_limit(r#"{"type":"message","content":"hi"}"#, 5),
+            Ok(ClientFrame::Message {
+                content: "hi".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_with_limit(r#"{"type":"time_sync","client_time":null}"#, 5),
+            Ok(ClientFrame::TimeSync { client_time: None })
+        );
+
+        assert_eq!(
+            field_of(r#"{"type":"message","content":1}"#),
+            Some("content".into())
+        );
+        assert_eq!(
+            field_of(r#"{"type":"message","content":"toolong"}"#),
+            Some("content".into())
+        );
+        assert_eq!(
+            field_of(r#"{"type":"join","room":" "}"#),
+            Some("room".into())
+        );
+        assert_eq!(field_of(r#"{"type":"message"}"#), None);
+        assert_eq!(
+            field_of(r#"{"type":"message","content":"hi","x":1}"#),
+            Some("x".into())
+        );
+        assert_eq!(field_of(r#"{"type":"dance"}"#), Some("type".into()));
+        assert_eq!(field_of("{not json"), None);
+
+        assert_eq!(
+            parse_with_limit(r#"{"type":"draw","op":{"kind":"clear"}}"#, 5),
+            Ok(ClientFrame::Draw { op: DrawOp::Clear })
+        );
+        assert_eq!(
+            field_of(
+                r#"{"type":"draw","op":{"kind":"stroke","points":[],"color":"red","width":1}}"#
+            ),
+            Some("op.points".into())
+        );
+
+        assert!(is_frame(r#"{"type":"dance"}"#));
+        assert!(!is_frame("{braces} are fine in chat"));
+        assert!(!is_frame(r#"{"content":"hi"}"#));
+
+        assert!(valid_name("bob"));
+        assert!(valid_name("Émile_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("x: y"));
+        assert!(!valid_name("x:y"));
+        assert!(!valid_name("bob smith"));
+        assert!(!valid_name("bob\u{7}"));
+        assert!(!valid_name(&"b".repeat(MAX_NAME_CHARS + 1)));
+        assert!(valid_emoji("👍"));
+        assert!(valid_emoji("🇧🇷"));
+        assert!(!valid_emoji("+1"));
+        assert_eq!(
+            field_of(r#"{"type":"react","id":3,"emoji":"ok"}"#),
+            Some("emoji".into())
+        );
+
+        let err = parse_with_limit(r#"{"type":"message","content":"hi","x":1}"#, 5);
+        assert!(err.unwrap_err().error.contains("unknown field `x`"));
+    }
+
+    #[test]
+    fn test_message_frame() {
+        let frame = message_frame("bob: hi: \"there\"", 7, 1000);
+        assert_eq!(
+            serde_json::from_str::<Value>(&frame).unwrap(),
+            serde_json::json!({
+                "type": "message",
+                "id": 7,
+                "posted": 1000,
+                "from": "bob",
+                "content": "hi: \"there\"",
+            })
+        );
+        assert_eq!(plain_message(&frame), Some("bob: hi: \"there\"".to_owned()));
+
+        assert_eq!(message_id(&frame), Some(7));
+
+        let frame = message_frame("hello", 8, 1000);
+        assert_eq!(plain_message(&frame), Some("hello".to_owned()));
+
+        let frame = mention_frame("Main", "bob: @alice hi", 9, 1000);
+        assert_eq!(plain_message(&frame), None);
+        assert_eq!(message_id(&frame), None);
+        assert_eq!(message_id(r#"{"type":"ack","id":8}"#), None);
+
+        assert_eq!(
+            plain_message(r#"{"type":"draw","op":{"kind":"clear"}}"#),
+            None
+        );
+        assert_eq!(plain_message("bob: hi"), None);
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let text = |reply: Reply| plain_text(&reply.to_json()).into_owned();
+
+        assert_eq!(plain_text(&message_frame("bob: hi", 8, 1000)), "bob: hi");
+        assert_eq!(text(Reply::error("no such room")), "!!! no such room");
+        assert_eq!(
+            text(Reply::notice("logged in as: bob")),
+            "logged in as: bob"
+        );
+        let whisper = Reply::Whisper {
+            from: Some("bob".to_owned()),
+            to: None,
+            content: "psst".to_owned(),
+        };
+        assert_eq!(text(whisper), "[whisper] bob: psst");
+        let echo = Reply::Whisper {
+            from: None,
+            to: Some("bob".to_owned()),
+            content: "psst".to_owned(),
+        };
+        assert_eq!(
+            echo.to_json(),
+            r#"{"type":"whisper","to":"bob","content":"psst"}"#
+        );
+        assert_eq!(text(echo), "[whisper to bob] psst");
+        let cross_post = Reply::CrossPost {
+            id: 1604000000000001,
+            from: "ann".to_owned(),
+            rooms: vec!["Dev".to_owned(), "Ops".to_owned()],
+            content: "release at 5".to_owned(),
+        };
+        assert_eq!(text(cross_post), "ann: [posted to Dev, Ops] release at 5");
+
+        let frame = Reply::from(FrameError::new(Some("size"), "too big")).to_json();
+        assert_eq!(plain_text(&frame), "!!! too big");
+        assert_eq!(plain_text(r#"{"type":"stats"}"#), r#"{"type":"stats"}"#);
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Daily window, in UTC, during which a room accepts messages, e.g.
+/// `09:00-17:00`. Windows may wrap past midnight (`22:00-02:00`).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OpeningHours {
+    /// minute of the day the room opens
+    opens: u32,
+    /// minute of the day the room closes
+    closes: u32,
+}
+
+/// Minutes since midnight UTC
+pub fn utc_minute_of_day() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+
+    ((secs / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.trim().splitn(2, ':');
+    let hours: u32 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts.next()?.parse().ok()?;
+
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+fn format_time(minute: u32) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+impl OpeningHours {
+    pub fn is_open_at(&self, minute: u32) -> bool {
+        if self.opens <= self.closes {
+            self.opens <= minute && minute < self.closes
+        } else {
+            minute >= self.opens || minute < self.closes
+        }
+    }
+
+    /// Time of the next opening, for rejection messages
+    pub fn next_opening(&self) -> String {
+        format!("{} UTC", format_time(self.opens))
+    }
+}
+
+impl FromStr for OpeningHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid opening hours {:?}, expected HH:MM-HH:MM", s);
+
+        let mut times = s.splitn(2, '-');
+        let opens = times.next().and_then(parse_time).ok_or_else(err)?;
+        let closes = times.next().and_then(parse_time).ok_or_else(err)?;
+
+        if opens == closes {
+            return Err(err());
+        }
+
+        Ok(OpeningHours { opens, closes })
+    }
+}
+
+impl fmt::Display for OpeningHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{} UTC",
+            format_time(self.opens),
+            format_time(self.closes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opening_hours() {
+        let office: OpeningHours = "09:00-17:30".parse().unwrap();
+        assert!(!office.is_open_at(8 * 60 + 59));
+        assert!(office.is_open_at(9 * 60));
+        assert!(!office.is_open_at(17 * 60 + 30));
+        assert_eq!(office.to_string(), "09:00-17:30 UTC");
+
+        let night: OpeningHours = "22:00-02:00".parse().unwrap();
+        assert!(night.is_open_at(23 * 60));
+        assert!(night.is_open_at(60));
+        assert!(!night.is_open_at(12 * 60));
+
+        assert!("9-17".parse::<OpeningHours>().is_err());
+        assert!("24:00-01:00".parse::<OpeningHours>().is_err());
+        assert!("10:00-10:00".parse::<OpeningHours>().is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! Reading archived history back into a room's journal. Archives are a JSON
+//! array or NDJSON, one event per line, holding either this server's own
+//! events, as streamed by `/api/rooms/{name}/events`, or the messages of a
+//! Slack export's per-day channel files. Events keep their original time, and
+//! authors can be renamed on the way in, e.g. Slack user ids to chat names.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::blobs;
+use crate::journal::EventKind;
+
+/// Largest archive accepted in one request, in bytes
+pub const MAX_ARCHIVE_SIZE: usize = 16 * 1024 * 1024;
+
+/// One of this server's events, the journal's `seq` and room are ignored
+#[derive(Deserialize)]
+struct ArchivedEvent {
+    /// unix milliseconds
+    time: u64,
+    #[serde(flatten)]
+    kind: EventKind,
+}
+
+/// The fields of a message in a Slack export this cares about
+#[derive(Deserialize)]
+struct SlackMessage {
+    /// unix seconds with microseconds, e.g. `"1604000000.000200"`
+    ts: String,
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    /// bots post with a name rather than a user
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    user_profile: Option<SlackProfile>,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct SlackProfile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+}
+
+/// Slack subtypes that are someone saying something, the rest are joins,
+/// topic changes and the like
+const SLACK_MESSAGES: &[&str] = &["bot_message", "me_message", "thread_broadcast"];
+
+/// What an archive holds, oldest first
+#[derive(Debug, Default)]
+pub struct Archive {
+    /// (unix milliseconds, event)
+    pub events: Vec<(u64, EventKind)>,
+    /// entries that aren't chat, such as settings changes or Slack joins
+    pub skipped: usize,
+}
+
+/// `old:new` pairs separated by commas, e.g. `U024BE7LH:bob,carol:caroline`
+pub fn parse_authors(authors: &str) -> Result<HashMap<String, String>, String> {
+    authors
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                Ok((from.trim().to_owned(), to.trim().to_owned()))
+            }
+            _ => Err(format!("author mapping {:?} isn't old:new", pair)),
+        })
+        .collect()
+}
+
+fn rename(authors: &HashMap<String, String>, name: &str) -> String {
+    authors
+        .get(name)
+        .cloned()
+        .unwrap_or_else(|| name.to_owned())
+}
+
+/// Moves an archived attachment's inline bytes to the blob store, so the
+/// journal only keeps the link to them, as it does for uploads
+fn keep_inline(
+    name: &str,
+    mime: &str,
+    data: String,
+    url: Option<String>,
+    size: usize,
+) -> Result<(Option<String>, usize), String> {
+    if data.is_empty() {
+        return Ok((url, size));
+    }
+
+    let data = base64::decode(&data).map_err(|err| format!("data: {}", err))?;
+    let size = data.len();
+    let url = blobs::store(name.to_owned(), mime.to_owned(), data)
+        .map_err(|err| format!("data: {}", err))?;
+
+    Ok((Some(url), size))
+}
+
+fn slack_time(ts: &str) -> Option<u64> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let micros = format!("{:0<6}", micros);
+
+    Some(secs.parse::<u64>().ok()? * 1000 + micros.get(..3)?.parse::<u64>().ok()?)
+}
+
+/// Slack writes mentions as `<@U024BE7LH>`, shown as `@bob` once mapped
+fn slack_text(text: &str, authors: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("<@") {
+        out.push_str(&rest[..start]);
+        let mention = &rest[start + 2..];
+
+        match mention.find('>') {
+            Some(end) => {
+                let user = mention[..end].split('|').next().unwrap_or_default();
+                out.push('@');
+                out.push_str(&rename(authors, user));
+                rest = &mention[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// The event an archive entry becomes, `None` if it isn't chat
+fn read_entry(
+    entry: Value,
+    authors: &HashMap<String, String>,
+) -> Result<Option<(u64, EventKind)>, String> {
+    if entry.get("ts").is_some() {
+        let message: SlackMessage =
+            serde_json::from_value(entry).map_err(|err| err.to_string())?;
+
+        if message
+            .subtype
+            .as_deref()
+            .is_some_and(|subtype| !SLACK_MESSAGES.contains(&subtype))
+        {
+            return Ok(None);
+        }
+
+        let time = slack_time(&message.ts)
+            .ok_or_else(|| format!("invalid ts {:?}", message.ts))?;
+        let author = match (&message.user, &message.user_profile, &message.username) {
+            (Some(user), _, _) if authors.contains_key(user) => rename(authors, user),
+            (_, Some(profile), _) if !profile.display_name.is_empty() => {
+                profile.display_name.clone()
+            }
+            (_, Some(profile), _) if !profile.real_name.is_empty() => {
+                profile.real_name.clone()
+            }
+            (_, _, Some(username)) => rename(authors, username),
+            (Some(user), _, _) => user.clone(),
+            (None, _, None) => "unknown".to_owned(),
+        };
+        let content = format!("{}: {}", author, slack_text(&message.text, authors));
+
+        return Ok(Some((time, EventKind::message(content))));
+    }
+
+    let ArchivedEvent { time, kind } =
+        serde_json::from_value(entry).map_err(|err| err.to_string())?;
+
+    let kind = match kind {
+        EventKind::Message { content, .. } => {
+            let content = match content.split_once(": ") {
+                Some((author, text)) => format!("{}: {}", rename(authors, author), text),
+                None => content,
+            };
+            EventKind::message(content)
+        }
+        EventKind::Attachment {
+            from,
+            name,
+            mime,
+            data,
+            url,
+            size,
+        } => {
+            let (url, size) = keep_inline(&name, &mime, data, url, size)?;
+            EventKind::Attachment {
+                from: rename(authors, &from),
+                name,
+                mime,
+                data: String::new(),
+                url,
+                size,
+            }
+        }
+        EventKind::VoiceNote {
+            from,
+            mime,
+            duration_ms,
+            data,
+            url,
+            size,
+        } => {
+            let (url, size) = keep_inline("voice note", &mime, data, url, size)?;
+            EventKind::VoiceNote {
+                from: rename(authors, &from),
+                mime,
+                duration_ms,
+                data: String::new(),
+                url,
+                size,
+            }
+        }
+        EventKind::Question { id, from, text } => EventKind::Question {
+            id,
+            from: rename(authors, &from),
+            text,
+        },
+        EventKind::Forwarded {
+            from,
+            room,
+            original_seq,
+            content,
+        } => EventKind::Forwarded {
+            from: rename(authors, &from),
+            room,
+            original_seq,
+            content: match content.split_once(": ") {
+                Some((author, text)) => format!("{}: {}", rename(authors, author), text),
+                None => content,
+            },
+        },
+        EventKind::CrossPost {
+            post,
+            from,
+            rooms,
+            content,
+        } => EventKind::CrossPost {
+            post,
+            from: rename(authors, &from),
+            rooms,
+            content,
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some((time, kind)))
+}
+
+/// Reads a JSON array or NDJSON archive, naming the entry that doesn't parse
+pub fn parse_archive(
+    body: &str,
+    authors: &HashMap<String, String>,
+) -> Result<Archive, String> {
+    let entries: Vec<Value> = if body.trim_start().starts_with('[') {
+        serde_json::from_str(body).map_err(|err| format!("invalid archive: {}", err))?
+    } else {
+        body.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|err| format!("line {}: {}", i + 1, err))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut archive = Archive::default();
+    for (i, entry) in entries.into_iter().enumerate() {
+        match read_entry(entry, authors)
+            .map_err(|err| format!("entry {}: {}", i + 1, err))?
+        {
+            Some(event) => archive.events.push(event),
+            None => archive.skipped += 1,
+        }
+    }
+    archive.events.sort_by_key(|(time, _)| *time);
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(archive: &Archive) -> Vec<(u64, String)> {
+        archive
+            .events
+            .iter()
+            .map(|(time, kind)| match kind {
+                EventKind::Message { content, .. } => (*time, content.clone()),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_archive() {
+        let authors = parse_authors("U1:bob, carol:caroline").unwrap();
+        assert!(parse_authors("U1").is_err());
+
+        let slack = r#"[
+            {"type":"message","user":"U2","text":"hi <@U1>","ts":"1604000001.500000",
+             "user_profile":{"display_name":"","real_name":"Alice A"}},
+            {"type":"message","subtype":"channel_join","user":"U1","text":"","ts":"1604000000.000100"},
+            {"type":"message","user":"U1","text":"hey","ts":"1604000000.000200"}
+        ]"#;
+        let archive = parse_archive(slack, &authors).unwrap();
+        assert_eq!(archive.skipped, 1);
+        assert_eq!(
+            content(&archive),
+            vec![
+                (1_604_000_000_000, "bob: hey".to_owned()),
+                (1_604_000_001_500, "Alice A: hi @bob".to_owned()),
+            ]
+        );
+
+        let ndjson = concat!(
+            r#"{"seq":3,"room_name":"Main","time":20,"type":"message","content":"carol: yes"}"#,
+            "\n\n",
+            r#"{"seq":2,"room_name":"Main","time":10,"type":"joined","name":"carol"}"#,
+        );
+        let archive = parse_archive(ndjson, &authors).unwrap();
+        assert_eq!(archive.skipped, 1);
+        assert_eq!(content(&archive), vec![(20, "caroline: yes".to_owned())]);
+
+        assert_eq!(
+            parse_archive("{}\nnope", &authors).unwrap_err(),
+            "line 2: expected ident at line 1 column 2"
+        );
+    }
+}
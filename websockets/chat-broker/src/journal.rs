@@ -0,0 +1,832 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::config;
+use crate::digest::Digest;
+use crate::frames::system_frame;
+use crate::hours::OpeningHours;
+use crate::lanes::Lane;
+use crate::message::{
+    ChatMessage, FindEvents, ForgetRoom, ImportEvents, RecordEvent, Reply, RoomHistory,
+    SaveEvent, SendEventLog, SubscribeEvents,
+};
+use crate::scheduler::format_delay;
+use crate::server::AccessPolicy;
+use crate::session::unix_millis;
+use crate::settings::{RoomFlag, RoomSettings};
+use crate::storage::store;
+
+/// Events `/events` shows unless told otherwise, and at most
+pub const EVENT_LOG_SIZE: usize = 20;
+pub const MAX_EVENT_LOG_SIZE: usize = 200;
+
+/// Something that happened in a room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    Joined {
+        name: String,
+    },
+    Left {
+        name: String,
+    },
+    /// `id` increases with every message the room's home node accepts, and
+    /// `posted` is when, in unix milliseconds. Both are 0 until then, and for
+    /// messages imported or kept from before messages had them.
+    Message {
+        content: String,
+        #[serde(default)]
+        id: u64,
+        #[serde(default)]
+        posted: u64,
+    },
+    /// The `size` bytes are kept at `url`, see `blobs`. Attachments from
+    /// before blobs were kept have them inline in `data`, base64.
+    Attachment {
+        from: String,
+        name: String,
+        mime: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        #[serde(default)]
+        size: usize,
+    },
+    /// Kept like attachments
+    VoiceNote {
+        from: String,
+        mime: String,
+        duration_ms: u64,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+        #[serde(default)]
+        size: usize,
+    },
+    /// `from` reposted the message `original_seq` of `room`, `content` is
+    /// the original's, author included
+    Forwarded {
+        from: String,
+        room: String,
+        original_seq: u64,
+        content: String,
+    },
+    /// `from` posted `content` to all of `rooms` at once, every copy with
+    /// the same `post`, its message id
+    CrossPost {
+        post: u64,
+        from: String,
+        rooms: Vec<String>,
+        content: String,
+    },
+    Archived {
+        archived: bool,
+    },
+    /// the owner changed who may join the room
+    Access {
+        policy: AccessPolicy,
+    },
+    /// the owner saved a canned response, or removed it if `content` is `None`
+    Canned {
+        name: String,
+        content: Option<String>,
+    },
+    Hours {
+        hours: Option<OpeningHours>,
+    },
+    Opened,
+    Closed,
+    Aliased {
+        alias: String,
+    },
+    /// the join code was replaced or removed, the code itself isn't shown
+    JoinCode {
+        enabled: bool,
+    },
+    /// the owner removed `name` from the room
+    Kicked {
+        name: String,
+    },
+    /// `name` may no longer join or post, or may again if not `banned`.
+    /// `account` is the one `name` was signed in to when banned, if any,
+    /// which is banned with it.
+    Banned {
+        name: String,
+        banned: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        account: Option<String>,
+    },
+    /// `name` may not post for `secs` seconds, 0 lifts the mute. `account`
+    /// is the one `name` was signed in to when muted, if any, which is muted
+    /// with it.
+    Muted {
+        name: String,
+        secs: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        account: Option<String>,
+    },
+    /// the owner `by` took `with` aside to the private room `breakout`
+    Breakout {
+        breakout: String,
+        by: String,
+        with: String,
+    },
+    /// `name` may post in the moderated room for `secs` seconds
+    CalledOn {
+        name: String,
+        secs: u64,
+    },
+    /// asked in a room in Q&A mode
+    Question {
+        id: u64,
+        from: String,
+        text: String,
+    },
+    /// not shown, only kept so every node can rank the questions
+    QuestionVotes {
+        id: u64,
+        votes: usize,
+    },
+    Answered {
+        id: u64,
+    },
+    /// periodic summary of the room's activity, see `digest::Digests`
+    Digest(Digest),
+    StreamStarted {
+        stream_id: String,
+        host: String,
+        title: String,
+    },
+    StreamViewers {
+        stream_id: String,
+        viewers: usize,
+    },
+    StreamEnded {
+        stream_id: String,
+    },
+    /// `changed` names the settings that changed
+    Settings {
+        changed: Vec<String>,
+        settings: RoomSettings,
+    },
+    /// posted through the admin API
+    Announcement {
+        text: String,
+    },
+    /// through the admin API, every member was removed
+    Deleted,
+    /// the author changed the message `id`, `content` is the new one with the
+    /// author, as in `Message`
+    MessageEdited {
+        id: u64,
+        content: String,
+    },
+    /// the author took the message `id` back
+    MessageDeleted {
+        id: u64,
+    },
+    /// how many members reacted to the message `id` with each emoji, all of
+    /// them since the message was posted
+    Reactions {
+        id: u64,
+        reactions: BTreeMap<String, usize>,
+    },
+}
+
+/// Where an attachment's or voice note's bytes are, in its frame
+fn blob_fields(
+    frame: &mut serde_json::Value,
+    data: &str,
+    url: &Option<String>,
+    size: usize,
+) {
+    match url {
+        Some(url) => {
+            frame["url"] = url.as_str().into();
+            frame["size"] = size.into();
+        }
+        None => frame["data"] = data.into(),
+    }
+}
+
+impl EventKind {
+    /// A chat message yet to be posted
+    pub fn message(content: String) -> EventKind {
+        EventKind::Message {
+            content,
+            id: 0,
+            posted: 0,
+        }
+    }
+
+    /// The name of whoever posted it, for the kinds members post
+    pub fn author(&self) -> Option<&str> {
+        match self {
+            EventKind::Message { content, .. }
+            | EventKind::MessageEdited { content, .. } => {
+                content.split_once(": ").map(|(author, _)| author)
+            }
+            EventKind::Forwarded { from, .. }
+            | EventKind::CrossPost { from, .. }
+            | EventKind::Attachment { from, .. }
+            | EventKind::VoiceNote { from, .. }
+            | EventKind::Question { from, .. } => Some(from),
+            _ => None,
+        }
+    }
+
+    /// How urgently members get the event, compared to other frames
+    pub fn lane(&self) -> Lane {
+        match self {
+            EventKind::Message { .. }
+            | EventKind::Forwarded { .. }
+            | EventKind::CrossPost { .. }
+            | EventKind::Attachment { .. }
+            | EventKind::VoiceNote { .. }
+            | EventKind::Question { .. }
+            | EventKind::MessageEdited { .. }
+            | EventKind::MessageDeleted { .. }
+            | EventKind::Reactions { .. } => Lane::Chat,
+            _ => Lane::System,
+        }
+    }
+
+    /// What room members see of the event, if anything. Forwards, cross-posts
+    /// and questions are sent as their `Reply`, anything else but chat as a
+    /// system frame, with the event's fields and its type as `kind`.
+    pub fn text(&self, room_name: &str) -> Option<String> {
+        let notice = match self {
+            EventKind::QuestionVotes { .. } => return None,
+            EventKind::Message { content, .. } => return Some(content.clone()),
+            EventKind::Forwarded {
+                from,
+                room,
+                content,
+                ..
+            } => {
+                let reply = Reply::Forwarded {
+                    from: from.clone(),
+                    room: room.clone(),
+                    content: content.clone(),
+                };
+                return Some(reply.to_json());
+            }
+            EventKind::CrossPost {
+                post,
+                from,
+                rooms,
+                content,
+            } => {
+                let reply = Reply::CrossPost {
+                    id: *post,
+                    from: from.clone(),
+                    rooms: rooms.clone(),
+                    content: content.clone(),
+                };
+                return Some(reply.to_json());
+            }
+            EventKind::Question { id, from, text } => {
+                let reply = Reply::Question {
+                    id: *id,
+                    from: from.clone(),
+                    text: text.clone(),
+                };
+                return Some(reply.to_json());
+            }
+            EventKind::Attachment {
+                from,
+                name,
+                mime,
+                data,
+                url,
+                size,
+            } => {
+                let mut frame = serde_json::json!({
+                    "type": "attachment",
+                    "from": from,
+                    "name": name,
+                    "mime": mime,
+                });
+                blob_fields(&mut frame, data, url, *size);
+                return Some(frame.to_string());
+            }
+            EventKind::VoiceNote {
+                from,
+                mime,
+                duration_ms,
+                data,
+                url,
+                size,
+            } => {
+                let mut frame = serde_json::json!({
+                    "type": "voice_note",
+                    "from": from,
+                    "mime": mime,
+                    "duration_ms": duration_ms,
+                });
+                blob_fields(&mut frame, data, url, *size);
+                return Some(frame.to_string());
+            }
+            EventKind::MessageEdited { id, content } => {
+                let (from, content) = content.split_once(": ").unwrap_or(("", content));
+                let mut fields = Map::new();
+                fields.insert("id".to_owned(), (*id).into());
+                fields.insert("from".to_owned(), from.into());
+                fields.insert("content".to_owned(), content.into());
+
+                let text = format!("{} edited message {}", from, id);
+                return Some(system_frame(
+                    "message_edited",
+                    Some(room_name),
+                    &text,
+                    fields,
+                ));
+            }
+            EventKind::MessageDeleted { id } => {
+                let mut fields = Map::new();
+                fields.insert("id".to_owned(), (*id).into());
+
+                let text = format!("message {} was deleted", id);
+                return Some(system_frame(
+                    "message_deleted",
+                    Some(room_name),
+                    &text,
+                    fields,
+                ));
+            }
+            EventKind::Reactions { id, reactions } => {
+                let frame = serde_json::json!({
+                    "type": "reactions",
+                    "room": room_name,
+                    "id": id,
+                    "reactions": reactions,
+                });
+                return Some(frame.to_string());
+            }
+            // sent as is, for clients to show or hide stream controls
+            EventKind::StreamStarted { .. }
+            | EventKind::StreamViewers { .. }
+            | EventKind::StreamEnded { .. } => return serde_json::to_string(self).ok(),
+            _ => self.notice(room_name)?,
+        };
+
+        let mut fields = match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        let kind = match fields.remove("type") {
+            Some(Value::String(kind)) => kind,
+            _ => return None,
+        };
+
+        Some(system_frame(&kind, Some(room_name), &notice, fields))
+    }
+
+    /// The event as a line of text, for the system notices members get and
+    /// `/events`. `None` for chat, streams and vote counts.
+    pub fn notice(&self, room_name: &str) -> Option<String> {
+        Some(match self {
+            EventKind::Left { name } => format!("{} left {}", name, room_name),
+            EventKind::Joined { name } => format!("{} joined {}", name, room_name),
+            EventKind::Archived { archived: true } => {
+                format!("{} has been archived and is now read-only", room_name)
+            }
+            EventKind::Archived { archived: false } => {
+                format!("{} has been unarchived", room_name)
+            }
+            EventKind::Hours { hours: Some(hours) } => {
+                format!("{} is now open {}", room_name, hours)
+            }
+            EventKind::Hours { hours: None } => {
+                format!("{} no longer has opening hours", room_name)
+            }
+            EventKind::Opened => format!("{} is now open", room_name),
+            EventKind::Closed => format!("{} is now closed", room_name),
+            EventKind::Aliased { alias } => {
+                format!("{} can now also be joined as {}", room_name, alias)
+            }
+            EventKind::JoinCode { enabled: true } => {
+                format!("{} has a new join code, old ones no longer work", room_name)
+            }
+            EventKind::JoinCode { enabled: false } => {
+                format!("{} no longer has a join code", room_name)
+            }
+            EventKind::Canned {
+                name,
+                content: Some(_),
+            } => format!(
+                "canned response {} saved, post it with /canned {}",
+                name, name
+            ),
+            EventKind::Canned {
+                name,
+                content: None,
+            } => {
+                format!("canned response {} was removed", name)
+            }
+            EventKind::Access { policy } => match policy {
+                AccessPolicy::Open => format!("{} is open to everyone", room_name),
+                AccessPolicy::Password => format!("{} now needs a password", room_name),
+                AccessPolicy::InviteOnly => format!("{} is now invite only", room_name),
+            },
+            EventKind::CalledOn { name, secs } => {
+                format!("{} may speak for the next {} seconds", name, secs)
+            }
+            EventKind::Answered { id } => format!("question {} has been answered", id),
+            EventKind::Kicked { name } => {
+                format!("{} was removed from {}", name, room_name)
+            }
+            EventKind::Banned {
+                name, banned: true, ..
+            } => format!("{} is banned from {}", name, room_name),
+            EventKind::Banned {
+                name,
+                banned: false,
+                ..
+            } => format!("{} is no longer banned from {}", name, room_name),
+            EventKind::Muted { name, secs: 0, .. } => {
+                format!("{} may post in {} again", name, room_name)
+            }
+            EventKind::Muted { name, secs, .. } => format!(
+                "{} is muted in {} for {}",
+                name,
+                room_name,
+                format_delay(Duration::from_secs(*secs))
+            ),
+            EventKind::Breakout { breakout, by, with } => {
+                format!("{} took {} to the breakout room {}", by, with, breakout)
+            }
+            EventKind::Digest(digest) => format!("{}, {}", room_name, digest),
+            EventKind::Announcement { text } => text.clone(),
+            EventKind::Deleted => format!("{} has been deleted", room_name),
+            EventKind::Settings { changed, settings } => match &settings.topic {
+                Some(topic) if *changed == ["topic"] => {
+                    format!("topic of {} is now: {}", room_name, topic)
+                }
+                _ => format!("{} settings changed: {}", room_name, changed.join(", ")),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Applies an edit or deletion to the message or cross-post it's about, if
+/// it's still kept. The change is kept as well, for subscribers and the store,
+/// which replays it onto the message when restoring. Reaction counts are totals,
+/// so only a message's latest are kept.
+fn amend(events: &mut VecDeque<RoomEvent>, change: &EventKind) {
+    let reactions_to = |event: &RoomEvent, id: u64| match event.kind {
+        EventKind::Reactions { id: reacted, .. } => reacted == id,
+        _ => false,
+    };
+
+    match change {
+        EventKind::MessageEdited { id, content } => {
+            for event in events.iter_mut() {
+                if let EventKind::Message {
+                    id: message_id,
+                    content: old,
+                    ..
+                } = &mut event.kind
+                {
+                    if message_id == id {
+                        *old = content.clone();
+                    }
+                } else if let EventKind::CrossPost {
+                    post, content: old, ..
+                } = &mut event.kind
+                {
+                    // a cross-post's content goes without its author's name
+                    if post == id {
+                        let text = content.split_once(": ").map(|(_, text)| text);
+                        *old = text.unwrap_or(content).to_owned();
+                    }
+                }
+            }
+        }
+        EventKind::MessageDeleted { id } => events.retain(|event| {
+            let message = match &event.kind {
+                EventKind::Message { id: message_id, .. } => message_id == id,
+                EventKind::CrossPost { post, .. } => post == id,
+                _ => false,
+            };
+            !message && !reactions_to(event, *id)
+        }),
+        EventKind::Reactions { id, .. } => {
+            events.retain(|event| !reactions_to(event, *id))
+        }
+        _ => {}
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Message)]
+#[rtype(result = "()")]
+pub struct RoomEvent {
+    /// increases by one with every event recorded on this node
+    pub seq: u64,
+    pub room_name: String,
+    /// unix milliseconds
+    pub time: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Append-only log of the recent events of every room this node hears about,
+/// for consumers that want a room's activity without hooking into
+/// `WsChatServer`. Subscribers get the backlog they ask for, then every new
+/// event as it's recorded.
+#[derive(Default)]
+pub struct Journal {
+    last_seq: u64,
+    rooms: HashMap<String, VecDeque<RoomEvent>>,
+    /// events kept per room, where it isn't the config's `history_depth`
+    retention: HashMap<String, usize>,
+    /// rooms flagged `RoomFlag::NoLog`, whose events are dropped
+    unlogged: HashSet<String>,
+    /// (room, or every room if `None`, subscriber)
+    subscribers: Vec<(Option<String>, Recipient<RoomEvent>)>,
+}
+
+impl Actor for Journal {
+    type Context = Context<Self>;
+}
+
+impl Handler<RecordEvent> for Journal {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordEvent, _ctx: &mut Self::Context) {
+        let RecordEvent(room_name, kind) = msg;
+
+        if let EventKind::Settings { settings, .. } = &kind {
+            match settings.retention {
+                Some(retention) => self.retention.insert(room_name.clone(), retention),
+                None => self.retention.remove(&room_name),
+            };
+
+            if settings.has_flag(RoomFlag::NoLog) {
+                // what was logged before goes too
+                self.rooms.remove(&room_name);
+                if let Some(store) = store() {
+                    store.do_send(ForgetRoom(room_name.clone()));
+                }
+                self.unlogged.insert(room_name.clone());
+            } else {
+                self.unlogged.remove(&room_name);
+            }
+        }
+
+        if self.unlogged.contains(&room_name) {
+            return;
+        }
+
+        let retention = self
+            .retention
+            .get(&room_name)
+            .copied()
+            .unwrap_or(config().history_depth);
+
+        self.last_seq += 1;
+        let event = RoomEvent {
+            seq: self.last_seq,
+            room_name: room_name.clone(),
+            time: unix_millis() as u64,
+            kind,
+        };
+
+        if let Some(store) = store() {
+            store.do_send(SaveEvent(event.clone()));
+        }
+
+        // subscribers that went away are dropped
+        self.subscribers.retain(|(room, subscriber)| {
+            if room.as_ref().is_some_and(|room| *room != room_name) {
+                return true;
+            }
+
+            subscriber.do_send(event.clone()).is_ok()
+        });
+
+        let events = self.rooms.entry(room_name).or_default();
+        amend(events, &event.kind);
+        events.push_back(event);
+
+        while events.len() > retention {
+            events.pop_front();
+        }
+    }
+}
+
+impl Handler<ImportEvents> for Journal {
+    type Result = Result<usize, String>;
+
+    fn handle(&mut self, msg: ImportEvents, _ctx: &mut Self::Context) -> Self::Result {
+        let ImportEvents { room_name, events } = msg;
+
+        if self.unlogged.contains(&room_name) {
+            return Err(format!("{} is a no_log room", room_name));
+        }
+
+        let retention = self
+            .retention
+            .get(&room_name)
+            .copied()
+            .unwrap_or(config().history_depth);
+        let first_seq = self.last_seq + 1;
+
+        // new sequence numbers, so event stream consumers catch up on them,
+        // but not sent to subscribers as if they just happened
+        let journal = self.rooms.entry(room_name.clone()).or_default();
+        for (time, kind) in events {
+            amend(journal, &kind);
+            self.last_seq += 1;
+            journal.push_back(RoomEvent {
+                seq: self.last_seq,
+                room_name: room_name.clone(),
+                time,
+                kind,
+            });
+        }
+
+        journal.make_contiguous().sort_by_key(|event| event.time);
+        while journal.len() > retention {
+            journal.pop_front();
+        }
+
+        Ok(journal
+            .iter()
+            .filter(|event| event.seq >= first_seq)
+            .count())
+    }
+}
+
+impl Handler<SubscribeEvents> for Journal {
+    type Result = MessageResult<SubscribeEvents>;
+
+    fn handle(
+        &mut self,
+        msg: SubscribeEvents,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let SubscribeEvents {
+            room_name,
+            since,
+            subscriber,
+        } = msg;
+
+        let mut backlog: Vec<RoomEvent> = self
+            .rooms
+            .iter()
+            .filter(|(room, _)| room_name.as_ref().is_none_or(|name| name == *room))
+            .flat_map(|(_, events)| events.iter())
+            .filter(|event| event.seq > since)
+            .cloned()
+            .collect();
+        backlog.sort_by_key(|event| event.seq);
+
+        self.subscribers.push((room_name, subscriber));
+        MessageResult(backlog)
+    }
+}
+
+impl Handler<RoomHistory> for Journal {
+    type Result = MessageResult<RoomHistory>;
+
+    fn handle(&mut self, msg: RoomHistory, _ctx: &mut Self::Context) -> Self::Result {
+        let RoomHistory { room_name, since } = msg;
+
+        let events = self
+            .rooms
+            .get(&room_name)
+            .map(|events| {
+                events
+                    .iter()
+                    .filter(|event| event.time >= since)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        MessageResult(events)
+    }
+}
+
+/// One line per event, oldest first, leaving out chat
+impl Handler<SendEventLog> for Journal {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendEventLog, _ctx: &mut Self::Context) {
+        let SendEventLog {
+            room_name,
+            count,
+            to,
+        } = msg;
+
+        let now = unix_millis() as u64;
+        let mut lines: Vec<String> = self
+            .rooms
+            .get(&room_name)
+            .into_iter()
+            .flat_map(|events| events.iter().rev())
+            .filter_map(|event| {
+                let notice = event.kind.notice(&room_name)?;
+                let ago = Duration::from_millis(now.saturating_sub(event.time));
+                Some(format!("{} ago: {}", format_delay(ago), notice))
+            })
+            .take(count)
+            .collect();
+        lines.reverse();
+
+        if lines.is_empty() {
+            lines.push(format!("no events of {} in the log", room_name));
+        }
+        for line in lines {
+            to.do_send(ChatMessage(Reply::notice(line).to_json())).ok();
+        }
+    }
+}
+
+impl Handler<FindEvents> for Journal {
+    type Result = MessageResult<FindEvents>;
+
+    fn handle(&mut self, msg: FindEvents, _ctx: &mut Self::Context) -> Self::Result {
+        let seqs: HashSet<u64> = msg.0.into_iter().collect();
+
+        // imported events are in time order, not necessarily in seq order
+        let events = self
+            .rooms
+            .values()
+            .flat_map(|events| events.iter())
+            .filter(|event| seqs.contains(&event.seq))
+            .cloned()
+            .collect();
+
+        MessageResult(events)
+    }
+}
+
+impl SystemService for Journal {}
+impl Supervised for Journal {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: u64, kind: EventKind) -> RoomEvent {
+        RoomEvent {
+            seq,
+            room_name: "Main".to_owned(),
+            time: seq,
+            kind,
+        }
+    }
+
+    fn message(id: u64, content: &str) -> EventKind {
+        EventKind::Message {
+            content: content.to_owned(),
+            id,
+            posted: id,
+        }
+    }
+
+    #[test]
+    fn test_amend() {
+        let mut events: VecDeque<_> = vec![
+            event(1, message(10, "bob: hi")),
+            event(2, message(11, "bob: typo")),
+        ]
+        .into();
+
+        let edit = EventKind::MessageEdited {
+            id: 11,
+            content: "bob: fixed".to_owned(),
+        };
+        amend(&mut events, &edit);
+        assert_eq!(events[1].kind.text("Main"), Some("bob: fixed".to_owned()));
+
+        amend(&mut events, &EventKind::MessageDeleted { id: 10 });
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].seq, 2);
+
+        let reactions = |count| EventKind::Reactions {
+            id: 11,
+            reactions: BTreeMap::from([("👍".to_owned(), count)]),
+        };
+        for count in 1..=2 {
+            let reactions = reactions(count);
+            amend(&mut events, &reactions);
+            events.push_back(event(2 + count as u64, reactions));
+        }
+        assert_eq!(events.len(), 2);
+        assert!(events[1].kind.text("Main").unwrap().contains(r#"{"👍":2}"#));
+
+        let frame = edit.text("Main").unwrap();
+        assert!(frame.contains(r#""kind":"message_edited""#));
+        assert!(frame.contains(r#""content":"fixed""#));
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use actix::Recipient;
+
+use crate::message::ChatMessage;
+
+/// How often queued ephemeral frames are sent
+pub const EPHEMERAL_TICK: Duration = Duration::from_millis(10);
+
+/// Ephemeral frames sent per tick at most
+const EPHEMERAL_BUDGET: usize = 1000;
+
+/// Ephemeral frames queued at most, the oldest are dropped beyond that
+const EPHEMERAL_CAPACITY: usize = 10_000;
+
+/// Classes of frames going out to clients, the most urgent first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    /// server notices, e.g. settings changes and moderation
+    System,
+    /// messages, questions and attachments
+    Chat,
+    /// drawing and presence, see `frames::Ephemeral`
+    Ephemeral,
+}
+
+type Delivery = (Recipient<ChatMessage>, String);
+
+/// Frames waiting to go out, one queue per lane. System and chat frames are
+/// flushed straight away, system ones first. Ephemeral frames are only sent
+/// on the next tick, a budget at a time, so a flood of them costs the server
+/// a bounded amount of work between chat messages and can't hold them up.
+#[derive(Default)]
+pub struct Outbox {
+    system: VecDeque<Delivery>,
+    chat: VecDeque<Delivery>,
+    ephemeral: VecDeque<Delivery>,
+    /// ephemeral frames dropped for lack of room
+    dropped: u64,
+}
+
+impl Outbox {
+    pub fn push(&mut self, lane: Lane, client: Recipient<ChatMessage>, frame: String) {
+        let queue = match lane {
+            Lane::System => &mut self.system,
+            Lane::Chat => &mut self.chat,
+            Lane::Ephemeral => &mut self.ephemeral,
+        };
+        queue.push_back((client, frame));
+
+        if self.ephemeral.len() > EPHEMERAL_CAPACITY {
+            self.ephemeral.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    fn send(queue: &mut VecDeque<Delivery>, limit: usize) {
+        let count = limit.min(queue.len());
+        for (client, frame) in queue.drain(..count) {
+            client.do_send(ChatMessage(frame)).ok();
+        }
+    }
+
+    /// Sends every system and chat frame
+    pub fn flush(&mut self) {
+        Outbox::send(&mut self.system, usize::MAX);
+        Outbox::send(&mut self.chat, usize::MAX);
+    }
+
+    /// Sends a tick's worth of ephemeral frames, after anything more urgent
+    pub fn tick(&mut self) {
+        self.flush();
+        Outbox::send(&mut self.ephemeral, EPHEMERAL_BUDGET);
+    }
+
+    pub fn queued_ephemeral(&self) -> usize {
+        self.ephemeral.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
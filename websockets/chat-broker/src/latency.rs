@@ -0,0 +1,144 @@
+//! How long sessions wait on `WsChatServer`, from sending a request to having
+//! the answer. The server's own mailbox wait is `load::LoadMonitor`'s; this
+//! is what clients feel of it, per kind of request, as shown by
+//! `GET /api/admin/latency`. Requests give up after the config's
+//! `request_timeout_secs`, so an overloaded server can't hang the sessions
+//! waiting on it.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config::config;
+use crate::server::WsChatServer;
+
+/// Round trips kept per request for the percentiles
+const RECENT: usize = 512;
+
+static ROUND_TRIPS: Lazy<Mutex<BTreeMap<&'static str, RoundTrips>>> =
+    Lazy::new(Default::default);
+
+#[derive(Default)]
+struct RoundTrips {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    /// over the config's `latency_budget_ms`
+    slow: u64,
+    /// given up on after `request_timeout_secs`
+    timed_out: u64,
+    recent: VecDeque<Duration>,
+}
+
+/// One kind of request's round trips since the node started, the
+/// percentiles over the latest ones
+#[derive(Clone, Debug, Serialize)]
+pub struct RoundTripStats {
+    pub request: &'static str,
+    pub count: u64,
+    pub slow: u64,
+    pub timed_out: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Nearest rank, `sorted` mustn't be empty
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn record(request: &'static str, elapsed: Duration, timed_out: bool) {
+    let mut round_trips = ROUND_TRIPS.lock().unwrap();
+    let trips = round_trips.entry(request).or_default();
+
+    trips.count += 1;
+    trips.total += elapsed;
+    trips.max = trips.max.max(elapsed);
+    if elapsed > config().latency_budget() {
+        trips.slow += 1;
+    }
+    if timed_out {
+        trips.timed_out += 1;
+    }
+
+    trips.recent.push_back(elapsed);
+    if trips.recent.len() > RECENT {
+        trips.recent.pop_front();
+    }
+}
+
+/// Sends `msg` to the chat server, timing the round trip as `request`. Fails
+/// with `MailboxError::Timeout` after the config's `request_timeout_secs`.
+pub fn server_request<M>(
+    request: &'static str,
+    msg: M,
+) -> impl Future<Output = Result<M::Result, MailboxError>>
+where
+    M: Message + Send + 'static,
+    M::Result: Send,
+    WsChatServer: Handler<M>,
+{
+    let sent = Instant::now();
+    let response = WsChatServer::from_registry()
+        .send(msg)
+        .timeout(config().request_timeout());
+
+    async move {
+        let res = response.await;
+        let timed_out = matches!(res, Err(MailboxError::Timeout));
+        record(request, sent.elapsed(), timed_out);
+        res
+    }
+}
+
+/// Every kind of request timed so far, by name
+pub fn report() -> Vec<RoundTripStats> {
+    let round_trips = ROUND_TRIPS.lock().unwrap();
+
+    round_trips
+        .iter()
+        .map(|(request, trips)| {
+            let mut sorted: Vec<Duration> = trips.recent.iter().copied().collect();
+            sorted.sort_unstable();
+            let us = |duration: Duration| duration.as_micros() as u64;
+
+            RoundTripStats {
+                request,
+                count: trips.count,
+                slow: trips.slow,
+                timed_out: trips.timed_out,
+                mean_us: (trips.total.as_micros() / u128::from(trips.count)) as u64,
+                p50_us: us(percentile(&sorted, 50)),
+                p95_us: us(percentile(&sorted, 95)),
+                p99_us: us(percentile(&sorted, 99)),
+                max_us: us(trips.max),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let ms = Duration::from_millis;
+        let sorted: Vec<Duration> = (1..=100).map(ms).collect();
+
+        assert_eq!(percentile(&sorted, 50), ms(50));
+        assert_eq!(percentile(&sorted, 99), ms(99));
+        assert_eq!(percentile(&sorted, 100), ms(100));
+        assert_eq!(percentile(&[ms(7)], 50), ms(7));
+        assert_eq!(percentile(&[ms(1), ms(9)], 95), ms(9));
+    }
+}
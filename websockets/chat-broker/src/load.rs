@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config::config;
+use crate::frames::Ephemeral;
+use crate::session::unix_millis;
+
+/// How often the server measures how long messages wait in its mailbox
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Weight of the newest probe in the moving average
+const SMOOTHING: f64 = 0.3;
+
+/// Load changes kept for the admin API
+const MAX_CHANGES: usize = 50;
+
+/// Which events are dropped to catch up, the least important first. Chat
+/// messages and room events are never dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shedding {
+    None,
+    /// over the budget: presence updates and typing indicators
+    Presence,
+    /// over twice the budget: whiteboard ops as well
+    Ephemeral,
+}
+
+impl Shedding {
+    /// The level for `latency`. It eases off one level at a time, and only
+    /// once the latency is under half the threshold the level is raised at,
+    /// so it doesn't flap.
+    fn for_latency(self, latency: Duration, budget: Duration) -> Shedding {
+        let wanted = if latency > budget * 2 {
+            Shedding::Ephemeral
+        } else if latency > budget {
+            Shedding::Presence
+        } else {
+            Shedding::None
+        };
+
+        if wanted >= self {
+            return wanted;
+        }
+
+        match self {
+            Shedding::Presence if latency > budget / 2 => self,
+            Shedding::Ephemeral if latency > budget => self,
+            Shedding::Ephemeral => Shedding::Presence,
+            _ => Shedding::None,
+        }
+    }
+
+    pub fn drops(self, event: &Ephemeral) -> bool {
+        match event {
+            Ephemeral::Presence { .. } | Ephemeral::Typing { typing: true } => {
+                self >= Shedding::Presence
+            }
+            // dropping it would leave the indicator up until its expiry
+            Ephemeral::Typing { typing: false } => false,
+            Ephemeral::Draw { .. } => self >= Shedding::Ephemeral,
+        }
+    }
+}
+
+/// A change of `Shedding` level, as listed by the admin API
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadChange {
+    /// unix milliseconds
+    pub time: u64,
+    pub shedding: Shedding,
+    pub latency_ms: u64,
+}
+
+/// This node's load, as shown by `GET /api/admin/load`
+#[derive(Clone, Debug, Serialize)]
+pub struct LoadReport {
+    pub latency_ms: u64,
+    pub budget_ms: u64,
+    pub shedding: Shedding,
+    /// events dropped since the node started
+    pub shed: u64,
+    /// frames waiting in the server's ephemeral lane, see `lanes::Outbox`
+    pub queued_ephemeral: usize,
+    /// ephemeral frames dropped since the node started, the lane being full
+    pub dropped_frames: u64,
+    /// the latest changes of level, oldest first
+    pub changes: Vec<LoadChange>,
+}
+
+/// Tracks the broadcast latency of the server, how long a message waits in
+/// its mailbox before the server gets to fan it out, and decides what to
+/// shed when it goes over the budget
+#[derive(Debug)]
+pub struct LoadMonitor {
+    /// moving average, in seconds
+    latency: f64,
+    shedding: Shedding,
+    shed: u64,
+    changes: VecDeque<LoadChange>,
+}
+
+impl Default for LoadMonitor {
+    fn default() -> Self {
+        LoadMonitor {
+            latency: 0.0,
+            shedding: Shedding::None,
+            shed: 0,
+            changes: VecDeque::new(),
+        }
+    }
+}
+
+impl LoadMonitor {
+    fn latency(&self) -> Duration {
+        Duration::from_secs_f64(self.latency)
+    }
+
+    /// Takes a probe's wait into account, returning the change of level if
+    /// there is one
+    pub fn record(&mut self, wait: Duration) -> Option<&LoadChange> {
+        self.latency = self.latency * (1.0 - SMOOTHING) + wait.as_secs_f64() * SMOOTHING;
+
+        let shedding = self
+            .shedding
+            .for_latency(self.latency(), config().latency_budget());
+        if shedding == self.shedding {
+            return None;
+        }
+        self.shedding = shedding;
+
+        self.changes.push_back(LoadChange {
+            time: unix_millis() as u64,
+            shedding,
+            latency_ms: self.latency().as_millis() as u64,
+        });
+        if self.changes.len() > MAX_CHANGES {
+            self.changes.pop_front();
+        }
+
+        self.changes.back()
+    }
+
+    /// Whether to drop the event, counting it if so
+    pub fn shed(&mut self, event: &Ephemeral) -> bool {
+        let drop = self.shedding.drops(event);
+        if drop {
+            self.shed += 1;
+        }
+
+        drop
+    }
+
+    pub fn report(&self) -> LoadReport {
+        LoadReport {
+            latency_ms: self.latency().as_millis() as u64,
+            budget_ms: config().latency_budget_ms,
+            shedding: self.shedding,
+            shed: self.shed,
+            queued_ephemeral: 0,
+            dropped_frames: 0,
+            changes: self.changes.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shedding_levels() {
+        let budget = Duration::from_millis(100);
+        let ms = Duration::from_millis;
+
+        assert_eq!(Shedding::None.for_latency(ms(90), budget), Shedding::None);
+        assert_eq!(
+            Shedding::None.for_latency(ms(150), budget),
+            Shedding::Presence
+        );
+        assert_eq!(
+            Shedding::None.for_latency(ms(250), budget),
+            Shedding::Ephemeral
+        );
+
+        // easing off takes getting well under the threshold
+        assert_eq!(
+            Shedding::Ephemeral.for_latency(ms(150), budget),
+            Shedding::Ephemeral
+        );
+        assert_eq!(
+            Shedding::Ephemeral.for_latency(ms(90), budget),
+            Shedding::Presence
+        );
+        assert_eq!(
+            Shedding::Presence.for_latency(ms(60), budget),
+            Shedding::Presence
+        );
+        assert_eq!(
+            Shedding::Presence.for_latency(ms(40), budget),
+            Shedding::None
+        );
+
+        let presence = Ephemeral::Presence {
+            data: serde_json::json!({}),
+        };
+        assert!(Shedding::Presence.drops(&presence));
+        assert!(!Shedding::None.drops(&presence));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::web;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use log::{info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::breaker::{CircuitBreaker, COOL_OFF};
+use crate::config::config;
+use crate::message::{QueueMention, SendPasswordReset, SendVerification};
+
+/// Mails taking longer to hand over count against the SMTP server's circuit
+/// breaker
+const SLOW_SEND: Duration = Duration::from_secs(10);
+
+/// Mentions beyond this many per batch are summarised instead of listed
+const MAX_MENTIONS_PER_MAIL: usize = 20;
+
+/// A queued mention waiting for the next batch flush
+struct Mention {
+    room_name: String,
+    context: String,
+    /// false for messages of rooms the user wants every message of
+    mentioned: bool,
+}
+
+/// Collects outgoing notifications and mails them in batches, so a busy room
+/// produces one email per recipient per interval instead of one per mention.
+///
+/// Configured through `SMTP_HOST`, `SMTP_PORT`, `SMTP_USERNAME`,
+/// `SMTP_PASSWORD`, `MAIL_FROM` and `PUBLIC_URL`, and batched every
+/// `mail_batch_secs` of the config. Without `SMTP_HOST` mails are only logged.
+pub struct Mailer {
+    transport: Option<SmtpTransport>,
+    from: String,
+    public_url: String,
+    batch_interval: Duration,
+    /// pending mentions keyed by (email, account name)
+    pending: HashMap<(String, String), Vec<Mention>>,
+    /// keeps a failing SMTP server from tying up the blocking thread pool
+    breaker: CircuitBreaker,
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or_else(|_| default.to_owned())
+}
+
+fn build_transport() -> Option<SmtpTransport> {
+    let host = std::env::var("SMTP_HOST").ok()?;
+
+    let builder = match std::env::var("SMTP_USERNAME") {
+        Ok(username) => {
+            let password = env_or("SMTP_PASSWORD", "");
+
+            SmtpTransport::relay(&host)
+                .map_err(|err| warn!("build_transport() - {}", err))
+                .ok()?
+                .credentials(Credentials::new(username, password))
+        }
+        // plaintext, for local catch-all servers such as MailHog
+        Err(_) => SmtpTransport::builder_dangerous(&host),
+    };
+
+    let builder = match std::env::var("SMTP_PORT").ok().and_then(|p| p.parse().ok()) {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+
+    Some(builder.build())
+}
+
+fn build_message(
+    from: &str,
+    to: &str,
+    subject: &str,
+    body: String,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .body(body)?)
+}
+
+impl Default for Mailer {
+    fn default() -> Self {
+        Mailer {
+            transport: build_transport(),
+            from: env_or("MAIL_FROM", "chat-broker <chat-broker@localhost>"),
+            public_url: env_or("PUBLIC_URL", "http://localhost:8080"),
+            batch_interval: config().mail_batch_interval(),
+            pending: HashMap::new(),
+            breaker: CircuitBreaker::new(SLOW_SEND),
+        }
+    }
+}
+
+impl Mailer {
+    fn room_link(&self, room_name: &str) -> String {
+        format!(
+            "{}/?room={}",
+            self.public_url,
+            utf8_percent_encode(room_name, NON_ALPHANUMERIC)
+        )
+    }
+
+    fn mention_body(&self, name: &str, mentions: &[Mention]) -> String {
+        let mut body = if mentions.iter().all(|mention| mention.mentioned) {
+            format!("Hi {}, you were mentioned while you were away:\n\n", name)
+        } else {
+            format!(
+                "Hi {}, here is what you missed while you were away:\n\n",
+                name
+            )
+        };
+
+        for mention in mentions.iter().take(MAX_MENTIONS_PER_MAIL) {
+            body.push_str(&format!(
+                "[{}]{} {}\n  {}\n\n",
+                mention.room_name,
+                if mention.mentioned { " @" } else { "" },
+                mention.context,
+                self.room_link(&mention.room_name)
+            ));
+        }
+
+        if mentions.len() > MAX_MENTIONS_PER_MAIL {
+            body.push_str(&format!(
+                "... and {} more\n",
+                mentions.len() - MAX_MENTIONS_PER_MAIL
+            ));
+        }
+
+        body
+    }
+
+    pub fn send(
+        &mut self,
+        to: &str,
+        subject: &str,
+        body: String,
+        ctx: &mut Context<Self>,
+    ) {
+        let transport = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => {
+                info!("Mailer::send() - SMTP_HOST unset, to: {}\n{}", to, body);
+                return;
+            }
+        };
+
+        let email = match build_message(&self.from, to, subject, body) {
+            Ok(email) => email,
+            Err(err) => {
+                warn!("Mailer::send() - can't build mail to {}: {}", to, err);
+                return;
+            }
+        };
+
+        if !self.breaker.allow() {
+            warn!(
+                "Mailer::send() - SMTP server failing, dropped mail to {}",
+                to
+            );
+            return;
+        }
+
+        let to = to.to_owned();
+        let sent = Instant::now();
+
+        // SmtpTransport blocks, so hand it to the blocking thread pool
+        web::block(move || transport.send(&email))
+            .into_actor(self)
+            .map(move |res, act, _| {
+                match &res {
+                    Ok(_) => info!("Mailer::send() - sent to {}", to),
+                    Err(err) => warn!("Mailer::send() - failed for {}: {}", to, err),
+                }
+                if act.breaker.record(res.is_ok(), sent.elapsed()) {
+                    warn!(
+                        "Mailer::send() - SMTP server keeps failing, not sending for {}s",
+                        COOL_OFF.as_secs()
+                    );
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        for ((email, name), mentions) in std::mem::take(&mut self.pending) {
+            let subject = if mentions.iter().all(|mention| mention.mentioned) {
+                format!("You were mentioned {} time(s)", mentions.len())
+            } else {
+                format!("{} new message(s) while you were away", mentions.len())
+            };
+            let body = self.mention_body(&name, &mentions);
+            self.send(&email, &subject, body, ctx);
+        }
+    }
+}
+
+impl Actor for Mailer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.batch_interval, |act, ctx| act.flush(ctx));
+    }
+}
+
+impl Handler<QueueMention> for Mailer {
+    type Result = ();
+
+    fn handle(&mut self, msg: QueueMention, _ctx: &mut Self::Context) {
+        let QueueMention {
+            email,
+            name,
+            room_name,
+            context,
+            mentioned,
+        } = msg;
+
+        self.pending
+            .entry((email, name))
+            .or_default()
+            .push(Mention {
+                room_name,
+                context,
+                mentioned,
+            });
+    }
+}
+
+impl Handler<SendVerification> for Mailer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendVerification, ctx: &mut Self::Context) {
+        let SendVerification { email, name, token } = msg;
+
+        let body = format!(
+            "Hi {}, please confirm your email address by opening:\n\n  {}/verify?token={}\n\n\
+             Notification emails are only sent to confirmed addresses.\n",
+            name, self.public_url, token
+        );
+
+        // sent right away, verification isn't worth batching
+        self.send(&email, "Confirm your email address", body, ctx);
+    }
+}
+
+impl Handler<SendPasswordReset> for Mailer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendPasswordReset, ctx: &mut Self::Context) {
+        let SendPasswordReset { email, name, token } = msg;
+
+        let body = format!(
+            "Hi {}, a password reset was requested for your account. Your reset \
+             token is:\n\n  {}\n\nSubmit it with a new password to \
+             POST {}/api/password-reset/confirm within the hour. If you didn't \
+             ask for this, ignore this email.\n",
+            name, token, self.public_url
+        );
+
+        self.send(&email, "Reset your password", body, ctx);
+    }
+}
+
+impl SystemService for Mailer {}
+impl Supervised for Mailer {}
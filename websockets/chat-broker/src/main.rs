@@ -1,20 +1,425 @@
-use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use futures::future;
+use log::{info, warn};
+
+use actix::SystemService;
+use actix_broker::{Broker, SystemBroker};
 use actix_files::Files;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
-use actix_web_actors::ws;
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::dev::Server;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Cookie;
+use actix_web::{web, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use serde::Deserialize;
 
+mod access;
+mod accounts;
+mod admin;
+mod auth;
+mod blobs;
+mod blocklist;
+mod branding;
+mod breaker;
+mod bridge;
+mod captcha;
+mod chaos;
+mod cluster;
+mod commands;
+mod config;
+mod deflate;
+mod digest;
+mod directory;
+mod drafts;
+mod embed;
+mod features;
+mod frames;
+mod hours;
+mod import;
+mod journal;
+mod lanes;
+mod latency;
+mod load;
+mod mail;
+mod membership;
 mod message;
+mod metrics;
+mod migration;
+mod preview;
+mod qr;
+mod ratelimit;
+mod reads;
+mod recorder;
+mod reminders;
+mod repeats;
+mod scheduler;
 mod server;
 mod session;
+mod settings;
+mod sse;
+mod stars;
+mod storage;
+mod teams;
+mod templates;
+#[cfg(test)]
+mod testing;
+mod tokens;
+mod trust;
+mod watch;
+mod webhooks;
+
+use accounts::{random_token, AccountError, Accounts};
+use cluster::NODE_ID;
+use frames::valid_name;
+use message::{
+    ConfirmPasswordReset, Drain, EndSession, ListQuestions, ListSessions, Register,
+    RequestPasswordReset, ShuttingDown, TakeSession, VerifyEmail,
+};
+use migration::Migrations;
+use session::{DefaultRooms, WsChatSession};
+
+/// Names the node a client's session lives on, so a load balancer can route
+/// the client's reconnects back to it
+const NODE_COOKIE: &str = "chat_node";
+
+/// Set to the token resuming the session, for browsers to reconnect with
+const RESUME_COOKIE: &str = "chat_resume";
+
+/// Longest `User-Agent` kept to tell an account's sessions apart
+const MAX_DEVICE_LEN: usize = 128;
 
-use session::WsChatSession;
+/// How long sessions get to tell their clients the node is shutting down
+/// before the HTTP server stops
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Set by `/api/drain` and on shutdown, new sessions are refused from then on
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize)]
+struct ChatQuery {
+    /// token from a `migrate` frame or the `x-chat-resume` header, to resume a
+    /// session from a drained node or one that lost its connection
+    resume: Option<String>,
+    /// id of the last chat message the client got, to catch up on the ones
+    /// after it when resuming
+    last_id: Option<u64>,
+    /// JWT signing in, for clients that can't send `Authorization`, see `auth`
+    token: Option<String>,
+    /// the only room to join instead of the default ones, as the embed widget
+    /// does
+    room: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordResetRequestForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct PasswordResetConfirmForm {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterForm {
+    name: String,
+    email: String,
+    password: String,
+}
+
+async fn register(form: web::Json<RegisterForm>) -> Result<HttpResponse, Error> {
+    let RegisterForm {
+        name,
+        email,
+        password,
+    } = form.into_inner();
+    if !valid_name(&name) {
+        return Ok(HttpResponse::BadRequest().body("invalid name"));
+    }
+
+    let res = Accounts::from_registry()
+        .send(Register {
+            name,
+            email,
+            password,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::Created().finish(),
+        Err(err @ AccountError::NameTaken) | Err(err @ AccountError::EmailTaken) => {
+            HttpResponse::Conflict().body(err.to_string())
+        }
+        Err(err @ AccountError::WeakPassword) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    })
+}
 
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<ChatQuery>,
+    default_rooms: web::Data<DefaultRooms>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(WsChatSession::default(), &req, stream)
+    // lets the load balancer retry on another node
+    if DRAINING.load(Ordering::Relaxed) {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+
+    if let Some(node) = req.cookie(NODE_COOKIE) {
+        if node.value() != NODE_ID.as_str() {
+            info!(
+                "chat_route() - client of {} reconnected to {}",
+                node.value(),
+                NODE_ID.as_str()
+            );
+        }
+    }
+
+    let ChatQuery {
+        resume,
+        last_id,
+        token,
+        room,
+    } = query.into_inner();
+    let default_rooms = match room {
+        Some(room) => DefaultRooms(vec![room]),
+        None => default_rooms.get_ref().clone(),
+    };
+    let resume = resume.or_else(|| {
+        req.cookie(RESUME_COOKIE)
+            .map(|cookie| cookie.value().to_owned())
+    });
+
+    // before anything of the session exists
+    let signed_in = match auth::authenticate(&req, token.as_deref()) {
+        Ok(name) => name,
+        Err(res) => return Ok(res),
+    };
+
+    let resumed = match resume {
+        Some(token) => Migrations::from_registry()
+            .send(TakeSession(token.clone()))
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+            .map(|state| (token, state)),
+        None => None,
+    };
+
+    let mut session = match resumed {
+        Some((token, mut state)) => {
+            if let Some(last_id) = last_id {
+                state.last_message = last_id;
+            }
+            WsChatSession::resume(&default_rooms, token, state)
+        }
+        None => WsChatSession::new(&default_rooms),
+    };
+    let resume_token = random_token();
+    session.set_resume_token(resume_token.clone());
+    if let Some(name) = signed_in {
+        session.sign_in(name);
+    }
+    session.set_device(
+        req.headers()
+            .get("User-Agent")
+            .and_then(|agent| agent.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_DEVICE_LEN).collect()),
+    );
+
+    let mut res = deflate::start(session, &req, stream)?;
+
+    // browsers send the cookie on reconnect, other clients can echo the header
+    res.add_cookie(
+        &Cookie::build(NODE_COOKIE, NODE_ID.as_str())
+            .path("/ws/")
+            .http_only(true)
+            .finish(),
+    )?;
+    res.headers_mut().insert(
+        HeaderName::from_static("x-chat-node"),
+        HeaderValue::from_str(&NODE_ID)?,
+    );
+    res.add_cookie(
+        &Cookie::build(RESUME_COOKIE, resume_token.as_str())
+            .path("/ws/")
+            .http_only(true)
+            .finish(),
+    )?;
+    res.headers_mut().insert(
+        HeaderName::from_static("x-chat-resume"),
+        HeaderValue::from_str(&resume_token)?,
+    );
+
+    Ok(res)
+}
+
+/// Moves every session on this node to the others, before shutting it down.
+/// Only allowed from the node itself.
+async fn drain(req: HttpRequest) -> HttpResponse {
+    if !req.peer_addr().is_some_and(|addr| addr.ip().is_loopback()) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    info!("drain() - migrating all sessions");
+    DRAINING.store(true, Ordering::Relaxed);
+    Broker::<SystemBroker>::issue_async(Drain);
+
+    HttpResponse::Accepted().finish()
+}
+
+/// Waits for SIGINT or SIGTERM, in place of actix's own handling which would
+/// just drop the connections, then has every session tell its client and
+/// close with `Restart` before stopping the server
+async fn shut_down_on_signal(server: Server) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("can't listen for SIGTERM: {}", err);
+                future::pending::<()>().await
+            }
+        }
+    };
+    future::select(Box::pin(actix_rt::signal::ctrl_c()), Box::pin(terminate)).await;
+
+    info!("shutting down, closing every session");
+    DRAINING.store(true, Ordering::Relaxed);
+    Broker::<SystemBroker>::issue_async(ShuttingDown);
+
+    actix_rt::time::delay_for(SHUTDOWN_GRACE).await;
+    server.stop(true).await;
+}
+
+async fn verify_email(query: web::Query<TokenQuery>) -> Result<HttpResponse, Error> {
+    let res = Accounts::from_registry()
+        .send(VerifyEmail(query.into_inner().token))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(match res {
+        Ok(name) => HttpResponse::Ok().body(format!("email verified for {}", name)),
+        Err(err @ AccountError::EmailTaken) => {
+            HttpResponse::Conflict().body(err.to_string())
+        }
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    })
+}
+
+/// The questions of a room in Q&A mode, open ones ranked by upvotes. 404 if
+/// the room has no clients on this node.
+async fn room_questions(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let indexable = match access::authorize_history(&req, &room_name).await {
+        Ok(indexable) => indexable,
+        Err(res) => return Ok(res),
+    };
+
+    let questions = server::WsChatServer::from_registry()
+        .send(ListQuestions(room_name.into_inner()))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(match questions {
+        Some(questions) => access::history_response(indexable).json(questions),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// The sessions logged in as the account the request authenticates as
+async fn list_sessions(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let account = match access::authenticate(&req).await {
+        Ok(account) => account,
+        Err(res) => return Ok(res),
+    };
+
+    let devices = Accounts::from_registry()
+        .send(ListSessions(account))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(devices))
+}
+
+/// The account's starred messages, newest first, see `stars::starred`
+async fn list_stars(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let account = match access::authenticate(&req).await {
+        Ok(account) => account,
+        Err(res) => return Ok(res),
+    };
+
+    let stars = stars::starred(account)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(stars))
+}
+
+/// Signs one of the account's sessions out, wherever on this node it is
+async fn end_session(
+    req: HttpRequest,
+    login: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let account = match access::authenticate(&req).await {
+        Ok(account) => account,
+        Err(res) => return Ok(res),
+    };
+
+    let res = Accounts::from_registry()
+        .send(EndSession {
+            account,
+            login: login.into_inner(),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(AccountError::NoSuchSession) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    })
+}
+
+/// Always accepted, so the endpoint can't be used to probe for addresses
+async fn request_password_reset(
+    form: web::Json<PasswordResetRequestForm>,
+) -> HttpResponse {
+    Accounts::from_registry().do_send(RequestPasswordReset(form.into_inner().email));
+    HttpResponse::Accepted().finish()
+}
+
+async fn confirm_password_reset(
+    form: web::Json<PasswordResetConfirmForm>,
+) -> Result<HttpResponse, Error> {
+    let PasswordResetConfirmForm { token, password } = form.into_inner();
+
+    let res = Accounts::from_registry()
+        .send(ConfirmPasswordReset { token, password })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err @ AccountError::InvalidToken)
+        | Err(err @ AccountError::WeakPassword) => {
+            HttpResponse::BadRequest().body(err.to_string())
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    })
 }
 
 #[actix_web::main]
@@ -22,18 +427,110 @@ async fn main() -> std::io::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug"))
         .init();
 
-    let host = std::env::var("HOST").unwrap_or(String::from("127.0.0.1"));
-    let port = std::env::var("PORT").unwrap_or(String::from("8080"));
-    let address = format!("{}:{}", &host, &port);
+    // before anything reads it
+    let address = config::Config::init()
+        .map_err(std::io::Error::other)?
+        .address();
+    let default_rooms = DefaultRooms::from_config();
+    let index_page = branding::Branding::from_env().index_page();
+    // so the warning comes first if chaos mode is on
+    once_cell::sync::Lazy::force(&chaos::CHAOS);
+
+    // the journal is filled from the database before anything new happens
+    let _store = storage::Store::from_env()
+        .await
+        .map_err(std::io::Error::other)?;
+    let _bridge = bridge::UdpBridge::from_env().await?;
+    let _recorder = recorder::Recorder::from_env()?;
+    #[cfg(feature = "redis")]
+    let _redis_bridge = bridge::RedisBridge::from_env()
+        .await
+        .map_err(std::io::Error::other)?;
+
+    // join the cluster right away rather than on the first connection
+    server::WsChatServer::from_registry();
+    // and deliver reminders that came due while the node was down
+    reminders::Reminders::from_registry();
+    digest::Digests::from_registry();
 
     let server = HttpServer::new(move || {
         App::new()
+            .data(default_rooms.clone())
+            .data(index_page.clone())
             .service(web::resource("/ws/").to(chat_route))
+            .service(web::resource("/metrics").route(web::get().to(metrics::export)))
+            .service(web::resource("/embed/{room}").route(web::get().to(embed::widget)))
+            .service(web::resource("/api/accounts").route(web::post().to(register)))
+            .service(web::resource("/api/drain").route(web::post().to(drain)))
+            .service(web::resource("/api/sessions").route(web::get().to(list_sessions)))
+            .service(
+                web::resource("/api/sessions/{id}").route(web::delete().to(end_session)),
+            )
+            .service(web::resource("/api/stars").route(web::get().to(list_stars)))
+            .service(
+                web::scope("/api/admin")
+                    .wrap_fn(admin::guard)
+                    .configure(admin::config),
+            )
+            .service(
+                web::resource("/api/rooms/{name}")
+                    .route(web::get().to(admin::room_settings))
+                    .route(web::patch().to(admin::update_room_settings)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/events")
+                    .route(web::get().to(sse::room_events)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/questions")
+                    .route(web::get().to(room_questions)),
+            )
+            .service(
+                web::resource("/api/directory").route(web::get().to(directory::list)),
+            )
+            .service(
+                web::resource("/api/blobs/{id}").route(web::get().to(blobs::download)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/preview")
+                    .route(web::get().to(preview::room_preview)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/messages")
+                    .route(web::post().to(tokens::post_message)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/webhooks")
+                    .route(web::get().to(tokens::list_webhooks))
+                    .route(web::post().to(tokens::add_webhook)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/webhooks/{id}")
+                    .route(web::delete().to(tokens::remove_webhook)),
+            )
+            .service(
+                web::resource("/api/rooms/{name}/join-qr")
+                    .route(web::get().to(tokens::join_qr)),
+            )
+            .service(web::resource("/verify").route(web::get().to(verify_email)))
+            .service(
+                web::resource("/api/password-reset/request")
+                    .route(web::post().to(request_password_reset)),
+            )
+            .service(
+                web::resource("/api/password-reset/confirm")
+                    .route(web::post().to(confirm_password_reset)),
+            )
+            .service(web::resource("/").route(web::get().to(branding::index)))
+            .service(web::resource("/index.html").route(web::get().to(branding::index)))
             .service(Files::new("/", "./static/").index_file("index.html"))
     })
-    .bind(&address)?;
+    .disable_signals()
+    .bind(&address)?
+    .run();
 
     info!("Starting http server: {}", &address);
+    actix_rt::spawn(shut_down_on_signal(server.clone()));
 
-    server.run().await
+    server.await
 }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_broker::BrokerIssue;
+use log::info;
+
+use crate::cluster::{BridgeOut, Envelope, Gossip, MembersChanged, Payload, NODE_ID};
+use crate::config::config;
+
+/// A node is considered dead once its heartbeat count hasn't gone up for this
+/// long, neither directly nor through another node's gossip
+const NODE_TTL: Duration = Duration::from_secs(30);
+
+/// Tracks which nodes are alive with a simple gossip protocol.
+///
+/// Every node periodically sends the heartbeat counts of all the nodes it
+/// knows, its own count incremented. A node learns about peers it isn't
+/// bridged to directly from its neighbours' gossip, and drops peers whose
+/// count stopped increasing. Changes are issued as [`MembersChanged`] on the
+/// system broker.
+#[derive(Default)]
+pub struct Membership {
+    heartbeat: u64,
+    /// other nodes: node id -> (highest heartbeat seen, when it was seen)
+    members: HashMap<String, (u64, Instant)>,
+    /// the members last issued in `MembersChanged`
+    live: Vec<String>,
+}
+
+impl Membership {
+    fn send_heartbeat(&mut self) {
+        self.heartbeat += 1;
+
+        let mut members: HashMap<String, u64> = self
+            .members
+            .iter()
+            .map(|(node, (heartbeat, _))| (node.clone(), *heartbeat))
+            .collect();
+        members.insert(NODE_ID.clone(), self.heartbeat);
+
+        self.issue_system_async(BridgeOut(Envelope::new(Payload::Heartbeat {
+            members,
+        })));
+    }
+
+    /// Drops dead nodes and issues `MembersChanged` if the set changed
+    fn update(&mut self) {
+        let now = Instant::now();
+        self.members.retain(|node, (_, seen)| {
+            let alive = now.duration_since(*seen) < NODE_TTL;
+            if !alive {
+                info!("Membership - {} is gone", node);
+            }
+            alive
+        });
+
+        let mut live: Vec<String> = self.members.keys().cloned().collect();
+        live.push(NODE_ID.clone());
+        live.sort();
+
+        if live != self.live {
+            info!("Membership - live nodes: {:?}", &live);
+            self.live = live.clone();
+            self.issue_system_async(MembersChanged(live));
+        }
+    }
+}
+
+impl Actor for Membership {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.send_heartbeat();
+        self.update();
+
+        ctx.run_interval(config().gossip_interval(), |act, _ctx| {
+            act.send_heartbeat();
+            act.update();
+        });
+    }
+}
+
+impl Handler<Gossip> for Membership {
+    type Result = ();
+
+    fn handle(&mut self, msg: Gossip, _ctx: &mut Self::Context) {
+        let Gossip { origin, members } = msg;
+        let now = Instant::now();
+
+        // introduce ourselves to new nodes without waiting for the interval
+        if !self.members.contains_key(&origin) {
+            self.send_heartbeat();
+        }
+
+        // a node's own heartbeat always counts, even if it restarted from zero
+        let heartbeat = members.get(&origin).copied().unwrap_or(0);
+        self.members.insert(origin.clone(), (heartbeat, now));
+
+        for (node, heartbeat) in members {
+            if node == *NODE_ID || node == origin {
+                continue;
+            }
+
+            let known = self.members.get(&node).map(|(known, _)| *known);
+            if known.is_none_or(|known| heartbeat > known) {
+                self.members.insert(node, (heartbeat, now));
+            }
+        }
+
+        self.update();
+    }
+}
+
+impl SystemService for Membership {}
+impl Supervised for Membership {}
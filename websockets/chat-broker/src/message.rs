@@ -1,25 +1,1119 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
 use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{AccountError, Device, NotifyLevel};
+use crate::cluster::{
+    AccessAction, CodeAction, HandAction, ModAction, QuestionAction, StreamAction,
+};
+use crate::digest::Digest;
+use crate::drafts::Draft;
+use crate::frames::{Ephemeral, FrameError};
+use crate::hours::OpeningHours;
+use crate::journal::{EventKind, RoomEvent};
+use crate::load::LoadReport;
+use crate::metrics::ServerGauges;
+use crate::migration::SessionState;
+use crate::reads::ReadPosition;
+use crate::recorder::Record;
+use crate::reminders::{Owner, Reminder};
+use crate::scheduler::{ScheduleError, Scheduled};
+use crate::server::{
+    ClientPresence, DirectoryPage, ListedRoom, MemberChange, Members, Question,
+    RoomError, RoomPage, RoomPresence,
+};
+use crate::settings::{RoomSettings, Setting};
+use crate::stars::{Star, StarError};
+use crate::storage::{StorageError, StoredEvent};
+use crate::teams::{Team, TeamAction, TeamError, TeamRoom};
+use crate::templates::RoomTemplate;
+use crate::tokens::{Grant, Scope, TokenInfo};
+use crate::trust::Standing;
+use crate::webhooks::{Delivery, WebhookInfo};
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct ChatMessage(pub String);
 
+/// A reply to one client, sent as a frame to sessions that asked for JSON
+/// only and as a line of text to the others, see `text`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    /// a refused command or frame, shown as `!!! error`
+    Error {
+        /// the offending field, `None` if the frame as a whole is malformed
+        field: Option<String>,
+        error: String,
+    },
+    /// command output and anything else meant to be read as it is
+    Notice { text: String },
+    /// a private message, with `from` for whom it is for and `to` for the
+    /// echo to its sender
+    Whisper {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+        content: String,
+    },
+    /// a message forwarded from another room
+    Forwarded {
+        from: String,
+        room: String,
+        content: String,
+    },
+    /// a message posted to several rooms at once, `id` being the message id
+    /// every copy has
+    CrossPost {
+        id: u64,
+        from: String,
+        rooms: Vec<String>,
+        content: String,
+    },
+    /// a question asked with `/ask`
+    Question { id: u64, from: String, text: String },
+    /// a frame that is JSON already, sent the same either way
+    #[serde(skip)]
+    Frame(String),
+}
+
+impl Reply {
+    pub fn error(error: impl std::fmt::Display) -> Self {
+        Reply::Error {
+            field: None,
+            error: error.to_string(),
+        }
+    }
+
+    pub fn notice(text: impl Into<String>) -> Self {
+        Reply::Notice { text: text.into() }
+    }
+
+    /// The reply as a line of text
+    pub fn text(&self) -> String {
+        match self {
+            Reply::Error { error, .. } => format!("!!! {}", error),
+            Reply::Notice { text } => text.clone(),
+            Reply::Whisper {
+                from: Some(from),
+                content,
+                ..
+            } => format!("[whisper] {}: {}", from, content),
+            Reply::Whisper {
+                to: Some(to),
+                content,
+                ..
+            } => format!("[whisper to {}] {}", to, content),
+            Reply::Whisper { content, .. } => format!("[whisper] {}", content),
+            Reply::Forwarded {
+                from,
+                room,
+                content,
+            } => format!("{}: [forwarded from {}] {}", from, room, content),
+            Reply::CrossPost {
+                from,
+                rooms,
+                content,
+                ..
+            } => format!("{}: [posted to {}] {}", from, rooms.join(", "), content),
+            Reply::Question { id, from, text } => {
+                format!("[question {}] {}: {}", id, from, text)
+            }
+            Reply::Frame(frame) => frame.clone(),
+        }
+    }
+
+    /// The reply as a JSON frame
+    pub fn to_json(&self) -> String {
+        match self {
+            Reply::Frame(frame) => frame.clone(),
+            reply => serde_json::to_string(reply).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<FrameError> for Reply {
+    fn from(err: FrameError) -> Self {
+        Reply::Error {
+            field: err.field,
+            error: err.error,
+        }
+    }
+}
+
+/// Starts, joins, leaves or ends a live stream in a room
 #[derive(Clone, Message)]
-#[rtype(result = "usize")]
-pub struct JoinRoom(pub String, pub String, pub Recipient<ChatMessage>);
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageStream {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: StreamAction,
+}
 
+/// WebRTC signaling data (offers, answers, ICE candidates) for the client
+/// called `to`, relayed untouched
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct Signal {
+    pub from: String,
+    pub to: String,
+    pub data: serde_json::Value,
+}
+
+/// Drawing or presence for the other members of the room
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct LeaveRoom(pub String, pub usize);
+pub struct SendEphemeral {
+    pub room_name: String,
+    pub client_id: usize,
+    pub from: String,
+    pub event: Ephemeral,
+}
+
+/// Shows, replaces or removes the join code of a room, owners only
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageJoinCode {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: CodeAction,
+}
+
+/// Changes who may join a room, owners only
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageAccess {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: AccessAction,
+}
 
+/// Raises or lowers a hand in a moderated room, or for its owner lists the
+/// raised hands or calls on one of them
 #[derive(Clone, Message)]
-#[rtype(result = "Vec<String>")]
-pub struct ListRooms;
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageHand {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: HandAction,
+}
+
+/// Kicks, bans or mutes a member of the room, see `ModAction`
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ModerateRoom {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: ModAction,
+}
+
+/// Mutes `name` in the room on the blocklist's behalf, as the admin API
+/// would, see `blocklist::BlockAction::Mute`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct AutoMute {
+    pub room_name: String,
+    pub name: String,
+    pub secs: u64,
+}
+
+/// Shows the owner the room's last `count` events, see `SendEventLog`
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ShowEventLog {
+    pub room_name: String,
+    pub client_id: usize,
+    pub count: usize,
+}
+
+/// Asks, upvotes or answers a question in a room in Q&A mode
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageQuestion {
+    pub room_name: String,
+    pub client_id: usize,
+    pub action: QuestionAction,
+}
+
+/// A room's questions, `None` if the room has no clients on this node
+#[derive(Clone, Message)]
+#[rtype(result = "Option<Vec<Question>>")]
+pub struct ListQuestions(pub String);
+
+/// A room's canned responses by name, `None` if the room has no clients on
+/// this node
+#[derive(Clone, Message)]
+#[rtype(result = "Option<BTreeMap<String, String>>")]
+pub struct ListCanned(pub String);
+
+/// Saves or removes a canned response of a room, owners only
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ManageCanned {
+    pub room_name: String,
+    pub client_id: usize,
+    pub name: String,
+    pub content: Option<String>,
+}
+
+/// Whether the room takes an attachment or voice note of `size` bytes of
+/// `mime`, asked before its bytes are
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct CheckUpload {
+    pub room_name: String,
+    pub mime: String,
+    pub size: usize,
+}
+
+/// Replaces the text of a chat message, which `client_id` must have posted
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct EditMessage {
+    pub room_name: String,
+    pub client_id: usize,
+    pub id: u64,
+    pub text: String,
+}
+
+/// Takes back a chat message `client_id` posted
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct DeleteMessage {
+    pub room_name: String,
+    pub client_id: usize,
+    pub id: u64,
+}
+
+/// Reacts to a chat message with `emoji`, or takes `client_id`'s reaction
+/// back if it already reacted with it
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct React {
+    pub room_name: String,
+    pub client_id: usize,
+    pub id: u64,
+    pub emoji: String,
+}
+
+/// The room a join code points at
+#[derive(Clone, Message)]
+#[rtype(result = "Option<String>")]
+pub struct ResolveJoinCode(pub String);
+
+/// A room's join code, created if it has none. `None` while the room's home
+/// node, if it's another, creates it.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Option<String>, RoomError>")]
+pub struct GetJoinCode(pub String);
+
+/// Resolves to the new client id and the room name, which differs from the
+/// requested one when joining through an alias
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(usize, String), RoomError>")]
+pub struct JoinRoom {
+    pub room_name: String,
+    pub client_name: String,
+    pub client: Recipient<ChatMessage>,
+    /// whether the room is created if it doesn't exist yet, or refused
+    pub may_create: bool,
+    /// for `/create`: the room mustn't exist yet, and is created with these
+    /// settings
+    pub template: Option<Vec<Setting>>,
+    /// the account the client is logged in as, for rooms of a team and bans
+    pub account: Option<String>,
+    /// told if the owner removes the client from the room
+    pub removed: Recipient<RemovedFromRoom>,
+    /// told if the client opens a breakout room from this one
+    pub breakout: Recipient<BreakoutOpened>,
+    /// asked how recently the client was active, see `ListPresence`
+    pub presence: Recipient<QueryPresence>,
+    /// for rooms that need one, and creating a room with this one
+    pub password: Option<String>,
+    /// a migrated session entering its rooms again, which it already passed
+    /// the password or invitation of
+    pub rejoining: bool,
+}
+
+/// The owner kicked or banned the client from the room
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RemovedFromRoom {
+    pub room_name: String,
+    pub banned: bool,
+}
+
+/// The client opened the breakout room `room_name` from `source`, which it
+/// should enter
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct BreakoutOpened {
+    pub room_name: String,
+    pub source: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct LeaveRoom(pub String, pub usize, pub String);
+
+/// The public directory a page at a time, up to `limit` rooms after the one
+/// named `after`: the public rooms that aren't unlisted, a team's or a
+/// breakout, only those in `language` if it's given
+#[derive(Clone, Message)]
+#[rtype(result = "DirectoryPage")]
+pub struct ListDirectory {
+    pub after: Option<String>,
+    pub language: Option<String>,
+    pub limit: usize,
+}
+
+/// Lists the listed rooms a page at a time, up to `limit` starting after the
+/// room `after`, so huge listings needn't be built or sent in one go
+#[derive(Clone, Message)]
+#[rtype(result = "RoomPage")]
+pub struct ListRooms {
+    pub after: Option<ListedRoom>,
+    pub limit: usize,
+    /// for the admin API, rooms flagged `unlisted` too
+    pub unlisted: bool,
+}
+
+/// `/team ...` by `account`
+#[derive(Clone, Message)]
+#[rtype(result = "Result<String, TeamError>")]
+pub struct ManageTeam {
+    pub account: String,
+    pub team: String,
+    pub action: TeamAction,
+}
+
+/// The teams an account is in
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<Team>")]
+pub struct ListTeams(pub String);
+
+/// Whether an account belongs to a room, as far as this node knows: it is in
+/// the room's team, invited to it or joined it, and isn't banned from it
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct IsRoomMember {
+    pub room_name: String,
+    pub account: String,
+}
+
+/// Every room claimed by a team, by room name
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SetTeamRooms(pub HashMap<String, TeamRoom>);
 
 #[derive(Clone, Message)]
 #[rtype(result = "()")]
 pub struct SendMessage(pub String, pub usize, pub String);
 
+/// Posts `content` to the room as `author` after `delay`, resolves to the
+/// scheduled message's id. Only `owner` can list and cancel it.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<u64, ScheduleError>")]
+pub struct Schedule {
+    pub room_name: String,
+    pub client_id: usize,
+    pub owner: Owner,
+    pub author: String,
+    pub content: String,
+    pub delay: Duration,
+}
+
+/// The owner's scheduled messages, the next one due first
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<Scheduled>")]
+pub struct ListScheduled(pub Owner);
+
+/// Whispers `text` back to `owner` once `delay` is up, resolves to the
+/// reminder's id
+#[derive(Clone, Message)]
+#[rtype(result = "Result<u64, String>")]
+pub struct Remind {
+    pub owner: Owner,
+    pub text: String,
+    pub delay: Duration,
+}
+
+/// The reminders set by `owner`, the next one due first
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<Reminder>")]
+pub struct ListReminders(pub Owner);
+
+/// Drops the reminders of a session that wasn't logged in, once it ends
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ForgetReminders(pub Recipient<ChatMessage>);
+
+/// Text for every session of `account` on this node, `UnknownUser` if it has
+/// none
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct NotifyAccount {
+    pub account: String,
+    pub text: String,
+}
+
+/// Cancels a scheduled message, false if the owner has none with that id
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct Unschedule {
+    pub owner: Owner,
+    pub id: u64,
+}
+
+/// An assembled inline attachment for a room: (room, client id, event)
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendAttachment(pub String, pub usize, pub EventKind);
+
+/// A message reposted from another room, an `EventKind::Forwarded`:
+/// (room, client id, event)
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendForwarded(pub String, pub usize, pub EventKind);
+
+/// One message for several rooms, an `EventKind::CrossPost`: posted to all
+/// of them or, if the sender doesn't own one, to none. The copies share one
+/// message id, which the sender gets an ack with in each room.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct CrossPost {
+    /// room name and the sender's client id in it
+    pub rooms: Vec<(String, usize)>,
+    pub event: EventKind,
+}
+
+/// Asks a session who its client is and how long it has been idle
+#[derive(Clone, Message)]
+#[rtype(result = "ClientPresence")]
+pub struct QueryPresence;
+
+/// The room's clients on this node, `None` if it has none, with every
+/// client's presence as its session tells it. The sessions are asked, so the
+/// one asking mustn't `wait` for the answer.
+#[derive(Clone, Message)]
+#[rtype(result = "Option<RoomPresence>")]
+pub struct ListPresence(pub String);
+
+/// Resolves to the room's clients on this node, like `ListPresence`, after
+/// which `subscriber` gets a `MembershipEvent` for every join and leave, each
+/// with the next generation number, until client `client_id` leaves the room. `None` unless
+/// the client is in the room.
+#[derive(Clone, Message)]
+#[rtype(result = "Option<Members>")]
+pub struct SubscribeMembership {
+    pub room_name: String,
+    pub client_id: usize,
+    pub subscriber: Recipient<MembershipEvent>,
+}
+
+/// e.g. `{"type":"membership","room":"Main","generation":8,"change":"joined","client_id":42}`
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename = "membership")]
+pub struct MembershipEvent {
+    #[serde(rename = "room")]
+    pub room_name: String,
+    pub generation: u64,
+    #[serde(flatten)]
+    pub change: MemberChange,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "usize")]
+pub struct RoomSize(pub String);
+
+/// Claims a client name so private messages can be routed to the session,
+/// releasing the previous one. Fails if another client on any node has the
+/// name, unless `claim`, which takes it over and sends that client a
+/// `NameClaimed`.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct RegisterName {
+    pub name: String,
+    pub previous: Option<String>,
+    pub client: Recipient<ChatMessage>,
+    pub displaced: Recipient<NameClaimed>,
+    /// the account the session is signed in to, for bans and mutes
+    pub account: Option<String>,
+    /// the account's own name on login, or the name of a resumed session
+    pub claim: bool,
+}
+
+/// Tells a session that another one claimed its name, see `RegisterName`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct NameClaimed(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct UnregisterName(pub String, pub Recipient<ChatMessage>);
+
+/// Delivered to the named client wherever in the cluster it is connected
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct PrivateMessage {
+    pub from: String,
+    pub to: String,
+    pub content: String,
+}
+
+/// Freezes (or with `archived: false` thaws) a room, owners only
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct ArchiveRoom {
+    pub room_name: String,
+    pub client_id: usize,
+    pub archived: bool,
+}
+
+/// Sends every member of a room an announcement, for the admin API
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct Announce {
+    pub room_name: String,
+    pub text: String,
+}
+
+/// Removes every member of a room and forgets it, for the admin API
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct DeleteRoom(pub String);
+
+/// Restricts sending to a daily window (or with `None` lifts that), owners only
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetOpeningHours {
+    pub room_name: String,
+    pub client_id: usize,
+    pub hours: Option<OpeningHours>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct AddAlias {
+    pub alias: String,
+    pub room_name: String,
+    /// the requester's client id in that room
+    pub client_id: usize,
+}
+
+/// Changes room settings, for both `/settings` and `PATCH /api/rooms/{room}`.
+/// Owners only, unless `client_id` is `None` for the admin API. Resolves to the
+/// new settings, or `None` if the change was forwarded to the room's home node.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Option<RoomSettings>, RoomError>")]
+pub struct UpdateRoomSettings {
+    pub room_name: String,
+    pub client_id: Option<usize>,
+    pub changes: Vec<Setting>,
+}
+
+/// This node's copy of a room's settings
+#[derive(Clone, Message)]
+#[rtype(result = "Option<RoomSettings>")]
+pub struct GetRoomSettings(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), AccountError>")]
+pub struct Register {
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+/// Resolves to the login's id, which `Logout` is sent with
+#[derive(Clone, Message)]
+#[rtype(result = "Result<u64, AccountError>")]
+pub struct Login {
+    pub name: String,
+    pub password: String,
+    pub session: Recipient<SignedOut>,
+    /// the client's `User-Agent`
+    pub device: Option<String>,
+    /// the account and login the session is switching from, if any
+    pub previous: Option<(String, u64)>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Logout(pub String, pub u64);
+
+/// Logs a session that lost its client out, keeping its login for the
+/// session resumed with `token`, see `ResumeLogin`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct DetachLogin {
+    pub account: String,
+    pub login: u64,
+    pub token: String,
+}
+
+/// Signs a resumed session back in to the account whose login was detached
+/// with `token`, resolving to the account and login
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(String, u64), AccountError>")]
+pub struct ResumeLogin {
+    pub token: String,
+    pub session: Recipient<SignedOut>,
+    /// the client's `User-Agent`
+    pub device: Option<String>,
+}
+
+/// Keeps an account's unsent text for a room, discarding it if empty
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SaveDraft {
+    pub account: String,
+    pub room_name: String,
+    pub text: String,
+}
+
+/// A draft saved or discarded on another node
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SyncDraft {
+    pub account: String,
+    pub room_name: String,
+    pub draft: Option<Draft>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Option<Draft>")]
+pub struct GetDraft {
+    pub account: String,
+    pub room_name: String,
+}
+
+/// Moves an account's read position in a room up to `seq`, or the latest
+/// message if `None`, and tells its sessions other than `login`. Only for
+/// rooms the account belongs to, see `IsRoomMember`.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<ReadPosition, RoomError>")]
+pub struct MarkRead {
+    pub account: String,
+    pub login: Option<u64>,
+    pub room_name: String,
+    pub seq: Option<u64>,
+}
+
+/// Read positions of an account in `rooms` and every room it marked read
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<ReadPosition>")]
+pub struct GetUnread {
+    pub account: String,
+    pub rooms: Vec<String>,
+}
+
+/// Read positions of an account in those of `rooms` it marked read
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<ReadPosition>")]
+pub struct GetReadPositions {
+    pub account: String,
+    pub rooms: Vec<String>,
+}
+
+/// Sends a logged in session the account's read positions as they move
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct WatchReads {
+    pub account: String,
+    pub login: u64,
+    pub session: Recipient<ReadPosition>,
+}
+
+/// (account, login id)
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct UnwatchReads(pub String, pub u64);
+
+/// Stars the message with journal sequence number `seq`, posted in the room
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), StarError>")]
+pub struct StarMessage {
+    pub account: String,
+    pub room_name: String,
+    pub seq: u64,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), StarError>")]
+pub struct UnstarMessage {
+    pub account: String,
+    pub seq: u64,
+}
+
+/// An account's stars, newest first
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<Star>")]
+pub struct ListStars(pub String);
+
+/// The sessions logged in as an account, oldest first
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<Device>")]
+pub struct ListSessions(pub String);
+
+/// Signs one of the account's sessions out, by its login's id
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), AccountError>")]
+pub struct EndSession {
+    pub account: String,
+    pub login: u64,
+}
+
+/// Tells a session its login was ended, with why, after which it closes
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SignedOut(pub String);
+
+/// A chat message was accepted in a room, for notifying offline accounts
+/// according to their `NotifyLevel` for the room
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Posted {
+    pub room_name: String,
+    pub context: String,
+    /// names mentioned as `@name`
+    pub mentioned: Vec<String>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), AccountError>")]
+pub struct SetNotifyLevel {
+    pub account: String,
+    pub room_name: String,
+    pub level: NotifyLevel,
+}
+
+/// (account, room)
+#[derive(Clone, Message)]
+#[rtype(result = "NotifyLevel")]
+pub struct GetNotifyLevel(pub String, pub String);
+
+/// Sent by the server to itself, to measure how long it takes to get through
+/// its mailbox
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct LoadProbe(pub Instant);
+
+#[derive(Clone, Message)]
+#[rtype(result = "LoadReport")]
+pub struct GetLoad;
+
+/// For `GET /metrics`
+#[derive(Clone, Message)]
+#[rtype(result = "ServerGauges")]
+pub struct GetGauges;
+
+/// Checks an account's password without logging it in, for HTTP requests
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), AccountError>")]
+pub struct Authenticate(pub String, pub String);
+
+/// Standing of the client called `name`, logged in as `account` if any
+#[derive(Clone, Message)]
+#[rtype(result = "Standing")]
+pub struct GetTrust {
+    pub name: String,
+    pub account: Option<String>,
+}
+
+/// The account `reporter` reports the client called `name`, once per name
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Report {
+    pub reporter: String,
+    pub name: String,
+    pub reason: String,
+}
+
+/// The logged in `account` sent a chat message
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct CountMessage(pub String);
+
+/// The content filter blocked a message of the client called `name`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct FilterHit(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct QueueMention {
+    pub email: String,
+    pub name: String,
+    pub room_name: String,
+    pub context: String,
+    /// false for a message the account is only notified of because it wants
+    /// every message of the room
+    pub mentioned: bool,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<String, AccountError>")]
+pub struct VerifyEmail(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendVerification {
+    pub email: String,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RequestPasswordReset(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), AccountError>")]
+pub struct ConfirmPasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendPasswordReset {
+    pub email: String,
+    pub name: String,
+    pub token: String,
+}
+
+/// Tells every local session to migrate to another node
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct Drain;
+
+/// The node is stopping, on SIGINT or SIGTERM: every local session tells its
+/// client, then migrates as on `Drain`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ShuttingDown;
+
+/// Keeps a migrating session's state until its client reconnects with `token`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct StoreSession {
+    pub token: String,
+    pub state: SessionState,
+}
+
+/// Claims a migrated session for resuming, at most once
+#[derive(Clone, Message)]
+#[rtype(result = "Option<SessionState>")]
+pub struct TakeSession(pub String);
+
+/// A migrated session was resumed elsewhere
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ForgetSession(pub String);
+
+/// Keeps a session's memberships, by room, while its client may reconnect
+/// with `token`, leaving them once the config's `resume_grace_secs` is over
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct DetachSession {
+    pub token: String,
+    pub client_name: String,
+    pub memberships: Vec<(String, usize)>,
+}
+
+/// Hands the memberships kept for `token` to the session resuming it,
+/// resolving to the rooms and client ids it got back
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<(String, usize)>")]
+pub struct ReattachSession {
+    pub token: String,
+    pub client: Recipient<ChatMessage>,
+    pub removed: Recipient<RemovedFromRoom>,
+    pub breakout: Recipient<BreakoutOpened>,
+    pub presence: Recipient<QueryPresence>,
+}
+
+/// Appends an event to a room's journal
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RecordEvent(pub String, pub EventKind);
+
+/// Writes a journaled event to the database, see `storage::Store`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SaveEvent(pub RoomEvent);
+
+/// Deletes a room's stored events
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct ForgetRoom(pub String);
+
+/// The newest events of every room in the database, this many per room
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<StoredEvent>, StorageError>")]
+pub struct LoadRecent(pub usize);
+
+/// Writes an account's draft for a room to the database, or deletes it if
+/// `None`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct StoreDraft {
+    pub account: String,
+    pub room_name: String,
+    pub draft: Option<Draft>,
+}
+
+/// Every draft in the database, as (account, room, draft)
+#[derive(Clone, Message)]
+#[rtype(result = "Result<Vec<(String, String, Draft)>, StorageError>")]
+pub struct LoadDrafts;
+
+/// Appends a frame to a session's recording, by its id, see
+/// `recorder::Recorder`
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct RecordFrame(pub u64, pub Record);
+
+/// Closes a recording once its session is gone
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct EndRecording(pub u64);
+
+/// Subscribes to the events of one room, or of every room if `room_name` is
+/// `None`. Resolves to the journaled events after `since`, 0 for all of them.
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<RoomEvent>")]
+pub struct SubscribeEvents {
+    pub room_name: Option<String>,
+    pub since: u64,
+    pub subscriber: Recipient<RoomEvent>,
+}
+
+/// Adds archived events, (unix milliseconds, event), to a room's journal in
+/// time order. Resolves to how many are kept, as older ones beyond the room's
+/// retention are dropped, or an error if the room isn't logged.
+#[derive(Clone, Message)]
+#[rtype(result = "Result<usize, String>")]
+pub struct ImportEvents {
+    pub room_name: String,
+    pub events: Vec<(u64, EventKind)>,
+}
+
+/// A room's journaled events from `since` (unix milliseconds) on, oldest first
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<RoomEvent>")]
+pub struct RoomHistory {
+    pub room_name: String,
+    pub since: u64,
+}
+
+/// Sends the last `count` events of a room but its chat to a client, as text
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct SendEventLog {
+    pub room_name: String,
+    pub count: usize,
+    pub to: Recipient<ChatMessage>,
+}
+
+/// The journaled events with these sequence numbers, in no particular order,
+/// leaving out the ones no longer kept
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<RoomEvent>")]
+pub struct FindEvents(pub Vec<u64>);
+
+/// Rooms homed on this node that want digests, with their period in hours
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<(String, u64)>")]
+pub struct DigestRooms;
+
+/// Posts a digest to the room as a room event
+#[derive(Clone, Message)]
+#[rtype(result = "()")]
+pub struct PostDigest {
+    pub room_name: String,
+    pub digest: Digest,
+}
+
+/// The settings of a room template, `None` if there is no such template
+#[derive(Clone, Message)]
+#[rtype(result = "Option<Vec<Setting>>")]
+pub struct GetTemplate(pub String);
+
+#[derive(Clone, Message)]
+#[rtype(result = "BTreeMap<String, RoomTemplate>")]
+pub struct ListTemplates;
+
+/// Adds or replaces a room template, failing if its settings don't parse
+#[derive(Clone, Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct PutTemplate {
+    pub name: String,
+    pub template: RoomTemplate,
+}
+
+/// Resolves to whether the template existed
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct RemoveTemplate(pub String);
+
+/// Registers an outgoing webhook, resolves to it and its signing secret
+#[derive(Clone, Message)]
+#[rtype(result = "(WebhookInfo, String)")]
+pub struct AddWebhook {
+    pub url: String,
+    pub room_name: Option<String>,
+}
+
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<WebhookInfo>")]
+pub struct ListWebhooks;
+
+/// Resolves to false if there is no such hook
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct RemoveWebhook(pub String);
+
+/// Resolves to false if there is no such hook
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct EnableWebhook(pub String);
+
+/// Recent delivery attempts of a hook, oldest first
+#[derive(Clone, Message)]
+#[rtype(result = "Option<Vec<Delivery>>")]
+pub struct WebhookDeliveries(pub String);
+
+/// Resolves to the new token's info and the token itself, which is only
+/// kept hashed
+#[derive(Clone, Message)]
+#[rtype(result = "(TokenInfo, String)")]
+pub struct IssueRoomToken {
+    pub room_name: String,
+    pub label: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+/// The tokens issued for a room
+#[derive(Clone, Message)]
+#[rtype(result = "Vec<TokenInfo>")]
+pub struct ListRoomTokens(pub String);
+
+/// Resolves to false if there is no such token
+#[derive(Clone, Message)]
+#[rtype(result = "bool")]
+pub struct RevokeRoomToken(pub String);
+
+/// Resolves to `None` unless the token allows `scope` in the room
 #[derive(Clone, Message)]
-#[rtype(result = "Vec<String>")]
-pub struct ListClients(pub String);
+#[rtype(result = "Option<Grant>")]
+pub struct CheckRoomToken {
+    pub token: String,
+    pub room_name: String,
+    pub scope: Scope,
+}
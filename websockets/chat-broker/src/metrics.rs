@@ -0,0 +1,176 @@
+//! Counters and gauges of the node's chat activity, exposed on `GET /metrics`
+//! in the Prometheus text format for scraping. Counters only go up while the
+//! node runs; rates, e.g. messages a second, are for Prometheus to work out
+//! with `rate()`.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use actix::prelude::*;
+use actix_web::{Error, HttpResponse};
+
+use crate::config::config;
+use crate::message::GetGauges;
+use crate::server::WsChatServer;
+
+static SESSIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+static SESSIONS_CLOSED: AtomicU64 = AtomicU64::new(0);
+/// sessions that ended without a close frame either way
+static CONNECTIONS_DROPPED: AtomicU64 = AtomicU64::new(0);
+/// chat messages rooms homed here accepted
+static MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+/// What the chat server has, at the time it's asked
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerGauges {
+    pub rooms: usize,
+    /// clients of this node in rooms, counted once per room
+    pub members: usize,
+    /// sessions kept for their clients to resume
+    pub detached: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    value: u64,
+}
+
+pub fn session_opened() {
+    SESSIONS_OPENED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn session_closed(dropped: bool) {
+    SESSIONS_CLOSED.fetch_add(1, Ordering::Relaxed);
+    if dropped {
+        CONNECTIONS_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn message_accepted() {
+    MESSAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+fn render(metrics: &[Metric]) -> String {
+    let mut text = String::new();
+    for metric in metrics {
+        let kind = match metric.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        let _ = writeln!(text, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", metric.name, kind);
+        let _ = writeln!(text, "{} {}", metric.name, metric.value);
+    }
+
+    text
+}
+
+fn metrics(gauges: ServerGauges) -> Vec<Metric> {
+    let opened = SESSIONS_OPENED.load(Ordering::Relaxed);
+    let closed = SESSIONS_CLOSED.load(Ordering::Relaxed);
+    let metric = |name, help, kind, value| Metric {
+        name,
+        help,
+        kind,
+        value,
+    };
+
+    vec![
+        metric(
+            "chat_sessions_active",
+            "Websocket sessions connected to this node.",
+            Kind::Gauge,
+            opened.saturating_sub(closed),
+        ),
+        metric(
+            "chat_sessions_opened_total",
+            "Websocket sessions opened on this node.",
+            Kind::Counter,
+            opened,
+        ),
+        metric(
+            "chat_connections_dropped_total",
+            "Sessions that ended without a close frame, e.g. timed out.",
+            Kind::Counter,
+            CONNECTIONS_DROPPED.load(Ordering::Relaxed),
+        ),
+        metric(
+            "chat_messages_total",
+            "Chat messages accepted by the rooms homed on this node.",
+            Kind::Counter,
+            MESSAGES.load(Ordering::Relaxed),
+        ),
+        metric(
+            "chat_rooms",
+            "Rooms this node has clients in or is home to.",
+            Kind::Gauge,
+            gauges.rooms as u64,
+        ),
+        metric(
+            "chat_room_members",
+            "Clients of this node in rooms, once per room.",
+            Kind::Gauge,
+            gauges.members as u64,
+        ),
+        metric(
+            "chat_sessions_detached",
+            "Sessions kept for their clients to resume.",
+            Kind::Gauge,
+            gauges.detached as u64,
+        ),
+    ]
+}
+
+/// `GET /metrics`
+pub async fn export() -> Result<HttpResponse, Error> {
+    let gauges = WsChatServer::from_registry()
+        .send(GetGauges)
+        .timeout(config().request_timeout())
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&metrics(gauges))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = [
+            Metric {
+                name: "chat_rooms",
+                help: "Rooms.",
+                kind: Kind::Gauge,
+                value: 3,
+            },
+            Metric {
+                name: "chat_messages_total",
+                help: "Messages.",
+                kind: Kind::Counter,
+                value: 12,
+            },
+        ];
+
+        assert_eq!(
+            render(&metrics),
+            "# HELP chat_rooms Rooms.\n\
+             # TYPE chat_rooms gauge\n\
+             chat_rooms 3\n\
+             # HELP chat_messages_total Messages.\n\
+             # TYPE chat_messages_total counter\n\
+             chat_messages_total 12\n"
+        );
+    }
+}
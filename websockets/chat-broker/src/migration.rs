@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use actix::prelude::*;
+use actix_broker::BrokerIssue;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::{BridgeOut, Envelope, Payload};
+use crate::config::config;
+use crate::features::Features;
+use crate::message::{ForgetSession, StoreSession, TakeSession};
+
+/// What a session needs to carry on on another node
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionState {
+    pub client_name: Option<String>,
+    /// only tells that the session was logged in, it is signed back in to
+    /// the login `Accounts` kept for the resume token, see `ResumeLogin`
+    pub account: Option<String>,
+    /// every room joined
+    pub rooms: Vec<String>,
+    /// the room messages are sent to, empty while in the lobby
+    pub room_name: String,
+    /// passed the CAPTCHA challenge
+    #[serde(default)]
+    pub human: bool,
+    /// from the client's `hello` frame
+    #[serde(default)]
+    pub features: Features,
+    #[serde(default)]
+    pub json: bool,
+    #[serde(default)]
+    pub said_hello: bool,
+    /// chat messages with a higher id are caught up on when resuming, 0 for
+    /// none
+    #[serde(default)]
+    pub last_message: u64,
+}
+
+/// Sessions handed off by draining nodes or cut off from their clients,
+/// waiting to be resumed. Every node
+/// keeps a copy, so the client can reconnect to whichever node the load
+/// balancer picks.
+#[derive(Default)]
+pub struct Migrations {
+    /// resume token -> (state, when it was stored)
+    pending: HashMap<String, (SessionState, Instant)>,
+}
+
+impl Actor for Migrations {
+    type Context = Context<Self>;
+}
+
+impl Handler<StoreSession> for Migrations {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreSession, _ctx: &mut Self::Context) {
+        let StoreSession { token, state } = msg;
+
+        let now = Instant::now();
+        self.pending.retain(|_, (_, stored)| {
+            now.duration_since(*stored) < config().resume_grace()
+        });
+        self.pending.insert(token, (state, now));
+    }
+}
+
+impl Handler<TakeSession> for Migrations {
+    type Result = Option<SessionState>;
+
+    fn handle(&mut self, msg: TakeSession, _ctx: &mut Self::Context) -> Self::Result {
+        let TakeSession(token) = msg;
+
+        let (state, stored) = self.pending.remove(&token)?;
+        if stored.elapsed() >= config().resume_grace() {
+            return None;
+        }
+
+        // tokens are single use, cluster-wide
+        debug!("TakeSession::handle() - resuming {:?}", &state.client_name);
+        self.issue_system_async(BridgeOut(Envelope::new(Payload::SessionTaken {
+            token,
+        })));
+
+        Some(state)
+    }
+}
+
+impl Handler<ForgetSession> for Migrations {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForgetSession, _ctx: &mut Self::Context) {
+        let ForgetSession(token) = msg;
+        self.pending.remove(&token);
+    }
+}
+
+impl SystemService for Migrations {}
+impl Supervised for Migrations {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::testing::run;
+
+    fn state(name: &str) -> SessionState {
+        SessionState {
+            client_name: Some(name.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resume_grace() {
+        run(async {
+            let grace = config().resume_grace();
+            let now = Instant::now();
+
+            let mut pending = HashMap::new();
+            let almost = grace - Duration::from_secs(1);
+            pending.insert("inside".to_owned(), (state("alice"), now - almost));
+            pending.insert("after".to_owned(), (state("bob"), now - grace));
+            pending.insert("long after".to_owned(), (state("carol"), now - grace * 2));
+            let migrations = Migrations { pending }.start();
+            let take = |token: &str| migrations.send(TakeSession(token.to_owned()));
+
+            let resumed = take("inside").await.unwrap();
+            assert_eq!(resumed.unwrap().client_name.as_deref(), Some("alice"));
+            // tokens are single use
+            assert!(take("inside").await.unwrap().is_none());
+            assert!(take("after").await.unwrap().is_none());
+            assert!(take("long after").await.unwrap().is_none());
+
+            // a session stored now is resumable until the grace is up again
+            migrations
+                .send(StoreSession {
+                    token: "new".to_owned(),
+                    state: state("dave"),
+                })
+                .await
+                .unwrap();
+            assert!(take("new").await.unwrap().is_some());
+        });
+    }
+}
@@ -0,0 +1,257 @@
+//! A preview of public rooms for guests, `GET /api/rooms/{room}/preview`: the
+//! last few messages, over plain HTTP and without logging in, so a landing
+//! page can show a room's activity without opening a socket. Previews are
+//! cached for a while on the node and told to be cached by browsers and
+//! proxies for as long, and each address may only ask so often.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix::SystemService;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::access::history_response;
+use crate::journal::{EventKind, Journal, RoomEvent};
+use crate::message::{GetRoomSettings, RoomHistory};
+use crate::ratelimit::RateLimit;
+use crate::server::WsChatServer;
+use crate::settings::{RoomFlag, Visibility};
+
+/// Messages in a preview
+const PREVIEW_LENGTH: usize = 5;
+
+/// How long a preview is served from the cache, and may be cached elsewhere
+const PREVIEW_TTL: Duration = Duration::from_secs(10);
+
+/// Previews each address may ask for a second, and in a burst
+const PREVIEW_RATE: u32 = 1;
+const PREVIEW_BURST: u32 = 10;
+
+/// Addresses tracked before the ones whose limits have refilled are
+/// forgotten
+const MAX_TRACKED: usize = 10_000;
+
+/// By room
+static CACHE: Lazy<Mutex<HashMap<String, CachedPreview>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static LIMITS: Lazy<Mutex<GuestLimits>> =
+    Lazy::new(|| Mutex::new(GuestLimits::default()));
+
+#[derive(Clone)]
+struct CachedPreview {
+    made: Instant,
+    indexable: bool,
+    body: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PreviewMessage<'a> {
+    id: u64,
+    /// unix milliseconds
+    posted: u64,
+    from: Option<&'a str>,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct Preview<'a> {
+    room: &'a str,
+    messages: Vec<PreviewMessage<'a>>,
+}
+
+/// The last `count` chat messages among a room's events, oldest first
+fn last_messages(events: &[RoomEvent], count: usize) -> Vec<PreviewMessage<'_>> {
+    let mut messages: Vec<_> = events
+        .iter()
+        .rev()
+        .filter_map(|event| match &event.kind {
+            EventKind::Message {
+                content,
+                id,
+                posted,
+            } => {
+                let (from, content) = match content.split_once(": ") {
+                    Some((from, content)) => (Some(from), content),
+                    None => (None, content.as_str()),
+                };
+                Some(PreviewMessage {
+                    id: *id,
+                    posted: *posted,
+                    from,
+                    content,
+                })
+            }
+            _ => None,
+        })
+        .take(count)
+        .collect();
+    messages.reverse();
+
+    messages
+}
+
+/// Per address rate limits of guests
+#[derive(Default)]
+struct GuestLimits {
+    /// (when last asked, limit) by address
+    limits: HashMap<IpAddr, (Instant, RateLimit)>,
+}
+
+impl GuestLimits {
+    fn allow_at(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if self.limits.len() >= MAX_TRACKED && !self.limits.contains_key(&addr) {
+            // idle this long, a limit is back to a full burst anyway
+            let refill = Duration::from_secs((PREVIEW_BURST / PREVIEW_RATE).into());
+            self.limits
+                .retain(|_, (asked, _)| now.saturating_duration_since(*asked) < refill);
+        }
+
+        let (asked, limit) = self
+            .limits
+            .entry(addr)
+            .or_insert_with(|| (now, RateLimit::new(PREVIEW_RATE, PREVIEW_BURST)));
+        *asked = now;
+        limit.allow()
+    }
+}
+
+fn cached(room_name: &str, now: Instant) -> Option<CachedPreview> {
+    let cache = CACHE.lock().expect("preview cache poisoned");
+
+    cache
+        .get(room_name)
+        .filter(|preview| now.saturating_duration_since(preview.made) < PREVIEW_TTL)
+        .cloned()
+}
+
+fn cache(room_name: String, preview: CachedPreview) {
+    let mut cache = CACHE.lock().expect("preview cache poisoned");
+
+    cache.retain(|_, cached| {
+        preview.made.saturating_duration_since(cached.made) < PREVIEW_TTL
+    });
+    cache.insert(room_name, preview);
+}
+
+/// 404 for rooms that aren't public or the node doesn't know of, and
+/// 429 for addresses asking too often. A room made private may still be
+/// previewed until its cached preview expires.
+pub async fn room_preview(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let room_name = room_name.into_inner();
+    let now = Instant::now();
+
+    if let Some(addr) = req.peer_addr() {
+        let allowed = LIMITS
+            .lock()
+            .expect("preview limits poisoned")
+            .allow_at(addr.ip(), now);
+        if !allowed {
+            return Ok(HttpResponse::TooManyRequests()
+                .header("Retry-After", PREVIEW_RATE.to_string())
+                .finish());
+        }
+    }
+
+    let CachedPreview {
+        indexable, body, ..
+    } = match cached(&room_name, now) {
+        Some(cached) => cached,
+        None => {
+            let settings = WsChatServer::from_registry()
+                .send(GetRoomSettings(room_name.clone()))
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            let settings = match settings {
+                Some(settings) if settings.visibility == Visibility::Public => settings,
+                _ => return Ok(HttpResponse::NotFound().finish()),
+            };
+
+            let events = Journal::from_registry()
+                .send(RoomHistory {
+                    room_name: room_name.clone(),
+                    since: 0,
+                })
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            let preview = Preview {
+                room: &room_name,
+                messages: last_messages(&events, PREVIEW_LENGTH),
+            };
+            let preview = CachedPreview {
+                made: now,
+                // unlisted rooms may be previewed, but not found by crawlers
+                indexable: !settings.has_flag(RoomFlag::Unlisted),
+                body: serde_json::to_string(&preview)?,
+            };
+
+            cache(room_name, preview.clone());
+            preview
+        }
+    };
+
+    Ok(history_response(indexable)
+        .content_type("application/json")
+        .header(
+            "Cache-Control",
+            format!("public, max-age={}", PREVIEW_TTL.as_secs()),
+        )
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: u64, kind: EventKind) -> RoomEvent {
+        RoomEvent {
+            seq,
+            room_name: "Main".to_owned(),
+            time: seq,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        let events: Vec<_> = (1..=7)
+            .map(|seq| {
+                event(
+                    seq,
+                    EventKind::Message {
+                        content: format!("alice: hello {}", seq),
+                        id: seq,
+                        posted: seq,
+                    },
+                )
+            })
+            .chain(Some(event(
+                8,
+                EventKind::Announcement {
+                    text: "welcome".to_owned(),
+                },
+            )))
+            .collect();
+
+        let messages = last_messages(&events, 3);
+        let ids: Vec<_> = messages.iter().map(|message| message.id).collect();
+        assert_eq!(ids, [5, 6, 7]);
+        assert_eq!(messages[2].from, Some("alice"));
+        assert_eq!(messages[2].content, "hello 7");
+
+        let mut limits = GuestLimits::default();
+        let addr = IpAddr::from([127, 0, 0, 1]);
+        let now = Instant::now();
+        assert!((0..PREVIEW_BURST).all(|_| limits.allow_at(addr, now)));
+        assert!(!limits.allow_at(addr, now));
+        assert!(limits.allow_at(IpAddr::from([127, 0, 0, 2]), now));
+    }
+}
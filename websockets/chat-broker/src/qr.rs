@@ -0,0 +1,479 @@
+//! A QR code encoder, just enough of one for join links: bytes only, at the
+//! medium error correction level (15% of a code may be lost), up to version
+//! 10 (57 by 57 modules, 213 bytes). Renders to SVG.
+
+/// Data codewords and error correction codewords per block, and the blocks
+/// of each length, for versions 1 to 10 at level M
+const BLOCKS: [(usize, &[(usize, usize)]); 10] = [
+    (10, &[(1, 16)]),
+    (16, &[(1, 28)]),
+    (26, &[(1, 44)]),
+    (18, &[(2, 32)]),
+    (24, &[(2, 43)]),
+    (16, &[(4, 27)]),
+    (18, &[(4, 31)]),
+    (22, &[(2, 38), (2, 39)]),
+    (22, &[(3, 36), (2, 37)]),
+    (26, &[(4, 43), (1, 44)]),
+];
+
+/// Centers of the alignment patterns, by version
+const ALIGNMENT: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+/// Bits of level M in the format information
+const LEVEL_M: u32 = 0b00;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QrCode {
+    size: usize,
+    /// by row
+    modules: Vec<bool>,
+    /// finder, timing, alignment and format modules, which masks leave be
+    function: Vec<bool>,
+}
+
+/// A product in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((u32::from(y) >> i) & 1) * u32::from(x);
+    }
+    z as u8
+}
+
+/// The Reed-Solomon generator polynomial of `degree`, highest coefficient
+/// (always 1) left out
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0; degree];
+    divisor[degree - 1] = 1;
+
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_mul(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+
+    divisor
+}
+
+/// The error correction codewords of a block
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; divisor.len()];
+    for byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (coefficient, divisor) in remainder.iter_mut().zip(divisor) {
+            *coefficient ^= gf_mul(*divisor, factor);
+        }
+    }
+
+    remainder
+}
+
+fn data_capacity(version: usize) -> usize {
+    let (_, blocks) = BLOCKS[version - 1];
+    blocks.iter().map(|(count, len)| count * len).sum()
+}
+
+/// Mode, length, the bytes and padding, as codewords, `None` if they don't
+/// fit the version
+fn data_codewords(data: &[u8], version: usize) -> Option<Vec<u8>> {
+    let capacity = data_capacity(version);
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity * 8);
+    let mut push = |value: usize, len: usize| {
+        for i in (0..len).rev() {
+            bits.push((value >> i) & 1 == 1);
+        }
+    };
+
+    // byte mode, the length takes 16 bits from version 10 on
+    push(0b0100, 4);
+    let length_bits = if version < 10 { 8 } else { 16 };
+    if data.len() >= 1 << length_bits {
+        return None;
+    }
+    push(data.len(), length_bits);
+    for byte in data {
+        push(usize::from(*byte), 8);
+    }
+    if bits.len() > capacity * 8 {
+        return None;
+    }
+
+    // the terminator, then up to a whole byte
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    let padding = (8 - bits.len() % 8) % 8;
+    bits.extend(std::iter::repeat_n(false, padding));
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | u8::from(*bit)))
+        .collect();
+    for pad in [0xec, 0x11].iter().cycle() {
+        if codewords.len() == capacity {
+            break;
+        }
+        codewords.push(*pad);
+    }
+
+    Some(codewords)
+}
+
+/// Splits the data into blocks, adds each one's error correction, and
+/// interleaves them
+fn codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let (ec_len, groups) = BLOCKS[version - 1];
+    let divisor = rs_divisor(ec_len);
+
+    let mut blocks = Vec::new();
+    let mut rest = data;
+    for (count, len) in groups {
+        for _ in 0..*count {
+            let (block, after) = rest.split_at(*len);
+            blocks.push((block, rs_remainder(block, &divisor)));
+            rest = after;
+        }
+    }
+
+    let longest = blocks
+        .iter()
+        .map(|(block, _)| block.len())
+        .max()
+        .unwrap_or(0);
+    let mut interleaved = Vec::new();
+    for i in 0..longest {
+        interleaved.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+    }
+    for i in 0..ec_len {
+        interleaved.extend(blocks.iter().map(|(_, ec)| ec[i]));
+    }
+
+    interleaved
+}
+
+fn masked(mask: u8, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+impl QrCode {
+    /// The smallest code holding `data`, `None` if it's too long
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let (version, data) = (1..=10)
+            .find_map(|version| Some((version, data_codewords(data, version)?)))?;
+        let codewords = codewords(&data, version);
+
+        let mut best: Option<(u32, QrCode)> = None;
+        for mask in 0..8 {
+            let code = QrCode::with_mask(version, &codewords, mask);
+            let penalty = code.penalty();
+            if best.as_ref().is_none_or(|(least, _)| penalty < *least) {
+                best = Some((penalty, code));
+            }
+        }
+
+        best.map(|(_, code)| code)
+    }
+
+    fn with_mask(version: usize, codewords: &[u8], mask: u8) -> QrCode {
+        let size = 17 + 4 * version;
+        let mut code = QrCode {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+
+        code.draw_function_patterns(version);
+        code.draw_codewords(codewords);
+        for y in 0..size {
+            for x in 0..size {
+                if !code.function[y * size + x] && masked(mask, x, y) {
+                    code.modules[y * size + x] ^= true;
+                }
+            }
+        }
+        code.draw_format(mask);
+
+        code
+    }
+
+    pub fn dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            self.draw_finder(x, y);
+        }
+
+        let centers = ALIGNMENT[version - 1];
+        let last = centers.len().saturating_sub(1);
+        for (i, x) in centers.iter().enumerate() {
+            for (j, y) in centers.iter().enumerate() {
+                // the corners with finders
+                if (i == 0 && (j == 0 || j == last)) || (i == last && j == 0) {
+                    continue;
+                }
+                for dy in 0..5 {
+                    for dx in 0..5 {
+                        let ring = (dx as i32 - 2).abs().max((dy as i32 - 2).abs());
+                        self.set_function(x + dx - 2, y + dy - 2, ring != 1);
+                    }
+                }
+            }
+        }
+
+        // reserved until the mask is known
+        self.draw_format(0);
+
+        if version >= 7 {
+            let version = version as u32;
+            let mut remainder = version;
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+            }
+            let bits = version << 12 | remainder;
+
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    /// A finder pattern and its separator, clipped at the edges
+    fn draw_finder(&mut self, x: usize, y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+                if xx < 0 || yy < 0 || xx >= self.size as i32 || yy >= self.size as i32 {
+                    continue;
+                }
+                let ring = dx.abs().max(dy.abs());
+                self.set_function(xx as usize, yy as usize, ring != 2 && ring != 4);
+            }
+        }
+    }
+
+    fn draw_format(&mut self, mask: u8) {
+        let size = self.size;
+        let data = LEVEL_M << 3 | u32::from(mask);
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+
+        // around the top left finder
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        // and split between the other two
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Zigzags up and down two columns at a time from the bottom right,
+    /// around the function patterns. Modules left over stay light.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut bit = 0;
+
+        let mut right = size - 1;
+        while right >= 1 {
+            // the vertical timing pattern is skipped as a whole
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if self.function[y * size + x] || bit >= codewords.len() * 8 {
+                        continue;
+                    }
+                    self.modules[y * size + x] =
+                        (codewords[bit / 8] >> (7 - bit % 8)) & 1 == 1;
+                    bit += 1;
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// How hard the code is to scan, for picking the mask
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        let lines = (0..size).flat_map(|i| {
+            [
+                (0..size).map(|j| self.dark(j, i)).collect::<Vec<_>>(),
+                (0..size).map(|j| self.dark(i, j)).collect::<Vec<_>>(),
+            ]
+        });
+        for line in lines {
+            // runs of five or more of a color
+            let mut run = 1;
+            for i in 1..=size {
+                if i < size && line[i] == line[i - 1] {
+                    run += 1;
+                    continue;
+                }
+                if run >= 5 {
+                    penalty += 3 + (run - 5) as u32;
+                }
+                run = 1;
+            }
+
+            // what looks like a finder
+            for window in line.windows(11) {
+                let finder = [true, false, true, true, true, false, true];
+                let light = [false; 4];
+                if (window[..7] == finder && window[7..] == light)
+                    || (window[..4] == light && window[4..] == finder)
+                {
+                    penalty += 40;
+                }
+            }
+        }
+
+        // 2x2 blocks of a color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.dark(x, y);
+                if self.dark(x + 1, y) == color
+                    && self.dark(x, y + 1) == color
+                    && self.dark(x + 1, y + 1) == color
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // how far from half of them dark
+        let dark = self.modules.iter().filter(|dark| **dark).count();
+        let percent = dark * 100 / (size * size);
+        penalty += (percent.abs_diff(50) / 5) as u32 * 10;
+
+        penalty
+    }
+
+    /// An SVG drawing, each module `scale` pixels wide, with the four module
+    /// quiet zone around it scanners need
+    pub fn to_svg(&self, scale: usize) -> String {
+        let border = 4;
+        let width = (self.size + 2 * border) * scale;
+
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.dark(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + border, y + border));
+                }
+            }
+        }
+
+        format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{width}" "#,
+                r#"viewBox="0 0 {modules} {modules}" shape-rendering="crispEdges">"#,
+                r#"<rect width="100%" height="100%" fill="white"/>"#,
+                r#"<path d="{path}" fill="black"/></svg>"#,
+            ),
+            width = width,
+            modules = self.size + 2 * border,
+            path = path,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_correction() {
+        // "01234567" in numeric mode at 1-M, from the standard's example
+        let data = [
+            0x10, 0x20, 0x0c, 0x56, 0x61, 0x80, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11,
+            0xec, 0x11, 0xec, 0x11,
+        ];
+        assert_eq!(
+            rs_remainder(&data, &rs_divisor(10)),
+            [0xa5, 0x24, 0xd4, 0xc1, 0xed, 0x36, 0xc7, 0x87, 0x2c, 0x55]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let code = QrCode::encode(b"https://chat.example.com/?join=ABCD1234").unwrap();
+        assert_eq!(code.size, 29);
+        // the dark module, and a finder's corner
+        assert!(code.dark(8, code.size - 8));
+        assert!(code.dark(0, 0) && !code.dark(7, 0));
+
+        assert_eq!(QrCode::encode(&[b'a'; 213]).unwrap().size, 57);
+        assert!(QrCode::encode(&[b'a'; 214]).is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::Instant;
+
+/// Token bucket: holds up to `burst` tokens, refilled at `per_sec` a second.
+/// Each allowed event takes one.
+#[derive(Debug)]
+pub struct RateLimit {
+    burst: f64,
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        RateLimit {
+            burst: burst.into(),
+            per_sec: per_sec.into(),
+            tokens: burst.into(),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::new(10, 3);
+        let start = limit.refilled;
+
+        assert!(limit.allow_at(start));
+        assert!(limit.allow_at(start));
+        assert!(limit.allow_at(start));
+        assert!(!limit.allow_at(start));
+
+        // a token every 100ms
+        assert!(!limit.allow_at(start + Duration::from_millis(50)));
+        assert!(limit.allow_at(start + Duration::from_millis(100)));
+        assert!(!limit.allow_at(start + Duration::from_millis(110)));
+
+        // never more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limit.allow_at(later)));
+        assert!(!limit.allow_at(later));
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use actix::prelude::*;
+use log::warn;
+use serde::Serialize;
+
+use crate::config::config;
+use crate::journal::{EventKind, Journal, RoomEvent};
+use crate::message::{
+    GetReadPositions, GetUnread, IsRoomMember, MarkRead, SubscribeEvents, UnwatchReads,
+    WatchReads,
+};
+use crate::server::{RoomError, WsChatServer};
+
+/// How far an account has read a room, as sent to its sessions as
+/// `{"type":"read","room":"Main","seq":42,"unread":0}`
+#[derive(Clone, Debug, Serialize, Message)]
+#[rtype(result = "()")]
+pub struct ReadPosition {
+    pub room: String,
+    /// journal sequence number of the last message read, `None` if the room
+    /// was never marked read
+    pub seq: Option<u64>,
+    /// messages after it, as far as the journal goes back
+    pub unread: usize,
+}
+
+/// Where each account stopped reading each room, in journal sequence
+/// numbers, so every session of the account shows the same unread counts.
+/// Follows the journal for the messages of every room, and like it keeps
+/// positions per node.
+#[derive(Default)]
+pub struct ReadMarkers {
+    /// sequence numbers of chat messages, oldest first
+    messages: HashMap<String, VecDeque<u64>>,
+    /// account -> room -> last message read
+    read: HashMap<String, HashMap<String, u64>>,
+    /// logged in sessions by account, (login id, session)
+    sessions: HashMap<String, Vec<(u64, Recipient<ReadPosition>)>>,
+}
+
+impl ReadPosition {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "read",
+            "room": self.room,
+            "seq": self.seq,
+            "unread": self.unread,
+        })
+    }
+}
+
+impl ReadMarkers {
+    fn record(&mut self, event: &RoomEvent) {
+        let chat = matches!(
+            event.kind,
+            EventKind::Message { .. }
+                | EventKind::Forwarded { .. }
+                | EventKind::CrossPost { .. }
+                | EventKind::Attachment { .. }
+                | EventKind::VoiceNote { .. }
+        );
+        if !chat {
+            return;
+        }
+
+        let seqs = self.messages.entry(event.room_name.clone()).or_default();
+        seqs.push_back(event.seq);
+        if seqs.len() > config().history_depth {
+            seqs.pop_front();
+        }
+    }
+
+    fn position(&self, account: &str, room_name: &str) -> ReadPosition {
+        let seq = self
+            .read
+            .get(account)
+            .and_then(|rooms| rooms.get(room_name))
+            .copied();
+        // sequence numbers only grow, so the read ones are a prefix
+        let unread = self.messages.get(room_name).map_or(0, |seqs| match seq {
+            Some(seq) => seqs.len() - seqs.partition_point(|message| *message <= seq),
+            None => seqs.len(),
+        });
+
+        ReadPosition {
+            room: room_name.to_owned(),
+            seq,
+            unread,
+        }
+    }
+}
+
+impl Actor for ReadMarkers {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        Journal::from_registry()
+            .send(SubscribeEvents {
+                room_name: None,
+                since: 0,
+                subscriber: ctx.address().recipient(),
+            })
+            .into_actor(self)
+            .then(|res, act, _ctx| {
+                for event in res.unwrap_or_default() {
+                    act.record(&event);
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Handler<RoomEvent> for ReadMarkers {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomEvent, _ctx: &mut Self::Context) {
+        self.record(&msg);
+    }
+}
+
+impl ReadMarkers {
+    /// Positions only move forward, and the account's other sessions are told
+    fn mark_read(&mut self, msg: MarkRead) -> ReadPosition {
+        let MarkRead {
+            account,
+            login,
+            room_name,
+            seq,
+        } = msg;
+
+        let latest = self
+            .messages
+            .get(&room_name)
+            .and_then(|seqs| seqs.back())
+            .copied()
+            .unwrap_or(0);
+        let seq = seq.map_or(latest, |seq| seq.min(latest));
+
+        let read = self
+            .read
+            .entry(account.clone())
+            .or_default()
+            .entry(room_name.clone())
+            .or_insert(seq);
+        *read = (*read).max(seq);
+
+        let position = self.position(&account, &room_name);
+        if let Some(sessions) = self.sessions.get_mut(&account) {
+            sessions.retain(|(other, session)| {
+                Some(*other) == login || session.do_send(position.clone()).is_ok()
+            });
+        }
+
+        position
+    }
+}
+
+/// Asks the chat server whether the account belongs to the room first
+impl Handler<MarkRead> for ReadMarkers {
+    type Result = ResponseActFuture<Self, Result<ReadPosition, RoomError>>;
+
+    fn handle(&mut self, msg: MarkRead, _ctx: &mut Self::Context) -> Self::Result {
+        let member = WsChatServer::from_registry().send(IsRoomMember {
+            room_name: msg.room_name.clone(),
+            account: msg.account.clone(),
+        });
+
+        Box::pin(member.into_actor(self).map(move |member, act, _ctx| {
+            match member {
+                Ok(true) => Ok(act.mark_read(msg)),
+                Ok(false) => Err(RoomError::NotMember),
+                // as if it didn't, rather than let anyone in
+                Err(err) => {
+                    warn!("MarkRead::handle() - no answer from the server: {}", err);
+                    Err(RoomError::NotMember)
+                }
+            }
+        }))
+    }
+}
+
+impl Handler<GetUnread> for ReadMarkers {
+    type Result = MessageResult<GetUnread>;
+
+    fn handle(&mut self, msg: GetUnread, _ctx: &mut Self::Context) -> Self::Result {
+        let GetUnread { account, rooms } = msg;
+
+        let mut rooms: BTreeSet<String> = rooms.into_iter().collect();
+        if let Some(read) = self.read.get(&account) {
+            rooms.extend(read.keys().cloned());
+        }
+
+        MessageResult(
+            rooms
+                .iter()
+                .map(|room_name| self.position(&account, room_name))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<GetReadPositions> for ReadMarkers {
+    type Result = MessageResult<GetReadPositions>;
+
+    fn handle(
+        &mut self,
+        msg: GetReadPositions,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let GetReadPositions { account, rooms } = msg;
+        let read = match self.read.get(&account) {
+            Some(read) => read,
+            None => return MessageResult(Vec::new()),
+        };
+
+        MessageResult(
+            rooms
+                .iter()
+                .filter(|room_name| read.contains_key(*room_name))
+                .map(|room_name| self.position(&account, room_name))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<WatchReads> for ReadMarkers {
+    type Result = ();
+
+    fn handle(&mut self, msg: WatchReads, _ctx: &mut Self::Context) {
+        let WatchReads {
+            account,
+            login,
+            session,
+        } = msg;
+
+        self.sessions
+            .entry(account)
+            .or_default()
+            .push((login, session));
+    }
+}
+
+impl Handler<UnwatchReads> for ReadMarkers {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnwatchReads, _ctx: &mut Self::Context) {
+        let UnwatchReads(account, login) = msg;
+
+        if let Some(sessions) = self.sessions.get_mut(&account) {
+            sessions.retain(|(other, _)| *other != login);
+            if sessions.is_empty() {
+                self.sessions.remove(&account);
+            }
+        }
+    }
+}
+
+impl SystemService for ReadMarkers {}
+impl Supervised for ReadMarkers {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::message::Register;
+    use crate::testing::{run, TestClient};
+
+    fn message(seq: u64) -> RoomEvent {
+        RoomEvent {
+            seq,
+            room_name: "r".to_owned(),
+            time: 0,
+            kind: EventKind::message(format!("bob: {}", seq)),
+        }
+    }
+
+    #[test]
+    fn test_mark_read_is_monotonic() {
+        let mut markers = ReadMarkers::default();
+        for seq in 1..=5 {
+            markers.record(&message(seq));
+        }
+        let mut mark = |seq| {
+            let position = markers.mark_read(MarkRead {
+                account: "alice".to_owned(),
+                login: Some(1),
+                room_name: "r".to_owned(),
+                seq,
+            });
+            (position.seq, position.unread)
+        };
+
+        assert_eq!(mark(Some(3)), (Some(3), 2));
+        // an older one doesn't move the marker back
+        assert_eq!(mark(Some(1)), (Some(3), 2));
+        // nor past the latest message
+        assert_eq!(mark(Some(99)), (Some(5), 0));
+        assert_eq!(mark(None), (Some(5), 0));
+    }
+
+    #[test]
+    fn test_read_positions_across_logins() {
+        run(async {
+            let password = "correct horse";
+            Accounts::from_registry()
+                .send(Register {
+                    name: "alice".to_owned(),
+                    email: "alice@example.com".to_owned(),
+                    password: password.to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            // logged in before joining, so the room has the account as a member
+            let login = format!("/login alice {}", password);
+            let mut phone = TestClient::connect(&[]);
+            phone.texts().await;
+            phone.ask_for(&login, "logged in as").await;
+            phone.ask_for("/join r", "joined r").await;
+            let mut laptop = TestClient::connect(&[]);
+            laptop.texts().await;
+            laptop.ask_for(&login, "logged in as").await;
+            laptop.ask_for("/join r", "joined r").await;
+            phone.texts().await;
+
+            let mut bob = TestClient::connect(&["r"]);
+            bob.texts().await;
+            bob.ask("one").await;
+            bob.ask("two").await;
+
+            phone.ask_for("/read", "marked r read").await;
+            let read = laptop.wait_for(r#""type":"read""#).await;
+            assert!(read.contains(r#""room":"r""#) && read.contains(r#""unread":0"#));
+
+            bob.ask("three").await;
+            let positions = ReadMarkers::from_registry()
+                .send(GetReadPositions {
+                    account: "alice".to_owned(),
+                    rooms: vec!["r".to_owned(), "elsewhere".to_owned()],
+                })
+                .await
+                .unwrap();
+            assert_eq!(positions.len(), 1);
+            assert_eq!(positions[0].unread, 1);
+            laptop.wait_for("three").await;
+            assert_eq!(laptop.ask("/unread").await, ["r: 1 unread"]);
+        });
+    }
+}
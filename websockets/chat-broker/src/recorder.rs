@@ -0,0 +1,245 @@
+//! Recording sessions' websocket traffic, for reproducing bugs. With
+//! `RECORD_DIR` set, every session writes the frames it receives and sends to
+//! a file of its own there, one JSON line each, timed from when it connected.
+//! The `replay` binary feeds a recording back through a fresh session.
+//!
+//! Recordings hold everything clients sent, passwords and tokens included,
+//! so they are for debugging only.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use actix::prelude::*;
+use actix_web_actors::ws;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::cluster::NODE_ID;
+use crate::message::{EndRecording, RecordFrame};
+use crate::session::unix_millis;
+
+/// The recorder, if `RECORD_DIR` is set
+static RECORDER: OnceCell<Addr<Recorder>> = OnceCell::new();
+
+static NEXT_RECORDING: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// from the client
+    In,
+    Out,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+pub enum Frame {
+    Text {
+        text: String,
+    },
+    /// base64
+    Binary {
+        data: String,
+    },
+    Close {
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+}
+
+/// A line of a recording
+#[derive(Clone, Debug, Serialize)]
+pub struct Record {
+    /// milliseconds since the session connected
+    pub ms: u64,
+    pub dir: Direction,
+    #[serde(flatten)]
+    pub frame: Frame,
+}
+
+/// A session's recording, which every frame it receives and sends goes to
+pub struct Recording {
+    id: u64,
+    started: Instant,
+}
+
+impl Recording {
+    /// A recording for a session connecting now, `None` unless recording is on
+    pub fn start() -> Option<Self> {
+        RECORDER.get()?;
+
+        Some(Recording {
+            id: NEXT_RECORDING.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&self, dir: Direction, frame: Frame) {
+        let record = Record {
+            ms: self.started.elapsed().as_millis() as u64,
+            dir,
+            frame,
+        };
+
+        if let Some(recorder) = RECORDER.get() {
+            recorder.do_send(RecordFrame(self.id, record));
+        }
+    }
+
+    pub fn text(&self, dir: Direction, text: &str) {
+        self.record(
+            dir,
+            Frame::Text {
+                text: text.to_owned(),
+            },
+        );
+    }
+
+    pub fn binary(&self, dir: Direction, data: &[u8]) {
+        self.record(
+            dir,
+            Frame::Binary {
+                data: base64::encode(data),
+            },
+        );
+    }
+
+    pub fn close(&self, dir: Direction, reason: Option<&ws::CloseReason>) {
+        self.record(
+            dir,
+            Frame::Close {
+                code: reason.map(|reason| reason.code.into()),
+                reason: reason.and_then(|reason| reason.description.clone()),
+            },
+        );
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if let Some(recorder) = RECORDER.get() {
+            recorder.do_send(EndRecording(self.id));
+        }
+    }
+}
+
+/// Writes recordings to files, on a thread of its own
+pub struct Recorder {
+    dir: PathBuf,
+    /// recordings being written, by id
+    files: HashMap<u64, BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Starts recording sessions if `RECORD_DIR` is set, creating the
+    /// directory if need be
+    pub fn from_env() -> io::Result<Option<Addr<Recorder>>> {
+        let dir = match std::env::var("RECORD_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => return Ok(None),
+        };
+        fs::create_dir_all(&dir)?;
+        warn!(
+            "Recorder - recording every session to {}, clients' passwords included",
+            dir.display()
+        );
+
+        let recorder = SyncArbiter::start(1, move || Recorder {
+            dir: dir.clone(),
+            files: HashMap::new(),
+        });
+        RECORDER.set(recorder.clone()).ok();
+
+        Ok(Some(recorder))
+    }
+
+    /// Opens the file of a recording on its first frame, named after the node
+    /// and when that came so recordings sort by time
+    fn file(&mut self, id: u64) -> io::Result<&mut BufWriter<File>> {
+        if !self.files.contains_key(&id) {
+            let path = self.dir.join(format!(
+                "{}-{}-{}.jsonl",
+                NODE_ID.as_str(),
+                unix_millis(),
+                id
+            ));
+            let file = File::create(&path)?;
+            info!("Recorder - recording session to {}", path.display());
+            self.files.insert(id, BufWriter::new(file));
+        }
+
+        Ok(self.files.get_mut(&id).expect("just opened"))
+    }
+}
+
+impl Actor for Recorder {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<RecordFrame> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: RecordFrame, _ctx: &mut Self::Context) {
+        let RecordFrame(id, record) = msg;
+
+        let res = self.file(id).and_then(|file| {
+            serde_json::to_writer(&mut *file, &record)?;
+            file.write_all(b"\n")
+        });
+        if let Err(err) = res {
+            warn!("Recorder - writing recording {} failed: {}", id, err);
+        }
+    }
+}
+
+impl Handler<EndRecording> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: EndRecording, _ctx: &mut Self::Context) {
+        let EndRecording(id) = msg;
+
+        if let Some(mut file) = self.files.remove(&id) {
+            if let Err(err) = file.flush() {
+                warn!("Recorder - writing recording {} failed: {}", id, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_line() {
+        let record = Record {
+            ms: 1200,
+            dir: Direction::In,
+            frame: Frame::Text {
+                text: "/join lobby".to_owned(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"ms":1200,"dir":"in","frame":"text","text":"/join lobby"}"#
+        );
+
+        let record = Record {
+            ms: 0,
+            dir: Direction::Out,
+            frame: Frame::Close {
+                code: Some(1008),
+                reason: None,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"ms":0,"dir":"out","frame":"close","code":1008,"reason":null}"#
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use actix::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::NODE_ID;
+use crate::message::{
+    ChatMessage, ForgetReminders, ListReminders, NotifyAccount, Remind, Reply,
+};
+use crate::server::WsChatServer;
+use crate::session::unix_millis;
+
+/// How often due reminders are sent, and undelivered ones retried
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reminders each owner may have waiting at once
+const MAX_PER_OWNER: usize = 50;
+
+/// Who a reminder is whispered to, and a scheduled message is listed and
+/// cancelled by
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Owner {
+    /// the account the session was logged in to, any of its sessions on
+    /// this node
+    Account(String),
+    /// the session itself, which wasn't logged in, for as long as it lasts
+    #[serde(skip)]
+    Session(Recipient<ChatMessage>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reminder {
+    pub id: u64,
+    pub owner: Owner,
+    pub text: String,
+    /// unix milliseconds
+    pub due: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    next_id: u64,
+    reminders: Vec<Reminder>,
+}
+
+/// Whispers reminders back to whoever set them once they are due. An account
+/// with no session on this node gets its reminders as soon as one connects.
+/// Every change to those is saved to `REMINDERS_FILE` (default
+/// `reminders-<NODE_ID>.json`), so they outlive restarts. Reminders of
+/// sessions that aren't logged in are only kept in memory, and dropped when
+/// the session ends.
+pub struct Reminders {
+    path: PathBuf,
+    next_id: u64,
+    reminders: HashMap<u64, Reminder>,
+    /// being delivered right now
+    sending: HashSet<u64>,
+}
+
+impl Default for Reminders {
+    fn default() -> Self {
+        let path = std::env::var("REMINDERS_FILE")
+            .unwrap_or_else(|_| format!("reminders-{}.json", *NODE_ID));
+
+        Reminders {
+            path: path.into(),
+            next_id: 0,
+            reminders: HashMap::new(),
+            sending: HashSet::new(),
+        }
+    }
+}
+
+impl Reminders {
+    fn load(&mut self) {
+        let saved: Saved = match std::fs::read(&self.path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(saved) => saved,
+                Err(err) => {
+                    warn!("Reminders - ignoring {}: {}", self.path.display(), err);
+                    return;
+                }
+            },
+            Err(_) => return,
+        };
+
+        info!(
+            "Reminders - loaded {} from {}",
+            saved.reminders.len(),
+            self.path.display()
+        );
+        self.next_id = saved.next_id;
+        self.reminders = saved
+            .reminders
+            .into_iter()
+            .map(|reminder| (reminder.id, reminder))
+            .collect();
+    }
+
+    /// Written next to the file and renamed over it, so a crash mid-write
+    /// leaves the previous version
+    fn save(&self) {
+        let mut reminders: Vec<Reminder> = self
+            .reminders
+            .values()
+            .filter(|reminder| matches!(reminder.owner, Owner::Account(_)))
+            .cloned()
+            .collect();
+        reminders.sort_by_key(|reminder| reminder.id);
+
+        let saved = Saved {
+            next_id: self.next_id,
+            reminders,
+        };
+        let tmp = self.path.with_extension("tmp");
+
+        let res = serde_json::to_vec(&saved)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(err) = res {
+            warn!("Reminders - can't save {}: {}", self.path.display(), err);
+        }
+    }
+
+    fn send_due(&mut self, ctx: &mut Context<Self>) {
+        let now = unix_millis() as u64;
+
+        let due: Vec<Reminder> = self
+            .reminders
+            .values()
+            .filter(|reminder| reminder.due <= now)
+            .filter(|reminder| !self.sending.contains(&reminder.id))
+            .cloned()
+            .collect();
+
+        for reminder in due {
+            let id = reminder.id;
+            let text = format!("[reminder] {}", reminder.text);
+
+            let account = match reminder.owner {
+                Owner::Account(account) => account,
+                Owner::Session(session) => {
+                    session
+                        .do_send(ChatMessage(Reply::notice(text).to_json()))
+                        .ok();
+                    self.reminders.remove(&id);
+                    continue;
+                }
+            };
+            self.sending.insert(id);
+
+            let msg = NotifyAccount { account, text };
+
+            WsChatServer::from_registry()
+                .send(msg)
+                .into_actor(self)
+                .map(move |res, act, _ctx| {
+                    act.sending.remove(&id);
+
+                    // offline, tried again on the next tick
+                    if let Ok(Ok(())) = res {
+                        act.reminders.remove(&id);
+                        act.save();
+                    }
+                })
+                .spawn(ctx);
+        }
+    }
+}
+
+impl Actor for Reminders {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.load();
+        ctx.run_interval(TICK_INTERVAL, |act, ctx| act.send_due(ctx));
+    }
+}
+
+impl Handler<Remind> for Reminders {
+    type Result = Result<u64, String>;
+
+    fn handle(&mut self, msg: Remind, _ctx: &mut Self::Context) -> Self::Result {
+        let Remind { owner, text, delay } = msg;
+
+        let waiting = self
+            .reminders
+            .values()
+            .filter(|reminder| reminder.owner == owner)
+            .count();
+        if waiting >= MAX_PER_OWNER {
+            return Err(format!("you already have {} reminders", MAX_PER_OWNER));
+        }
+
+        let saved = matches!(owner, Owner::Account(_));
+        self.next_id += 1;
+        let reminder = Reminder {
+            id: self.next_id,
+            owner,
+            text,
+            due: unix_millis() as u64 + delay.as_millis() as u64,
+        };
+        self.reminders.insert(reminder.id, reminder);
+        if saved {
+            self.save();
+        }
+
+        Ok(self.next_id)
+    }
+}
+
+impl Handler<ListReminders> for Reminders {
+    type Result = MessageResult<ListReminders>;
+
+    fn handle(&mut self, msg: ListReminders, _ctx: &mut Self::Context) -> Self::Result {
+        let ListReminders(owner) = msg;
+
+        let mut reminders: Vec<Reminder> = self
+            .reminders
+            .values()
+            .filter(|reminder| reminder.owner == owner)
+            .cloned()
+            .collect();
+        reminders.sort_by_key(|reminder| reminder.due);
+
+        MessageResult(reminders)
+    }
+}
+
+impl Handler<ForgetReminders> for Reminders {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForgetReminders, _ctx: &mut Self::Context) {
+        let owner = Owner::Session(msg.0);
+        self.reminders.retain(|_, reminder| reminder.owner != owner);
+    }
+}
+
+impl SystemService for Reminders {}
+impl Supervised for Reminders {}
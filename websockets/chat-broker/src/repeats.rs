@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::scheduler::format_delay;
+
+/// How long a message counts as recent, for spotting repeats of it
+const WINDOW: Duration = Duration::from_secs(30);
+
+/// Recent messages remembered per client
+const MAX_RECENT: usize = 20;
+
+/// The mute for the second repeat, doubled for every one after it
+const FIRST_MUTE: Duration = Duration::from_secs(10);
+
+const MAX_MUTE: Duration = Duration::from_secs(10 * 60);
+
+/// Strikes are forgotten after this long without a repeat
+const STRIKE_RESET: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, PartialEq)]
+pub enum RepeatError {
+    /// the first repeat is only refused
+    Repeated,
+    /// carries how long the client can't post for
+    Muted(Duration),
+}
+
+impl fmt::Display for RepeatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepeatError::Repeated => {
+                write!(f, "you just sent that, repeating it will get you muted")
+            }
+            RepeatError::Muted(left) => write!(
+                f,
+                "muted for repeating yourself, wait {}",
+                // rounded up, never "wait 0s"
+                format_delay(Duration::from_secs(
+                    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+                ))
+            ),
+        }
+    }
+}
+
+/// Keeps a client from posting the same message over and over: a repeat within
+/// `WINDOW` is refused, and each further one mutes the client for twice as
+/// long as the last.
+#[derive(Debug, Default)]
+pub struct RepeatGuard {
+    /// normalized content and when it was sent, oldest first
+    recent: VecDeque<(String, Instant)>,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+/// Case and spacing don't make a message any different
+fn normalize(content: &str) -> String {
+    content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+impl RepeatGuard {
+    /// Records the message if it may be posted
+    pub fn check(&mut self, content: &str) -> Result<(), RepeatError> {
+        self.check_at(content, Instant::now())
+    }
+
+    fn check_at(&mut self, content: &str, now: Instant) -> Result<(), RepeatError> {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Err(RepeatError::Muted(until - now));
+            }
+            self.muted_until = None;
+        }
+
+        if self
+            .last_strike
+            .is_some_and(|last| now.saturating_duration_since(last) >= STRIKE_RESET)
+        {
+            self.strikes = 0;
+            self.last_strike = None;
+        }
+
+        while self
+            .recent
+            .front()
+            .is_some_and(|(_, sent)| now.saturating_duration_since(*sent) >= WINDOW)
+        {
+            self.recent.pop_front();
+        }
+
+        let content = normalize(content);
+        if !self.recent.iter().any(|(recent, _)| *recent == content) {
+            self.recent.push_back((content, now));
+            if self.recent.len() > MAX_RECENT {
+                self.recent.pop_front();
+            }
+            return Ok(());
+        }
+
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        if self.strikes == 1 {
+            return Err(RepeatError::Repeated);
+        }
+
+        let mute = FIRST_MUTE
+            .checked_mul(1 << (self.strikes - 2).min(16))
+            .map_or(MAX_MUTE, |mute| mute.min(MAX_MUTE));
+        self.muted_until = Some(now + mute);
+
+        Err(RepeatError::Muted(mute))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeat_guard() {
+        let mut guard = RepeatGuard::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(guard.check_at("buy now", at(0)), Ok(()));
+        assert_eq!(guard.check_at("something else", at(1)), Ok(()));
+        assert_eq!(
+            guard.check_at("Buy   NOW", at(2)),
+            Err(RepeatError::Repeated)
+        );
+        assert_eq!(
+            guard.check_at("buy now", at(3)),
+            Err(RepeatError::Muted(Duration::from_secs(10)))
+        );
+        assert_eq!(
+            guard.check_at("hello", at(8)),
+            Err(RepeatError::Muted(Duration::from_secs(5)))
+        );
+
+        // out of the window it's a new message
+        assert_eq!(guard.check_at("buy now", at(40)), Ok(()));
+        assert_eq!(
+            guard.check_at("buy now", at(41)),
+            Err(RepeatError::Muted(Duration::from_secs(20)))
+        );
+
+        // strikes wear off
+        assert_eq!(guard.check_at("buy now", at(400)), Ok(()));
+        assert_eq!(
+            guard.check_at("buy now", at(401)),
+            Err(RepeatError::Repeated)
+        );
+    }
+}
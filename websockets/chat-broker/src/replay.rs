@@ -0,0 +1,223 @@
+//! Plays a session recording back against a server, see `recorder`:
+//!
+//! ```sh
+//! cargo run --bin replay -- recordings/node-1604000000000-1.jsonl [ws://127.0.0.1:8080/ws/] [--fast]
+//! ```
+//!
+//! Sends the frames the client sent, at the times it sent them unless
+//! `--fast`, prints what comes back, and ends with the first frame that
+//! differs from the recording. Ids and times are left out of the comparison,
+//! since they differ every run.
+
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+use awc::ws::{CloseCode, CloseReason, Frame as WsFrame, Message};
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+
+/// How long to wait for the server's answers to the last frame
+const GRACE: Duration = Duration::from_secs(2);
+
+/// Keys of JSON frames left out of the comparison
+const VARYING: &[&str] = &["id", "posted", "server_time", "rtt_ms", "time", "resume"];
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    In,
+    Out,
+}
+
+/// The lines `recorder::Recorder` writes
+#[derive(Deserialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+enum Frame {
+    Text {
+        text: String,
+    },
+    Binary {
+        data: String,
+    },
+    Close {
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Record {
+    ms: u64,
+    dir: Direction,
+    #[serde(flatten)]
+    frame: Frame,
+}
+
+impl Frame {
+    fn message(self) -> io::Result<Message> {
+        Ok(match self {
+            Frame::Text { text } => Message::Text(text),
+            Frame::Binary { data } => Message::Binary(Bytes::from(
+                base64::decode(data).map_err(io::Error::other)?,
+            )),
+            Frame::Close { code, reason } => {
+                Message::Close(code.map(|code| CloseReason {
+                    code: CloseCode::from(code),
+                    description: reason,
+                }))
+            }
+        })
+    }
+
+    /// How the frame compares with the other run's
+    fn normalized(&self) -> String {
+        match self {
+            Frame::Text { text } => normalize(text),
+            Frame::Binary { data } => format!("binary {}", data),
+            Frame::Close { code, .. } => format!("close {:?}", code),
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(mut frame)) => {
+            for key in VARYING {
+                frame.remove(*key);
+            }
+            serde_json::Value::Object(frame).to_string()
+        }
+        _ => text.to_owned(),
+    }
+}
+
+fn usage() -> io::Error {
+    io::Error::other("usage: replay RECORDING [URL] [--fast]")
+}
+
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    let args = std::env::args().skip(1);
+    let mut path = None;
+    let mut url = String::from("ws://127.0.0.1:8080/ws/");
+    let mut fast = false;
+    for arg in args {
+        match arg.as_str() {
+            "--fast" => fast = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => url = arg,
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+
+    let mut sent = Vec::new();
+    let mut recorded = Vec::new();
+    for (number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+        let record: Record = serde_json::from_str(line).map_err(|err| {
+            io::Error::other(format!("{} line {}: {}", &path, number + 1, err))
+        })?;
+        match record.dir {
+            Direction::In => sent.push(record),
+            Direction::Out => recorded.push(record.frame.normalized()),
+        }
+    }
+
+    let (_res, framed) = awc::Client::new()
+        .ws(url.as_str())
+        .connect()
+        .await
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    let (sink, mut stream) = framed.split();
+    let started = Instant::now();
+
+    // the sink is shared with the pongs
+    let (tx, rx) = mpsc::unbounded();
+    actix_rt::spawn(async move {
+        let _ = rx.map(Ok).forward(sink).await;
+    });
+
+    let (done, received) = oneshot::channel();
+    let pongs = tx.clone();
+    actix_rt::spawn(async move {
+        let mut frames = Vec::new();
+        while let Some(Ok(frame)) = stream.next().await {
+            let frame = match frame {
+                WsFrame::Text(text) => Frame::Text {
+                    text: String::from_utf8_lossy(&text).into_owned(),
+                },
+                WsFrame::Binary(data) => Frame::Binary {
+                    data: base64::encode(&data),
+                },
+                WsFrame::Close(reason) => Frame::Close {
+                    code: reason.as_ref().map(|reason| reason.code.into()),
+                    reason: reason.and_then(|reason| reason.description),
+                },
+                WsFrame::Ping(ping) => {
+                    let _ = pongs.unbounded_send(Message::Pong(ping));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let frame = frame.normalized();
+            println!("{:>7} < {}", started.elapsed().as_millis(), &frame);
+            frames.push(frame);
+        }
+        let _ = done.send(frames);
+    });
+
+    let count = sent.len();
+    for Record { ms, frame, .. } in sent {
+        let due = Duration::from_millis(ms);
+        if !fast && due > started.elapsed() {
+            actix_rt::time::delay_for(due - started.elapsed()).await;
+        }
+
+        println!(
+            "{:>7} > {}",
+            started.elapsed().as_millis(),
+            frame.normalized()
+        );
+        tx.clone()
+            .send(frame.message()?)
+            .await
+            .map_err(io::Error::other)?;
+    }
+
+    actix_rt::time::delay_for(GRACE).await;
+    let _ = tx.unbounded_send(Message::Close(Some(CloseCode::Normal.into())));
+    let received = actix_rt::time::timeout(GRACE, received)
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or_default();
+
+    println!(
+        "replayed {} frames, {} came back, the recording has {}",
+        count,
+        received.len(),
+        recorded.len()
+    );
+    let differs = recorded
+        .iter()
+        .zip(&received)
+        .position(|(recorded, received)| recorded != received);
+    match differs {
+        Some(at) => println!(
+            "frame {} differs:\n  recorded {}\n  replayed {}",
+            at + 1,
+            recorded[at],
+            received[at]
+        ),
+        None if received.len() == recorded.len() => println!("no differences"),
+        None => println!(
+            "no differences in the first {} frames",
+            recorded.len().min(received.len())
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use log::debug;
+
+use crate::message::{ListScheduled, Schedule, SendMessage, Unschedule};
+use crate::reminders::Owner;
+use crate::server::WsChatServer;
+
+/// Furthest ahead a message can be scheduled
+const MAX_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Messages each owner may have waiting at once
+const MAX_PER_OWNER: usize = 20;
+
+/// Message waiting to be posted, as listed by `/scheduled`
+#[derive(Clone, Debug)]
+pub struct Scheduled {
+    /// per node, from 1
+    pub id: u64,
+    pub room_name: String,
+    /// the only one who can list and cancel it
+    owner: Owner,
+    /// the name it is posted under
+    pub author: String,
+    pub content: String,
+    pub due: Instant,
+    /// the author's client id in the room, the message is posted as it
+    client_id: usize,
+}
+
+/// Parses a delay such as `90s`, `15m`, `2h` or `1d`
+pub fn parse_delay(delay: &str) -> Result<Duration, String> {
+    let delay = delay.trim();
+    let expected = || format!("expected a delay like 15m, not {:?}", delay);
+
+    let unit = delay.chars().last().ok_or_else(expected)?;
+    let count: u64 = delay[..delay.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| expected())?;
+
+    let unit_secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit in {:?}, use s, m, h or d", delay)),
+    };
+    let secs = count.saturating_mul(unit_secs);
+    let delay = Duration::from_secs(secs);
+
+    if secs == 0 || delay > MAX_DELAY {
+        return Err("delay must be between 1s and 7d".to_owned());
+    }
+
+    Ok(delay)
+}
+
+/// e.g. `1h 05m`, `14m 32s` or `9s`
+pub fn format_delay(delay: Duration) -> String {
+    let secs = delay.as_secs();
+
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    TooMany,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::TooMany => write!(
+                f,
+                "you already have {} scheduled messages, /unschedule one first",
+                MAX_PER_OWNER
+            ),
+        }
+    }
+}
+
+/// Posts scheduled messages when they are due, as if their author sent them
+/// then. Kept in memory on the author's node only, so they are lost if the
+/// node goes down.
+#[derive(Default)]
+pub struct Scheduler {
+    next_id: u64,
+    pending: HashMap<u64, (Scheduled, SpawnHandle)>,
+}
+
+impl Actor for Scheduler {
+    type Context = Context<Self>;
+}
+
+impl Handler<Schedule> for Scheduler {
+    type Result = Result<u64, ScheduleError>;
+
+    fn handle(&mut self, msg: Schedule, ctx: &mut Self::Context) -> Self::Result {
+        let Schedule {
+            room_name,
+            client_id,
+            owner,
+            author,
+            content,
+            delay,
+        } = msg;
+
+        let waiting = self
+            .pending
+            .values()
+            .filter(|(scheduled, _)| scheduled.owner == owner)
+            .count();
+        if waiting >= MAX_PER_OWNER {
+            return Err(ScheduleError::TooMany);
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+
+        let handle = ctx.run_later(delay, move |act, _ctx| {
+            if let Some((scheduled, _)) = act.pending.remove(&id) {
+                debug!("Scheduler - posting {} to {}", id, &scheduled.room_name);
+
+                let content = format!("{}: {}", scheduled.author, scheduled.content);
+                WsChatServer::from_registry().do_send(SendMessage(
+                    scheduled.room_name,
+                    scheduled.client_id,
+                    content,
+                ));
+            }
+        });
+
+        let scheduled = Scheduled {
+            id,
+            room_name,
+            owner,
+            author,
+            content,
+            due: Instant::now() + delay,
+            client_id,
+        };
+        self.pending.insert(id, (scheduled, handle));
+
+        Ok(id)
+    }
+}
+
+impl Handler<ListScheduled> for Scheduler {
+    type Result = MessageResult<ListScheduled>;
+
+    fn handle(&mut self, msg: ListScheduled, _ctx: &mut Self::Context) -> Self::Result {
+        let ListScheduled(owner) = msg;
+
+        let mut scheduled: Vec<Scheduled> = self
+            .pending
+            .values()
+            .map(|(scheduled, _)| scheduled)
+            .filter(|scheduled| scheduled.owner == owner)
+            .cloned()
+            .collect();
+        scheduled.sort_by_key(|scheduled| scheduled.due);
+
+        MessageResult(scheduled)
+    }
+}
+
+impl Handler<Unschedule> for Scheduler {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Unschedule, ctx: &mut Self::Context) -> bool {
+        let Unschedule { owner, id } = msg;
+
+        match self.pending.get(&id) {
+            Some((scheduled, _)) if scheduled.owner == owner => {}
+            _ => return false,
+        }
+
+        if let Some((_, handle)) = self.pending.remove(&id) {
+            ctx.cancel_future(handle);
+        }
+        true
+    }
+}
+
+impl SystemService for Scheduler {}
+impl Supervised for Scheduler {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_delay() {
+        assert_eq!(parse_delay("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_delay("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_delay("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert!(parse_delay("0m").is_err());
+        assert!(parse_delay("8d").is_err());
+        assert!(parse_delay("15").is_err());
+        assert!(parse_delay("m").is_err());
+        assert!(parse_delay("").is_err());
+
+        assert_eq!(format_delay(Duration::from_secs(9)), "9s");
+        assert_eq!(format_delay(Duration::from_secs(14 * 60 + 32)), "14m 32s");
+        assert_eq!(format_delay(Duration::from_secs(3900)), "1h 05m");
+    }
+}
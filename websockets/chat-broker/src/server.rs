@@ -1,25 +1,868 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_broker::BrokerSubscribe;
-use log::{debug, warn};
+use actix_broker::{BrokerIssue, BrokerSubscribe};
+use futures::channel::oneshot;
+use futures::future::join_all;
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 
+use crate::accounts::{hash_token, Accounts};
+use crate::chaos::CHAOS;
+use crate::cluster::{
+    AccessAction, BridgeIn, BridgeOut, ClientRef, CodeAction, DedupeCache, Envelope,
+    Gossip, HandAction, HashRing, MembersChanged, ModAction, Payload, QuestionAction,
+    RoomAction, StreamAction, NODE_ID,
+};
+use crate::config::config;
+use crate::drafts::Drafts;
+use crate::frames::{mention_frame, message_frame, system_frame, Ephemeral};
+use crate::hours::{utc_minute_of_day, OpeningHours};
+use crate::journal::{EventKind, Journal};
+use crate::lanes::{Lane, Outbox, EPHEMERAL_TICK};
+use crate::load::{LoadMonitor, LoadReport, PROBE_INTERVAL};
+use crate::membership::Membership;
 use crate::message::{
-    ChatMessage, JoinRoom, LeaveRoom, ListClients, ListRooms, SendMessage,
+    AddAlias, Announce, ArchiveRoom, AutoMute, BreakoutOpened, ChatMessage, CheckUpload,
+    CrossPost, DeleteMessage, DeleteRoom, DetachSession, DigestRooms, EditMessage,
+    ForgetSession, GetGauges, GetJoinCode, GetLoad, GetRoomSettings, IsRoomMember,
+    JoinRoom, LeaveRoom, ListCanned, ListDirectory, ListPresence, ListQuestions,
+    ListRooms, LoadProbe, ManageAccess, ManageCanned, ManageHand, ManageJoinCode,
+    ManageQuestion, ManageStream, MembershipEvent, ModerateRoom, NameClaimed,
+    NotifyAccount, PostDigest, Posted, PrivateMessage, QueryPresence, React,
+    ReattachSession, RecordEvent, RegisterName, RemovedFromRoom, Reply, ResolveJoinCode,
+    RoomSize, SendAttachment, SendEphemeral, SendEventLog, SendForwarded, SendMessage,
+    SetOpeningHours, SetTeamRooms, ShowEventLog, Signal, StoreSession,
+    SubscribeMembership, SyncDraft, UnregisterName, UpdateRoomSettings,
 };
+use crate::metrics::{self, ServerGauges};
+use crate::migration::Migrations;
+use crate::scheduler::format_delay;
+use crate::session::unix_millis;
+use crate::settings::{language_matches, RoomFlag, RoomSettings, Visibility};
+use crate::teams::TeamRoom;
+use crate::trust::Capability;
+
+/// How often rooms with opening hours are opened or closed
+const OPENING_HOURS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often the local client names are announced to the other nodes
+const NAMES_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Names announced by another node are forgotten after this long without a
+/// refresh, e.g. when that node died
+const REMOTE_NAMES_TTL: Duration = Duration::from_secs(30);
+
+/// How often memberships kept for disconnected sessions are checked for
+/// having outlived the config's `resume_grace_secs`
+const DETACHED_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long sessions have to tell their presence for `ListPresence`
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Canned responses a room may have, so listing them stays short
+const MAX_CANNED_PER_ROOM: usize = 50;
+
+/// Different emoji a message may be reacted to with, so its frames stay short
+const MAX_REACTIONS: usize = 20;
+
+/// Rooms listed per page, see `ListRooms`
+pub const ROOM_PAGE_SIZE: usize = 50;
 
 type Client = Recipient<ChatMessage>;
-type Room = HashMap<usize, Client>;
+
+fn local_client(client_id: usize) -> ClientRef {
+    (NODE_ID.clone(), client_id)
+}
+
+#[derive(Debug)]
+pub enum RoomError {
+    NotFound,
+    NotOwner,
+    NameTaken,
+    Archived,
+    /// outside the room's opening hours, carries the next opening time
+    Closed(String),
+    UnknownUser(String),
+    NoSuchStream,
+    NotHost,
+    /// carries the overflow room to join instead, if the room has one
+    Full(Option<String>),
+    NoAttachments,
+    /// over the room's `max_upload_size`, which it carries
+    UploadTooLarge(usize),
+    /// not one of the room's `upload_types`, carries the type
+    UploadType(String),
+    /// slow mode is on, carries the seconds left to wait
+    SlowMode(u64),
+    NotSpeaker,
+    NoRaisedHand(String),
+    NotQa,
+    NoSuchQuestion(u64),
+    Answered(u64),
+    AlreadyVoted(u64),
+    /// the room doesn't exist and the client may not create it
+    CreateNotAllowed,
+    /// `/create` of a room that already exists
+    Exists,
+    /// the room belongs to a team the client isn't logged in as a member of
+    TeamOnly(String),
+    /// another client goes by the name
+    NicknameTaken(String),
+    /// renaming to or from a name banned or muted in the room it carries
+    NameRestricted(String),
+    Banned,
+    /// carries the seconds left
+    Muted(u64),
+    PasswordRequired,
+    WrongPassword,
+    InviteOnly,
+    /// `/invite` to a room anyone may join
+    NotInviteOnly,
+    NoSuchCanned(String),
+    TooManyCanned,
+    /// `/breakout` from a breakout room
+    NestedBreakout,
+    /// `/edit` or `/delete` of a message the room doesn't know of, or can't
+    /// tell the author of any more
+    NoSuchMessage(u64),
+    /// `/edit` or `/delete` of someone else's message
+    NotAuthor,
+    TooManyReactions,
+    /// the home node of a room didn't confirm a cross-post in time
+    Unconfirmed,
+    /// marking a room read the account doesn't belong to
+    NotMember,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NotFound => write!(f, "room not found"),
+            RoomError::Exists => write!(f, "room already exists, /join it instead"),
+            RoomError::TeamOnly(team) => {
+                write!(f, "only members of team {} may join this room", team)
+            }
+            RoomError::NotOwner => write!(f, "only the room owner can do that"),
+            RoomError::Banned => write!(f, "you are banned from this room"),
+            RoomError::PasswordRequired => {
+                write!(f, "this room needs a password, use /join room password")
+            }
+            RoomError::WrongPassword => write!(f, "wrong password for this room"),
+            RoomError::NoSuchCanned(name) => write!(f, "no canned response {}", name),
+            RoomError::NoSuchMessage(id) => write!(f, "no message {} to change", id),
+            RoomError::NotAuthor => write!(f, "only its author can change a message"),
+            RoomError::NotMember => write!(f, "you aren't a member of this room"),
+            RoomError::TooManyReactions => write!(
+                f,
+                "a message can have at most {} different reactions",
+                MAX_REACTIONS
+            ),
+            RoomError::Unconfirmed => {
+                write!(f, "a room's node didn't answer in time, nothing was posted")
+            }
+            RoomError::TooManyCanned => write!(
+                f,
+                "a room can have at most {} canned responses",
+                MAX_CANNED_PER_ROOM
+            ),
+            RoomError::NestedBreakout => {
+                write!(
+                    f,
+                    "this is a breakout room already, /breakout from its source"
+                )
+            }
+            RoomError::InviteOnly => {
+                write!(f, "this room is invite only, ask its owner for an invite")
+            }
+            RoomError::NotInviteOnly => {
+                write!(f, "anyone may join this room, use /access invite first")
+            }
+            RoomError::Muted(secs) => write!(
+                f,
+                "you are muted in this room for {}",
+                format_delay(Duration::from_secs(*secs))
+            ),
+            RoomError::NicknameTaken(name) => {
+                write!(f, "{} is already taken by another client", name)
+            }
+            RoomError::NameRestricted(room_name) => write!(
+                f,
+                "names banned or muted in {} can't be taken or given up there",
+                room_name
+            ),
+            RoomError::NameTaken => write!(f, "name is already used by another room"),
+            RoomError::Archived => write!(f, "room is archived, it is read-only"),
+            RoomError::Closed(opens) => {
+                write!(f, "room is closed, it opens at {}", opens)
+            }
+            RoomError::UnknownUser(name) => write!(f, "unknown user: {}", name),
+            RoomError::NoSuchStream => write!(f, "no such stream in this room"),
+            RoomError::NotHost => {
+                write!(f, "only the stream's host or the room owner can do that")
+            }
+            RoomError::Full(None) => write!(f, "room is full"),
+            RoomError::Full(Some(overflow)) => {
+                write!(f, "room is full, join {} instead", overflow)
+            }
+            RoomError::NoAttachments => {
+                write!(f, "attachments are turned off in this room")
+            }
+            RoomError::UploadTooLarge(max) => {
+                write!(f, "attachments in this room can be at most {} bytes", max)
+            }
+            RoomError::UploadType(mime) => {
+                write!(f, "{} attachments aren't allowed in this room", mime)
+            }
+            RoomError::SlowMode(secs) => {
+                write!(f, "slow mode is on, wait {} more seconds", secs)
+            }
+            RoomError::NotSpeaker => write!(
+                f,
+                "room is moderated, use /raise-hand and wait to be called on"
+            ),
+            RoomError::NoRaisedHand(name) => {
+                write!(f, "{} hasn't raised their hand", name)
+            }
+            RoomError::NotQa => {
+                write!(f, "room isn't in Q&A mode, see /settings qa on")
+            }
+            RoomError::NoSuchQuestion(id) => {
+                write!(f, "no question {} in this room", id)
+            }
+            RoomError::Answered(id) => write!(f, "question {} was already answered", id),
+            RoomError::AlreadyVoted(id) => {
+                write!(f, "you already upvoted question {}", id)
+            }
+            RoomError::CreateNotAllowed => {
+                write!(f, "no such room, and {}", Capability::CreateRoom.refusal())
+            }
+        }
+    }
+}
+
+/// Clients are the ones connected to this node. The other fields are only
+/// authoritative on the room's home node, see `WsChatServer::home_node`.
+#[derive(Debug)]
+struct Room {
+    clients: HashMap<usize, Client>,
+    /// whoever created the room
+    owner: ClientRef,
+    /// archived rooms are frozen: members stay but every send is rejected
+    archived: bool,
+    /// daily window in which messages are accepted, always open if unset
+    hours: Option<OpeningHours>,
+    /// kept up to date with `hours` by the opening hours scheduler
+    closed: bool,
+    /// live streams by id
+    streams: HashMap<String, Stream>,
+    /// a copy is kept on every node with clients in the room, for the
+    /// settings enforced there
+    settings: RoomSettings,
+    /// when each member last posted, while slow mode is on
+    last_post: HashMap<ClientRef, Instant>,
+    join_code: Option<String>,
+    /// members waiting to be called on in a moderated room, first come first
+    hands: Vec<(ClientRef, String)>,
+    /// members called on, and until when they may post
+    speakers: HashMap<ClientRef, Instant>,
+    /// asked while in Q&A mode, by id. A copy is kept on every node with
+    /// clients in the room.
+    questions: Vec<Question>,
+    /// snippets for answering recurring questions, by name. A copy is kept on
+    /// every node with clients in the room, too.
+    canned: BTreeMap<String, String>,
+    /// bumped whenever `clients` changes
+    generation: u64,
+    /// told about every change of `clients`, by the subscribed client's id,
+    /// see `SubscribeMembership`
+    watchers: HashMap<usize, Recipient<MembershipEvent>>,
+    /// the local clients' sessions, to tell them when they are kicked
+    sessions: HashMap<usize, MemberSession>,
+    /// names that may not post until then, with the account each was signed
+    /// in to
+    muted: HashMap<String, (Instant, Option<String>)>,
+    /// (client, name) having posted each of the last `history_depth` chat
+    /// messages, by id, for `/edit` and `/delete`. Only known to the home
+    /// node.
+    authors: BTreeMap<u64, (ClientRef, String)>,
+    /// who reacted to the messages in `authors` with what, by message id.
+    /// Only known to the home node.
+    reactions: HashMap<u64, BTreeMap<String, HashSet<ClientRef>>>,
+    /// the other rooms each cross-post in `authors` went to, so an edit or
+    /// deletion reaches every copy
+    copies: HashMap<u64, Vec<String>>,
+}
+
+/// What a room tells a local client's session besides chat
+#[derive(Debug)]
+struct MemberSession {
+    removed: Recipient<RemovedFromRoom>,
+    breakout: Recipient<BreakoutOpened>,
+    presence: Recipient<QueryPresence>,
+}
+
+/// A named client connected to this node
+#[derive(Debug)]
+struct NamedClient {
+    client: Client,
+    displaced: Recipient<NameClaimed>,
+    /// the account the client is signed in to, if any
+    account: Option<String>,
+}
+
+/// The memberships of a session that lost its client, see `DetachSession`
+#[derive(Debug)]
+struct Detached {
+    client_name: String,
+    memberships: Vec<(String, usize)>,
+    since: Instant,
+}
+
+/// A private room opened from another one with `/breakout`, kept on every
+/// node from the events of both rooms
+#[derive(Debug)]
+struct Breakout {
+    source: String,
+    /// names in the room, it is closed once the last one leaves
+    present: BTreeSet<String>,
+}
+
+/// The breakout room the owner of `source` opens for `with`
+fn breakout_name(source: &str, with: &str) -> String {
+    format!("{}-breakout-{}", source, with)
+}
+
+/// A room's clients on this node as of `generation`
+#[derive(Clone, Debug, Serialize)]
+pub struct Members {
+    pub generation: u64,
+    pub client_ids: Vec<usize>,
+}
+
+/// A client as its session sees it
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientPresence {
+    pub name: String,
+    /// `None` while the client is active, how long it hasn't done anything
+    /// once it counts as idle
+    pub idle: Option<Duration>,
+}
+
+/// `Members` with each client's presence, `None` for sessions that didn't
+/// answer in time
+#[derive(Clone, Debug)]
+pub struct RoomPresence {
+    pub generation: u64,
+    pub clients: Vec<(usize, Option<ClientPresence>)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum MemberChange {
+    Joined { client_id: usize },
+    Left { client_id: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ListedRoom {
+    /// teamless rooms come first
+    pub team: Option<String>,
+    pub name: String,
+    /// messages since the account listing the rooms last read this one, if
+    /// it marked it read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unread: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// A page of the listed rooms, grouped by team and in name order within
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomPage {
+    pub rooms: Vec<ListedRoom>,
+    /// continuation token for the next page, `None` on the last one
+    pub next: Option<String>,
+}
+
+/// A room of the public directory, see `ListDirectory`
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectoryRoom {
+    pub name: String,
+    pub topic: Option<String>,
+    pub language: Option<String>,
+    /// clients in the room on this node
+    pub members: usize,
+    pub access: AccessPolicy,
+}
+
+/// A page of the public directory, in name order
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectoryPage {
+    pub rooms: Vec<DirectoryRoom>,
+    /// continuation token for the next page, `None` on the last one
+    pub next: Option<String>,
+}
+
+/// Continuation token resuming a listing after `room`
+fn page_token(room: &ListedRoom) -> String {
+    let key = format!(
+        "{}\n{}",
+        room.team.as_deref().unwrap_or_default(),
+        room.name
+    );
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+/// The room a continuation token resumes after
+pub fn parse_page_token(token: &str) -> Option<ListedRoom> {
+    let key = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+    let key = String::from_utf8(key).ok()?;
+    let (team, name) = key.split_once('\n')?;
+
+    Some(ListedRoom {
+        team: Some(team.to_owned()).filter(|team| !team.is_empty()),
+        name: name.to_owned(),
+        unread: None,
+        language: None,
+    })
+}
+
+/// Who may join a room, see `WsChatServer::access`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RoomAccess {
+    Open,
+    /// carries the password's hash, see `password_hash`
+    Password(String),
+    /// the names invited, which cover the accounts of that name too
+    InviteOnly(BTreeSet<String>),
+}
+
+/// `RoomAccess` without its secrets, for room events
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessPolicy {
+    Open,
+    Password,
+    InviteOnly,
+}
+
+impl RoomAccess {
+    pub fn policy(&self) -> AccessPolicy {
+        match self {
+            RoomAccess::Open => AccessPolicy::Open,
+            RoomAccess::Password(_) => AccessPolicy::Password,
+            RoomAccess::InviteOnly(_) => AccessPolicy::InviteOnly,
+        }
+    }
+}
+
+/// Room passwords only travel and are kept hashed, salted with the room name
+pub fn password_hash(room_name: &str, password: &str) -> String {
+    hash_token(&format!("{}\n{}", room_name, password))
+}
+
+/// Question asked in a room in Q&A mode
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Question {
+    /// from 1, in the order the questions were asked
+    pub id: u64,
+    pub from: String,
+    pub text: String,
+    pub votes: usize,
+    pub answered: bool,
+    /// only known to the home node
+    #[serde(skip)]
+    voters: HashSet<ClientRef>,
+}
+
+/// Open questions first, the most upvoted and then the oldest at the top
+fn rank_questions(questions: &[Question]) -> Vec<Question> {
+    let mut ranked = questions.to_vec();
+    ranked.sort_by_key(|question| {
+        (
+            question.answered,
+            std::cmp::Reverse(question.votes),
+            question.id,
+        )
+    });
+    ranked
+}
+
+#[derive(Debug)]
+struct Stream {
+    host: ClientRef,
+    viewers: HashSet<ClientRef>,
+}
+
+/// How long a member called on in a moderated room may post
+const SPEAKER_TIME: Duration = Duration::from_secs(5 * 60);
+
+/// Letters and digits that can't be mistaken for each other
+const JOIN_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const JOIN_CODE_LEN: usize = 6;
+
+/// Short, easily typed code such as `ABXK42`, from the OS seeded CSPRNG
+fn new_join_code() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..JOIN_CODE_LEN)
+        .filter_map(|_| JOIN_CODE_ALPHABET.choose(&mut rng))
+        .map(|&c| c as char)
+        .collect()
+}
+
+/// Why a room with these settings doesn't take an attachment or voice note
+/// of `size` bytes of `mime`
+fn upload_rejection(
+    settings: &RoomSettings,
+    mime: &str,
+    size: usize,
+) -> Option<RoomError> {
+    if settings.has_flag(RoomFlag::NoAttachments) {
+        return Some(RoomError::NoAttachments);
+    }
+    if let Some(max) = settings.max_upload_size.filter(|max| size > *max) {
+        return Some(RoomError::UploadTooLarge(max));
+    }
+    if !settings.takes_upload_type(mime) {
+        return Some(RoomError::UploadType(mime.to_owned()));
+    }
+
+    None
+}
+
+/// Names referenced as `@name` in a chat message
+fn mentioned_names(msg: &str) -> Vec<String> {
+    let mut names: Vec<String> = msg
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| {
+            name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        })
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    names.sort();
+    names.dedup();
+    names
+}
 
 #[derive(Default)]
 pub struct WsChatServer {
     rooms: HashMap<String, Room>,
+    /// alternative names resolving to a room: alias -> room name
+    aliases: HashMap<String, String>,
+    /// random codes for joining a room without knowing its name: code -> room
+    /// name, at most one per room
+    join_codes: HashMap<String, String>,
+    /// messages already relayed in from other nodes
+    bridged: DedupeCache,
+    /// named clients connected to this node
+    names: HashMap<String, NamedClient>,
+    /// named clients on other nodes: name -> (node id, last announced,
+    /// account signed in to)
+    remote_names: HashMap<String, (String, Instant, Option<String>)>,
+    /// live nodes as last reported by `Membership`
+    members: Vec<String>,
+    /// places rooms on `members`
+    ring: HashRing,
+    /// every room this node has seen an event of, including rooms with no
+    /// members here, with their settings as of the last change
+    known_rooms: HashMap<String, RoomSettings>,
+    /// rooms claimed by a team, only its members may join them
+    team_rooms: HashMap<String, TeamRoom>,
+    /// names banned from each room, with the account each was signed in to,
+    /// learned from the room's `Banned` events
+    bans: HashMap<String, HashMap<String, Option<String>>>,
+    /// accounts that joined each room on this node, which its `members`
+    /// history is served to
+    joined_accounts: HashMap<String, HashSet<String>>,
+    /// who may join the rooms that aren't open to everyone, kept on every
+    /// node like `join_codes`
+    access: HashMap<String, RoomAccess>,
+    /// open breakout rooms by name
+    breakouts: HashMap<String, Breakout>,
+    /// the rooms full rooms flagged `overflow` sent clients to, with the room
+    /// they overflow
+    overflows: HashMap<String, String>,
+    /// id of the last chat message accepted here, see `next_message_id`
+    last_message_id: u64,
+    /// cross-posts waiting for the home nodes of their rooms to confirm the
+    /// sender owns them, by post and room
+    owner_checks: HashMap<(u64, String), oneshot::Sender<bool>>,
+    /// memberships held for disconnected sessions, by resume token
+    detached: HashMap<String, Detached>,
+    load: LoadMonitor,
+    outbox: Outbox,
+}
+
+impl Room {
+    /// Whether the room has as many clients as its `capacity`, or the
+    /// config's `max_room_size`, allows
+    fn is_full(&self) -> bool {
+        self.settings
+            .capacity
+            .or(config().max_room_size)
+            .is_some_and(|capacity| self.clients.len() >= capacity)
+    }
+
+    fn new(owner: ClientRef) -> Self {
+        Room {
+            clients: HashMap::new(),
+            owner,
+            archived: false,
+            hours: None,
+            closed: false,
+            streams: HashMap::new(),
+            settings: RoomSettings::default(),
+            last_post: HashMap::new(),
+            join_code: None,
+            hands: Vec::new(),
+            speakers: HashMap::new(),
+            questions: Vec::new(),
+            canned: BTreeMap::new(),
+            generation: 0,
+            watchers: HashMap::new(),
+            sessions: HashMap::new(),
+            muted: HashMap::new(),
+            authors: BTreeMap::new(),
+            reactions: HashMap::new(),
+            copies: HashMap::new(),
+        }
+    }
+
+    fn members(&self) -> Members {
+        let mut client_ids: Vec<usize> = self.clients.keys().copied().collect();
+        client_ids.sort_unstable();
+
+        Members {
+            generation: self.generation,
+            client_ids,
+        }
+    }
+
+    /// Adds or removes a client, moving on to the next generation
+    fn change_members(
+        &mut self,
+        room_name: &str,
+        change: MemberChange,
+        client: Option<Client>,
+    ) {
+        match (&change, client) {
+            (MemberChange::Joined { client_id }, Some(client)) => {
+                self.clients.insert(*client_id, client);
+            }
+            (MemberChange::Left { client_id }, _) => {
+                if self.clients.remove(client_id).is_none() {
+                    return;
+                }
+                self.watchers.remove(client_id);
+                self.sessions.remove(client_id);
+            }
+            _ => return,
+        }
+        self.generation += 1;
+
+        let event = MembershipEvent {
+            room_name: room_name.to_owned(),
+            generation: self.generation,
+            change,
+        };
+        // gone once their session stopped
+        self.watchers
+            .retain(|_, watcher| watcher.do_send(event.clone()).is_ok());
+    }
+
+    fn question_mut(&mut self, id: u64) -> Option<&mut Question> {
+        self.questions.iter_mut().find(|question| question.id == id)
+    }
+
+    /// Why `from` can't post `event` to the room right now, if it can't
+    fn rejection(&self, from: &ClientRef, event: &EventKind) -> Option<RoomError> {
+        if self.archived {
+            return Some(RoomError::Archived);
+        }
+
+        if let Some(left) = event.author().and_then(|author| self.mute_left(author)) {
+            return Some(RoomError::Muted(left.as_secs().max(1)));
+        }
+
+        if self.closed {
+            return self
+                .hours
+                .map(|hours| RoomError::Closed(hours.next_opening()));
+        }
+
+        if let EventKind::Attachment { mime, size, .. }
+        | EventKind::VoiceNote { mime, size, .. } = event
+        {
+            if let Some(err) = upload_rejection(&self.settings, mime, *size) {
+                return Some(err);
+            }
+        }
+
+        let speaking = self
+            .speakers
+            .get(from)
+            .is_some_and(|until| *until > Instant::now());
+        if self.settings.has_flag(RoomFlag::Moderated)
+            && *from != self.owner
+            && !speaking
+        {
+            return Some(RoomError::NotSpeaker);
+        }
+
+        let slow_mode = Duration::from_secs(self.settings.slow_mode?);
+        let since = self.last_post.get(from)?.elapsed();
+        if since < slow_mode {
+            // rounded up, so waiting that long is always enough
+            let left = slow_mode - since;
+            return Some(RoomError::SlowMode(
+                left.as_secs() + u64::from(left.subsec_nanos() > 0),
+            ));
+        }
+
+        None
+    }
+
+    /// Remembers who posted `id`, forgetting the oldest message past
+    /// `history_depth`
+    fn remember_author(&mut self, id: u64, by: ClientRef, name: String) {
+        self.authors.insert(id, (by, name));
+        while self.authors.len() > config().history_depth {
+            if let Some((id, _)) = self.authors.pop_first() {
+                self.reactions.remove(&id);
+                self.copies.remove(&id);
+            }
+        }
+    }
+
+    /// Forgets a deleted message, its reactions and where its copies went
+    fn forget_message(&mut self, id: u64) {
+        self.authors.remove(&id);
+        self.reactions.remove(&id);
+        self.copies.remove(&id);
+    }
+
+    /// The name `id` was posted under, if `by` posted it. The admin API may
+    /// change any message.
+    fn author_of(&self, id: u64, by: Option<&ClientRef>) -> Result<String, RoomError> {
+        let (author, name) =
+            self.authors.get(&id).ok_or(RoomError::NoSuchMessage(id))?;
+
+        match by {
+            Some(by) if by != author => Err(RoomError::NotAuthor),
+            _ => Ok(name.clone()),
+        }
+    }
+
+    /// Why an edit can't be made, for the reasons a new message couldn't be
+    /// posted. Slow mode doesn't hold edits back, nor does a moderated room
+    /// once the author was allowed to post.
+    fn edit_rejection(&self, author: &str) -> Option<RoomError> {
+        if self.archived {
+            return Some(RoomError::Archived);
+        }
+
+        let left = self.mute_left(author)?;
+        Some(RoomError::Muted(left.as_secs().max(1)))
+    }
+
+    /// How much longer `name` is muted, by name or as the account a muted
+    /// name was signed in to
+    fn mute_left(&self, name: &str) -> Option<Duration> {
+        let now = Instant::now();
+
+        self.muted
+            .iter()
+            .filter(|(muted, (_, account))| {
+                *muted == name || account.as_deref() == Some(name)
+            })
+            .map(|(_, (until, _))| until.saturating_duration_since(now))
+            .filter(|left| !left.is_zero())
+            .max()
+    }
+
+    fn stream_event(
+        &mut self,
+        by: ClientRef,
+        action: StreamAction,
+    ) -> Result<EventKind, RoomError> {
+        Ok(match action {
+            StreamAction::Start { host, title } => {
+                let stream_id = hex::encode(rand::random::<[u8; 4]>());
+
+                self.streams.insert(
+                    stream_id.clone(),
+                    Stream {
+                        host: by,
+                        viewers: HashSet::new(),
+                    },
+                );
+
+                EventKind::StreamStarted {
+                    stream_id,
+                    host,
+                    title,
+                }
+            }
+
+            StreamAction::Join(stream_id) | StreamAction::Leave(stream_id)
+                if !self.streams.contains_key(&stream_id) =>
+            {
+                return Err(RoomError::NoSuchStream);
+            }
+
+            StreamAction::Join(stream_id) => {
+                let stream = self.streams.get_mut(&stream_id).unwrap();
+                stream.viewers.insert(by);
+
+                EventKind::StreamViewers {
+                    viewers: stream.viewers.len(),
+                    stream_id,
+                }
+            }
+
+            StreamAction::Leave(stream_id) => {
+                let stream = self.streams.get_mut(&stream_id).unwrap();
+                stream.viewers.remove(&by);
+
+                EventKind::StreamViewers {
+                    viewers: stream.viewers.len(),
+                    stream_id,
+                }
+            }
+
+            StreamAction::End(stream_id) => {
+                let stream = self
+                    .streams
+                    .get(&stream_id)
+                    .ok_or(RoomError::NoSuchStream)?;
+
+                if stream.host != by && self.owner != by {
+                    return Err(RoomError::NotHost);
+                }
+
+                self.streams.remove(&stream_id);
+                EventKind::StreamEnded { stream_id }
+            }
+        })
+    }
 }
 
 impl WsChatServer {
-    fn get_room(&mut self, room_name: &str) -> Option<&mut Room> {
-        let room = self.rooms.get_mut(room_name)?;
-        Some(room)
+    /// The room name an alias points at, or the name itself
+    fn resolve_room_name(&self, room_name: &str) -> String {
+        self.aliases
+            .get(room_name)
+            .cloned()
+            .unwrap_or_else(|| room_name.to_owned())
     }
 
     fn add_client_to_room(
@@ -34,7 +877,7 @@ impl WsChatServer {
             debug!("add_client_to_room() - room found, {:?}", &room);
 
             loop {
-                if room.contains_key(&client_id) {
+                if room.clients.contains_key(&client_id) {
                     warn!(
                         "add_client_to_room() - client id already here, creating new client id: {}",
                         &client_id
@@ -49,119 +892,2783 @@ impl WsChatServer {
                 "add_client_to_room() - adding client to existing room, {}",
                 &client_id
             );
-            room.insert(client_id, client);
+            room.change_members(
+                room_name,
+                MemberChange::Joined { client_id },
+                Some(client),
+            );
             return client_id;
         }
 
-        // Create a new room for the first client
-        let mut room: Room = HashMap::new();
+        // Create a new room for the first client, who becomes its owner
+        let mut room = Room::new(local_client(client_id));
         debug!(
             "add_client_to_room() - adding client to new room, {}",
             &client_id
         );
 
-        room.insert(client_id, client);
+        room.change_members(room_name, MemberChange::Joined { client_id }, Some(client));
         self.rooms.insert(room_name.to_owned(), room);
 
         client_id
     }
 
-    fn send_chat_message(
-        &mut self,
-        room_name: &str,
-        msg: &str,
-        _src: usize,
-    ) -> Option<()> {
-        let room = self.get_room(room_name)?;
-
-        for (_client_id, client) in room.iter() {
-            client.do_send(ChatMessage(msg.to_owned())).ok()?;
+    /// Sends a message to a single client of a room, e.g. an error reply to
+    /// messages that came in through the broker
+    fn send_to_client(&self, room_name: &str, client_id: usize, msg: &str) {
+        if let Some(client) = self
+            .rooms
+            .get(room_name)
+            .and_then(|room| room.clients.get(&client_id))
+        {
+            client.do_send(ChatMessage(msg.to_owned())).ok();
         }
+    }
 
-        Some(())
+    /// Has the journal send the room's last events to a local client
+    fn send_event_log(&self, room_name: &str, client_id: usize, count: usize) {
+        if let Some(client) = self
+            .rooms
+            .get(room_name)
+            .and_then(|room| room.clients.get(&client_id))
+        {
+            Journal::from_registry().do_send(SendEventLog {
+                room_name: room_name.to_owned(),
+                count,
+                to: client.clone(),
+            });
+        }
     }
-}
 
-impl Actor for WsChatServer {
-    type Context = Context<Self>;
+    /// The node holding the state of a room. Rooms are spread over the live
+    /// nodes by consistent hashing, so each room's settings and ownership live
+    /// on exactly one node; without peers that is always this node.
+    fn home_node(&self, room_name: &str) -> String {
+        self.ring.node_for(room_name).unwrap_or(&NODE_ID).to_owned()
+    }
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        self.subscribe_system_async::<LeaveRoom>(ctx);
-        self.subscribe_system_async::<SendMessage>(ctx);
+    /// The home node of a room, if that isn't this node
+    fn remote_home(&self, room_name: &str) -> Option<String> {
+        Some(self.home_node(room_name)).filter(|node| *node != *NODE_ID)
     }
-}
 
-impl Handler<JoinRoom> for WsChatServer {
-    type Result = MessageResult<JoinRoom>;
+    /// Sends a reply to one client, wherever in the cluster it is connected
+    fn reply(&mut self, to: ClientRef, room_name: &str, reply: Reply) {
+        let (to_node, client_id) = to;
+        let text = reply.to_json();
 
-    fn handle(&mut self, msg: JoinRoom, _ctx: &mut Self::Context) -> Self::Result {
-        let JoinRoom(room_name, client_name, client) = msg;
-        debug!(
-            "JoinRoom::handle() - room_name: {}, client_name: {}",
-            &room_name, &client_name
-        );
+        if to_node == *NODE_ID {
+            self.send_to_client(room_name, client_id, &text);
+        } else {
+            self.publish(Payload::Reply {
+                to_node,
+                room_name: room_name.to_owned(),
+                client_id,
+                text,
+            });
+        }
+    }
 
-        let id = self.add_client_to_room(&room_name, None, client);
-        let join_msg = format!("{} joined {}", client_name, room_name);
+    /// Sends a room's topic, welcome message and rules to a client that just
+    /// joined
+    fn greet(&mut self, room_name: &str, client: ClientRef) {
+        let settings = match self.rooms.get(room_name) {
+            Some(room) => room.settings.clone(),
+            None => return,
+        };
 
-        self.send_chat_message(&room_name, &join_msg, id);
-        MessageResult(id)
-    }
-}
+        if let Some(topic) = &settings.topic {
+            let mut fields = serde_json::Map::new();
+            fields.insert("topic".to_owned(), topic.as_str().into());
 
-impl Handler<LeaveRoom> for WsChatServer {
-    type Result = ();
+            let text = format!("topic: {}", topic);
+            let frame = system_frame("topic", Some(room_name), &text, fields);
+            self.reply(client.clone(), room_name, Reply::Frame(frame));
+        }
 
-    fn handle(&mut self, msg: LeaveRoom, _ctx: &mut Self::Context) {
-        let LeaveRoom(room_name, client_id) = msg;
+        if let Some(welcome) = &settings.welcome {
+            let frame =
+                system_frame("welcome", Some(room_name), welcome, Default::default());
+            self.reply(client.clone(), room_name, Reply::Frame(frame));
+        }
 
-        if let Some(room) = self.rooms.get_mut(&room_name) {
-            debug!(
-                "LeaveRoom::handle() - removing {} from {}",
-                &client_id, &room_name
-            );
-            room.remove(&client_id);
+        if let Some(rules) = &settings.rules {
+            let text = format!("rules: {}", rules);
+            let frame =
+                system_frame("rules", Some(room_name), &text, Default::default());
+            self.reply(client, room_name, Reply::Frame(frame));
         }
     }
-}
 
-impl Handler<ListRooms> for WsChatServer {
-    type Result = MessageResult<ListRooms>;
+    /// Records an event in the journal of every node and shows it to the
+    /// room's members
+    fn broadcast(&mut self, room_name: &str, event: EventKind) {
+        let log = !self
+            .rooms
+            .get(room_name)
+            .is_some_and(|room| room.settings.has_flag(RoomFlag::NoLog));
 
-    fn handle(&mut self, _: ListRooms, _ctx: &mut Self::Context) -> Self::Result {
-        MessageResult(self.rooms.keys().cloned().collect())
+        self.deliver(room_name, event.clone(), log);
+        self.publish(Payload::Event {
+            room_name: room_name.to_owned(),
+            event,
+            log,
+        });
     }
-}
 
-impl Handler<SendMessage> for WsChatServer {
-    type Result = ();
+    /// The local half of `broadcast`, only journaling the event if `log`
+    fn deliver(&mut self, room_name: &str, event: EventKind, log: bool) {
+        if !self.known_rooms.contains_key(room_name) {
+            self.known_rooms
+                .insert(room_name.to_owned(), RoomSettings::default());
+        }
+        if let (EventKind::Settings { settings, .. }, Some(known)) =
+            (&event, self.known_rooms.get_mut(room_name))
+        {
+            *known = settings.clone();
+        }
 
-    fn handle(&mut self, msg: SendMessage, _ctx: &mut Self::Context) {
-        let SendMessage(room_name, id, msg) = msg;
-        self.send_chat_message(&room_name, &msg, id);
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            match &event {
+                EventKind::Settings { settings, .. } => room.settings = settings.clone(),
+                // hands are only kept on the home node, which only learns of
+                // leaving members by name
+                EventKind::Left { name } | EventKind::Kicked { name } => {
+                    room.hands.retain(|(_, raised)| raised != name)
+                }
+                EventKind::Canned {
+                    name,
+                    content: Some(content),
+                } => {
+                    room.canned.insert(name.clone(), content.clone());
+                }
+                EventKind::Canned {
+                    name,
+                    content: None,
+                } => {
+                    room.canned.remove(name);
+                }
+                EventKind::Muted { name, secs: 0, .. } => {
+                    room.muted.remove(name);
+                }
+                EventKind::Muted {
+                    name,
+                    secs,
+                    account,
+                } => {
+                    let until = Instant::now() + Duration::from_secs(*secs);
+                    room.muted.retain(|_, (until, _)| *until > Instant::now());
+                    room.muted.insert(name.clone(), (until, account.clone()));
+                }
+                // already known if the room was handed over meanwhile
+                EventKind::Question { id, from, text }
+                    if room.question_mut(*id).is_none() =>
+                {
+                    room.questions.push(Question {
+                        id: *id,
+                        from: from.clone(),
+                        text: text.clone(),
+                        votes: 0,
+                        answered: false,
+                        voters: HashSet::new(),
+                    });
+                }
+                EventKind::QuestionVotes { id, votes } => {
+                    if let Some(question) = room.question_mut(*id) {
+                        question.votes = *votes;
+                    }
+                }
+                EventKind::Answered { id } => {
+                    if let Some(question) = room.question_mut(*id) {
+                        question.answered = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match &event {
+            EventKind::Banned {
+                name,
+                banned: true,
+                account,
+            } => {
+                self.bans
+                    .entry(room_name.to_owned())
+                    .or_default()
+                    .insert(name.clone(), account.clone());
+                self.remove_client(room_name, name, true);
+            }
+            EventKind::Banned {
+                name,
+                banned: false,
+                ..
+            } => {
+                if let Some(names) = self.bans.get_mut(room_name) {
+                    names.remove(name);
+                }
+            }
+            EventKind::Kicked { name } => self.remove_client(room_name, name, false),
+            EventKind::Breakout { breakout, by, with } => {
+                let invited = BTreeSet::from([by.clone(), with.clone()]);
+                self.set_access(breakout, RoomAccess::InviteOnly(invited));
+                self.breakouts.insert(
+                    breakout.clone(),
+                    Breakout {
+                        source: room_name.to_owned(),
+                        present: BTreeSet::new(),
+                    },
+                );
+                self.open_breakout(room_name, breakout, by);
+            }
+            _ => {}
+        }
+
+        if let Some(breakout) = self.breakouts.get_mut(room_name) {
+            match &event {
+                EventKind::Joined { name } => {
+                    breakout.present.insert(name.clone());
+                }
+                EventKind::Left { name }
+                | EventKind::Kicked { name }
+                | EventKind::Banned {
+                    name, banned: true, ..
+                } => {
+                    breakout.present.remove(name);
+                    if breakout.present.is_empty() {
+                        self.close_breakout(room_name);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let text = match &event {
+            EventKind::Message {
+                content,
+                id,
+                posted,
+            } => Some(message_frame(content, *id, *posted)),
+            _ => event.text(room_name),
+        };
+        if let Some(text) = text {
+            self.send_chat_message(room_name, &text, event.lane());
+        }
+        if let EventKind::Message {
+            content,
+            id,
+            posted,
+        } = &event
+        {
+            self.send_mentions(room_name, content, *id, *posted);
+        }
+        // once the members were told
+        if matches!(event, EventKind::Deleted) {
+            self.delete_room(room_name);
+        }
+
+        // settings changes always are, so the journal learns of `no_log`
+        if log || matches!(event, EventKind::Settings { .. }) {
+            Journal::from_registry().do_send(RecordEvent(room_name.to_owned(), event));
+        }
     }
-}
 
-impl Handler<ListClients> for WsChatServer {
-    type Result = MessageResult<ListClients>;
+    /// Takes the local client called `name` out of the room and tells its
+    /// session, on every node as the event comes by
+    fn remove_client(&mut self, room_name: &str, name: &str, banned: bool) {
+        let (client, room) = match (
+            self.names.get(name).map(|named| &named.client),
+            self.rooms.get_mut(room_name),
+        ) {
+            (Some(client), Some(room)) => (client, room),
+            _ => return,
+        };
+
+        let ids: Vec<usize> = room
+            .clients
+            .iter()
+            .filter(|(_, member)| *member == client)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in ids {
+            if let Some(session) = room.sessions.get(&client_id) {
+                session
+                    .removed
+                    .do_send(RemovedFromRoom {
+                        room_name: room_name.to_owned(),
+                        banned,
+                    })
+                    .ok();
+            }
+            room.change_members(room_name, MemberChange::Left { client_id }, None);
+        }
+    }
 
-    fn handle(&mut self, msg: ListClients, _ctx: &mut Self::Context) -> Self::Result {
-        let ListClients(room_name) = msg;
+    /// Tells the session of `by` in `source`, if it is connected here, to
+    /// enter the breakout room it opened
+    fn open_breakout(&mut self, source: &str, breakout: &str, by: &str) {
+        let (client, room) = match (self.named(by), self.rooms.get(source)) {
+            (Some(client), Some(room)) => (client, room),
+            _ => return,
+        };
 
-        if let Some(room) = self.rooms.get(room_name.as_str()).cloned() {
-            let client_names: Vec<String> = room
-                .keys()
-                .map(|client_id| format!("{:?}", client_id))
-                .collect();
+        let sessions = room
+            .clients
+            .iter()
+            .filter(|(_, member)| *member == client)
+            .filter_map(|(client_id, _)| room.sessions.get(client_id));
+        for session in sessions {
+            session
+                .breakout
+                .do_send(BreakoutOpened {
+                    room_name: breakout.to_owned(),
+                    source: source.to_owned(),
+                })
+                .ok();
+        }
+    }
 
+    /// Forgets a breakout room everyone left, which every node does as the
+    /// last `Left` comes by
+    fn close_breakout(&mut self, room_name: &str) {
+        if let Some(breakout) = self.breakouts.remove(room_name) {
             debug!(
-                "ListClients::handle() - listing {} clients in room {}",
-                &client_names.len(),
-                &room_name
+                "close_breakout() - {} from {} is empty",
+                room_name, &breakout.source
             );
-            MessageResult(client_names)
+        }
+        self.forget_room(room_name);
+    }
+
+    /// Takes the local clients out of a deleted room and forgets it, which
+    /// every node does as `Deleted` comes by
+    fn delete_room(&mut self, room_name: &str) {
+        if let Some(room) = self.rooms.get(room_name) {
+            info!(
+                "delete_room() - {} deleted with {} local clients",
+                room_name,
+                room.clients.len()
+            );
+            for session in room.sessions.values() {
+                session
+                    .removed
+                    .do_send(RemovedFromRoom {
+                        room_name: room_name.to_owned(),
+                        banned: false,
+                    })
+                    .ok();
+            }
+        }
+        self.breakouts.remove(room_name);
+        self.forget_room(room_name);
+    }
+
+    /// The room to send clients to while `room_name` is full, the first of
+    /// `{room}-2`, `{room}-3` and so on that doesn't exist or has space. An
+    /// overflow room overflows into the next one of its source.
+    fn overflow_room(&mut self, room_name: &str) -> String {
+        let source = self
+            .overflows
+            .get(room_name)
+            .cloned()
+            .unwrap_or_else(|| room_name.to_owned());
+
+        let overflow = (2..)
+            .map(|n| format!("{}-{}", source, n))
+            .find(|overflow| {
+                let exists = self.rooms.contains_key(overflow)
+                    || self.known_rooms.contains_key(overflow);
+                if !exists {
+                    return true;
+                }
+                // someone else's room of the name
+                if self.overflows.get(overflow) != Some(&source) {
+                    return false;
+                }
+                self.rooms.get(overflow).is_none_or(|room| !room.is_full())
+            })
+            .expect("there is always a next room");
+
+        self.overflows.insert(overflow.clone(), source);
+        overflow
+    }
+
+    /// Gives an overflow room just created the settings of its source, and
+    /// the source's owner if both are homed here
+    fn open_overflow(&mut self, room_name: &str, source: &str) {
+        let (owner, settings) = match self.rooms.get(source) {
+            Some(room) => (Some(room.owner.clone()), room.settings.clone()),
+            None => match self.known_rooms.get(source) {
+                Some(settings) => (None, settings.clone()),
+                None => return,
+            },
+        };
+        info!("open_overflow() - {} overflows into {}", source, room_name);
+
+        if self.remote_home(room_name).is_none() && self.remote_home(source).is_none() {
+            if let (Some(owner), Some(room)) = (owner, self.rooms.get_mut(room_name)) {
+                room.owner = owner;
+            }
+        }
+        let action = RoomAction::Settings(settings.changes());
+        if let Err(err) = self.route_action(room_name.to_owned(), None, action) {
+            warn!("open_overflow() - settings for {}: {}", room_name, err);
+        }
+    }
+
+    fn forget_room(&mut self, room_name: &str) {
+        self.rooms.remove(room_name);
+        self.known_rooms.remove(room_name);
+        self.access.remove(room_name);
+        self.bans.remove(room_name);
+        self.joined_accounts.remove(room_name);
+        self.overflows.remove(room_name);
+        self.aliases.retain(|_, room| room != room_name);
+        self.set_join_code(room_name, None);
+    }
+
+    /// Whether `name` is banned from the room, by name or as the account a
+    /// banned name was signed in to
+    fn is_banned(&self, room_name: &str, name: &str) -> bool {
+        self.bans.get(room_name).is_some_and(|bans| {
+            bans.contains_key(name)
+                || bans
+                    .values()
+                    .any(|account| account.as_deref() == Some(name))
+        })
+    }
+
+    /// Sends a message or attachment to the room's home node
+    fn route_message(&mut self, room_name: String, client_id: usize, event: EventKind) {
+        match self.remote_home(&room_name) {
+            Some(to_node) => self.publish(Payload::Forward {
+                to_node,
+                room_name,
+                client_id,
+                event,
+            }),
+            None => self.accept_message(room_name, local_client(client_id), event),
+        }
+    }
+
+    /// Message ids go up by one from the current time in microseconds, so
+    /// they keep increasing across restarts and when a room moves to another
+    /// node, as long as the clocks roughly agree
+    fn next_message_id(&mut self) -> u64 {
+        let now = unix_millis() as u64 * 1000;
+        self.last_message_id = (self.last_message_id + 1).max(now);
+        self.last_message_id
+    }
+
+    /// Home node side of `SendMessage`, `SendAttachment` and `SendForwarded`.
+    /// Chat messages get their id here, and the sender an ack with it.
+    fn accept_message(&mut self, room_name: String, from: ClientRef, event: EventKind) {
+        let banned = event
+            .author()
+            .is_some_and(|author| self.is_banned(&room_name, author));
+        let rejection = match banned {
+            true => Some(RoomError::Banned),
+            false => self
+                .rooms
+                .get(&room_name)
+                .and_then(|room| room.rejection(&from, &event)),
+        };
+
+        if let Some(err) = rejection {
+            self.reply(from, &room_name, Reply::error(err));
+            return;
+        }
+
+        if let Some(room) = self.rooms.get_mut(&room_name) {
+            if let Some(secs) = room.settings.slow_mode {
+                let slow_mode = Duration::from_secs(secs);
+                room.last_post
+                    .retain(|_, posted| posted.elapsed() < slow_mode);
+                room.last_post.insert(from.clone(), Instant::now());
+            }
+        }
+
+        let event = match event {
+            EventKind::Message { content, .. } => {
+                metrics::message_accepted();
+                Accounts::from_registry().do_send(Posted {
+                    room_name: room_name.clone(),
+                    context: content.clone(),
+                    mentioned: mentioned_names(&content),
+                });
+
+                let id = self.next_message_id();
+                let posted = unix_millis() as u64;
+                if let (Some(room), Some((author, _))) =
+                    (self.rooms.get_mut(&room_name), content.split_once(": "))
+                {
+                    room.remember_author(id, from.clone(), author.to_owned());
+                }
+                let ack = serde_json::json!({
+                    "type": "ack",
+                    "room": room_name,
+                    "id": id,
+                    "posted": posted,
+                });
+                self.reply(from, &room_name, Reply::Frame(ack.to_string()));
+
+                EventKind::Message {
+                    content,
+                    id,
+                    posted,
+                }
+            }
+            event => event,
+        };
+
+        self.broadcast(&room_name, event);
+    }
+
+    /// Applies a settings change on the room's home node, or forwards it there.
+    /// `client_id` is `None` for the admin API.
+    fn route_action(
+        &mut self,
+        room_name: String,
+        client_id: Option<usize>,
+        action: RoomAction,
+    ) -> Result<(), RoomError> {
+        match self.remote_home(&room_name) {
+            Some(to_node) => {
+                self.publish(Payload::Manage {
+                    to_node,
+                    room_name,
+                    client_id,
+                    action,
+                });
+                Ok(())
+            }
+            None => self.apply_action(&room_name, client_id.map(local_client), action),
+        }
+    }
+
+    /// Home node side of the room settings commands, owners only. `by` is
+    /// `None` for the admin API.
+    fn apply_action(
+        &mut self,
+        room_name: &str,
+        by: Option<ClientRef>,
+        action: RoomAction,
+    ) -> Result<(), RoomError> {
+        let room = self.rooms.get_mut(room_name).ok_or(RoomError::NotFound)?;
+
+        if action.owner_only() && by.as_ref().is_some_and(|by| *by != room.owner) {
+            return Err(RoomError::NotOwner);
+        }
+
+        // rooms holding copies of a cross-post this edits or deletes
+        let mut copies = Vec::new();
+        let event = match action {
+            RoomAction::Stream(action) => {
+                room.stream_event(by.ok_or(RoomError::NotHost)?, action)?
+            }
+
+            RoomAction::Settings(changes) => {
+                let changed = changes
+                    .iter()
+                    .map(|change| change.key().to_owned())
+                    .collect();
+                for change in changes {
+                    change.apply(&mut room.settings);
+                }
+                if room.settings.slow_mode.is_none() {
+                    room.last_post.clear();
+                }
+
+                EventKind::Settings {
+                    changed,
+                    settings: room.settings.clone(),
+                }
+            }
+
+            RoomAction::Archive(archived) => {
+                room.archived = archived;
+                EventKind::Archived { archived }
+            }
+
+            RoomAction::Announce(text) => EventKind::Announcement { text },
+
+            RoomAction::Delete => EventKind::Deleted,
+
+            RoomAction::Hours(hours) => {
+                room.hours = hours;
+                room.closed =
+                    hours.is_some_and(|hours| !hours.is_open_at(utc_minute_of_day()));
+                EventKind::Hours { hours }
+            }
+
+            RoomAction::JoinCode(action) => {
+                let code = match action {
+                    CodeAction::Show if room.join_code.is_some() => {
+                        room.join_code.clone()
+                    }
+                    CodeAction::Show | CodeAction::Rotate => Some(new_join_code()),
+                    CodeAction::Remove => None,
+                };
+                let changed = code != room.join_code;
+                room.join_code = code.clone();
+
+                let text = match &code {
+                    Some(code) => format!("join code for {}: {}", room_name, code),
+                    None => format!("{} has no join code", room_name),
+                };
+                if let Some(by) = by {
+                    self.reply(by, room_name, Reply::notice(text));
+                }
+
+                if changed {
+                    self.set_join_code(room_name, code.clone());
+                    self.publish(Payload::JoinCode {
+                        room_name: room_name.to_owned(),
+                        code: code.clone(),
+                    });
+
+                    let event = EventKind::JoinCode {
+                        enabled: code.is_some(),
+                    };
+                    self.broadcast(room_name, event);
+                }
+
+                return Ok(());
+            }
+
+            RoomAction::Hand(action) => {
+                let by = by.ok_or(RoomError::NotOwner)?;
+                let owner = room.owner.clone();
+
+                match action {
+                    HandAction::Raise(name) => {
+                        if !room.hands.iter().any(|(client, _)| *client == by) {
+                            room.hands.push((by.clone(), name.clone()));
+                        }
+                        let waiting = room.hands.len();
+
+                        let mut fields = serde_json::Map::new();
+                        fields.insert("name".to_owned(), name.as_str().into());
+                        let text = format!("{} raised their hand", name);
+                        let frame =
+                            system_frame("hand_raised", Some(room_name), &text, fields);
+
+                        self.reply(owner, room_name, Reply::Frame(frame));
+                        self.reply(
+                            by,
+                            room_name,
+                            Reply::notice(format!(
+                                "hand raised, {} waiting to speak",
+                                waiting
+                            )),
+                        );
+                    }
+
+                    HandAction::Lower => {
+                        room.hands.retain(|(client, _)| *client != by);
+                        self.reply(by, room_name, Reply::notice("hand lowered"));
+                    }
+
+                    HandAction::List => {
+                        let names: Vec<&str> =
+                            room.hands.iter().map(|(_, name)| name.as_str()).collect();
+
+                        let text = if names.is_empty() {
+                            "no hands raised".to_owned()
+                        } else {
+                            format!("raised hands: {}", names.join(", "))
+                        };
+                        self.reply(by, room_name, Reply::notice(text));
+                    }
+
+                    HandAction::CallOn(name) => {
+                        let index = room
+                            .hands
+                            .iter()
+                            .position(|(_, raised)| *raised == name)
+                            .ok_or(RoomError::NoRaisedHand(name))?;
+                        let (client, name) = room.hands.remove(index);
+
+                        let now = Instant::now();
+                        room.speakers.retain(|_, until| *until > now);
+                        room.speakers.insert(client, now + SPEAKER_TIME);
+
+                        let event = EventKind::CalledOn {
+                            name,
+                            secs: SPEAKER_TIME.as_secs(),
+                        };
+                        self.broadcast(room_name, event);
+                    }
+                }
+
+                return Ok(());
+            }
+
+            RoomAction::Question(action) => {
+                let by = by.ok_or(RoomError::NotOwner)?;
+
+                if !room.settings.has_flag(RoomFlag::Qa) {
+                    return Err(RoomError::NotQa);
+                }
+
+                match action {
+                    QuestionAction::Ask { from, text } => {
+                        let event = EventKind::Question {
+                            id: room.questions.len() as u64 + 1,
+                            from,
+                            text,
+                        };
+                        if let Some(err) = room.rejection(&by, &event) {
+                            return Err(err);
+                        }
+
+                        event
+                    }
+
+                    QuestionAction::Upvote(id) => {
+                        let question = room
+                            .question_mut(id)
+                            .ok_or(RoomError::NoSuchQuestion(id))?;
+                        if question.answered {
+                            return Err(RoomError::Answered(id));
+                        }
+                        if !question.voters.insert(by.clone()) {
+                            return Err(RoomError::AlreadyVoted(id));
+                        }
+                        let votes = question.voters.len();
+
+                        self.reply(
+                            by,
+                            room_name,
+                            Reply::notice(format!(
+                                "upvoted question {}, {} votes",
+                                id, votes
+                            )),
+                        );
+                        EventKind::QuestionVotes { id, votes }
+                    }
+
+                    QuestionAction::Answer(id) => {
+                        let question = room
+                            .question_mut(id)
+                            .ok_or(RoomError::NoSuchQuestion(id))?;
+                        if question.answered {
+                            return Err(RoomError::Answered(id));
+                        }
+
+                        EventKind::Answered { id }
+                    }
+                }
+            }
+
+            RoomAction::Moderate(action) => {
+                let known = |name: &String| {
+                    self.names.contains_key(name) || self.remote_names.contains_key(name)
+                };
+
+                match action {
+                    ModAction::Kick(name) if known(&name) => EventKind::Kicked { name },
+                    ModAction::Ban(name) => EventKind::Banned {
+                        account: self.account_of(&name),
+                        name,
+                        banned: true,
+                    },
+                    ModAction::Unban(name) => EventKind::Banned {
+                        name,
+                        banned: false,
+                        account: None,
+                    },
+                    ModAction::Mute(name, secs) if known(&name) => EventKind::Muted {
+                        account: self.account_of(&name),
+                        name,
+                        secs,
+                    },
+                    ModAction::Unmute(name) => EventKind::Muted {
+                        name,
+                        secs: 0,
+                        account: None,
+                    },
+                    ModAction::Breakout { by, with } if known(&with) => {
+                        if self.breakouts.contains_key(room_name) {
+                            return Err(RoomError::NestedBreakout);
+                        }
+                        let breakout = breakout_name(room_name, &with);
+                        if self.known_rooms.contains_key(&breakout) {
+                            return Err(RoomError::Exists);
+                        }
+
+                        let mut fields = serde_json::Map::new();
+                        fields.insert("source".to_owned(), room_name.into());
+                        fields.insert("by".to_owned(), by.as_str().into());
+                        let text = format!(
+                            "{} invites you to the breakout room {} from {}, /join {} to enter it",
+                            &by, &breakout, room_name, &breakout
+                        );
+                        let frame = system_frame(
+                            "breakout_invite",
+                            Some(&breakout),
+                            &text,
+                            fields,
+                        );
+                        self.send_to_name(with.clone(), Reply::Frame(frame)).ok();
+
+                        EventKind::Breakout { breakout, by, with }
+                    }
+                    ModAction::Kick(name)
+                    | ModAction::Mute(name, _)
+                    | ModAction::Breakout { with: name, .. } => {
+                        return Err(RoomError::UnknownUser(name));
+                    }
+                }
+            }
+
+            RoomAction::CrossPost(event) => {
+                if let (
+                    Some(by),
+                    EventKind::CrossPost {
+                        post, from, rooms, ..
+                    },
+                ) = (by, &*event)
+                {
+                    room.remember_author(*post, by, from.clone());
+                    let others = rooms.iter().filter(|other| *other != room_name);
+                    room.copies.insert(*post, others.cloned().collect());
+                }
+
+                *event
+            }
+
+            RoomAction::CopyChange(change) => {
+                let id = match *change {
+                    EventKind::MessageEdited { id, .. }
+                    | EventKind::MessageDeleted { id } => id,
+                    _ => return Ok(()),
+                };
+                if !room.authors.contains_key(&id) {
+                    return Err(RoomError::NoSuchMessage(id));
+                }
+                if matches!(*change, EventKind::MessageDeleted { .. }) {
+                    room.forget_message(id);
+                }
+
+                *change
+            }
+
+            RoomAction::EventLog(count) => {
+                match by {
+                    Some((to_node, client_id)) if to_node == *NODE_ID => {
+                        self.send_event_log(room_name, client_id, count)
+                    }
+                    Some((to_node, client_id)) => self.publish(Payload::EventLog {
+                        to_node,
+                        room_name: room_name.to_owned(),
+                        client_id,
+                        count,
+                    }),
+                    None => {}
+                }
+                return Ok(());
+            }
+
+            RoomAction::EditMessage { id, text } => {
+                let name = room.author_of(id, by.as_ref())?;
+                if let Some(err) = room.edit_rejection(&name) {
+                    return Err(err);
+                }
+                copies = room.copies.get(&id).cloned().unwrap_or_default();
+
+                EventKind::MessageEdited {
+                    id,
+                    content: format!("{}: {}", name, text),
+                }
+            }
+
+            RoomAction::DeleteMessage(id) => {
+                room.author_of(id, by.as_ref())?;
+                if room.archived {
+                    return Err(RoomError::Archived);
+                }
+                copies = room.copies.get(&id).cloned().unwrap_or_default();
+                room.forget_message(id);
+
+                EventKind::MessageDeleted { id }
+            }
+
+            RoomAction::React { id, emoji } => {
+                if !room.authors.contains_key(&id) {
+                    return Err(RoomError::NoSuchMessage(id));
+                }
+                if room.archived {
+                    return Err(RoomError::Archived);
+                }
+                let by = by.ok_or(RoomError::NoSuchMessage(id))?;
+
+                let reactions = room.reactions.entry(id).or_default();
+                if !reactions.contains_key(&emoji) && reactions.len() >= MAX_REACTIONS {
+                    return Err(RoomError::TooManyReactions);
+                }
+                let reacted = reactions.entry(emoji.clone()).or_default();
+                // reacting again takes it back
+                if !reacted.insert(by.clone()) {
+                    reacted.remove(&by);
+                }
+                if reacted.is_empty() {
+                    reactions.remove(&emoji);
+                }
+
+                EventKind::Reactions {
+                    id,
+                    reactions: reactions
+                        .iter()
+                        .map(|(emoji, reacted)| (emoji.clone(), reacted.len()))
+                        .collect(),
+                }
+            }
+
+            RoomAction::Canned { name, content } => {
+                let exists = room.canned.contains_key(&name);
+                if content.is_none() && !exists {
+                    return Err(RoomError::NoSuchCanned(name));
+                }
+                if !exists && room.canned.len() >= MAX_CANNED_PER_ROOM {
+                    return Err(RoomError::TooManyCanned);
+                }
+
+                EventKind::Canned { name, content }
+            }
+
+            RoomAction::Access(action) => {
+                let access = match (action, self.access.get(room_name)) {
+                    (AccessAction::Open, _) => RoomAccess::Open,
+                    (AccessAction::Password(hash), _) => RoomAccess::Password(hash),
+                    (AccessAction::InviteOnly(owner), _) => {
+                        RoomAccess::InviteOnly(BTreeSet::from([owner]))
+                    }
+                    (
+                        AccessAction::Invite(name),
+                        Some(RoomAccess::InviteOnly(invited)),
+                    ) => {
+                        let mut invited = invited.clone();
+                        invited.insert(name.clone());
+                        self.set_access(
+                            room_name,
+                            RoomAccess::InviteOnly(invited.clone()),
+                        );
+                        self.publish(Payload::Access {
+                            room_name: room_name.to_owned(),
+                            access: RoomAccess::InviteOnly(invited),
+                        });
+
+                        let invitation = format!(
+                            "you are invited to {}, /join {} to enter it",
+                            room_name, room_name
+                        );
+                        self.send_to_name(name.clone(), Reply::notice(invitation))
+                            .ok();
+                        if let Some(by) = by {
+                            self.reply(
+                                by,
+                                room_name,
+                                Reply::notice(format!("invited {}", name)),
+                            );
+                        }
+                        return Ok(());
+                    }
+                    (AccessAction::Invite(_), _) => {
+                        return Err(RoomError::NotInviteOnly)
+                    }
+                };
+
+                let policy = access.policy();
+                self.set_access(room_name, access.clone());
+                self.publish(Payload::Access {
+                    room_name: room_name.to_owned(),
+                    access,
+                });
+                EventKind::Access { policy }
+            }
+
+            RoomAction::Alias(alias) => {
+                if alias == room_name
+                    || self.rooms.contains_key(&alias)
+                    || self.aliases.contains_key(&alias)
+                {
+                    return Err(RoomError::NameTaken);
+                }
+
+                debug!("apply_action() - alias {} -> {}", &alias, room_name);
+                self.aliases.insert(alias.clone(), room_name.to_owned());
+                self.publish(Payload::Aliased {
+                    alias: alias.clone(),
+                    room_name: room_name.to_owned(),
+                });
+
+                EventKind::Aliased { alias }
+            }
+        };
+
+        for copy in copies {
+            let change = RoomAction::CopyChange(Box::new(event.clone()));
+            if let Err(err) = self.route_action(copy.clone(), None, change) {
+                debug!("apply_action() - copy in {} not changed: {}", copy, err);
+            }
+        }
+        self.broadcast(room_name, event);
+        Ok(())
+    }
+
+    fn set_access(&mut self, room_name: &str, access: RoomAccess) {
+        match access {
+            RoomAccess::Open => self.access.remove(room_name),
+            access => self.access.insert(room_name.to_owned(), access),
+        };
+    }
+
+    /// Why the client may not enter the room, if it may not
+    fn access_denied(
+        &self,
+        room_name: &str,
+        client_name: &str,
+        account: Option<&str>,
+        password: Option<&str>,
+    ) -> Option<RoomError> {
+        match self.access.get(room_name)? {
+            RoomAccess::Open => None,
+            RoomAccess::Password(hash) => match password {
+                None => Some(RoomError::PasswordRequired),
+                Some(password) if password_hash(room_name, password) != *hash => {
+                    Some(RoomError::WrongPassword)
+                }
+                Some(_) => None,
+            },
+            RoomAccess::InviteOnly(invited) => {
+                let invited = invited.contains(client_name)
+                    || account.is_some_and(|account| invited.contains(account));
+                (!invited).then_some(RoomError::InviteOnly)
+            }
+        }
+    }
+
+    /// Points `code` at the room, dropping the room's previous code
+    fn set_join_code(&mut self, room_name: &str, code: Option<String>) {
+        self.join_codes.retain(|_, room| room != room_name);
+
+        if let Some(code) = code {
+            self.join_codes.insert(code, room_name.to_owned());
+        }
+    }
+
+    /// The local client going by `name`
+    fn named(&self, name: &str) -> Option<&Client> {
+        self.names.get(name).map(|named| &named.client)
+    }
+
+    /// Sends a reply to the client with the given name, on whichever node it is
+    fn send_to_name(&mut self, to: String, reply: Reply) -> Result<(), RoomError> {
+        let text = reply.to_json();
+        if let Some(client) = self.named(&to) {
+            client.do_send(ChatMessage(text)).ok();
+            return Ok(());
+        }
+
+        // not connected here, route it to whichever node claims the name
+        let to_node = match self.remote_names.get(&to) {
+            Some((node, ..)) => node.clone(),
+            None => return Err(RoomError::UnknownUser(to)),
+        };
+
+        debug!("send_to_name() - routing to {} via {}", &to, &to_node);
+        self.publish(Payload::Whisper { to_node, to, text });
+        Ok(())
+    }
+
+    /// Hands a payload to the cluster bridges
+    fn publish(&mut self, payload: Payload) {
+        self.issue_system_async(BridgeOut(Envelope::new(payload)));
+    }
+
+    /// Hands rooms whose home moved to another node over to it. The old home
+    /// sends the room's state, and every node with clients in the room asks
+    /// the new home to create it, in case the old home died with the state.
+    fn rehome_rooms(&mut self, old_ring: &HashRing) {
+        let mut payloads = Vec::new();
+
+        for (room_name, room) in &self.rooms {
+            let old_home = old_ring.node_for(room_name).unwrap_or(&NODE_ID);
+            let new_home = self.home_node(room_name);
+
+            if new_home == old_home || new_home == *NODE_ID {
+                continue;
+            }
+
+            if old_home == *NODE_ID {
+                let aliases = self
+                    .aliases
+                    .iter()
+                    .filter(|(_, target)| *target == room_name)
+                    .map(|(alias, _)| alias.clone())
+                    .collect();
+
+                payloads.push(Payload::Handoff {
+                    to_node: new_home.clone(),
+                    room_name: room_name.clone(),
+                    owner: room.owner.clone(),
+                    archived: room.archived,
+                    hours: room.hours,
+                    aliases,
+                    settings: room.settings.clone(),
+                    join_code: room.join_code.clone(),
+                    questions: room.questions.clone(),
+                    canned: room.canned.clone(),
+                });
+            }
+
+            if let Some(&client_id) = room.clients.keys().next() {
+                payloads.push(Payload::Rehome {
+                    to_node: new_home,
+                    room_name: room_name.clone(),
+                    client_id,
+                });
+            }
+        }
+
+        for payload in payloads {
+            self.publish(payload);
+        }
+    }
+
+    fn announce_names(&mut self) {
+        let names = self.names.keys().cloned().collect();
+        let accounts = self
+            .names
+            .iter()
+            .filter_map(|(name, named)| Some((name.clone(), named.account.clone()?)))
+            .collect();
+        self.publish(Payload::Names { names, accounts });
+    }
+
+    /// The account the client going by `name` is signed in to, on whichever
+    /// node it is
+    fn account_of(&self, name: &str) -> Option<String> {
+        match self.names.get(name) {
+            Some(named) => named.account.clone(),
+            None => self
+                .remote_names
+                .get(name)
+                .and_then(|(_, _, account)| account.clone()),
+        }
+    }
+
+    /// Opens and closes rooms according to their opening hours, announcing
+    /// each change to the room
+    fn update_opening_hours(&mut self) {
+        let now = utc_minute_of_day();
+        let mut changes = Vec::new();
+
+        for (room_name, room) in self.rooms.iter_mut() {
+            let hours = match room.hours {
+                Some(hours) => hours,
+                None => continue,
+            };
+
+            let closed = !hours.is_open_at(now);
+
+            if closed != room.closed {
+                room.closed = closed;
+
+                changes.push(if closed {
+                    (room_name.clone(), EventKind::Closed)
+                } else {
+                    (room_name.clone(), EventKind::Opened)
+                });
+            }
+        }
+
+        for (room_name, event) in changes {
+            debug!("update_opening_hours() - {}: {:?}", &room_name, &event);
+            self.broadcast(&room_name, event);
+        }
+    }
+
+    /// Shows drawing or presence to the room's clients on this node, except
+    /// the one who sent it
+    /// Queues the event for the room's members, see `Outbox`
+    fn send_ephemeral(
+        &mut self,
+        room_name: &str,
+        from: &str,
+        event: &Ephemeral,
+        skip: Option<usize>,
+    ) {
+        let room = match self.rooms.get(room_name) {
+            Some(room) => room,
+            None => return,
+        };
+
+        let frame = event.frame(room_name, from);
+
+        for (client_id, client) in &room.clients {
+            if Some(*client_id) != skip {
+                self.outbox
+                    .push(Lane::Ephemeral, client.clone(), frame.clone());
+            }
+        }
+    }
+
+    fn leave(&mut self, room_name: &str, client_id: usize, client_name: String) {
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            debug!("leave() - removing {} from {}", &client_id, room_name);
+            room.change_members(room_name, MemberChange::Left { client_id }, None);
+            self.broadcast(room_name, EventKind::Left { name: client_name });
+        }
+    }
+
+    /// Gives up on the memberships kept for a disconnected session
+    fn leave_detached(&mut self, token: &str) {
+        if let Some(detached) = self.detached.remove(token) {
+            for (room_name, client_id) in detached.memberships {
+                self.leave(&room_name, client_id, detached.client_name.clone());
+            }
+        }
+    }
+
+    /// Tells the local members named as `@name` in a chat line they were
+    /// mentioned, after the line itself. Every node does so for its own
+    /// clients as the message comes by, and the author isn't told.
+    fn send_mentions(&mut self, room_name: &str, line: &str, id: u64, posted: u64) {
+        let room = match self.rooms.get(room_name) {
+            Some(room) => room,
+            None => return,
+        };
+        let author = line.split_once(": ").map(|(author, _)| author);
+
+        let mut frame = None;
+        for name in mentioned_names(line) {
+            if Some(name.as_str()) == author {
+                continue;
+            }
+            let client = match self.named(&name) {
+                Some(client) if room.clients.values().any(|member| member == client) => {
+                    client
+                }
+                _ => continue,
+            };
+
+            let frame = frame
+                .get_or_insert_with(|| mention_frame(room_name, line, id, posted))
+                .clone();
+            self.outbox.push(Lane::Chat, client.clone(), frame);
+        }
+
+        self.outbox.flush();
+    }
+
+    fn send_chat_message(&mut self, room_name: &str, msg: &str, lane: Lane) {
+        if let Some(room) = self.rooms.get(room_name) {
+            for client in room.clients.values() {
+                self.outbox.push(lane, client.clone(), msg.to_owned());
+            }
+        }
+
+        self.outbox.flush();
+    }
+}
+
+impl Actor for WsChatServer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.subscribe_system_async::<LeaveRoom>(ctx);
+        self.subscribe_system_async::<SendMessage>(ctx);
+        self.subscribe_system_async::<MembersChanged>(ctx);
+
+        // start gossiping
+        Membership::from_registry();
+
+        ctx.run_interval(OPENING_HOURS_INTERVAL, |act, _ctx| {
+            act.update_opening_hours()
+        });
+
+        ctx.run_interval(PROBE_INTERVAL, |_act, ctx| {
+            ctx.address().do_send(LoadProbe(Instant::now()))
+        });
+
+        ctx.run_interval(EPHEMERAL_TICK, |act, _ctx| act.outbox.tick());
+
+        ctx.run_interval(DETACHED_INTERVAL, |act, _ctx| {
+            let expired: Vec<String> = act
+                .detached
+                .iter()
+                .filter(|(_, detached)| {
+                    detached.since.elapsed() >= config().resume_grace()
+                })
+                .map(|(token, _)| token.clone())
+                .collect();
+            for token in expired {
+                act.leave_detached(&token);
+            }
+        });
+
+        ctx.run_interval(NAMES_INTERVAL, |act, _ctx| {
+            act.announce_names();
+
+            let now = Instant::now();
+            act.remote_names.retain(|_, (_, announced, _)| {
+                now.duration_since(*announced) < REMOTE_NAMES_TTL
+            });
+        });
+    }
+}
+
+impl Handler<MembersChanged> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MembersChanged, _ctx: &mut Self::Context) {
+        let MembersChanged(members) = msg;
+
+        let old_ring = std::mem::replace(
+            &mut self.ring,
+            HashRing::new(members.iter().map(String::as_str)),
+        );
+        self.members = members;
+
+        let members = &self.members;
+        self.remote_names
+            .retain(|_, (node, ..)| members.contains(node));
+
+        self.rehome_rooms(&old_ring);
+    }
+}
+
+impl Handler<JoinRoom> for WsChatServer {
+    type Result = MessageResult<JoinRoom>;
+
+    fn handle(&mut self, msg: JoinRoom, _ctx: &mut Self::Context) -> Self::Result {
+        let JoinRoom {
+            room_name,
+            client_name,
+            client,
+            may_create,
+            template,
+            account,
+            removed,
+            breakout,
+            presence,
+            password,
+            rejoining,
+        } = msg;
+        let room_name = self.resolve_room_name(&room_name);
+        debug!(
+            "JoinRoom::handle() - room_name: {}, client_name: {}",
+            &room_name, &client_name
+        );
+
+        let banned = self.is_banned(&room_name, &client_name)
+            || account
+                .as_deref()
+                .is_some_and(|account| self.is_banned(&room_name, account));
+        if banned {
+            return MessageResult(Err(RoomError::Banned));
+        }
+
+        let denied = self.access_denied(
+            &room_name,
+            &client_name,
+            account.as_deref(),
+            password.as_deref(),
+        );
+        if let Some(err) = denied.filter(|_| !rejoining) {
+            return MessageResult(Err(err));
+        }
+
+        if let Some(team_room) = self.team_rooms.get(&room_name) {
+            if !account
+                .as_ref()
+                .is_some_and(|account| team_room.members.contains(account))
+            {
+                return MessageResult(Err(RoomError::TeamOnly(team_room.team.clone())));
+            }
+        }
+
+        let exists = self.rooms.contains_key(&room_name)
+            || self.known_rooms.contains_key(&room_name);
+        // created for whoever a full room sent to it, whether they may create
+        // rooms or not
+        let overflow_of = match exists || template.is_some() {
+            true => None,
+            false => self.overflows.get(&room_name).cloned(),
+        };
+        if !may_create && !exists && overflow_of.is_none() {
+            return MessageResult(Err(RoomError::CreateNotAllowed));
+        }
+        if template.is_some() && exists {
+            return MessageResult(Err(RoomError::Exists));
+        }
+
+        if let Some(room) = self.rooms.get(&room_name) {
+            if room.is_full() {
+                let overflow = match room.settings.has_flag(RoomFlag::Overflow) {
+                    true => Some(self.overflow_room(&room_name)),
+                    false => None,
+                };
+                return MessageResult(Err(RoomError::Full(overflow)));
+            }
+        }
+
+        let id = self.add_client_to_room(&room_name, None, client);
+        if let Some(room) = self.rooms.get_mut(&room_name) {
+            room.sessions.insert(
+                id,
+                MemberSession {
+                    removed,
+                    breakout,
+                    presence,
+                },
+            );
+        }
+        let remote_home = self.remote_home(&room_name);
+
+        match remote_home.clone() {
+            Some(to_node) => self.publish(Payload::Join {
+                to_node,
+                room_name: room_name.clone(),
+                client_id: id,
+                client_name,
+            }),
+            None => {
+                self.broadcast(&room_name, EventKind::Joined { name: client_name });
+            }
+        }
+
+        if let Some(source) = overflow_of {
+            self.open_overflow(&room_name, &source);
+        }
+        // the creator is the owner, so it may change them
+        if let Some(changes) = template.filter(|changes| !changes.is_empty()) {
+            let action = RoomAction::Settings(changes);
+            if let Err(err) = self.route_action(room_name.clone(), Some(id), action) {
+                warn!("JoinRoom::handle() - template for {}: {}", &room_name, err);
+            }
+        }
+        if let Some(password) = password.filter(|_| !exists) {
+            let hash = password_hash(&room_name, &password);
+            let action = RoomAction::Access(AccessAction::Password(hash));
+            if let Err(err) = self.route_action(room_name.clone(), Some(id), action) {
+                warn!("JoinRoom::handle() - password for {}: {}", &room_name, err);
+            }
+        }
+
+        if remote_home.is_none() {
+            self.greet(&room_name, local_client(id));
+        }
+        if let Some(account) = account {
+            self.joined_accounts
+                .entry(room_name.clone())
+                .or_default()
+                .insert(account);
+        }
+
+        MessageResult(Ok((id, room_name)))
+    }
+}
+
+impl Handler<LeaveRoom> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, _ctx: &mut Self::Context) {
+        let LeaveRoom(room_name, client_id, client_name) = msg;
+        self.leave(&room_name, client_id, client_name);
+    }
+}
+
+impl Handler<DetachSession> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DetachSession, _ctx: &mut Self::Context) {
+        let DetachSession {
+            token,
+            client_name,
+            memberships,
+        } = msg;
+
+        self.detached.insert(
+            token,
+            Detached {
+                client_name,
+                memberships,
+                since: Instant::now(),
+            },
+        );
+    }
+}
+
+impl Handler<ReattachSession> for WsChatServer {
+    type Result = MessageResult<ReattachSession>;
+
+    fn handle(
+        &mut self,
+        msg: ReattachSession,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let ReattachSession {
+            token,
+            client,
+            removed,
+            breakout,
+            presence,
+        } = msg;
+
+        let Detached {
+            client_name,
+            memberships,
+            ..
+        } = match self.detached.remove(&token) {
+            Some(detached) => detached,
+            None => return MessageResult(Vec::new()),
+        };
+
+        let mut reattached = Vec::new();
+        for (room_name, client_id) in memberships {
+            // banned meanwhile, which only finds connected clients
+            if self.is_banned(&room_name, &client_name) {
+                self.leave(&room_name, client_id, client_name.clone());
+                continue;
+            }
+            let room = match self.rooms.get_mut(&room_name) {
+                Some(room) if room.clients.contains_key(&client_id) => room,
+                _ => continue,
+            };
+
+            debug!(
+                "ReattachSession::handle() - {} is back in {} as {}",
+                &client_name, &room_name, client_id
+            );
+            room.clients.insert(client_id, client.clone());
+            room.sessions.insert(
+                client_id,
+                MemberSession {
+                    removed: removed.clone(),
+                    breakout: breakout.clone(),
+                    presence: presence.clone(),
+                },
+            );
+            reattached.push((room_name, client_id));
+        }
+
+        MessageResult(reattached)
+    }
+}
+
+impl Handler<IsRoomMember> for WsChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: IsRoomMember, _ctx: &mut Self::Context) -> Self::Result {
+        let IsRoomMember { room_name, account } = msg;
+        let room_name = self.resolve_room_name(&room_name);
+        if self.is_banned(&room_name, &account) {
+            return false;
+        }
+
+        let in_team = self
+            .team_rooms
+            .get(&room_name)
+            .is_some_and(|team_room| team_room.members.contains(&account));
+        let invited = match self.access.get(&room_name) {
+            Some(RoomAccess::InviteOnly(invited)) => invited.contains(&account),
+            _ => false,
+        };
+        let joined = self
+            .joined_accounts
+            .get(&room_name)
+            .is_some_and(|accounts| accounts.contains(&account));
+
+        in_team || invited || joined
+    }
+}
+
+impl Handler<SetTeamRooms> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetTeamRooms, _ctx: &mut Self::Context) {
+        self.team_rooms = msg.0;
+    }
+}
+
+impl Handler<ListRooms> for WsChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, msg: ListRooms, _ctx: &mut Self::Context) -> Self::Result {
+        let ListRooms {
+            after,
+            limit,
+            unlisted,
+        } = msg;
+
+        let mut rooms: Vec<ListedRoom> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| unlisted || !room.settings.has_flag(RoomFlag::Unlisted))
+            .map(|(room_name, room)| ListedRoom {
+                team: self.team_rooms.get(room_name).map(|room| room.team.clone()),
+                name: room_name.clone(),
+                unread: None,
+                language: room.settings.language.clone(),
+            })
+            .filter(|room| after.as_ref().is_none_or(|after| room > after))
+            .collect();
+        rooms.sort_unstable();
+
+        let limit = limit.max(1);
+        let next = if rooms.len() > limit {
+            Some(page_token(&rooms[limit - 1]))
+        } else {
+            None
+        };
+        rooms.truncate(limit);
+
+        MessageResult(RoomPage { rooms, next })
+    }
+}
+
+impl Handler<ListDirectory> for WsChatServer {
+    type Result = MessageResult<ListDirectory>;
+
+    fn handle(&mut self, msg: ListDirectory, _ctx: &mut Self::Context) -> Self::Result {
+        let ListDirectory {
+            after,
+            language,
+            limit,
+        } = msg;
+
+        let mut rooms: Vec<DirectoryRoom> = self
+            .rooms
+            .iter()
+            .filter(|(room_name, room)| {
+                room.settings.visibility == Visibility::Public
+                    && !room.settings.has_flag(RoomFlag::Unlisted)
+                    && !self.team_rooms.contains_key(*room_name)
+                    && !self.breakouts.contains_key(*room_name)
+            })
+            .filter(|(_, room)| match (&language, &room.settings.language) {
+                (Some(range), Some(tag)) => language_matches(tag, range),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .filter(|(room_name, _)| {
+                after.as_ref().is_none_or(|after| *room_name > after)
+            })
+            .map(|(room_name, room)| DirectoryRoom {
+                name: room_name.clone(),
+                topic: room.settings.topic.clone(),
+                language: room.settings.language.clone(),
+                members: room.clients.len(),
+                access: self
+                    .access
+                    .get(room_name)
+                    .map_or(AccessPolicy::Open, RoomAccess::policy),
+            })
+            .collect();
+        rooms.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        let limit = limit.max(1);
+        let next = if rooms.len() > limit {
+            Some(page_token(&ListedRoom {
+                team: None,
+                name: rooms[limit - 1].name.clone(),
+                unread: None,
+                language: None,
+            }))
+        } else {
+            None
+        };
+        rooms.truncate(limit);
+
+        MessageResult(DirectoryPage { rooms, next })
+    }
+}
+
+impl Handler<SendMessage> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendMessage, _ctx: &mut Self::Context) {
+        let SendMessage(room_name, id, msg) = msg;
+        if let Some(chaos) = CHAOS.as_ref() {
+            chaos.stall();
+        }
+        self.route_message(room_name, id, EventKind::message(msg));
+    }
+}
+
+impl Handler<SendAttachment> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendAttachment, _ctx: &mut Self::Context) {
+        let SendAttachment(room_name, id, attachment) = msg;
+        self.route_message(room_name, id, attachment);
+    }
+}
+
+impl Handler<SendForwarded> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendForwarded, _ctx: &mut Self::Context) {
+        let SendForwarded(room_name, id, forwarded) = msg;
+        self.route_message(room_name, id, forwarded);
+    }
+}
+
+/// Rooms homed here are checked before any copy goes out, the home nodes of
+/// the others check them as theirs arrive
+impl Handler<CrossPost> for WsChatServer {
+    type Result = ResponseActFuture<Self, Result<(), RoomError>>;
+
+    fn handle(&mut self, msg: CrossPost, _ctx: &mut Self::Context) -> Self::Result {
+        let CrossPost { rooms, mut event } = msg;
+        let id = self.next_message_id();
+        if let EventKind::CrossPost { post, .. } = &mut event {
+            *post = id;
+        }
+
+        // every room is checked before any gets its copy, the ones homed
+        // elsewhere by their home node
+        for (room_name, client_id) in &rooms {
+            if self.remote_home(room_name).is_some() {
+                continue;
+            }
+            let owner = self.rooms.get(room_name).map(|room| &room.owner);
+            if owner != Some(&local_client(*client_id)) {
+                let err = match owner {
+                    Some(_) => RoomError::NotOwner,
+                    None => RoomError::NotFound,
+                };
+                return Box::pin(fut::ready(Err(err)));
+            }
+        }
+        let mut answers = Vec::new();
+        for (room_name, client_id) in &rooms {
+            if let Some(to_node) = self.remote_home(room_name) {
+                let (answer, answered) = oneshot::channel();
+                self.owner_checks.insert((id, room_name.clone()), answer);
+                self.publish(Payload::CheckOwner {
+                    to_node,
+                    room_name: room_name.clone(),
+                    client_id: *client_id,
+                    post: id,
+                });
+                answers.push(answered);
+            }
+        }
+
+        // in time for the session's request to still get the answer
+        let answered =
+            actix_rt::time::timeout(config().request_timeout() / 2, join_all(answers));
+        Box::pin(answered.into_actor(self).map(move |answers, act, _ctx| {
+            act.owner_checks.retain(|(post, _), _| *post != id);
+            let answers = answers.map_err(|_| RoomError::Unconfirmed)?;
+            if !answers.into_iter().all(|owner| owner == Ok(true)) {
+                return Err(RoomError::NotOwner);
+            }
+
+            let posted = unix_millis() as u64;
+            for (room_name, client_id) in rooms {
+                let action = RoomAction::CrossPost(Box::new(event.clone()));
+                act.route_action(room_name.clone(), Some(client_id), action)?;
+
+                let ack = serde_json::json!({
+                    "type": "ack",
+                    "room": room_name,
+                    "id": id,
+                    "posted": posted,
+                });
+                act.reply(
+                    local_client(client_id),
+                    &room_name,
+                    Reply::Frame(ack.to_string()),
+                );
+            }
+
+            Ok(())
+        }))
+    }
+}
+
+impl Handler<BridgeIn> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: BridgeIn, _ctx: &mut Self::Context) {
+        let BridgeIn(envelope) = msg;
+
+        // our own messages coming back, or ones relayed by several bridges
+        if envelope.origin == *NODE_ID || !self.bridged.insert(&envelope) {
+            debug!(
+                "BridgeIn::handle() - dropping duplicate {}/{}",
+                &envelope.origin, envelope.id
+            );
+            return;
+        }
+
+        let origin = envelope.origin;
+
+        match envelope.payload {
+            Payload::Event {
+                room_name,
+                event,
+                log,
+            } => {
+                self.deliver(&room_name, event, log);
+            }
+
+            Payload::Ephemeral {
+                room_name,
+                from,
+                event,
+            } => {
+                if !self.load.shed(&event) {
+                    self.send_ephemeral(&room_name, &from, &event, None)
+                }
+            }
+
+            Payload::Names {
+                names,
+                mut accounts,
+            } => {
+                let now = Instant::now();
+
+                self.remote_names.retain(|_, (node, ..)| *node != origin);
+                for name in names {
+                    let account = accounts.remove(&name);
+                    self.remote_names
+                        .insert(name, (origin.clone(), now, account));
+                }
+            }
+
+            Payload::NameClaimed { name } => {
+                if let Some(other) = self.names.remove(&name) {
+                    other.displaced.do_send(NameClaimed(name)).ok();
+                    self.announce_names();
+                }
+            }
+
+            Payload::Whisper { to_node, to, text } if to_node == *NODE_ID => {
+                if let Some(client) = self.named(&to) {
+                    client.do_send(ChatMessage(text)).ok();
+                }
+            }
+
+            Payload::Forward {
+                to_node,
+                room_name,
+                client_id,
+                event,
+            } if to_node == *NODE_ID => {
+                self.accept_message(room_name, (origin, client_id), event);
+            }
+
+            Payload::Join {
+                to_node,
+                room_name,
+                client_id,
+                client_name,
+            } if to_node == *NODE_ID => {
+                let room = self
+                    .rooms
+                    .entry(room_name.clone())
+                    .or_insert_with(|| Room::new((origin.clone(), client_id)));
+
+                // the joining node may not have a copy of the settings yet
+                if room.settings != RoomSettings::default() {
+                    let settings = room.settings.clone();
+                    self.publish(Payload::RoomSettings {
+                        room_name: room_name.clone(),
+                        settings,
+                    });
+                }
+
+                self.broadcast(&room_name, EventKind::Joined { name: client_name });
+                self.greet(&room_name, (origin, client_id));
+            }
+
+            Payload::Manage {
+                to_node,
+                room_name,
+                client_id,
+                action,
+            } if to_node == *NODE_ID => {
+                let by = client_id.map(|client_id| (origin, client_id));
+
+                if let Err(err) = self.apply_action(&room_name, by.clone(), action) {
+                    if let Some(by) = by {
+                        self.reply(by, &room_name, Reply::error(err));
+                    }
+                }
+            }
+
+            Payload::CheckOwner {
+                to_node,
+                room_name,
+                client_id,
+                post,
+            } if to_node == *NODE_ID => {
+                let owner = self
+                    .rooms
+                    .get(&room_name)
+                    .is_some_and(|room| room.owner == (origin.clone(), client_id));
+                self.publish(Payload::OwnerChecked {
+                    to_node: origin,
+                    room_name,
+                    post,
+                    owner,
+                });
+            }
+
+            Payload::OwnerChecked {
+                to_node,
+                room_name,
+                post,
+                owner,
+            } if to_node == *NODE_ID => {
+                if let Some(answer) = self.owner_checks.remove(&(post, room_name)) {
+                    answer.send(owner).ok();
+                }
+            }
+
+            Payload::Reply {
+                to_node,
+                room_name,
+                client_id,
+                text,
+            } if to_node == *NODE_ID => {
+                self.send_to_client(&room_name, client_id, &text);
+            }
+
+            Payload::EventLog {
+                to_node,
+                room_name,
+                client_id,
+                count,
+            } if to_node == *NODE_ID => {
+                self.send_event_log(&room_name, client_id, count);
+            }
+
+            Payload::Aliased { alias, room_name } => {
+                self.aliases.insert(alias, room_name);
+            }
+
+            Payload::JoinCode { room_name, code } => {
+                self.set_join_code(&room_name, code);
+            }
+
+            Payload::Access { room_name, access } => {
+                self.set_access(&room_name, access);
+            }
+
+            Payload::Heartbeat { members } => {
+                Membership::from_registry().do_send(Gossip { origin, members });
+            }
+
+            Payload::Session { token, state } => {
+                Migrations::from_registry().do_send(StoreSession { token, state });
+            }
+
+            Payload::Draft {
+                account,
+                room_name,
+                draft,
+            } => Drafts::from_registry().do_send(SyncDraft {
+                account,
+                room_name,
+                draft,
+            }),
+
+            Payload::SessionTaken { token } => {
+                // resumed on another node, which joined the rooms again
+                self.leave_detached(&token);
+                Migrations::from_registry().do_send(ForgetSession(token));
+            }
+
+            Payload::Handoff {
+                to_node,
+                room_name,
+                owner,
+                archived,
+                hours,
+                aliases,
+                settings,
+                join_code,
+                questions,
+                canned,
+            } if to_node == *NODE_ID => {
+                debug!(
+                    "BridgeIn::handle() - taking over {} from {}",
+                    &room_name, &origin
+                );
+
+                let room = self
+                    .rooms
+                    .entry(room_name.clone())
+                    .or_insert_with(|| Room::new(owner.clone()));
+                room.owner = owner;
+                room.archived = archived;
+                room.hours = hours;
+                room.closed =
+                    hours.is_some_and(|hours| !hours.is_open_at(utc_minute_of_day()));
+                room.settings = settings;
+                room.join_code = join_code.clone();
+                room.questions = questions;
+                room.canned = canned;
+
+                for alias in aliases {
+                    self.aliases.insert(alias, room_name.clone());
+                }
+                self.set_join_code(&room_name, join_code);
+            }
+
+            Payload::RoomSettings {
+                room_name,
+                settings,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&room_name) {
+                    room.settings = settings;
+                }
+            }
+
+            Payload::Rehome {
+                to_node,
+                room_name,
+                client_id,
+            } if to_node == *NODE_ID => {
+                self.rooms
+                    .entry(room_name)
+                    .or_insert_with(|| Room::new((origin, client_id)));
+            }
+
+            // addressed to another node
+            Payload::Whisper { .. }
+            | Payload::Forward { .. }
+            | Payload::Join { .. }
+            | Payload::Manage { .. }
+            | Payload::CheckOwner { .. }
+            | Payload::OwnerChecked { .. }
+            | Payload::Reply { .. }
+            | Payload::EventLog { .. }
+            | Payload::Handoff { .. }
+            | Payload::Rehome { .. } => {}
+        }
+    }
+}
+
+impl Handler<RegisterName> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: RegisterName, _ctx: &mut Self::Context) -> Self::Result {
+        let RegisterName {
+            name,
+            previous,
+            client,
+            displaced,
+            account,
+            claim,
+        } = msg;
+
+        let taken = match self.names.get(&name) {
+            Some(other) => other.client != client,
+            None => self.remote_names.contains_key(&name),
+        };
+        if taken && !claim {
+            return Err(RoomError::NicknameTaken(name));
+        }
+
+        // bans and mutes go by name, so they mustn't be shed by renaming, nor
+        // a ban once it took the client out of the room
+        let renamed = previous.as_deref().filter(|previous| *previous != name);
+        let restricted = |room_name: &String, room: &Room, name: &str| {
+            self.is_banned(room_name, name) || room.mute_left(name).is_some()
+        };
+        if previous.as_deref() != Some(name.as_str()) {
+            let banned = renamed.and_then(|previous| {
+                self.bans
+                    .keys()
+                    .find(|room_name| self.is_banned(room_name, previous))
+            });
+            if let Some(room_name) = banned {
+                return Err(RoomError::NameRestricted(room_name.clone()));
+            }
+
+            for (room_name, room) in &self.rooms {
+                if !room.clients.values().any(|member| *member == client) {
+                    continue;
+                }
+                if restricted(room_name, room, &name)
+                    || renamed
+                        .is_some_and(|previous| restricted(room_name, room, previous))
+                {
+                    return Err(RoomError::NameRestricted(room_name.clone()));
+                }
+            }
+        }
+
+        if let Some(previous) = previous {
+            if self.named(&previous) == Some(&client) {
+                self.names.remove(&previous);
+            }
+        }
+
+        if taken {
+            match self.names.remove(&name) {
+                Some(other) => {
+                    other.displaced.do_send(NameClaimed(name.clone())).ok();
+                }
+                None => self.publish(Payload::NameClaimed { name: name.clone() }),
+            }
+        }
+
+        let named = NamedClient {
+            client,
+            displaced,
+            account,
+        };
+        self.names.insert(name, named);
+        self.announce_names();
+
+        Ok(())
+    }
+}
+
+impl Handler<UnregisterName> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnregisterName, _ctx: &mut Self::Context) {
+        let UnregisterName(name, client) = msg;
+
+        // the name may have been claimed by a newer session since
+        if self.named(&name) == Some(&client) {
+            self.names.remove(&name);
+            self.announce_names();
+        }
+    }
+}
+
+impl Handler<PrivateMessage> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: PrivateMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let PrivateMessage { from, to, content } = msg;
+        let whisper = Reply::Whisper {
+            from: Some(from),
+            to: None,
+            content,
+        };
+        self.send_to_name(to, whisper)
+    }
+}
+
+impl Handler<SendEphemeral> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendEphemeral, _ctx: &mut Self::Context) {
+        let SendEphemeral {
+            room_name,
+            client_id,
+            from,
+            event,
+        } = msg;
+
+        let member = self
+            .rooms
+            .get(&room_name)
+            .is_some_and(|room| room.clients.contains_key(&client_id));
+        if !member || self.load.shed(&event) {
+            return;
+        }
+
+        self.send_ephemeral(&room_name, &from, &event, Some(client_id));
+        self.publish(Payload::Ephemeral {
+            room_name,
+            from,
+            event,
+        });
+    }
+}
+
+impl Handler<LoadProbe> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoadProbe, _ctx: &mut Self::Context) {
+        let LoadProbe(sent) = msg;
+
+        if let Some(change) = self.load.record(sent.elapsed()) {
+            warn!(
+                "WsChatServer - broadcast latency {}ms, shedding {:?}",
+                change.latency_ms, change.shedding
+            );
+        }
+    }
+}
+
+impl Handler<GetLoad> for WsChatServer {
+    type Result = MessageResult<GetLoad>;
+
+    fn handle(&mut self, _: GetLoad, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(LoadReport {
+            queued_ephemeral: self.outbox.queued_ephemeral(),
+            dropped_frames: self.outbox.dropped(),
+            ..self.load.report()
+        })
+    }
+}
+
+impl Handler<GetGauges> for WsChatServer {
+    type Result = MessageResult<GetGauges>;
+
+    fn handle(&mut self, _: GetGauges, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(ServerGauges {
+            rooms: self.rooms.len(),
+            members: self.rooms.values().map(|room| room.sessions.len()).sum(),
+            detached: self.detached.len(),
+        })
+    }
+}
+
+impl Handler<DigestRooms> for WsChatServer {
+    type Result = MessageResult<DigestRooms>;
+
+    fn handle(&mut self, _: DigestRooms, _ctx: &mut Self::Context) -> Self::Result {
+        let rooms = self
+            .rooms
+            .iter()
+            .filter(|(room_name, _)| self.remote_home(room_name).is_none())
+            .filter(|(_, room)| !room.settings.has_flag(RoomFlag::NoLog))
+            .filter_map(|(room_name, room)| {
+                room.settings.digest.map(|hours| (room_name.clone(), hours))
+            })
+            .collect();
+
+        MessageResult(rooms)
+    }
+}
+
+impl Handler<PostDigest> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PostDigest, _ctx: &mut Self::Context) {
+        let PostDigest { room_name, digest } = msg;
+
+        if self.rooms.contains_key(&room_name) {
+            self.broadcast(&room_name, EventKind::Digest(digest));
+        }
+    }
+}
+
+impl Handler<NotifyAccount> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: NotifyAccount, _ctx: &mut Self::Context) -> Self::Result {
+        let NotifyAccount { account, text } = msg;
+        let text = Reply::Notice { text }.to_json();
+
+        // accounts are per node, so a session of the same name elsewhere is
+        // someone else's
+        let mut sent = false;
+        for named in self.names.values() {
+            if named.account.as_ref() == Some(&account) {
+                named.client.do_send(ChatMessage(text.clone())).ok();
+                sent = true;
+            }
+        }
+
+        if sent {
+            Ok(())
         } else {
-            panic!("ListClients::handle() - 404 room not found")
+            Err(RoomError::UnknownUser(account))
+        }
+    }
+}
+
+impl Handler<Signal> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Signal, _ctx: &mut Self::Context) -> Self::Result {
+        let Signal { from, to, data } = msg;
+
+        let frame = serde_json::json!({ "type": "signal", "from": from, "data": data });
+        self.send_to_name(to, Reply::Frame(frame.to_string()))
+    }
+}
+
+impl Handler<ManageStream> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageStream, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageStream {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Stream(action))
+    }
+}
+
+impl Handler<ListPresence> for WsChatServer {
+    type Result = ResponseFuture<Option<RoomPresence>>;
+
+    fn handle(&mut self, msg: ListPresence, _ctx: &mut Self::Context) -> Self::Result {
+        let ListPresence(room_name) = msg;
+        debug!("ListPresence::handle() - listing room {}", &room_name);
+
+        let room = match self.rooms.get(&room_name) {
+            Some(room) => room,
+            None => return Box::pin(async { None }),
+        };
+        let generation = room.generation;
+        let Members { client_ids, .. } = room.members();
+        let queries: Vec<_> = client_ids
+            .into_iter()
+            .map(|client_id| {
+                let query = room.sessions.get(&client_id).map(|session| {
+                    session
+                        .presence
+                        .send(QueryPresence)
+                        .timeout(PRESENCE_TIMEOUT)
+                });
+                async move {
+                    let presence = match query {
+                        Some(query) => query.await.ok(),
+                        None => None,
+                    };
+                    (client_id, presence)
+                }
+            })
+            .collect();
+
+        Box::pin(async move {
+            let clients = futures::future::join_all(queries).await;
+            Some(RoomPresence {
+                generation,
+                clients,
+            })
+        })
+    }
+}
+
+impl Handler<SubscribeMembership> for WsChatServer {
+    type Result = MessageResult<SubscribeMembership>;
+
+    fn handle(
+        &mut self,
+        msg: SubscribeMembership,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let SubscribeMembership {
+            room_name,
+            client_id,
+            subscriber,
+        } = msg;
+
+        // handled in one go, so no change can fall between the snapshot and
+        // the subscription
+        let members = self
+            .rooms
+            .get_mut(&room_name)
+            .filter(|room| room.clients.contains_key(&client_id))
+            .map(|room| {
+                room.watchers.insert(client_id, subscriber);
+                room.members()
+            });
+
+        MessageResult(members)
+    }
+}
+
+impl Handler<RoomSize> for WsChatServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: RoomSize, _ctx: &mut Self::Context) -> Self::Result {
+        let RoomSize(room_name) = msg;
+        self.rooms
+            .get(&room_name)
+            .map_or(0, |room| room.clients.len())
+    }
+}
+
+impl Handler<ArchiveRoom> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ArchiveRoom, _ctx: &mut Self::Context) -> Self::Result {
+        let ArchiveRoom {
+            room_name,
+            client_id,
+            archived,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Archive(archived))
+    }
+}
+
+impl Handler<Announce> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Announce, _ctx: &mut Self::Context) -> Self::Result {
+        let Announce { room_name, text } = msg;
+        let room_name = self.resolve_room_name(&room_name);
+        if !self.rooms.contains_key(&room_name)
+            && !self.known_rooms.contains_key(&room_name)
+        {
+            return Err(RoomError::NotFound);
+        }
+
+        self.route_action(room_name, None, RoomAction::Announce(text))
+    }
+}
+
+impl Handler<DeleteRoom> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: DeleteRoom, _ctx: &mut Self::Context) -> Self::Result {
+        let DeleteRoom(room_name) = msg;
+        let room_name = self.resolve_room_name(&room_name);
+        if !self.rooms.contains_key(&room_name)
+            && !self.known_rooms.contains_key(&room_name)
+        {
+            return Err(RoomError::NotFound);
+        }
+
+        self.route_action(room_name, None, RoomAction::Delete)
+    }
+}
+
+impl Handler<SetOpeningHours> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(
+        &mut self,
+        msg: SetOpeningHours,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let SetOpeningHours {
+            room_name,
+            client_id,
+            hours,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Hours(hours))
+    }
+}
+
+impl Handler<AddAlias> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: AddAlias, _ctx: &mut Self::Context) -> Self::Result {
+        let AddAlias {
+            alias,
+            room_name,
+            client_id,
+        } = msg;
+        let room_name = self.resolve_room_name(&room_name);
+
+        self.route_action(room_name, Some(client_id), RoomAction::Alias(alias))
+    }
+}
+
+impl Handler<UpdateRoomSettings> for WsChatServer {
+    type Result = Result<Option<RoomSettings>, RoomError>;
+
+    fn handle(
+        &mut self,
+        msg: UpdateRoomSettings,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let UpdateRoomSettings {
+            room_name,
+            client_id,
+            changes,
+        } = msg;
+        let room_name = self.resolve_room_name(&room_name);
+
+        self.route_action(room_name.clone(), client_id, RoomAction::Settings(changes))?;
+
+        Ok(match self.remote_home(&room_name) {
+            Some(_) => None,
+            None => self.rooms.get(&room_name).map(|room| room.settings.clone()),
+        })
+    }
+}
+
+impl Handler<ManageAccess> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageAccess, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageAccess {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Access(action))
+    }
+}
+
+impl Handler<ManageJoinCode> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageJoinCode, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageJoinCode {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::JoinCode(action))
+    }
+}
+
+impl Handler<ManageHand> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageHand, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageHand {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Hand(action))
+    }
+}
+
+impl Handler<ModerateRoom> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ModerateRoom, _ctx: &mut Self::Context) -> Self::Result {
+        let ModerateRoom {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Moderate(action))
+    }
+}
+
+impl Handler<AutoMute> for WsChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AutoMute, _ctx: &mut Self::Context) {
+        let AutoMute {
+            room_name,
+            name,
+            secs,
+        } = msg;
+
+        let action = RoomAction::Moderate(ModAction::Mute(name, secs));
+        if let Err(err) = self.route_action(room_name, None, action) {
+            debug!("WsChatServer - couldn't mute for the blocklist: {}", err);
+        }
+    }
+}
+
+impl Handler<ShowEventLog> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ShowEventLog, _ctx: &mut Self::Context) -> Self::Result {
+        let ShowEventLog {
+            room_name,
+            client_id,
+            count,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::EventLog(count))
+    }
+}
+
+impl Handler<ManageQuestion> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageQuestion, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageQuestion {
+            room_name,
+            client_id,
+            action,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::Question(action))
+    }
+}
+
+/// This node's copy of the room's questions, ranked
+impl Handler<ListQuestions> for WsChatServer {
+    type Result = Option<Vec<Question>>;
+
+    fn handle(&mut self, msg: ListQuestions, _ctx: &mut Self::Context) -> Self::Result {
+        let ListQuestions(room_name) = msg;
+        let room_name = self.resolve_room_name(&room_name);
+
+        self.rooms
+            .get(&room_name)
+            .map(|room| rank_questions(&room.questions))
+    }
+}
+
+impl Handler<ListCanned> for WsChatServer {
+    type Result = Option<BTreeMap<String, String>>;
+
+    fn handle(&mut self, msg: ListCanned, _ctx: &mut Self::Context) -> Self::Result {
+        let ListCanned(room_name) = msg;
+        let room_name = self.resolve_room_name(&room_name);
+
+        self.rooms.get(&room_name).map(|room| room.canned.clone())
+    }
+}
+
+impl Handler<ManageCanned> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: ManageCanned, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageCanned {
+            room_name,
+            client_id,
+            name,
+            content,
+        } = msg;
+
+        self.route_action(
+            room_name,
+            Some(client_id),
+            RoomAction::Canned { name, content },
+        )
+    }
+}
+
+/// Answered from this node's copy of the room's settings, the home node
+/// checks again once the upload is sent
+impl Handler<CheckUpload> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: CheckUpload, _ctx: &mut Self::Context) -> Self::Result {
+        let CheckUpload {
+            room_name,
+            mime,
+            size,
+        } = msg;
+
+        let settings = match self.rooms.get(&room_name) {
+            Some(room) => &room.settings,
+            None => self
+                .known_rooms
+                .get(&room_name)
+                .ok_or(RoomError::NotFound)?,
+        };
+
+        upload_rejection(settings, &mime, size).map_or(Ok(()), Err)
+    }
+}
+
+impl Handler<React> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: React, _ctx: &mut Self::Context) -> Self::Result {
+        let React {
+            room_name,
+            client_id,
+            id,
+            emoji,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::React { id, emoji })
+    }
+}
+
+impl Handler<EditMessage> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: EditMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let EditMessage {
+            room_name,
+            client_id,
+            id,
+            text,
+        } = msg;
+
+        self.route_action(
+            room_name,
+            Some(client_id),
+            RoomAction::EditMessage { id, text },
+        )
+    }
+}
+
+impl Handler<DeleteMessage> for WsChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: DeleteMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let DeleteMessage {
+            room_name,
+            client_id,
+            id,
+        } = msg;
+
+        self.route_action(room_name, Some(client_id), RoomAction::DeleteMessage(id))
+    }
+}
+
+impl Handler<ResolveJoinCode> for WsChatServer {
+    type Result = Option<String>;
+
+    fn handle(
+        &mut self,
+        msg: ResolveJoinCode,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let ResolveJoinCode(code) = msg;
+        self.join_codes.get(&code.to_uppercase()).cloned()
+    }
+}
+
+impl Handler<GetJoinCode> for WsChatServer {
+    type Result = Result<Option<String>, RoomError>;
+
+    fn handle(&mut self, msg: GetJoinCode, _ctx: &mut Self::Context) -> Self::Result {
+        let GetJoinCode(room_name) = msg;
+        let room_name = self.resolve_room_name(&room_name);
+        let code = |act: &Self| {
+            act.join_codes
+                .iter()
+                .find(|(_, room)| **room == room_name)
+                .map(|(code, _)| code.clone())
+        };
+
+        if let Some(code) = code(self) {
+            return Ok(Some(code));
+        }
+        if !self.rooms.contains_key(&room_name)
+            && !self.known_rooms.contains_key(&room_name)
+        {
+            return Err(RoomError::NotFound);
+        }
+
+        self.route_action(
+            room_name.clone(),
+            None,
+            RoomAction::JoinCode(CodeAction::Show),
+        )?;
+        Ok(code(self))
+    }
+}
+
+impl Handler<GetRoomSettings> for WsChatServer {
+    type Result = Option<RoomSettings>;
+
+    fn handle(
+        &mut self,
+        msg: GetRoomSettings,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let GetRoomSettings(room_name) = msg;
+        let room_name = self.resolve_room_name(&room_name);
+
+        match self.rooms.get(&room_name) {
+            Some(room) => Some(room.settings.clone()),
+            None => self.known_rooms.get(&room_name).cloned(),
         }
     }
 }
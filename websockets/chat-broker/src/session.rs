@@ -1,48 +1,3468 @@
-use log::{debug, info};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future;
+use log::{debug, info, warn};
 
 use actix::fut;
 use actix::prelude::*;
-use actix_broker::BrokerIssue;
+use actix_broker::{BrokerIssue, BrokerSubscribe};
 use actix_web_actors::ws;
 
-use crate::message::{
-    ChatMessage, JoinRoom, LeaveRoom, ListClients, ListRooms, SendMessage,
-};
-use crate::server::WsChatServer;
+use crate::accounts::{random_token, Accounts, NotifyLevel};
+use crate::blobs;
+use crate::blocklist::{self, Verdict};
+use crate::captcha::CAPTCHA;
+use crate::chaos::{Chaos, CHAOS, DISCONNECT_CHECK};
+use crate::cluster::{
+    AccessAction, BridgeOut, CodeAction, Envelope, HandAction, ModAction, Payload,
+    QuestionAction, StreamAction,
+};
+use crate::commands;
+use crate::config::config;
+use crate::drafts::{Drafts, MAX_DRAFT_LEN};
+use crate::features::Features;
+use crate::frames::{
+    is_frame, message_frame, message_id, parse_frame, plain_text, system_frame,
+    valid_emoji, valid_name, ClientFrame, DrawOp, Ephemeral, FrameError, MAX_NAME_CHARS,
+};
+use crate::journal::{EventKind, Journal};
+use crate::latency::server_request;
+use crate::message::{
+    AddAlias, ArchiveRoom, AutoMute, BreakoutOpened, ChatMessage, CheckUpload,
+    CountMessage, CrossPost, DeleteMessage, DetachLogin, DetachSession, Drain,
+    EditMessage, EndSession, FilterHit, FindEvents, ForgetReminders, GetDraft,
+    GetNotifyLevel, GetReadPositions, GetRoomSettings, GetTemplate, GetTrust, GetUnread,
+    JoinRoom, LeaveRoom, ListCanned, ListPresence, ListQuestions, ListReminders,
+    ListRooms, ListScheduled, ListSessions, ListTeams, Login, Logout, ManageAccess,
+    ManageCanned, ManageHand, ManageJoinCode, ManageQuestion, ManageStream, ManageTeam,
+    MarkRead, MembershipEvent, ModerateRoom, NameClaimed, PrivateMessage, QueryPresence,
+    React, ReattachSession, RegisterName, Remind, RemovedFromRoom, Reply, Report,
+    ResolveJoinCode, ResumeLogin, RoomHistory, RoomSize, SaveDraft, Schedule,
+    SendAttachment, SendEphemeral, SendForwarded, SendMessage, SetNotifyLevel,
+    SetOpeningHours, ShowEventLog, ShuttingDown, Signal, SignedOut, StarMessage,
+    StoreSession, SubscribeMembership, UnregisterName, Unschedule, UnstarMessage,
+    UnwatchReads, UpdateRoomSettings, WatchReads,
+};
+use crate::metrics;
+use crate::migration::{Migrations, SessionState};
+use crate::ratelimit::RateLimit;
+use crate::reads::{ReadMarkers, ReadPosition};
+use crate::recorder::{Direction, Recording};
+use crate::reminders::{Owner, Reminders};
+use crate::repeats::RepeatGuard;
+use crate::scheduler::{format_delay, parse_delay, Scheduler};
+use crate::server::{
+    parse_page_token, ClientPresence, RoomError, RoomPage, WsChatServer, ROOM_PAGE_SIZE,
+};
+use crate::settings::{RoomFlag, Setting, Visibility};
+use crate::stars::{self, Stars};
+use crate::teams::{TeamAction, Teams};
+use crate::templates::Templates;
+use crate::trust::{self, Capability, Experience, Standing};
+use crate::watch;
+
+/// Messages dropped for going over the config's `message_rate` within this
+/// that count towards its `flood_strikes`
+const FLOOD_WINDOW: Duration = Duration::from_secs(60);
+
+/// Whiteboard ops a client may send at once, on top of the config's
+/// `draw_rate` a second
+const DRAW_BURST: u32 = 60;
+
+/// Clients that haven't sent anything for this long count as idle
+const IDLE_AFTER: Duration = Duration::from_secs(300);
+
+/// A typing indicator goes away this long after the client last said it was
+/// typing
+const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+/// Presence updates go out at most this often, the latest one wins
+const PRESENCE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Milliseconds since the unix epoch, as the server sees it
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis())
+}
+
+/// Rooms every new session joins, `default_rooms` of the config unless the
+/// client asked for one room. An empty list is "lobby" mode: the client has
+/// to `/join` a room before it can chat.
+#[derive(Clone)]
+pub struct DefaultRooms(pub Vec<String>);
+
+impl DefaultRooms {
+    pub fn from_config() -> Self {
+        DefaultRooms(config().default_rooms.clone())
+    }
+}
+
+/// What an upload turns into once complete
+enum UploadKind {
+    Attachment { name: String },
+    VoiceNote { duration_ms: u64 },
+}
+
+/// An inline attachment being received, see `ClientFrame::AttachmentStart`
+struct Upload {
+    id: String,
+    kind: UploadKind,
+    mime: String,
+    size: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct WsChatSession {
+    client_id: usize,
+    /// the room messages are sent to, empty while in the lobby
+    room_name: String,
+    /// the client id in every room joined, so all of them are left on stop
+    memberships: HashMap<String, usize>,
+    default_rooms: Vec<String>,
+    client_name: Option<String>,
+    /// registered account this session is logged in as
+    account: Option<String>,
+    /// the name the session signed in as on connecting, which is kept
+    token_name: Option<String>,
+    /// id of the account's login, set unless the session was migrated
+    login: Option<u64>,
+    /// the client's `User-Agent`, listed by `/sessions`
+    device: Option<String>,
+    /// when the last unanswered ping went out
+    ping_sent: Option<Instant>,
+    /// when the client last sent a frame of any kind
+    heard: Option<Instant>,
+    /// round trip time measured from the last pong
+    rtt: Option<Duration>,
+    /// state migrated from another node, or kept for a client that lost its
+    /// connection, restored instead of joining the default rooms
+    resumed: Option<SessionState>,
+    /// the token `resumed` was claimed with, for the memberships kept for it
+    resumed_from: Option<String>,
+    /// what the client reconnects with to carry on, if it loses the connection
+    resume_token: Option<String>,
+    /// until the session is ended on purpose, by the client or the server
+    resumable: bool,
+    /// whether a close frame went out, those that end without one count as
+    /// dropped connections
+    closed: bool,
+    /// ids of the messages caught up on after resuming, not to send them twice
+    caught_up: HashSet<u64>,
+    /// attachment whose chunks are arriving as binary frames
+    upload: Option<Upload>,
+    /// for whiteboard ops, set up on the first one
+    draw_limit: Option<RateLimit>,
+    /// when presence was last relayed
+    presence_sent: Option<Instant>,
+    /// newest presence held back by the throttle, sent once it allows
+    presence_pending: Option<serde_json::Value>,
+    /// room the client is typing in, and when it last said so
+    typing: Option<(String, Instant)>,
+    /// last fetched from `Accounts`
+    standing: Standing,
+    connected: Option<Instant>,
+    /// when the client last sent a message, command or frame, pongs aside
+    active: Option<Instant>,
+    /// when the room traffic chaos mode last held back goes out
+    chaos_due: Option<Instant>,
+    /// where the frames to and from the client go, with `RECORD_DIR` set
+    recording: Option<Recording>,
+    /// chat messages sent by this session
+    messages: u64,
+    repeats: RepeatGuard,
+    /// for chat messages, set up on the first one
+    message_limit: Option<RateLimit>,
+    /// when messages were dropped for going over it, within `FLOOD_WINDOW`
+    floods: VecDeque<Instant>,
+    /// passed the CAPTCHA challenge, only asked of anonymous sessions
+    human: bool,
+    /// what the client declared it supports in its `hello` frame
+    features: Features,
+    /// replies are sent as JSON only, asked for in the `hello` frame
+    json: bool,
+    /// sent a `hello` frame, from then on any text starting with `{` is taken
+    /// for a JSON frame, typed or not
+    said_hello: bool,
+}
+
+impl WsChatSession {
+    pub fn new(default_rooms: &DefaultRooms) -> Self {
+        WsChatSession {
+            default_rooms: default_rooms.0.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// A session carrying on where one on a draining node, or one that lost
+    /// its client, left off
+    pub fn resume(
+        default_rooms: &DefaultRooms,
+        token: String,
+        state: SessionState,
+    ) -> Self {
+        WsChatSession {
+            resumed: Some(state),
+            resumed_from: Some(token),
+            ..WsChatSession::new(default_rooms)
+        }
+    }
+
+    /// The token the client resumes the session with after losing the
+    /// connection, see `DetachSession`
+    pub fn set_resume_token(&mut self, token: String) {
+        self.resume_token = Some(token);
+    }
+
+    /// Names the session after the token it connected with, see `auth`
+    pub fn sign_in(&mut self, name: String) {
+        self.token_name = Some(name);
+    }
+
+    pub fn set_device(&mut self, device: Option<String>) {
+        self.device = device;
+    }
+
+    /// Getter for self.name, the client's name for this session
+    pub fn client_name(&self) -> String {
+        self.client_name
+            .as_ref()
+            .unwrap_or(&String::from("anon"))
+            .clone()
+    }
+
+    /// The room messages are sent to, empty in the lobby
+    pub fn room_name(&self) -> &str {
+        &self.room_name
+    }
+
+    /// Whether the name comes from the `name` or `sub` claim of the JWT the
+    /// session signed in with, see `auth`, and can't be changed
+    fn signed_in_with_token(&self) -> bool {
+        self.token_name.is_some()
+    }
+
+    /// A password given for a room that doesn't exist yet protects it
+    pub fn join_room(
+        &mut self,
+        room_name: &str,
+        password: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let may_create = Capability::CreateRoom.allowed(self.standing.score);
+        self.join(room_name, may_create, None, password, ctx);
+    }
+
+    /// `/create name [--template template]`, joins a room that mustn't exist
+    /// yet, set up with the template's settings
+    pub fn create_room(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let (room_name, template) = match args.split_once("--template") {
+            Some((room_name, template)) => (room_name.trim(), Some(template.trim())),
+            None => (args.trim(), None),
+        };
+        if room_name.is_empty() || template == Some("") {
+            self.reply(
+                ctx,
+                Reply::error("usage: /create name [--template template]"),
+            );
+            return;
+        }
+        if !self.check_capability(Capability::CreateRoom, ctx) {
+            return;
+        }
+
+        let template = match template {
+            Some(template) => template.to_owned(),
+            None => {
+                self.join(room_name, true, Some(Vec::new()), None, ctx);
+                return;
+            }
+        };
+        let room_name = room_name.to_owned();
+
+        Templates::from_registry()
+            .send(GetTemplate(template.clone()))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Some(settings)) => {
+                        act.join(&room_name, true, Some(settings), None, ctx)
+                    }
+                    Ok(None) => act.reply(
+                        ctx,
+                        Reply::error(format!("no such template: {}", template)),
+                    ),
+                    Err(err) => act.request_failed(ctx, err, "creating room"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn join(
+        &mut self,
+        room_name: &str,
+        may_create: bool,
+        template: Option<Vec<Setting>>,
+        password: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        // already a member, a room being created from a template mustn't exist
+        if template.is_none() && self.memberships.contains_key(room_name) {
+            self.switch_room(room_name, ctx);
+            return;
+        }
+
+        // Then send a join message for the new room
+        let join_msg = JoinRoom {
+            room_name: room_name.to_owned(),
+            client_name: self.client_name(),
+            client: ctx.address().recipient(),
+            may_create,
+            template,
+            account: self.account.clone(),
+            removed: ctx.address().recipient(),
+            breakout: ctx.address().recipient(),
+            presence: ctx.address().recipient(),
+            password,
+            rejoining: false,
+        };
+
+        let requested = room_name.to_owned();
+        server_request("JoinRoom", join_msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok((id, room_name))) => {
+                        // joined again through an alias
+                        if let Some(old) = act.memberships.insert(room_name.clone(), id)
+                        {
+                            act.issue_system_async(LeaveRoom(
+                                room_name.clone(),
+                                old,
+                                act.client_name(),
+                            ));
+                        }
+                        act.client_id = id;
+                        act.room_name = room_name.clone();
+                        act.greet_unread(room_name, ctx);
+                    }
+                    Ok(Err(RoomError::Full(overflow))) => {
+                        let text = format!("!!! {}", RoomError::Full(overflow.clone()));
+                        let mut fields = serde_json::Map::new();
+                        fields
+                            .insert("overflow".to_owned(), serde_json::json!(overflow));
+                        let frame =
+                            system_frame("full", Some(&requested), &text, fields);
+                        act.reply(ctx, Reply::Frame(frame));
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "joining room"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Makes a room already joined the one messages are sent to
+    pub fn switch_room(
+        &mut self,
+        room_name: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match self.memberships.get(room_name) {
+            Some(client_id) => {
+                self.client_id = *client_id;
+                self.room_name = room_name.to_owned();
+                self.reply(ctx, Reply::notice(format!("switched to {}", room_name)));
+            }
+            None => self.reply(
+                ctx,
+                Reply::error(format!(
+                    "you are not in room {}, /join it first",
+                    room_name
+                )),
+            ),
+        }
+    }
+
+    /// Leaves a room, the current one if `room_name` is empty. Leaving the
+    /// current room switches to another one joined, or to the lobby.
+    pub fn leave_room(&mut self, room_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = match room_name {
+            "" => self.room_name.clone(),
+            room_name => room_name.to_owned(),
+        };
+        if matches!(&self.typing, Some((typing, _)) if *typing == room_name) {
+            self.stop_typing();
+        }
+        let client_id = match self.memberships.remove(&room_name) {
+            Some(client_id) => client_id,
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(format!("you are not in room {}", room_name)),
+                );
+                return;
+            }
+        };
+
+        self.issue_system_async(LeaveRoom(
+            room_name.clone(),
+            client_id,
+            self.client_name(),
+        ));
+        self.reply(ctx, Reply::notice(format!("left {}", room_name)));
+
+        if room_name == self.room_name {
+            self.leave_current(ctx);
+        }
+    }
+
+    /// Switches to another room joined once the current one is gone, or to
+    /// the lobby
+    fn leave_current(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let next = self
+            .memberships
+            .iter()
+            .min_by(|a, b| a.0.cmp(b.0))
+            .map(|(room_name, client_id)| (room_name.clone(), *client_id));
+        match next {
+            Some((room_name, client_id)) => {
+                self.client_id = client_id;
+                self.room_name = room_name;
+                self.reply(
+                    ctx,
+                    Reply::notice(format!("switched to {}", self.room_name)),
+                );
+            }
+            None => {
+                self.client_id = 0;
+                self.room_name.clear();
+                self.reply(
+                    ctx,
+                    Reply::notice(
+                        "you are in the lobby, use /join name to enter a room",
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Sends a reply to the client, as JSON if it asked for that
+    pub fn reply(&self, ctx: &mut ws::WebsocketContext<Self>, reply: impl Into<Reply>) {
+        let reply = reply.into();
+        if self.json {
+            self.send_text(ctx, reply.to_json());
+        } else {
+            self.send_text(ctx, reply.text());
+        }
+    }
+
+    /// Every frame to the client goes through here or `close`, to be recorded
+    fn send_text(&self, ctx: &mut ws::WebsocketContext<Self>, text: String) {
+        if let Some(recording) = &self.recording {
+            recording.text(Direction::Out, &text);
+        }
+        ctx.text(text);
+    }
+
+    /// Ending the session on purpose, so it can't be resumed
+    fn close(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        reason: Option<ws::CloseReason>,
+    ) {
+        if let Some(recording) = &self.recording {
+            recording.close(Direction::Out, reason.as_ref());
+        }
+        self.resumable = false;
+        self.closed = true;
+        ctx.close(reason);
+    }
+
+    /// Answers a request that didn't get through, `failed` naming what it
+    /// was for, e.g. "listing rooms". Timeouts are worth retrying.
+    fn request_failed(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        err: MailboxError,
+        failed: &str,
+    ) {
+        if let MailboxError::Timeout = err {
+            let mut fields = serde_json::Map::new();
+            fields.insert("failed".to_owned(), failed.into());
+            let frame = system_frame("busy", None, "server busy, try again", fields);
+            self.reply(ctx, Reply::Frame(frame));
+        } else {
+            self.reply(ctx, Reply::error(format!("{} failed", failed)));
+        }
+    }
+
+    pub fn login(
+        &mut self,
+        name: &str,
+        password: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let name = name.to_owned();
+
+        let previous = match (&self.account, self.login) {
+            (Some(account), Some(login)) => Some((account.clone(), login)),
+            _ => None,
+        };
+
+        Accounts::from_registry()
+            .send(Login {
+                name: name.clone(),
+                password: password.to_owned(),
+                session: ctx.address().recipient(),
+                device: self.device.clone(),
+                previous,
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(login)) => {
+                        if let (Some(account), Some(login)) =
+                            (act.account.take(), act.login.take())
+                        {
+                            ReadMarkers::from_registry()
+                                .do_send(UnwatchReads(account, login));
+                        }
+                        act.logged_in(name.clone(), login, ctx);
+
+                        act.reply(ctx, Reply::notice(format!("logged in as: {}", name)));
+                        // a signed in session keeps the token's name, which
+                        // is registered again with the account
+                        let name = act.token_name.clone().unwrap_or(name);
+                        act.set_name(name, true, ctx);
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "login"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Signs a resumed session back in to the account whose login was
+    /// detached with `token`, so it counts against the session policy and is
+    /// listed in `/sessions` like any login, then restores the rest of `state`
+    fn resume_login(
+        &mut self,
+        token: String,
+        state: SessionState,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        Accounts::from_registry()
+            .send(ResumeLogin {
+                token,
+                session: ctx.address().recipient(),
+                device: self.device.clone(),
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok((account, login))) => act.logged_in(account, login, ctx),
+                    // e.g. the login was ended meanwhile, or detached on
+                    // another node
+                    Ok(Err(_)) => act.reply(
+                        ctx,
+                        Reply::error("not logged back in, use /login to log in again"),
+                    ),
+                    Err(err) => act.request_failed(ctx, err, "login"),
+                }
+                // after, so the name and the rooms rejoined see the account
+                act.restore(state, ctx);
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Logs the session out of its account, keeping the login for the session
+    /// resumed with `token`
+    fn detach_login(&mut self, token: String) {
+        if let (Some(account), Some(login)) = (self.account.take(), self.login.take()) {
+            ReadMarkers::from_registry().do_send(UnwatchReads(account.clone(), login));
+            Accounts::from_registry().do_send(DetachLogin {
+                account,
+                login,
+                token,
+            });
+        }
+    }
+
+    fn logged_in(
+        &mut self,
+        account: String,
+        login: u64,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        ReadMarkers::from_registry().do_send(WatchReads {
+            account: account.clone(),
+            login,
+            session: ctx.address().recipient(),
+        });
+        self.account = Some(account);
+        self.login = Some(login);
+    }
+
+    /// Registers `alias` as another name for `room_name`, owners only
+    pub fn add_alias(
+        &mut self,
+        alias: &str,
+        room_name: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let client_id = match self.memberships.get(room_name) {
+            Some(client_id) => *client_id,
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(format!("you are not in room {}", room_name)),
+                );
+                return;
+            }
+        };
+
+        let alias = alias.to_owned();
+        let msg = AddAlias {
+            alias: alias.clone(),
+            room_name: room_name.to_owned(),
+            client_id,
+        };
+
+        server_request("AddAlias", msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.reply(ctx, Reply::notice(format!("alias added: {}", alias)))
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "adding alias"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn archive_room(
+        &mut self,
+        archived: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ArchiveRoom {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            archived,
+        };
+
+        server_request("ArchiveRoom", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "archiving room"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn set_opening_hours(
+        &mut self,
+        hours: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let hours = match hours {
+            "off" => None,
+            hours => match hours.parse() {
+                Ok(hours) => Some(hours),
+                Err(err) => {
+                    self.reply(ctx, Reply::error(err));
+                    return;
+                }
+            },
+        };
+
+        let msg = SetOpeningHours {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            hours,
+        };
+
+        server_request("SetOpeningHours", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "setting opening hours"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Shows or sets which messages of the current room the logged in account
+    /// is notified of while offline
+    pub fn notify(&mut self, level: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(
+                        "log in first, notifications are kept with your account",
+                    ),
+                );
+                return;
+            }
+        };
+
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let room_name = self.room_name.clone();
+
+        if level.is_empty() {
+            Accounts::from_registry()
+                .send(GetNotifyLevel(account, room_name.clone()))
+                .timeout(config().request_timeout())
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    if let Ok(level) = res {
+                        act.reply(
+                            ctx,
+                            Reply::notice(format!(
+                                "notifications for {}: {}",
+                                room_name, level
+                            )),
+                        );
+                    }
+
+                    fut::ready(())
+                })
+                .wait(ctx);
+            return;
+        }
+
+        let level: NotifyLevel = match level.parse() {
+            Ok(level) => level,
+            Err(err) => {
+                self.reply(ctx, Reply::error(err));
+                return;
+            }
+        };
+
+        let msg = SetNotifyLevel {
+            account,
+            room_name: room_name.clone(),
+            level,
+        };
+
+        Accounts::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(
+                        ctx,
+                        Reply::notice(format!(
+                            "notifications for {}: {}",
+                            room_name, level
+                        )),
+                    ),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "setting notifications"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Shows the current room's settings, or with `key value` changes one,
+    /// owners only
+    pub fn room_settings(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let (key, value) = match args.trim() {
+            "" => {
+                server_request(
+                    "GetRoomSettings",
+                    GetRoomSettings(self.room_name.clone()),
+                )
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    for line in res.ok().flatten().unwrap_or_default().lines() {
+                        act.reply(ctx, Reply::notice(line));
+                    }
+
+                    fut::ready(())
+                })
+                .wait(ctx);
+                return;
+            }
+            args => args.split_once(' ').unwrap_or((args, "")),
+        };
+
+        let change = match Setting::from_command(key, value) {
+            Ok(change) => change,
+            Err(err) => {
+                self.reply(ctx, Reply::error(err));
+                return;
+            }
+        };
+
+        let msg = UpdateRoomSettings {
+            room_name: self.room_name.clone(),
+            client_id: Some(self.client_id),
+            changes: vec![change],
+        };
+
+        server_request("UpdateRoomSettings", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "changing settings"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Names the session, if no other client has the name. `claim` takes it
+    /// over anyway for logins and resumed sessions, and whoever had it is
+    /// left without a name, see `NameClaimed`.
+    pub fn set_name(
+        &mut self,
+        name: String,
+        claim: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if !claim && self.signed_in_with_token() {
+            let err = "your name comes from the token you signed in with";
+            self.reply(ctx, Reply::error(err));
+            return;
+        }
+        if !valid_name(&name) {
+            let err = format!(
+                "a name is 1 to {} characters, without blanks or ':'",
+                MAX_NAME_CHARS
+            );
+            self.reply(ctx, Reply::error(err));
+            return;
+        }
+
+        server_request(
+            "RegisterName",
+            RegisterName {
+                name: name.clone(),
+                previous: self.client_name.clone(),
+                client: ctx.address().recipient(),
+                displaced: ctx.address().recipient(),
+                account: self.account.clone(),
+                claim,
+            },
+        )
+        .into_actor(self)
+        .then(move |res, act, ctx| {
+            match res {
+                Ok(Ok(())) => {
+                    if !claim {
+                        act.reply(
+                            ctx,
+                            Reply::notice(format!("name changed to: {}", name)),
+                        );
+                    }
+                    act.client_name = Some(name);
+                    act.refresh_trust(ctx);
+                }
+                Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                Err(err) => act.request_failed(ctx, err, "changing name"),
+            }
+
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    /// Fetches the trust score of the current name, and account if logged in
+    fn refresh_trust(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let msg = GetTrust {
+            name: self.client_name(),
+            account: self.account.clone(),
+        };
+
+        Accounts::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, _ctx| {
+                if let Ok(standing) = res {
+                    act.standing = standing;
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Shows the trust score and what it allows
+    pub fn show_trust(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let capabilities: Vec<String> = [
+            ("links", Capability::Links),
+            ("uploads", Capability::Uploads),
+            ("creating rooms", Capability::CreateRoom),
+        ]
+        .iter()
+        .map(|(what, capability)| {
+            let allowed = if capability.allowed(self.standing.score) {
+                "yes"
+            } else {
+                "no"
+            };
+            format!("{} {} (needs {})", what, allowed, capability.threshold())
+        })
+        .collect();
+
+        self.reply(
+            ctx,
+            Reply::notice(format!(
+                "trust score: {}, {}",
+                self.standing.score,
+                capabilities.join(", ")
+            )),
+        );
+
+        let experience = self.experience();
+        if experience.is_newcomer() {
+            self.reply(
+                ctx,
+                Reply::notice(format!(
+                    "new user: {} for links and uploads",
+                    experience.waiting()
+                )),
+            );
+        }
+    }
+
+    /// Settles what the session sends the client, answering with the features
+    /// both sides support as `{"type":"hello","features":["attachments",..]}`
+    fn hello(
+        &mut self,
+        features: &[String],
+        json: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.features = Features::negotiate(features);
+        self.json = json;
+        self.said_hello = true;
+
+        let reply = serde_json::json!({
+            "type": "hello",
+            "features": self.features,
+            "json": self.json,
+        });
+        self.reply(ctx, Reply::Frame(reply.to_string()));
+    }
+
+    /// Checks a CAPTCHA token with the provider, letting the session chat
+    /// if it passed
+    pub fn captcha(&mut self, token: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let captcha = match &*CAPTCHA {
+            Some(captcha) => captcha,
+            None => {
+                self.reply(ctx, Reply::error("no captcha is needed here"));
+                return;
+            }
+        };
+
+        captcha
+            .verify(token)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(()) => {
+                        act.human = true;
+                        act.reply(ctx, Reply::notice("captcha passed"));
+                    }
+                    Err(err) => act.reply(ctx, Reply::error(err)),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Account age and messages if logged in, otherwise the session's
+    fn experience(&self) -> Experience {
+        match self.standing.account_created {
+            Some(created) => Experience {
+                age: Duration::from_millis(
+                    (unix_millis() as u64).saturating_sub(created),
+                ),
+                messages: self.standing.account_messages,
+            },
+            None => Experience {
+                age: self
+                    .connected
+                    .map_or(Duration::ZERO, |since| since.elapsed()),
+                messages: self.messages,
+            },
+        }
+    }
+
+    /// Whether the client may use `capability`, replying with why not if it
+    /// can't
+    fn check_capability(
+        &self,
+        capability: Capability,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        if !capability.allowed(self.standing.score) {
+            self.reply(ctx, Reply::error(capability.refusal()));
+            return false;
+        }
+
+        let experience = self.experience();
+        if capability.closed_to_newcomers() && experience.is_newcomer() {
+            self.reply(ctx, Reply::error(experience.refusal(capability)));
+            return false;
+        }
+
+        true
+    }
+
+    /// `/team` lists the account's teams, `/team create|leave name`, and for
+    /// team admins `/team add|remove|promote name account` and
+    /// `/team room name room`
+    pub fn team(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(ctx, Reply::error("log in first to use teams"));
+                return;
+            }
+        };
+
+        let args: Vec<&str> = args.split_whitespace().collect();
+        let (team, action) = match args.as_slice() {
+            [] => {
+                self.list_teams(account, ctx);
+                return;
+            }
+            ["create", team] => (team, TeamAction::Create),
+            ["leave", team] => (team, TeamAction::Leave),
+            ["add", team, name] => (team, TeamAction::Add((*name).to_owned())),
+            ["remove", team, name] => (team, TeamAction::Remove((*name).to_owned())),
+            ["promote", team, name] => (team, TeamAction::Promote((*name).to_owned())),
+            ["room", team, room_name] => {
+                let msg = ManageTeam {
+                    account,
+                    team: (*team).to_owned(),
+                    action: TeamAction::Room((*room_name).to_owned()),
+                };
+                self.claim_room(msg, ctx);
+                return;
+            }
+            _ => {
+                self.reply(ctx, Reply::error("usage: /team [create|leave name | add|remove|promote name account | room name room]"));
+                return;
+            }
+        };
+
+        let msg = ManageTeam {
+            account,
+            team: (*team).to_owned(),
+            action,
+        };
+        self.manage_team(msg, ctx);
+    }
+
+    fn manage_team(&mut self, msg: ManageTeam, ctx: &mut ws::WebsocketContext<Self>) {
+        Teams::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(reply)) => act.reply(ctx, Reply::notice(reply)),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "managing team"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Teams only claim rooms that don't exist yet, so admins can't lock
+    /// members out of somebody else's room
+    fn claim_room(&mut self, msg: ManageTeam, ctx: &mut ws::WebsocketContext<Self>) {
+        let room_name = match &msg.action {
+            TeamAction::Room(room_name) => room_name.clone(),
+            _ => return self.manage_team(msg, ctx),
+        };
+
+        server_request("GetRoomSettings", GetRoomSettings(room_name))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(None) => act.manage_team(msg, ctx),
+                    Ok(Some(_)) => act.reply(
+                        ctx,
+                        Reply::error(
+                            "room already exists, teams can only claim new rooms",
+                        ),
+                    ),
+                    Err(err) => act.request_failed(ctx, err, "managing team"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/sessions` lists the account's sessions, `/sessions end id` signs one
+    /// out
+    pub fn sessions(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error("log in first, sessions are those of your account"),
+                );
+                return;
+            }
+        };
+
+        let args: Vec<&str> = args.split_whitespace().collect();
+        let login = match args.as_slice() {
+            [] => {
+                self.list_sessions(account, ctx);
+                return;
+            }
+            ["end", id] => match id.parse() {
+                Ok(login) => login,
+                Err(_) => {
+                    self.reply(
+                        ctx,
+                        Reply::error(format!("invalid session id: {:?}", id)),
+                    );
+                    return;
+                }
+            },
+            _ => {
+                self.reply(ctx, Reply::error("usage: /sessions [end id]"));
+                return;
+            }
+        };
+
+        Accounts::from_registry()
+            .send(EndSession { account, login })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(
+                        ctx,
+                        Reply::notice(format!("signed out session {}", login)),
+                    ),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "signing out"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn list_sessions(&mut self, account: String, ctx: &mut ws::WebsocketContext<Self>) {
+        Accounts::from_registry()
+            .send(ListSessions(account))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                let now = unix_millis() as u64;
+                for device in res.unwrap_or_default() {
+                    let ago = Duration::from_millis(now.saturating_sub(device.since));
+                    let this = if act.login == Some(device.id) {
+                        " (this session)"
+                    } else {
+                        ""
+                    };
+                    act.reply(
+                        ctx,
+                        Reply::notice(format!(
+                            "session {}: {}, signed in {} ago{}",
+                            device.id,
+                            device.device.as_deref().unwrap_or("unknown device"),
+                            format_delay(ago),
+                            this
+                        )),
+                    );
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn list_teams(&mut self, account: String, ctx: &mut ws::WebsocketContext<Self>) {
+        Teams::from_registry()
+            .send(ListTeams(account.clone()))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let teams = res.unwrap_or_default();
+                if teams.is_empty() {
+                    act.reply(
+                        ctx,
+                        Reply::notice(
+                            "you are in no team, /team create name starts one",
+                        ),
+                    );
+                }
+                for team in teams {
+                    let role = if team.admins.contains(&account) {
+                        "admin"
+                    } else {
+                        "member"
+                    };
+                    let rooms: Vec<&str> =
+                        team.rooms.iter().map(String::as_str).collect();
+                    act.reply(
+                        ctx,
+                        Reply::notice(format!(
+                            "{} ({}, {} members): {}",
+                            team.name,
+                            role,
+                            team.members.len(),
+                            rooms.join(", ")
+                        )),
+                    );
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/report name reason`, by logged in clients only so reports can't be
+    /// made up under throwaway names
+    pub fn report(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let reporter = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(ctx, Reply::error("log in first to report someone"));
+                return;
+            }
+        };
+
+        let (name, reason) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        if name.is_empty() || name == "anon" {
+            self.reply(ctx, Reply::error("usage: /report name reason"));
+            return;
+        }
+
+        let name = name.to_owned();
+        let msg = Report {
+            reporter,
+            name: name.clone(),
+            reason: reason.trim().to_owned(),
+        };
+
+        Accounts::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.reply(ctx, Reply::notice(format!("reported {}", name)))
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "reporting"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// The content as it may be posted, masked by the blocklist, `None`
+    /// replying with why if it can't be: blocked patterns count against the
+    /// client's trust. Watch rules see it either way.
+    fn check_content<'a>(
+        &self,
+        content: &'a str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<Cow<'a, str>> {
+        watch::watch(content, || {
+            format!(
+                "a message from {} in {}",
+                self.client_name(),
+                self.room_name
+            )
+        });
+
+        if !self.check_human(ctx) {
+            return None;
+        }
+
+        self.filter_content(content, ctx)
+    }
+
+    /// Whether the session may send anything to others, asking it to pass
+    /// the CAPTCHA first if it isn't logged in and hasn't yet
+    fn check_human(&self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let captcha = match &*CAPTCHA {
+            Some(captcha) if self.account.is_none() && !self.human => captcha,
+            _ => return true,
+        };
+
+        let mut fields = serde_json::Map::new();
+        fields.insert("provider".to_owned(), captcha.provider.name().into());
+        fields.insert("site_key".to_owned(), captcha.site_key.clone().into());
+
+        self.reply(
+            ctx,
+            Reply::Frame(system_frame(
+                "captcha",
+                None,
+                "complete the captcha before sending messages, or log in",
+                fields,
+            )),
+        );
+        false
+    }
+
+    /// The blocklist and the trust needed for links, the part of
+    /// `check_content` for any text others get to see
+    fn filter_content<'a>(
+        &self,
+        content: &'a str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<Cow<'a, str>> {
+        let mute = match blocklist::check(content) {
+            Verdict::Post(content) => {
+                if trust::has_link(&content)
+                    && !self.check_capability(Capability::Links, ctx)
+                {
+                    return None;
+                }
+                return Some(content);
+            }
+            Verdict::Warn(pattern) => {
+                self.reply(
+                    ctx,
+                    Reply::error(format!(
+                        "\"{}\" isn't allowed, the message wasn't sent",
+                        pattern
+                    )),
+                );
+                return None;
+            }
+            Verdict::Drop => None,
+            Verdict::Mute(mute) => Some(mute),
+        };
+
+        if let Some(name) = &self.client_name {
+            Accounts::from_registry().do_send(FilterHit(name.clone()));
+        }
+        match mute {
+            Some(mute) if !self.room_name.is_empty() => {
+                WsChatServer::from_registry().do_send(AutoMute {
+                    room_name: self.room_name.clone(),
+                    name: self.client_name(),
+                    secs: mute.as_secs(),
+                });
+                self.reply(
+                    ctx,
+                    Reply::error(format!(
+                        "message blocked by the content filter, muted for {}",
+                        format_delay(mute)
+                    )),
+                );
+            }
+            _ => self.reply(ctx, Reply::error("message blocked by the content filter")),
+        }
+
+        None
+    }
+
+    pub fn private_message(
+        &mut self,
+        to: &str,
+        content: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if !self.check_rate(ctx) {
+            return;
+        }
+        watch::watch(content, || {
+            format!("a whisper from {} to {}", self.client_name(), to)
+        });
+        if !self.check_human(ctx) {
+            return;
+        }
+        let content = match self.filter_content(content, ctx) {
+            Some(content) => content.into_owned(),
+            None => return,
+        };
+
+        let msg = PrivateMessage {
+            from: self.client_name(),
+            to: to.to_owned(),
+            content: content.clone(),
+        };
+        let echo = Reply::Whisper {
+            from: None,
+            to: Some(to.to_owned()),
+            content,
+        };
+
+        server_request("PrivateMessage", msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.reply(ctx, echo),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "sending private message"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Joins the room a join code points at
+    pub fn join_by_code(&mut self, code: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        server_request("ResolveJoinCode", ResolveJoinCode(code.to_owned()))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some(room_name)) => act.join_room(&room_name, None, ctx),
+                    Ok(None) => act.reply(ctx, Reply::error("unknown join code")),
+                    Err(err) => act.request_failed(ctx, err, "joining room"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Shows (creating one if needed), rotates or removes the current room's
+    /// join code, owners only
+    pub fn join_code(
+        &mut self,
+        action: CodeAction,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ManageJoinCode {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ManageJoinCode", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "join code update"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Speaker queue of a moderated room: raising or lowering a hand, and
+    /// for owners listing the hands and calling on one
+    pub fn hand(&mut self, action: HandAction, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ManageHand {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ManageHand", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "speaker queue update"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Who may join the current room, for its owner
+    pub fn access(
+        &mut self,
+        action: AccessAction,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ManageAccess {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ManageAccess", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "changing room access"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Kicking, banning and muting members of the current room, for its owner
+    pub fn moderate(&mut self, action: ModAction, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ModerateRoom {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ModerateRoom", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "moderation"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// The current room's last events but its chat, for its owner
+    pub fn event_log(&mut self, count: usize, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ShowEventLog {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            count,
+        };
+
+        server_request("ShowEventLog", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "showing the event log"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Asking, upvoting and answering questions in a room in Q&A mode
+    pub fn question(
+        &mut self,
+        action: QuestionAction,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ManageQuestion {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ManageQuestion", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "question update"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/canned` lists the current room's canned responses, `/canned name`
+    /// posts one like any message, and owners save and remove them with
+    /// `/canned add name text` and `/canned remove name`
+    pub fn canned(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let args = args.trim();
+        let mut words = args.splitn(3, ' ');
+        let change = match (words.next(), words.next(), words.next()) {
+            (Some("add"), Some(name), Some(text)) if !text.trim().is_empty() => {
+                let text = match self.check_content(text.trim(), ctx) {
+                    Some(text) => text.into_owned(),
+                    None => return,
+                };
+                Some((name, Some(text)))
+            }
+            (Some("remove"), Some(name), None) => Some((name, None)),
+            (Some("add" | "remove"), _, _) => {
+                self.reply(
+                    ctx,
+                    Reply::error("usage: /canned add name text or /canned remove name"),
+                );
+                return;
+            }
+            _ => None,
+        };
+
+        if let Some((name, content)) = change {
+            let msg = ManageCanned {
+                room_name: self.room_name.clone(),
+                client_id: self.client_id,
+                name: name.to_owned(),
+                content,
+            };
+
+            server_request("ManageCanned", msg)
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                        Err(err) => {
+                            act.request_failed(ctx, err, "saving canned response")
+                        }
+                    }
+
+                    fut::ready(())
+                })
+                .wait(ctx);
+            return;
+        }
+
+        let name = args.to_owned();
+        server_request("ListCanned", ListCanned(self.room_name.clone()))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let canned = match res {
+                    Ok(Some(canned)) => canned,
+                    Ok(None) => {
+                        act.reply(
+                            ctx,
+                            Reply::error("you are not in a room, use /join name"),
+                        );
+                        return fut::ready(());
+                    }
+                    Err(err) => {
+                        act.request_failed(ctx, err, "listing canned responses");
+                        return fut::ready(());
+                    }
+                };
+
+                if name.is_empty() && canned.is_empty() {
+                    act.reply(
+                        ctx,
+                        Reply::notice("no canned responses yet, /canned add name text"),
+                    );
+                } else if name.is_empty() {
+                    let names: Vec<&str> = canned.keys().map(String::as_str).collect();
+                    act.reply(
+                        ctx,
+                        Reply::notice(format!("canned responses: {}", names.join(", "))),
+                    );
+                } else {
+                    match canned.get(&name) {
+                        Some(text) => act.send_msg(text, ctx),
+                        None => act.reply(
+                            ctx,
+                            Reply::error(format!("no canned response {}", name)),
+                        ),
+                    }
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// The room's questions, open ones ranked by upvotes
+    pub fn list_questions(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        server_request("ListQuestions", ListQuestions(self.room_name.clone()))
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some(questions)) if questions.is_empty() => {
+                        act.reply(ctx, Reply::notice("no questions yet"))
+                    }
+                    Ok(Some(questions)) => {
+                        for question in questions {
+                            let state = if question.answered {
+                                "answered".to_owned()
+                            } else {
+                                format!("{} votes", question.votes)
+                            };
+                            act.reply(
+                                ctx,
+                                Reply::notice(format!(
+                                    "[question {}] ({}) {}: {}",
+                                    question.id, state, question.from, question.text
+                                )),
+                            );
+                        }
+                    }
+                    Ok(None) => act.reply(
+                        ctx,
+                        Reply::error("you are not in a room, use /join name"),
+                    ),
+                    Err(err) => act.request_failed(ctx, err, "listing questions"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/schedule 15m Standup in 5!` posts the message to the current room
+    /// once the delay is up
+    pub fn schedule(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let (delay, content) = match args.trim().split_once(' ') {
+            Some((delay, content)) if !content.trim().is_empty() => (delay, content),
+            _ => {
+                self.reply(ctx, Reply::error("usage: /schedule 15m message"));
+                return;
+            }
+        };
+        let delay = match parse_delay(delay) {
+            Ok(delay) => delay,
+            Err(err) => {
+                self.reply(ctx, Reply::error(err));
+                return;
+            }
+        };
+        let content = match self.check_content(content, ctx) {
+            Some(content) => content.trim().to_owned(),
+            None => return,
+        };
+
+        let msg = Schedule {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            owner: self.owner(ctx),
+            author: self.client_name(),
+            content,
+            delay,
+        };
+
+        Scheduler::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(id)) => act.reply(
+                        ctx,
+                        Reply::notice(format!(
+                            "scheduled message {} for {} in {}",
+                            id,
+                            act.room_name,
+                            format_delay(delay)
+                        )),
+                    ),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "scheduling"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// What reminders and scheduled messages set from this session belong to,
+    /// the account if logged in, otherwise the session itself
+    fn owner(&self, ctx: &mut ws::WebsocketContext<Self>) -> Owner {
+        match &self.account {
+            Some(account) => Owner::Account(account.clone()),
+            None => Owner::Session(ctx.address().recipient()),
+        }
+    }
+
+    /// `/remind me 2h check the oven` whispers the text back once the delay is
+    /// up, to every session of the account if logged in, as soon as one is
+    /// back if none is connected then
+    pub fn remind(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let mut args = args.trim().splitn(3, ' ');
+        let (delay, text) = match (args.next(), args.next(), args.next()) {
+            (Some("me"), Some(delay), Some(text)) if !text.trim().is_empty() => {
+                (delay, text.trim())
+            }
+            _ => {
+                self.reply(ctx, Reply::error("usage: /remind me 2h text"));
+                return;
+            }
+        };
+        let delay = match parse_delay(delay) {
+            Ok(delay) => delay,
+            Err(err) => {
+                self.reply(ctx, Reply::error(err));
+                return;
+            }
+        };
+
+        let msg = Remind {
+            owner: self.owner(ctx),
+            text: text.to_owned(),
+            delay,
+        };
+
+        Reminders::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(id)) => act.reply(
+                        ctx,
+                        Reply::notice(format!(
+                            "reminder {} set for {} from now",
+                            id,
+                            format_delay(delay)
+                        )),
+                    ),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "setting the reminder"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn list_reminders(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        Reminders::from_registry()
+            .send(ListReminders(self.owner(ctx)))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(reminders) if reminders.is_empty() => {
+                        act.reply(ctx, Reply::notice("no reminders"))
+                    }
+                    Ok(reminders) => {
+                        let now = unix_millis() as u64;
+                        for reminder in reminders {
+                            let left = reminder.due.saturating_sub(now);
+                            act.reply(
+                                ctx,
+                                Reply::notice(format!(
+                                    "[{}] in {}: {}",
+                                    reminder.id,
+                                    format_delay(Duration::from_millis(left)),
+                                    reminder.text
+                                )),
+                            );
+                        }
+                    }
+                    Err(err) => act.request_failed(ctx, err, "listing reminders"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn list_scheduled(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        Scheduler::from_registry()
+            .send(ListScheduled(self.owner(ctx)))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(scheduled) if scheduled.is_empty() => {
+                        act.reply(ctx, Reply::notice("no scheduled messages"))
+                    }
+                    Ok(scheduled) => {
+                        let now = Instant::now();
+                        for scheduled in scheduled {
+                            act.reply(
+                                ctx,
+                                Reply::notice(format!(
+                                    "[{}] in {} to {}: {}",
+                                    scheduled.id,
+                                    format_delay(
+                                        scheduled.due.saturating_duration_since(now)
+                                    ),
+                                    scheduled.room_name,
+                                    scheduled.content
+                                )),
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        act.request_failed(ctx, err, "listing scheduled messages")
+                    }
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn unschedule(&mut self, id: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        let msg = Unschedule {
+            owner: self.owner(ctx),
+            id,
+        };
+
+        Scheduler::from_registry()
+            .send(msg)
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(true) => act.reply(
+                        ctx,
+                        Reply::notice(format!("unscheduled message {}", id)),
+                    ),
+                    Ok(false) => act.reply(
+                        ctx,
+                        Reply::error(format!("no scheduled message {}", id)),
+                    ),
+                    Err(err) => act.request_failed(ctx, err, "unscheduling"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Lists a page of rooms, one per line, ending with the command for the
+    /// next page if there is one
+    pub fn list_rooms(
+        &mut self,
+        token: Option<&str>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.list_room_page(
+            token,
+            |page, act, ctx| {
+                let mut team = None;
+                for room in page.rooms {
+                    if room.team.is_some() && room.team != team {
+                        act.reply(
+                            ctx,
+                            Reply::notice(format!(
+                                "team {}:",
+                                room.team.as_deref().unwrap_or_default()
+                            )),
+                        );
+                    }
+                    let mut name = match &room.language {
+                        Some(language) => format!("{} [{}]", room.name, language),
+                        None => room.name,
+                    };
+                    if let Some(unread) = room.unread.filter(|unread| *unread > 0) {
+                        name = format!("{} ({} unread)", name, unread);
+                    }
+                    match &room.team {
+                        Some(_) => act.reply(ctx, Reply::notice(format!("  {}", name))),
+                        None => act.reply(ctx, Reply::notice(name)),
+                    }
+                    team = room.team;
+                }
+                if let Some(next) = page.next {
+                    act.reply(ctx, Reply::notice(format!("more rooms: /list {}", next)));
+                }
+            },
+            ctx,
+        );
+    }
+
+    /// Like `list_rooms`, answering with
+    /// `{"type":"rooms","rooms":[{"name":"Main","team":null},..],"next":"<token>"}`
+    fn list_rooms_frame(
+        &mut self,
+        token: Option<&str>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.list_room_page(
+            token,
+            |page, act, ctx| {
+                let frame = serde_json::json!({
+                    "type": "rooms",
+                    "rooms": page.rooms,
+                    "next": page.next,
+                });
+                act.reply(ctx, Reply::Frame(frame.to_string()));
+            },
+            ctx,
+        );
+    }
+
+    fn list_room_page(
+        &mut self,
+        token: Option<&str>,
+        reply: impl FnOnce(RoomPage, &mut Self, &mut ws::WebsocketContext<Self>) + 'static,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let after = match token.map(parse_page_token) {
+            Some(None) => {
+                self.reply(
+                    ctx,
+                    Reply::error("invalid continuation token, start over with /list"),
+                );
+                return;
+            }
+            Some(after) => after,
+            None => None,
+        };
+
+        let list = ListRooms {
+            after,
+            limit: ROOM_PAGE_SIZE,
+            unlisted: false,
+        };
+
+        server_request("ListRooms", list)
+            .into_actor(self)
+            .then(|result, act, ctx| {
+                match result {
+                    Ok(page) => act.count_unread(page, reply, ctx),
+                    Err(err) => act.request_failed(ctx, err, "listing rooms"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Fills in the unread counts of the rooms the account marked read
+    fn count_unread(
+        &mut self,
+        mut page: RoomPage,
+        reply: impl FnOnce(RoomPage, &mut Self, &mut ws::WebsocketContext<Self>) + 'static,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => return reply(page, self, ctx),
+        };
+        let rooms = page.rooms.iter().map(|room| room.name.clone()).collect();
+
+        ReadMarkers::from_registry()
+            .send(GetReadPositions { account, rooms })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |result, act, ctx| {
+                // the list is still worth having without the counts
+                for position in result.unwrap_or_default() {
+                    if let Some(room) = page
+                        .rooms
+                        .iter_mut()
+                        .find(|room| room.name == position.room)
+                    {
+                        room.unread = Some(position.unread);
+                    }
+                }
+                reply(page, act, ctx);
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Greets a logged in member with what was posted since they last read
+    /// the room, if anything
+    fn greet_unread(&mut self, room_name: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => return,
+        };
+
+        ReadMarkers::from_registry()
+            .send(GetReadPositions {
+                account,
+                rooms: vec![room_name],
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(|result, act, ctx| {
+                for position in result.unwrap_or_default() {
+                    if position.unread == 0 {
+                        continue;
+                    }
+
+                    let mut fields = serde_json::Map::new();
+                    fields.insert("seq".to_owned(), serde_json::json!(position.seq));
+                    fields.insert("unread".to_owned(), position.unread.into());
+
+                    let text =
+                        format!("{} unread since you last read it", position.unread);
+                    let frame =
+                        system_frame("unread", Some(&position.room), &text, fields);
+                    act.reply(ctx, Reply::Frame(frame));
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn list_clients(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        // spawned, this session is asked its presence as well
+        server_request("ListPresence", ListPresence(self.room_name.clone()))
+            .into_actor(self)
+            .then(|result, act, ctx| {
+                if let Ok(Some(presence)) = result {
+                    act.reply(
+                        ctx,
+                        Reply::notice(format!("generation {}:", presence.generation)),
+                    );
+                    for (client_id, presence) in presence.clients {
+                        let line = match presence {
+                            Some(ClientPresence {
+                                name,
+                                idle: Some(idle),
+                            }) => {
+                                format!(
+                                    "{} {} idle for {}",
+                                    client_id,
+                                    name,
+                                    format_delay(idle)
+                                )
+                            }
+                            Some(ClientPresence { name, idle: None }) => {
+                                format!("{} {} online", client_id, name)
+                            }
+                            None => client_id.to_string(),
+                        };
+                        act.reply(ctx, Reply::notice(line));
+                    }
+                }
+
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Drafts are kept with the account, so they follow it to other devices
+    fn save_draft(
+        &mut self,
+        room_name: String,
+        text: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if text.chars().count() > MAX_DRAFT_LEN {
+            let err = format!("longer than {} characters", MAX_DRAFT_LEN);
+            self.reply(ctx, FrameError::new(Some("text"), err));
+            return;
+        }
+
+        match &self.account {
+            Some(account) => Drafts::from_registry().do_send(SaveDraft {
+                account: account.clone(),
+                room_name,
+                text,
+            }),
+            None => self.reply(
+                ctx,
+                Reply::error("log in first, drafts are kept with your account"),
+            ),
+        }
+    }
+
+    /// Answers with `{"type":"draft","room":"Main","text":"...","updated":...}`,
+    /// an empty `text` and no `updated` time if there is none
+    fn get_draft(&mut self, room_name: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error("log in first, drafts are kept with your account"),
+                );
+                return;
+            }
+        };
+
+        Drafts::from_registry()
+            .send(GetDraft {
+                account,
+                room_name: room_name.clone(),
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(draft) => {
+                        let frame = serde_json::json!({
+                            "type": "draft",
+                            "room": room_name,
+                            "text": draft.as_ref().map_or("", |draft| &draft.text),
+                            "updated": draft.map(|draft| draft.updated),
+                        });
+                        act.reply(ctx, Reply::Frame(frame.to_string()));
+                    }
+                    Err(err) => act.request_failed(ctx, err, "fetching draft"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Marks the room read up to `seq`, the latest message if `None`, for
+    /// every session of the account. `frame` answers like the other sessions
+    /// are told, with a `ReadPosition` frame.
+    pub fn mark_read(
+        &mut self,
+        room_name: String,
+        seq: Option<u64>,
+        frame: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(
+                        "log in first, read positions are kept with your account",
+                    ),
+                );
+                return;
+            }
+        };
+
+        ReadMarkers::from_registry()
+            .send(MarkRead {
+                account,
+                login: self.login,
+                room_name,
+                seq,
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(position)) if frame => {
+                        act.reply(ctx, Reply::Frame(position.to_json().to_string()))
+                    }
+                    Ok(Ok(position)) => act.reply(
+                        ctx,
+                        Reply::notice(format!("marked {} read", position.room)),
+                    ),
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "marking read"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Unread counts of the rooms joined and every room the account marked
+    /// read, as `{"type":"unread","rooms":[..]}` if `frame`
+    pub fn unread(&mut self, frame: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(
+                        "log in first, read positions are kept with your account",
+                    ),
+                );
+                return;
+            }
+        };
+        let rooms = self.memberships.keys().cloned().collect();
+
+        ReadMarkers::from_registry()
+            .send(GetUnread { account, rooms })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(rooms) if frame => {
+                        let frame = serde_json::json!({
+                            "type": "unread",
+                            "rooms": rooms,
+                        });
+                        act.reply(ctx, Reply::Frame(frame.to_string()));
+                    }
+                    Ok(rooms) => {
+                        for position in rooms {
+                            act.reply(
+                                ctx,
+                                Reply::notice(format!(
+                                    "{}: {} unread",
+                                    position.room, position.unread
+                                )),
+                            );
+                        }
+                    }
+                    Err(err) => act.request_failed(ctx, err, "fetching unread counts"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/star id`, saves a message of a room joined to the account's stars.
+    /// Ids are journal sequence numbers, as the events API lists them.
+    pub fn star(&mut self, id: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error("log in first, stars are kept with your account"),
+                );
+                return;
+            }
+        };
+        let seq: u64 = match id.trim().trim_start_matches('#').parse() {
+            Ok(seq) => seq,
+            Err(_) => {
+                self.reply(ctx, Reply::error("usage: /star id"));
+                return;
+            }
+        };
+
+        Journal::from_registry()
+            .send(FindEvents(vec![seq]))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let event = res.unwrap_or_default().into_iter().find(|event| {
+                    act.memberships.contains_key(&event.room_name)
+                        && matches!(
+                            event.kind,
+                            EventKind::Message { .. }
+                                | EventKind::Forwarded { .. }
+                                | EventKind::CrossPost { .. }
+                                | EventKind::Attachment { .. }
+                                | EventKind::VoiceNote { .. }
+                        )
+                });
+                match event {
+                    Some(event) => act.save_star(account, event.room_name, seq, ctx),
+                    None => act.reply(
+                        ctx,
+                        Reply::error(format!("no message #{} in your rooms", seq)),
+                    ),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/forward id room`, reposts a message of one room joined into another,
+    /// where it has to pass what any post does
+    pub fn forward(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let (seq, room_name) = match args.trim().split_once(' ') {
+            Some((id, room_name)) if !room_name.trim().is_empty() => {
+                match id.trim_start_matches('#').parse::<u64>() {
+                    Ok(seq) => (seq, room_name.trim().to_owned()),
+                    Err(_) => {
+                        self.reply(
+                            ctx,
+                            Reply::error(format!("invalid message id: {:?}", id)),
+                        );
+                        return;
+                    }
+                }
+            }
+            _ => {
+                self.reply(ctx, Reply::error("usage: /forward id room"));
+                return;
+            }
+        };
+        let client_id = match self.memberships.get(&room_name) {
+            Some(client_id) => *client_id,
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error(format!("you are not in room {}", room_name)),
+                );
+                return;
+            }
+        };
+
+        Journal::from_registry()
+            .send(FindEvents(vec![seq]))
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let event = res
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|event| act.memberships.contains_key(&event.room_name));
+                let mut forwarded = match event
+                    .map(|event| (event.room_name, event.kind))
+                {
+                    Some((room, EventKind::Message { content, .. })) => {
+                        EventKind::Forwarded {
+                            from: act.client_name(),
+                            room,
+                            original_seq: seq,
+                            content,
+                        }
+                    }
+                    Some((room, EventKind::CrossPost { from, content, .. })) => {
+                        EventKind::Forwarded {
+                            from: act.client_name(),
+                            room,
+                            original_seq: seq,
+                            content: format!("{}: {}", from, content),
+                        }
+                    }
+                    // the original's origin is kept
+                    Some((
+                        _,
+                        EventKind::Forwarded {
+                            room,
+                            original_seq,
+                            content,
+                            ..
+                        },
+                    )) => EventKind::Forwarded {
+                        from: act.client_name(),
+                        room,
+                        original_seq,
+                        content,
+                    },
+                    _ => {
+                        act.reply(
+                            ctx,
+                            Reply::error(format!("no message #{} in your rooms", seq)),
+                        );
+                        return fut::ready(());
+                    }
+                };
+
+                if let EventKind::Forwarded { room, content, .. } = &mut forwarded {
+                    if *room == room_name {
+                        act.reply(
+                            ctx,
+                            Reply::error(format!(
+                                "#{} is already in {}",
+                                seq, room_name
+                            )),
+                        );
+                        return fut::ready(());
+                    }
+                    *content = match act.check_content(content, ctx) {
+                        Some(checked) => checked.into_owned(),
+                        None => return fut::ready(()),
+                    };
+                }
+
+                act.messages += 1;
+                WsChatServer::from_registry()
+                    .do_send(SendForwarded(room_name, client_id, forwarded));
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// `/xpost #a #b text`, one announcement for several rooms joined, for
+    /// their owner. The copies share a post id, so they can be told apart
+    /// from the same text posted to each room.
+    pub fn cross_post(&mut self, args: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let mut rooms = Vec::new();
+        let mut content = args.trim();
+        while let Some(rest) = content.strip_prefix('#') {
+            let (room_name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            if !rooms.iter().any(|other| other == room_name) {
+                rooms.push(room_name.to_owned());
+            }
+            content = rest.trim_start();
+        }
+        if rooms.is_empty() || content.is_empty() {
+            self.reply(ctx, Reply::error("usage: /xpost #room #room message"));
+            return;
+        }
+
+        let mut targets = Vec::new();
+        for room_name in &rooms {
+            match self.memberships.get(room_name) {
+                Some(client_id) => targets.push((room_name.clone(), *client_id)),
+                None => {
+                    self.reply(
+                        ctx,
+                        Reply::error(format!("you are not in room {}", room_name)),
+                    );
+                    return;
+                }
+            }
+        }
+        let content = match self.check_content(content, ctx) {
+            Some(content) => content.into_owned(),
+            None => return,
+        };
+
+        let msg = CrossPost {
+            rooms: targets,
+            event: EventKind::CrossPost {
+                // given by the server
+                post: 0,
+                from: self.client_name(),
+                rooms,
+                content,
+            },
+        };
+
+        server_request("CrossPost", msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.messages += 1,
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "cross-posting"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn save_star(
+        &mut self,
+        account: String,
+        room_name: String,
+        seq: u64,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        Stars::from_registry()
+            .send(StarMessage {
+                account,
+                room_name,
+                seq,
+            })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.reply(ctx, Reply::notice(format!("starred #{}", seq)))
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "starring"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    pub fn unstar(&mut self, id: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error("log in first, stars are kept with your account"),
+                );
+                return;
+            }
+        };
+        let seq: u64 = match id.trim().trim_start_matches('#').parse() {
+            Ok(seq) => seq,
+            Err(_) => {
+                self.reply(ctx, Reply::error("usage: /unstar id"));
+                return;
+            }
+        };
+
+        Stars::from_registry()
+            .send(UnstarMessage { account, seq })
+            .timeout(config().request_timeout())
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.reply(ctx, Reply::notice(format!("unstarred #{}", seq)))
+                    }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "unstarring"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Lists the account's stars, newest first, whatever room they are in
+    pub fn starred(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let account = match &self.account {
+            Some(account) => account.clone(),
+            None => {
+                self.reply(
+                    ctx,
+                    Reply::error("log in first, stars are kept with your account"),
+                );
+                return;
+            }
+        };
+
+        stars::starred(account)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(stars) if stars.is_empty() => {
+                        act.reply(ctx, Reply::notice("no starred messages"))
+                    }
+                    Ok(stars) => {
+                        for starred in stars {
+                            let text = starred
+                                .event
+                                .and_then(|event| event.kind.text(&event.room_name))
+                                .map(|text| plain_text(&text).into_owned())
+                                .unwrap_or_else(|| "(no longer in history)".to_owned());
+                            act.reply(
+                                ctx,
+                                Reply::notice(format!(
+                                    "#{} in {}: {}",
+                                    starred.star.seq, starred.star.room_name, text
+                                )),
+                            );
+                        }
+                    }
+                    Err(err) => act.request_failed(ctx, err, "listing stars"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Answers with the room's members as
+    /// `{"type":"members","room":"Main","generation":7,"client_ids":[..]}`,
+    /// followed by a `MembershipEvent` for every join and leave
+    fn subscribe_members(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        server_request(
+            "SubscribeMembership",
+            SubscribeMembership {
+                room_name: self.room_name.clone(),
+                client_id: self.client_id,
+                subscriber: ctx.address().recipient(),
+            },
+        )
+        .into_actor(self)
+        .then(|result, act, ctx| {
+            match result {
+                Ok(Some(members)) => {
+                    let frame = serde_json::json!({
+                        "type": "members",
+                        "room": act.room_name,
+                        "generation": members.generation,
+                        "client_ids": members.client_ids,
+                    });
+                    act.reply(ctx, Reply::Frame(frame.to_string()));
+                }
+                Ok(None) => {
+                    act.reply(ctx, Reply::error("you are not in a room, use /join name"))
+                }
+                Err(err) => act.request_failed(ctx, err, "listing members"),
+            }
+
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    pub fn send_msg(&mut self, msg: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        if !self.check_rate(ctx) {
+            return;
+        }
+        let msg = match self.check_content(msg, ctx) {
+            Some(msg) => msg,
+            None => return,
+        };
+        if let Err(err) = self.repeats.check(&msg) {
+            self.reply(ctx, Reply::error(err));
+            return;
+        }
+
+        self.messages += 1;
+        if let Some(account) = &self.account {
+            self.standing.account_messages += 1;
+            Accounts::from_registry().do_send(CountMessage(account.clone()));
+            // it was sent, from here or another device
+            Drafts::from_registry().do_send(SaveDraft {
+                account: account.clone(),
+                room_name: self.room_name.clone(),
+                text: String::new(),
+            });
+        }
+
+        let content = format!("{}: {}", self.client_name(), msg);
+
+        let msg = SendMessage(self.room_name.clone(), self.client_id, content);
+
+        // issue_async comes from having the `BrokerIssue` trait in scope.
+        self.issue_system_async(msg);
+        self.stop_typing();
+    }
+
+    /// Replaces the text of one of this session's messages in the current
+    /// room, checked like a new message
+    pub fn edit_message(
+        &mut self,
+        id: u64,
+        text: &str,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        if !self.check_rate(ctx) {
+            return;
+        }
+        let text = match self.check_content(text, ctx) {
+            Some(text) => text.into_owned(),
+            None => return,
+        };
+
+        let msg = EditMessage {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            id,
+            text,
+        };
+        self.change_message("EditMessage", msg, ctx);
+    }
+
+    /// Takes back one of this session's messages in the current room
+    pub fn delete_message(&mut self, id: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = DeleteMessage {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            id,
+        };
+        self.change_message("DeleteMessage", msg, ctx);
+    }
+
+    /// Reacts to a message in the current room with `emoji`, or takes the
+    /// reaction back if this session already reacted with it
+    pub fn react(&mut self, id: u64, emoji: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+        if !valid_emoji(emoji) {
+            self.reply(ctx, Reply::error("expected an emoji to react with"));
+            return;
+        }
+
+        if !self.check_rate(ctx) {
+            return;
+        }
+
+        let msg = React {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            id,
+            emoji: emoji.to_owned(),
+        };
+        self.change_message("React", msg, ctx);
+    }
+
+    /// The room tells its members of the change, this session included
+    fn change_message<M>(
+        &mut self,
+        name: &'static str,
+        msg: M,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) where
+        M: Message<Result = Result<(), RoomError>> + Send + 'static,
+        WsChatServer: Handler<M>,
+    {
+        server_request(name, msg)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "changing the message"),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Drops messages over the config's `message_rate` with a warning, and
+    /// closes the connection of a client that keeps flooding
+    fn check_rate(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let rate = config().message_rate;
+        let allowed = self
+            .message_limit
+            .get_or_insert_with(|| RateLimit::new(rate, rate))
+            .allow();
+        if allowed {
+            return true;
+        }
+
+        let now = Instant::now();
+        while self
+            .floods
+            .front()
+            .is_some_and(|flood| now.duration_since(*flood) > FLOOD_WINDOW)
+        {
+            self.floods.pop_front();
+        }
+        self.floods.push_back(now);
+
+        if self.floods.len() >= config().flood_strikes {
+            info!(
+                "WsChatSession - {} kept flooding, closing the session",
+                self.client_name()
+            );
+            self.reply(ctx, Reply::error("sending too fast, disconnected"));
+            self.close(
+                ctx,
+                Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("flooding".to_owned()),
+                }),
+            );
+            ctx.stop();
+            return false;
+        }
+
+        self.reply(
+            ctx,
+            Reply::error(format!(
+                "sending too fast, at most {} messages a second, message dropped",
+                rate
+            )),
+        );
+        false
+    }
+
+    /// Pings the client and sends it a stats frame carrying the last measured
+    /// RTT, the occupancy of its room, and the server time, e.g.
+    /// `{"type":"stats","rtt_ms":12,"occupancy":3,"server_time":1604000000000}`
+    pub fn send_stats(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        // the TCP connection can be gone without a close frame ever arriving,
+        // which would leave the client in its rooms for good. Only once a ping
+        // went unanswered, quiet clients aren't dead ones.
+        let silent = self
+            .heard
+            .is_some_and(|heard| heard.elapsed() > config().client_timeout());
+        if self.ping_sent.is_some() && silent {
+            info!(
+                "WsChatSession - {} timed out, closing the session",
+                self.client_name()
+            );
+            ctx.stop();
+            return;
+        }
+
+        // a ping that is still outstanding keeps its original send time
+        if self.ping_sent.is_none() {
+            self.ping_sent = Some(Instant::now());
+        }
+        ctx.ping(b"");
+
+        // picks up reports and the account getting older
+        self.refresh_trust(ctx);
+
+        server_request("RoomSize", RoomSize(self.room_name.clone()))
+            .into_actor(self)
+            .then(|result, act, ctx| {
+                if let Ok(occupancy) = result {
+                    let stats = serde_json::json!({
+                        "type": "stats",
+                        "rtt_ms": act.rtt.map(|rtt| rtt.as_millis() as u64),
+                        "occupancy": occupancy,
+                        "server_time": unix_millis() as u64,
+                    });
+                    act.reply(ctx, Reply::Frame(stats.to_string()));
+                }
+
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// NTP-style exchange: echoes the client's transmit time with the server
+    /// receive and transmit times so the client can work out its clock offset
+    /// as `((server_receive - client_time) + (server_transmit - t3)) / 2`,
+    /// where `t3` is when the reply arrived.
+    pub fn time_sync(
+        &self,
+        client_time: Option<u64>,
+        server_receive: u128,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let reply = serde_json::json!({
+            "type": "time_sync",
+            "client_time": client_time,
+            "server_receive": server_receive as u64,
+            "server_transmit": unix_millis() as u64,
+        });
+        self.reply(ctx, Reply::Frame(reply.to_string()));
+    }
+
+    /// Names the session after the token it connected with, or else `resumed`,
+    /// the name of the session it resumes
+    fn name_session(
+        &mut self,
+        resumed: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if let Some(name) = self.token_name.clone().or(resumed) {
+            // already, so the rooms are joined under it
+            self.client_name = Some(name.clone());
+            self.set_name(name, true, ctx);
+        }
+    }
+
+    /// Carries on with the name, settings and rooms of a resumed session
+    fn restore(
+        &mut self,
+        mut state: SessionState,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.name_session(state.client_name.take(), ctx);
+        self.human = state.human;
+        self.features = state.features.clone();
+        self.json = state.json;
+        self.said_hello = state.said_hello;
+
+        self.rejoin(state, ctx);
+    }
+
+    /// Takes back the memberships kept for a session that lost its client,
+    /// joins the rest of its rooms at once, then switches back to the room it
+    /// was in and catches up on the messages missed meanwhile
+    fn rejoin(&mut self, state: SessionState, ctx: &mut ws::WebsocketContext<Self>) {
+        let SessionState {
+            rooms,
+            room_name,
+            last_message,
+            ..
+        } = state;
+
+        let reattach = self.resumed_from.take().map(|token| ReattachSession {
+            token,
+            client: ctx.address().recipient(),
+            removed: ctx.address().recipient(),
+            breakout: ctx.address().recipient(),
+            presence: ctx.address().recipient(),
+        });
+        let reattach = async move {
+            match reattach {
+                Some(reattach) => server_request("ReattachSession", reattach)
+                    .await
+                    .unwrap_or_default(),
+                None => Vec::new(),
+            }
+        };
+
+        reattach
+            .into_actor(self)
+            .then(move |reattached, act, ctx| {
+                act.memberships.extend(reattached);
+
+                // the rooms exist, but maybe not yet on this node
+                let joins = rooms
+                    .into_iter()
+                    .filter(|room_name| !act.memberships.contains_key(room_name))
+                    .map(|room_name| {
+                        let join = JoinRoom {
+                            room_name,
+                            client_name: act.client_name(),
+                            client: ctx.address().recipient(),
+                            may_create: true,
+                            template: None,
+                            account: act.account.clone(),
+                            removed: ctx.address().recipient(),
+                            breakout: ctx.address().recipient(),
+                            presence: ctx.address().recipient(),
+                            password: None,
+                            rejoining: true,
+                        };
+                        server_request("JoinRoom", join)
+                    });
+
+                future::join_all(joins).into_actor(act)
+            })
+            .map(move |results, act, ctx| {
+                for (id, joined) in results.into_iter().flatten().flatten() {
+                    act.memberships.insert(joined, id);
+                }
+                if let Some(id) = act.memberships.get(&room_name) {
+                    act.client_id = *id;
+                    act.room_name = room_name;
+                }
+
+                if last_message > 0 {
+                    act.catch_up(last_message, ctx);
+                }
+            })
+            .wait(ctx);
+    }
+
+    /// Sends the chat messages of every room joined that came after the one
+    /// with id `last_message`, each room's after a `catch_up` frame counting
+    /// them. Room traffic waits until they are out.
+    fn catch_up(&mut self, last_message: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        let journal = Journal::from_registry();
+        let histories = self.memberships.keys().map(|room_name| {
+            // ids start from the time in microseconds they were posted
+            let history = journal
+                .send(RoomHistory {
+                    room_name: room_name.clone(),
+                    since: last_message / 1000,
+                })
+                .timeout(config().request_timeout());
+            let room_name = room_name.clone();
+            async move { (room_name, history.await.unwrap_or_default()) }
+        });
+
+        future::join_all(histories)
+            .into_actor(self)
+            .map(move |histories, act, ctx| {
+                for (room_name, events) in histories {
+                    let missed: Vec<(u64, String)> = events
+                        .into_iter()
+                        .filter_map(|event| match event.kind {
+                            EventKind::Message {
+                                content,
+                                id,
+                                posted,
+                            } if id > last_message => {
+                                Some((id, message_frame(&content, id, posted)))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    if missed.is_empty() {
+                        continue;
+                    }
+
+                    let mut fields = serde_json::Map::new();
+                    fields.insert("count".to_owned(), missed.len().into());
+                    let text = format!(
+                        "{} messages sent to {} while you were away",
+                        missed.len(),
+                        &room_name
+                    );
+                    act.reply(
+                        ctx,
+                        Reply::Frame(system_frame(
+                            "catch_up",
+                            Some(&room_name),
+                            &text,
+                            fields,
+                        )),
+                    );
+
+                    for (id, frame) in missed {
+                        act.caught_up.insert(id);
+                        act.send_frame(&frame, ctx);
+                    }
+                }
+            })
+            .wait(ctx);
+    }
+
+    /// Sends room traffic on, in the form the client asked for
+    fn send_frame(&mut self, frame: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let frame = match self.features.downgrade(frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let text = if self.json {
+            frame.into_owned()
+        } else {
+            plain_text(&frame).into_owned()
+        };
+
+        match CHAOS.as_ref() {
+            None => self.send_text(ctx, text),
+            Some(chaos) if chaos.drops() => {}
+            Some(chaos) => {
+                let due = chaos.due(self.chaos_due);
+                self.chaos_due = Some(due);
+                let delay = due.saturating_duration_since(Instant::now());
+                ctx.run_later(delay, move |act, ctx| act.send_text(ctx, text));
+            }
+        }
+    }
+
+    /// Hands the session's state to every node, for the client to resume it
+    /// on any of them with `token`
+    fn hand_off(&self, token: String, state: SessionState) {
+        self.issue_system_async(BridgeOut(Envelope::new(Payload::Session {
+            token: token.clone(),
+            state: state.clone(),
+        })));
+        Migrations::from_registry().do_send(StoreSession { token, state });
+    }
+
+    /// Hands the session to the other nodes and tells the client to reconnect
+    /// with `{"type":"migrate","resume":"<token>"}`, closing with `Restart`
+    fn migrate(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let token = random_token();
+        self.hand_off(token.clone(), self.state());
+        self.detach_login(token.clone());
+
+        let reply = serde_json::json!({ "type": "migrate", "resume": token });
+        self.reply(ctx, Reply::Frame(reply.to_string()));
+        self.close(ctx, Some(ws::CloseCode::Restart.into()));
+        ctx.stop();
+    }
+
+    /// What `resume` needs to restore this session on another node
+    fn state(&self) -> SessionState {
+        SessionState {
+            client_name: self.client_name.clone(),
+            account: self.account.clone(),
+            rooms: self.memberships.keys().cloned().collect(),
+            room_name: self.room_name.clone(),
+            human: self.human,
+            features: self.features.clone(),
+            json: self.json,
+            said_hello: self.said_hello,
+            last_message: unix_millis() as u64 * 1000,
+        }
+    }
+
+    /// Handles a JSON frame, replying with an error frame naming the offending
+    /// field if it doesn't validate
+    pub fn handle_frame(
+        &mut self,
+        text: &str,
+        received: u128,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let frame = match parse_frame(text) {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.reply(ctx, err);
+                return;
+            }
+        };
+
+        match frame {
+            ClientFrame::Message { content } => self.send_msg(&content, ctx),
+            ClientFrame::Join { room, password } => self.join_room(&room, password, ctx),
+            ClientFrame::Name { name } => self.set_name(name, false, ctx),
+            ClientFrame::Whisper { to, content } => {
+                self.private_message(&to, &content, ctx)
+            }
+            ClientFrame::TimeSync { client_time } => {
+                self.time_sync(client_time, received, ctx)
+            }
+            ClientFrame::AttachmentStart {
+                id,
+                name,
+                mime,
+                size,
+            } => self.start_upload(id, UploadKind::Attachment { name }, mime, size, ctx),
+            ClientFrame::VoiceNoteStart {
+                id,
+                mime,
+                duration_ms,
+                size,
+            } => self.start_upload(
+                id,
+                UploadKind::VoiceNote { duration_ms },
+                mime,
+                size,
+                ctx,
+            ),
+            ClientFrame::AttachmentEnd { id } => self.finish_upload(&id, ctx),
+            ClientFrame::StreamStart { title } => {
+                let host = self.client_name();
+                self.manage_stream(StreamAction::Start { host, title }, ctx)
+            }
+            ClientFrame::StreamJoin { stream_id } => {
+                self.manage_stream(StreamAction::Join(stream_id), ctx)
+            }
+            ClientFrame::StreamLeave { stream_id } => {
+                self.manage_stream(StreamAction::Leave(stream_id), ctx)
+            }
+            ClientFrame::StreamEnd { stream_id } => {
+                self.manage_stream(StreamAction::End(stream_id), ctx)
+            }
+            ClientFrame::Signal { to, data } => self.signal(to, data, ctx),
+            ClientFrame::Draw { op } => self.draw(op, ctx),
+            ClientFrame::Presence { data } => self.presence(data, ctx),
+            ClientFrame::Captcha { token } => self.captcha(token, ctx),
+            ClientFrame::Members {} => self.subscribe_members(ctx),
+            ClientFrame::Hello { features, json } => self.hello(&features, json, ctx),
+            ClientFrame::ListRooms { after } => {
+                self.list_rooms_frame(after.as_deref(), ctx)
+            }
+            ClientFrame::SaveDraft { room, text } => self.save_draft(room, text, ctx),
+            ClientFrame::GetDraft { room } => self.get_draft(room, ctx),
+            ClientFrame::MarkRead { room, seq } => self.mark_read(room, seq, true, ctx),
+            ClientFrame::Unread {} => self.unread(true, ctx),
+            ClientFrame::Typing { typing } => self.typing(typing, ctx),
+            ClientFrame::React { id, emoji } => self.react(id, &emoji, ctx),
+        }
+    }
+
+    fn send_ephemeral(&self, event: Ephemeral) {
+        WsChatServer::from_registry().do_send(SendEphemeral {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            from: self.client_name(),
+            event,
+        });
+    }
+
+    /// Relays a whiteboard op to the room. Ops over the rate limit are
+    /// dropped, answered with an error frame.
+    fn draw(&mut self, op: DrawOp, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let allowed = self
+            .draw_limit
+            .get_or_insert_with(|| RateLimit::new(config().draw_rate, DRAW_BURST))
+            .allow();
+        if !allowed {
+            self.reply(ctx, FrameError::new(None, "drawing too fast, op dropped"));
+            return;
+        }
+
+        self.send_ephemeral(Ephemeral::Draw { op });
+    }
+
+    /// Relays presence to the room, at most every `PRESENCE_INTERVAL`. An
+    /// update coming in sooner replaces whichever one is still waiting.
+    fn presence(
+        &mut self,
+        data: serde_json::Value,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            return;
+        }
+
+        let wait = self.presence_sent.map_or(Duration::from_secs(0), |sent| {
+            PRESENCE_INTERVAL
+                .checked_sub(sent.elapsed())
+                .unwrap_or_default()
+        });
 
-#[derive(Default)]
-pub struct WsChatSession {
-    client_id: usize,
-    room_name: String,
-    client_name: Option<String>,
-}
+        if wait == Duration::from_secs(0) {
+            self.presence_sent = Some(Instant::now());
+            self.send_ephemeral(Ephemeral::Presence { data });
+            return;
+        }
 
-impl WsChatSession {
-    /// Getter for self.name, the client's name for this session
-    pub fn client_name(&self) -> String {
-        self.client_name
-            .as_ref()
-            .unwrap_or(&String::from("anon"))
-            .clone()
+        // a flush is already on its way if something was waiting
+        if self.presence_pending.replace(data).is_none() {
+            ctx.run_later(wait, |act, _ctx| {
+                if let Some(data) = act.presence_pending.take() {
+                    act.presence_sent = Some(Instant::now());
+                    act.send_ephemeral(Ephemeral::Presence { data });
+                }
+            });
+        }
     }
 
-    pub fn join_room(&mut self, room_name: &str, ctx: &mut ws::WebsocketContext<Self>) {
-        let room_name = room_name.to_owned();
+    /// Shows the current room that the client is typing, until it stops,
+    /// sends a message, or doesn't say so again within `TYPING_EXPIRY`.
+    /// Only the start and the end are relayed, not every keystroke.
+    pub fn typing(&mut self, typing: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+        if !typing {
+            self.stop_typing();
+            return;
+        }
 
-        // Then send a join message for the new room
-        let join_msg = JoinRoom(
-            room_name.to_owned(),
-            self.client_name(),
-            ctx.address().recipient(),
-        );
+        let already =
+            matches!(&self.typing, Some((room_name, _)) if *room_name == self.room_name);
+        if !already {
+            self.stop_typing();
+            self.send_ephemeral(Ephemeral::Typing { typing: true });
+            ctx.run_later(TYPING_EXPIRY, Self::expire_typing);
+        }
+        self.typing = Some((self.room_name.clone(), Instant::now()));
+    }
+
+    fn expire_typing(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let heard = match &self.typing {
+            Some((_, heard)) => heard.elapsed(),
+            None => return,
+        };
+
+        match TYPING_EXPIRY.checked_sub(heard) {
+            Some(left) if left > Duration::from_secs(0) => {
+                ctx.run_later(left, Self::expire_typing);
+            }
+            _ => self.stop_typing(),
+        }
+    }
+
+    /// Takes down the typing indicator, if one is up
+    fn stop_typing(&mut self) {
+        let (room_name, _) = match self.typing.take() {
+            Some(typing) => typing,
+            None => return,
+        };
+        let client_id = match self.memberships.get(&room_name) {
+            Some(client_id) => *client_id,
+            None => return,
+        };
+
+        WsChatServer::from_registry().do_send(SendEphemeral {
+            room_name,
+            client_id,
+            from: self.client_name(),
+            event: Ephemeral::Typing { typing: false },
+        });
+    }
 
-        WsChatServer::from_registry()
-            .send(join_msg)
+    fn manage_stream(
+        &mut self,
+        action: StreamAction,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        let msg = ManageStream {
+            room_name: self.room_name.clone(),
+            client_id: self.client_id,
+            action,
+        };
+
+        server_request("ManageStream", msg)
             .into_actor(self)
-            .then(|id, act, _ctx| {
-                if let Ok(id) = id {
-                    act.client_id = id;
-                    act.room_name = room_name;
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "stream update"),
                 }
 
                 fut::ready(())
@@ -50,15 +3470,27 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn list_rooms(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
-        WsChatServer::from_registry()
-            .send(ListRooms)
+    /// Relays WebRTC signaling to another client, e.g. a stream viewer's offer
+    /// to the host
+    fn signal(
+        &mut self,
+        to: String,
+        data: serde_json::Value,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let msg = Signal {
+            from: self.client_name(),
+            to,
+            data,
+        };
+
+        server_request("Signal", msg)
             .into_actor(self)
-            .then(|result, _, ctx| {
-                if let Ok(rooms) = result {
-                    for room in rooms {
-                        ctx.text(room);
-                    }
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "signaling"),
                 }
 
                 fut::ready(())
@@ -66,15 +3498,70 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn list_clients(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
-        WsChatServer::from_registry()
-            .send(ListClients(self.room_name.clone()))
+    fn start_upload(
+        &mut self,
+        id: String,
+        kind: UploadKind,
+        mime: String,
+        size: usize,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.room_name.is_empty() {
+            self.reply(ctx, Reply::error("you are not in a room, use /join name"));
+            return;
+        }
+
+        // each counts as a message, however many chunks it takes
+        if !self.check_rate(ctx)
+            || !self.check_human(ctx)
+            || !self.check_capability(Capability::Uploads, ctx)
+        {
+            return;
+        }
+        // the room sees the name like a message
+        let kind = match kind {
+            UploadKind::Attachment { name } => {
+                watch::watch(&name, || {
+                    format!(
+                        "an attachment from {} in {}",
+                        self.client_name(),
+                        self.room_name
+                    )
+                });
+                match self.filter_content(&name, ctx) {
+                    Some(name) => UploadKind::Attachment {
+                        name: name.into_owned(),
+                    },
+                    None => return,
+                }
+            }
+            kind => kind,
+        };
+
+        // a new start abandons an unfinished upload
+        self.upload = None;
+
+        // before any bytes arrive, as awaiting it holds them back
+        let msg = CheckUpload {
+            room_name: self.room_name.clone(),
+            mime: mime.clone(),
+            size,
+        };
+        server_request("CheckUpload", msg)
             .into_actor(self)
-            .then(|result, _, ctx| {
-                if let Ok(clients) = result {
-                    for client in clients {
-                        ctx.text(client);
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.upload = Some(Upload {
+                            id,
+                            kind,
+                            mime,
+                            size,
+                            data: Vec::with_capacity(size),
+                        })
                     }
+                    Ok(Err(err)) => act.reply(ctx, Reply::error(err)),
+                    Err(err) => act.request_failed(ctx, err, "starting the upload"),
                 }
 
                 fut::ready(())
@@ -82,13 +3569,88 @@ impl WsChatSession {
             .wait(ctx);
     }
 
-    pub fn send_msg(&self, msg: &str) {
-        let content = format!("{}: {}", self.client_name(), msg);
+    /// Appends a binary frame to the current upload
+    fn upload_chunk(&mut self, chunk: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        let upload = match &mut self.upload {
+            Some(upload) => upload,
+            None => {
+                self.reply(ctx, FrameError::new(None, "no attachment started"));
+                return;
+            }
+        };
 
-        let msg = SendMessage(self.room_name.clone(), self.client_id, content);
+        if upload.data.len() + chunk.len() > upload.size {
+            let err = format!("attachment is larger than its size of {}", upload.size);
+            self.upload = None;
+            self.reply(ctx, FrameError::new(Some("size"), err));
+            return;
+        }
 
-        // issue_async comes from having the `BrokerIssue` trait in scope.
-        self.issue_system_async(msg);
+        upload.data.extend_from_slice(chunk);
+    }
+
+    fn finish_upload(&mut self, id: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let upload = match self.upload.take() {
+            Some(upload) if upload.id == id => upload,
+            other => {
+                self.upload = other;
+                self.reply(
+                    ctx,
+                    FrameError::new(Some("id"), "no such attachment started"),
+                );
+                return;
+            }
+        };
+
+        if upload.data.len() != upload.size {
+            let err = format!("got {} of {} bytes", upload.data.len(), upload.size);
+            self.reply(ctx, FrameError::new(Some("size"), err));
+            return;
+        }
+
+        let from = self.client_name();
+        let mime = upload.mime;
+        let size = upload.size;
+        let name = match &upload.kind {
+            UploadKind::Attachment { name } => name.clone(),
+            UploadKind::VoiceNote { .. } => "voice note".to_owned(),
+        };
+        let url = match blobs::store(name, mime.clone(), upload.data) {
+            Ok(url) => Some(url),
+            Err(err) => {
+                warn!("couldn't keep an attachment: {}", err);
+                self.reply(
+                    ctx,
+                    Reply::error("the attachment couldn't be kept, try again later"),
+                );
+                return;
+            }
+        };
+
+        let attachment = match upload.kind {
+            UploadKind::Attachment { name } => EventKind::Attachment {
+                from,
+                name,
+                mime,
+                data: String::new(),
+                url,
+                size,
+            },
+            UploadKind::VoiceNote { duration_ms } => EventKind::VoiceNote {
+                from,
+                mime,
+                duration_ms,
+                data: String::new(),
+                url,
+                size,
+            },
+        };
+
+        WsChatServer::from_registry().do_send(SendAttachment(
+            self.room_name.clone(),
+            self.client_id,
+            attachment,
+        ));
     }
 
     pub fn who_am_i(&self, ctx: &mut ws::WebsocketContext<Self>) {
@@ -98,7 +3660,7 @@ impl WsChatSession {
             self.client_id,
             self.room_name
         );
-        ctx.text(msg);
+        self.reply(ctx, Reply::notice(msg));
     }
 }
 
@@ -106,15 +3668,99 @@ impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.join_room("Main", ctx);
+        self.subscribe_system_async::<Drain>(ctx);
+        self.subscribe_system_async::<ShuttingDown>(ctx);
+        self.resumable = true;
+        self.connected = Some(Instant::now());
+        metrics::session_opened();
+        self.heard = self.connected;
+        self.recording = Recording::start();
+
+        let default_rooms = std::mem::take(&mut self.default_rooms);
+
+        if let Some(mut state) = self.resumed.take() {
+            // whichever account the state names, only the login `Accounts`
+            // kept for the token is signed back in to
+            let token = self
+                .resumed_from
+                .clone()
+                .filter(|_| state.account.take().is_some());
+            match token {
+                Some(token) => self.resume_login(token, state, ctx),
+                None => self.restore(state, ctx),
+            }
+        } else {
+            self.name_session(None, ctx);
+
+            if default_rooms.is_empty() {
+                self.reply(
+                    ctx,
+                    Reply::Frame(system_frame(
+                        "welcome",
+                        None,
+                        "welcome to the lobby, use /list and /join name to enter a room",
+                        Default::default(),
+                    )),
+                );
+            } else {
+                // whatever the client's trust, so it always has somewhere to go
+                for room_name in &default_rooms {
+                    self.join(room_name, true, None, None, ctx);
+                }
+            }
+        }
+
+        ctx.run_interval(config().ping_interval(), |act, ctx| act.send_stats(ctx));
+
+        if let Some(chaos) = CHAOS.as_ref().filter(|chaos| chaos.disconnecting()) {
+            ctx.run_interval(DISCONNECT_CHECK, move |act, ctx| {
+                if chaos.disconnects() {
+                    info!(
+                        "WsChatSession - chaos mode cutting off {}",
+                        act.client_name()
+                    );
+                    ctx.stop();
+                }
+            });
+        }
     }
 
     fn stopped(&mut self, ctx: &mut Self::Context) {
-        // send a leave message for the current room
-        let leave_msg = LeaveRoom(self.room_name.clone(), self.client_id);
+        self.stop_typing();
+        metrics::session_closed(!self.closed);
+
+        match self.resume_token.take().filter(|_| self.resumable) {
+            // the rooms are kept for the client to come back to
+            Some(token) => {
+                self.hand_off(token.clone(), self.state());
+                self.detach_login(token.clone());
+                WsChatServer::from_registry().do_send(DetachSession {
+                    token,
+                    client_name: self.client_name(),
+                    memberships: self.memberships.drain().collect(),
+                });
+            }
+            None => {
+                // send a leave message for every room joined
+                for (room_name, client_id) in std::mem::take(&mut self.memberships) {
+                    let leave_msg = LeaveRoom(room_name, client_id, self.client_name());
+
+                    // issue_sync comes from having the `BrokerIssue` trait in scope.
+                    self.issue_system_sync(leave_msg, ctx);
+                }
+            }
+        }
+
+        if let (Some(account), Some(login)) = (self.account.take(), self.login.take()) {
+            ReadMarkers::from_registry().do_send(UnwatchReads(account.clone(), login));
+            Accounts::from_registry().do_send(Logout(account, login));
+        }
 
-        // issue_sync comes from having the `BrokerIssue` trait in scope.
-        self.issue_system_sync(leave_msg, ctx);
+        if let Some(name) = &self.client_name {
+            WsChatServer::from_registry()
+                .do_send(UnregisterName(name.clone(), ctx.address().recipient()));
+        }
+        Reminders::from_registry().do_send(ForgetReminders(ctx.address().recipient()));
 
         info!(
             "WsChatSession closed for {}({}) in room {}",
@@ -125,11 +3771,175 @@ impl Actor for WsChatSession {
     }
 }
 
+impl Handler<QueryPresence> for WsChatSession {
+    type Result = MessageResult<QueryPresence>;
+
+    fn handle(&mut self, _: QueryPresence, _ctx: &mut Self::Context) -> Self::Result {
+        let idle = self
+            .active
+            .or(self.connected)
+            .map(|active| active.elapsed())
+            .filter(|idle| *idle > IDLE_AFTER);
+
+        MessageResult(ClientPresence {
+            name: self.client_name(),
+            idle,
+        })
+    }
+}
+
 impl Handler<ChatMessage> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        if !self.caught_up.is_empty() {
+            if let Some(id) = message_id(&msg.0) {
+                if self.caught_up.remove(&id) {
+                    return;
+                }
+            }
+        }
+
+        self.send_frame(&msg.0, ctx);
+    }
+}
+
+impl Handler<NameClaimed> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: NameClaimed, ctx: &mut Self::Context) {
+        let NameClaimed(name) = msg;
+        if self.client_name.as_ref() != Some(&name) {
+            return;
+        }
+
+        self.client_name = None;
+        self.refresh_trust(ctx);
+        let text = format!(
+            "{} was claimed on logging in or resuming a session, /name picks \
+             another name",
+            name
+        );
+        let mut fields = serde_json::Map::new();
+        fields.insert("name".to_owned(), name.into());
+        let frame = system_frame("name_claimed", None, &text, fields);
+        self.reply(ctx, Reply::Frame(frame));
+    }
+}
+
+impl Handler<MembershipEvent> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: MembershipEvent, ctx: &mut Self::Context) {
+        if let Ok(frame) = serde_json::to_string(&msg) {
+            self.reply(ctx, Reply::Frame(frame));
+        }
+    }
+}
+
+/// Another session of the account took this one's place, see
+/// `SESSIONS_PER_ACCOUNT`
+impl Handler<SignedOut> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: SignedOut, ctx: &mut Self::Context) {
+        let SignedOut(reason) = msg;
+        // the login is already gone
+        if let (Some(account), Some(login)) = (self.account.clone(), self.login.take()) {
+            ReadMarkers::from_registry().do_send(UnwatchReads(account, login));
+        }
+
+        self.reply(
+            ctx,
+            Reply::Frame(system_frame(
+                "signed_out",
+                None,
+                &reason,
+                Default::default(),
+            )),
+        );
+        self.close(
+            ctx,
+            Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(reason),
+            }),
+        );
+        ctx.stop();
+    }
+}
+
+/// Another session of the account moved a read position
+impl Handler<ReadPosition> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReadPosition, ctx: &mut Self::Context) {
+        self.reply(ctx, Reply::Frame(msg.to_json().to_string()));
+    }
+}
+
+/// The server already took the client out of the room
+impl Handler<RemovedFromRoom> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemovedFromRoom, ctx: &mut Self::Context) {
+        let RemovedFromRoom { room_name, banned } = msg;
+        if self.memberships.remove(&room_name).is_none() {
+            return;
+        }
+
+        let (kind, text) = match banned {
+            true => ("banned", format!("you are banned from {}", room_name)),
+            false => ("removed", format!("you were removed from {}", room_name)),
+        };
+        let frame = system_frame(kind, Some(&room_name), &text, serde_json::Map::new());
+        self.reply(ctx, Reply::Frame(frame));
+
+        if room_name == self.room_name {
+            self.leave_current(ctx);
+        }
+    }
+}
+
+/// Enters the breakout room as its creator, which keeps it off `/list` and
+/// points back at the room it was opened from
+impl Handler<BreakoutOpened> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: BreakoutOpened, ctx: &mut Self::Context) {
+        let BreakoutOpened { room_name, source } = msg;
+
+        let topic = format!("breakout from {}, /join {} to go back", source, source);
+        let template = vec![
+            Setting::Topic(Some(topic)),
+            Setting::Visibility(Visibility::Private),
+            Setting::Flag(RoomFlag::Unlisted, true),
+        ];
+        self.join(&room_name, true, Some(template), None, ctx);
+    }
+}
+
+impl Handler<Drain> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Drain, ctx: &mut Self::Context) {
+        self.migrate(ctx);
+    }
+}
+
+/// Lets the client know before migrating it, as a `shutdown` notice
+impl Handler<ShuttingDown> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ShuttingDown, ctx: &mut Self::Context) {
+        let frame = system_frame(
+            "shutdown",
+            None,
+            "server shutting down, reconnect in a moment",
+            Default::default(),
+        );
+        self.reply(ctx, Reply::Frame(frame));
+        self.migrate(ctx);
     }
 }
 
@@ -146,6 +3956,18 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             }
             Ok(msg) => msg,
         };
+        self.heard = Some(Instant::now());
+
+        if let Some(recording) = &self.recording {
+            match &msg {
+                ws::Message::Text(text) => recording.text(Direction::In, text),
+                ws::Message::Binary(data) => recording.binary(Direction::In, data),
+                ws::Message::Close(reason) => {
+                    recording.close(Direction::In, reason.as_ref())
+                }
+                _ => {}
+            }
+        }
 
         debug!(
             "WsChatSession::handle() - message: {:?} from: {}",
@@ -154,48 +3976,379 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
         );
 
         match msg {
+            ws::Message::Text(_) if CHAOS.as_ref().is_some_and(Chaos::drops) => {}
             ws::Message::Text(text) => {
+                self.active = Some(Instant::now());
+                let received = unix_millis();
                 let msg = text.trim();
 
-                if msg.starts_with('/') {
-                    let mut command = msg.splitn(2, ' ');
-
-                    match command.next() {
-                        Some("/list") => self.list_rooms(ctx),
-
-                        Some("/join") => {
-                            if let Some(room_name) = command.next() {
-                                self.join_room(room_name, ctx);
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-
-                        Some("/name") => {
-                            if let Some(name) = command.next() {
-                                self.client_name = Some(name.to_owned());
-                                ctx.text(format!("name changed to: {}", name));
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-
-                        Some("/list-clients") => self.list_clients(ctx),
-
-                        Some("/whoami") => self.who_am_i(ctx),
-
-                        _ => ctx.text(format!("!!! unknown command: {:?}", msg)),
-                    }
+                if is_frame(msg) || (self.said_hello && msg.starts_with('{')) {
+                    self.handle_frame(msg, received, ctx);
+                    return;
+                }
 
+                if msg.starts_with('/') {
+                    commands::dispatch(self, msg, received, ctx);
                     return;
                 }
-                self.send_msg(msg);
+                self.send_msg(msg, ctx);
+            }
+            ws::Message::Binary(chunk) => {
+                self.active = Some(Instant::now());
+                self.upload_chunk(&chunk, ctx)
+            }
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Pong(_) => {
+                if let Some(sent) = self.ping_sent.take() {
+                    self.rtt = Some(sent.elapsed());
+                }
             }
             ws::Message::Close(reason) => {
-                ctx.close(reason);
+                // anything but going away on purpose, e.g. a page reloading,
+                // may come back
+                let on_purpose = reason
+                    .as_ref()
+                    .is_none_or(|reason| reason.code == ws::CloseCode::Normal);
+                self.close(ctx, reason);
+                self.resumable = !on_purpose;
                 ctx.stop();
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocklist::{BlockAction, BlockRule};
+    use crate::testing::{run, TestClient};
+
+    #[test]
+    fn test_commands() {
+        run(async {
+            let mut client = TestClient::connect(&[]);
+            let welcome = client.texts().await;
+            assert!(welcome[0].contains(r#""kind":"welcome""#));
+
+            assert_eq!(client.ask("/name bob").await, ["name changed to: bob"]);
+            assert_eq!(client.ask("/name").await, ["!!! name is required"]);
+            let refused = client.ask("/name x:y").await;
+            assert!(refused[0].starts_with("!!! a name is"));
+            assert_eq!(client.ask("/name ann y").await, ["name changed to: ann"]);
+            assert_eq!(
+                client.ask("/frob").await,
+                [r#"!!! unknown command: "/frob""#]
+            );
+        });
+    }
+
+    #[test]
+    fn test_token_name() {
+        run(async {
+            let mut session = WsChatSession::new(&DefaultRooms(Vec::new()));
+            session.sign_in("alice".to_owned());
+            let mut client = TestClient::start(session);
+            client.texts().await;
+
+            let locked = "!!! your name comes from the token you signed in with";
+            assert_eq!(client.ask("/name mallory").await, [locked]);
+            assert_eq!(
+                client.ask(r#"{"type":"name","name":"mallory"}"#).await,
+                [locked]
+            );
+            let whoami = client.ask("/whoami").await;
+            assert!(whoami[0].starts_with("name: alice,"));
+        });
+    }
+
+    #[test]
+    fn test_room_messages() {
+        run(async {
+            let mut alice = TestClient::connect(&["Main"]);
+            alice.texts().await;
+            alice.ask("/name alice").await;
+
+            let mut bob = TestClient::connect(&["Main"]);
+            bob.texts().await;
+            let joined = alice.texts().await;
+            assert!(joined[0].contains(r#""kind":"joined""#));
+
+            let sent = alice.ask("hi bob").await;
+            assert!(sent[0].contains(r#""type":"ack""#));
+            assert_eq!(sent[1], "alice: hi bob");
+            assert_eq!(bob.texts().await, ["alice: hi bob"]);
+        });
+    }
+
+    /// `alice` owning room `r`, and `bob` in it
+    async fn owner_and_member() -> (TestClient, TestClient) {
+        let mut alice = TestClient::connect(&[]);
+        alice.texts().await;
+        alice.ask_for("/name alice", "name changed").await;
+        alice.ask_for("/join r", "alice joined r").await;
+
+        let mut bob = TestClient::connect(&[]);
+        bob.texts().await;
+        bob.ask_for("/name bob", "name changed").await;
+        bob.ask_for("/join r", "bob joined r").await;
+        alice.wait_for("bob joined r").await;
+
+        (alice, bob)
+    }
+
+    /// The id in the ack of a chat message just sent
+    async fn post(client: &mut TestClient, text: &str) -> u64 {
+        let ack = client.ask_for(text, r#""type":"ack""#).await;
+        let ack: serde_json::Value = serde_json::from_str(&ack).unwrap();
+        ack["id"].as_u64().unwrap()
+    }
+
+    #[test]
+    fn test_names_are_unique() {
+        run(async {
+            let (_alice, mut bob) = owner_and_member().await;
+
+            assert_eq!(
+                bob.ask_for("/name alice", "!!!").await,
+                "!!! alice is already taken by another client"
+            );
+            let whoami = bob.ask_for("/whoami", "name:").await;
+            assert!(whoami.starts_with("name: bob,"));
+        });
+    }
+
+    #[test]
+    fn test_whispers_are_filtered() {
+        run(async {
+            let rule = BlockRule {
+                action: BlockAction::Warn,
+                mute_secs: None,
+            };
+            blocklist::put("zorblax", rule).unwrap();
+            let (mut alice, mut bob) = owner_and_member().await;
+
+            assert_eq!(
+                alice.ask_for("/msg bob zorblax!", "!!!").await,
+                "!!! \"zorblax\" isn't allowed, the message wasn't sent"
+            );
+            alice.ask_for("/msg bob hi", "bob").await;
+            assert_eq!(bob.wait_for("alice").await, "[whisper] alice: hi");
+
+            blocklist::remove("zorblax");
+        });
+    }
+
+    #[test]
+    fn test_whispers_are_rate_limited() {
+        run(async {
+            let (mut alice, _bob) = owner_and_member().await;
+
+            for _ in 0..=config().message_rate {
+                alice.send("/msg bob hi");
+            }
+            alice.wait_for("!!! sending too fast").await;
+        });
+    }
+
+    #[test]
+    fn test_reminders_belong_to_the_session() {
+        run(async {
+            let mut alice = TestClient::connect(&[]);
+            alice.texts().await;
+            alice.ask("/name alice").await;
+
+            alice.ask_for("/remind me 1s hi", "set for").await;
+            alice.wait_for("[reminder] hi").await;
+
+            alice.ask_for("/remind me 1s bye", "set for").await;
+            alice.send_message(ws::Message::Close(None));
+            alice.texts().await;
+
+            // whoever takes the name next doesn't get the rest
+            let mut mallory = TestClient::connect(&[]);
+            mallory.texts().await;
+            mallory.ask("/name alice").await;
+            assert_eq!(mallory.ask("/reminders").await, ["no reminders"]);
+            actix_rt::time::delay_for(Duration::from_millis(1500)).await;
+            assert!(!mallory
+                .texts()
+                .await
+                .iter()
+                .any(|text| text.contains("bye")));
+        });
+    }
+
+    #[test]
+    fn test_scheduled_messages_belong_to_the_session() {
+        run(async {
+            let mut alice = TestClient::connect(&["r"]);
+            alice.texts().await;
+            alice.ask("/name alice").await;
+            alice
+                .ask_for("/schedule 1h hi", "scheduled message 1")
+                .await;
+            alice.send_message(ws::Message::Close(None));
+            alice.texts().await;
+
+            let mut mallory = TestClient::connect(&["r"]);
+            mallory.texts().await;
+            mallory.ask("/name alice").await;
+            assert_eq!(mallory.ask("/scheduled").await, ["no scheduled messages"]);
+            assert_eq!(
+                mallory.ask("/unschedule 1").await,
+                ["!!! no scheduled message 1"]
+            );
+        });
+    }
+
+    #[test]
+    fn test_bans() {
+        run(async {
+            let (mut alice, mut bob) = owner_and_member().await;
+
+            assert_eq!(
+                bob.ask_for("/ban alice", "!!!").await,
+                "!!! only the room owner can do that"
+            );
+            alice.ask_for("/ban bob", "bob is banned from r").await;
+            bob.wait_for("you are banned from r").await;
+            bob.wait_for("you are in the lobby").await;
+            assert_eq!(
+                bob.ask_for("/join r", "!!!").await,
+                "!!! you are banned from this room"
+            );
+
+            alice.ask_for("/unban bob", "no longer banned").await;
+            bob.ask_for("/join r", "bob joined r").await;
+        });
+    }
+
+    #[test]
+    fn test_mutes() {
+        run(async {
+            let (mut alice, mut bob) = owner_and_member().await;
+
+            alice.ask_for("/mute bob 10m", "bob is muted in r").await;
+            let muted = bob.ask_for("hi", "!!!").await;
+            assert!(muted.starts_with("!!! you are muted in this room for"));
+            assert_eq!(
+                bob.ask_for("/name bobby", "!!!").await,
+                "!!! names banned or muted in r can't be taken or given up there"
+            );
+
+            alice
+                .ask_for("/unmute bob", "bob may post in r again")
+                .await;
+            post(&mut bob, "hi again").await;
+            alice.wait_for("bob: hi again").await;
+        });
+    }
+
+    #[test]
+    fn test_access_policies() {
+        run(async {
+            let (mut alice, _bob) = owner_and_member().await;
+            let mut carol = TestClient::connect(&[]);
+            carol.texts().await;
+            carol.ask_for("/name carol", "name changed").await;
+
+            alice
+                .ask_for("/access invite", "r is now invite only")
+                .await;
+            assert_eq!(
+                carol.ask_for("/join r", "!!!").await,
+                "!!! this room is invite only, ask its owner for an invite"
+            );
+            alice.ask_for("/invite carol", "invited carol").await;
+            carol.wait_for("you are invited to r").await;
+            carol.ask_for("/join r", "carol joined r").await;
+            carol.ask_for("/leave r", "left r").await;
+
+            alice
+                .ask_for("/access password s3cret", "r now needs a password")
+                .await;
+            assert_eq!(
+                carol.ask_for("/join r", "!!!").await,
+                "!!! this room needs a password, use /join room password"
+            );
+            assert_eq!(
+                carol.ask_for("/join r nope", "!!!").await,
+                "!!! wrong password for this room"
+            );
+            carol.ask_for("/join r s3cret", "carol joined r").await;
+        });
+    }
+
+    #[test]
+    fn test_edit_and_delete() {
+        run(async {
+            let (mut alice, mut bob) = owner_and_member().await;
+            let id = post(&mut alice, "helo").await;
+            bob.wait_for("alice: helo").await;
+
+            let not_author = "!!! only its author can change a message";
+            assert_eq!(
+                bob.ask_for(&format!("/edit {} hi", id), "!!!").await,
+                not_author
+            );
+            assert_eq!(
+                bob.ask_for(&format!("/delete {}", id), "!!!").await,
+                not_author
+            );
+
+            alice.ask(&format!("/edit {} hello", id)).await;
+            let edited = bob.wait_for(r#""kind":"message_edited""#).await;
+            assert!(edited.contains(r#""content":"hello""#));
+            alice.ask(&format!("/delete {}", id)).await;
+            bob.wait_for(r#""kind":"message_deleted""#).await;
+            assert!(alice
+                .ask_for(&format!("/edit {} again", id), "!!!")
+                .await
+                .starts_with("!!! no message"));
+        });
+    }
+
+    #[test]
+    fn test_reactions() {
+        run(async {
+            let (mut alice, mut bob) = owner_and_member().await;
+            let id = post(&mut alice, "lunch?").await;
+            bob.wait_for("alice: lunch?").await;
+
+            bob.ask(&format!("/react {} 👍", id)).await;
+            let reacted = alice.wait_for(r#""type":"reactions""#).await;
+            assert!(reacted.contains(r#""reactions":{"👍":1}"#));
+            // reacting again takes it back
+            bob.ask(&format!("/react {} 👍", id)).await;
+            let reacted = alice.wait_for(r#""type":"reactions""#).await;
+            assert!(reacted.contains(r#""reactions":{}"#));
+            assert!(bob
+                .ask_for("/react 1 👍", "!!!")
+                .await
+                .starts_with("!!! no message"));
+        });
+    }
+
+    #[test]
+    fn test_upload_policy() {
+        run(async {
+            let (mut alice, _bob) = owner_and_member().await;
+            alice
+                .ask_for("/settings upload_types image/*", "settings changed")
+                .await;
+
+            let check = |mime: &str| CheckUpload {
+                room_name: "r".to_owned(),
+                mime: mime.to_owned(),
+                size: 3,
+            };
+            let server = WsChatServer::from_registry();
+            assert!(matches!(
+                server.send(check("text/plain")).await,
+                Ok(Err(RoomError::UploadType(_)))
+            ));
+            assert!(matches!(server.send(check("image/png")).await, Ok(Ok(()))));
+        });
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::config::config;
+
+const MAX_TOPIC_LEN: usize = 256;
+const MAX_WELCOME_LEN: usize = 1024;
+const MAX_RULES_LEN: usize = 2048;
+const MAX_LANGUAGE_LEN: usize = 35;
+const MAX_UPLOAD_TYPES: usize = 20;
+
+/// Room settings, changed with `/settings` or `PATCH /api/rooms/{room}`. Both
+/// go through `UpdateRoomSettings`, so they accept exactly the same settings.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomSettings {
+    pub topic: Option<String>,
+    /// seconds each member has to wait between messages
+    pub slow_mode: Option<u64>,
+    /// most clients in the room at once, counted per node
+    pub capacity: Option<usize>,
+    /// events the journal keeps for the room, at most the config's
+    /// `history_depth`
+    pub retention: Option<usize>,
+    /// shown to every client joining the room
+    pub welcome: Option<String>,
+    /// pinned under the welcome message
+    pub rules: Option<String>,
+    /// BCP 47 tag of the language spoken in the room, e.g. `pt-BR`
+    pub language: Option<String>,
+    /// largest attachment or voice note accepted, in bytes, below the
+    /// node's `max_attachment_size`
+    pub max_upload_size: Option<usize>,
+    /// the only types attachments and voice notes may have, e.g. `image/*`
+    /// or `application/pdf`, any if unset
+    pub upload_types: Option<Vec<String>>,
+    /// hours between activity digests posted to the room
+    pub digest: Option<u64>,
+    /// who may read the room's history over HTTP
+    #[serde(default)]
+    pub visibility: Visibility,
+    pub flags: BTreeSet<RoomFlag>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// anyone
+    #[default]
+    Public,
+    /// clients authenticating as a registered account
+    Members,
+    /// only the admin API token
+    Private,
+}
+
+impl Visibility {
+    fn name(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Members => "members",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomFlag {
+    /// left out of `/list`
+    Unlisted,
+    /// attachments and voice notes are rejected
+    NoAttachments,
+    /// nothing of the room is kept in the journal or passed on from it, so
+    /// it never reaches webhooks or event streams
+    NoLog,
+    /// only the owner and whoever they called on may post
+    Moderated,
+    /// members can `/ask` questions and upvote them
+    Qa,
+    /// clients finding the room full are sent to an overflow room, created
+    /// for them with its settings
+    Overflow,
+}
+
+impl RoomFlag {
+    const ALL: [RoomFlag; 6] = [
+        RoomFlag::Unlisted,
+        RoomFlag::NoAttachments,
+        RoomFlag::NoLog,
+        RoomFlag::Moderated,
+        RoomFlag::Qa,
+        RoomFlag::Overflow,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            RoomFlag::Unlisted => "unlisted",
+            RoomFlag::NoAttachments => "no_attachments",
+            RoomFlag::NoLog => "no_log",
+            RoomFlag::Moderated => "moderated",
+            RoomFlag::Qa => "qa",
+            RoomFlag::Overflow => "overflow",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<RoomFlag> {
+        RoomFlag::ALL
+            .iter()
+            .copied()
+            .find(|flag| flag.name() == name)
+    }
+}
+
+/// A change to one setting, `None` clears it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Setting {
+    Topic(Option<String>),
+    SlowMode(Option<u64>),
+    Capacity(Option<usize>),
+    Retention(Option<usize>),
+    Welcome(Option<String>),
+    Rules(Option<String>),
+    Language(Option<String>),
+    MaxUploadSize(Option<usize>),
+    UploadTypes(Option<Vec<String>>),
+    Digest(Option<u64>),
+    Visibility(Visibility),
+    Flag(RoomFlag, bool),
+}
+
+fn text_setting(key: &str, value: Value, max: usize) -> Result<Option<String>, String> {
+    let text = match value {
+        Value::Null => return Ok(None),
+        Value::String(text) => text,
+        _ => return Err(format!("{}: expected a string or null", key)),
+    };
+
+    if text.chars().count() > max {
+        return Err(format!("{}: longer than {} characters", key, max));
+    }
+
+    Ok(Some(text).filter(|text| !text.trim().is_empty()))
+}
+
+fn number_setting(key: &str, value: Value, max: u64) -> Result<Option<u64>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Number(n) => match n.as_u64() {
+            Some(n) if n > 0 && n <= max => Ok(Some(n)),
+            _ => Err(format!("{}: expected a number from 1 to {}", key, max)),
+        },
+        _ => Err(format!("{}: expected a number or null", key)),
+    }
+}
+
+/// `tag` as a BCP 47 language tag in its usual case, e.g. `zh-Hant-TW`,
+/// `None` if it isn't one
+pub fn language_tag(tag: &str) -> Option<String> {
+    if tag.len() > MAX_LANGUAGE_LEN {
+        return None;
+    }
+
+    let mut subtags = tag.split('-');
+    let language = subtags.next()?;
+    if !(2..=8).contains(&language.len())
+        || !language.chars().all(|c| c.is_ascii_alphabetic())
+    {
+        return None;
+    }
+
+    let mut normalized = language.to_ascii_lowercase();
+    for subtag in subtags {
+        if subtag.is_empty()
+            || subtag.len() > 8
+            || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+
+        normalized.push('-');
+        match subtag.len() {
+            // a script
+            4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                normalized.push_str(&subtag[..1].to_ascii_uppercase());
+                normalized.push_str(&subtag[1..].to_ascii_lowercase());
+            }
+            // a region
+            2 => normalized.push_str(&subtag.to_ascii_uppercase()),
+            _ => normalized.push_str(&subtag.to_ascii_lowercase()),
+        }
+    }
+
+    Some(normalized)
+}
+
+/// Whether a room tagged `tag` is in the language `range`, e.g. `pt-BR` is
+/// in `pt` but not the other way round
+pub fn language_matches(tag: &str, range: &str) -> bool {
+    let (tag, range) = (tag.to_ascii_lowercase(), range.to_ascii_lowercase());
+
+    tag == range || tag.starts_with(&format!("{}-", range))
+}
+
+/// A MIME type or a `type/*` range, lower case
+fn mime_range(range: &str) -> Option<String> {
+    let token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+    };
+
+    let range = range.trim().to_ascii_lowercase();
+    let (kind, subtype) = range.split_once('/')?;
+    (token(kind) && (subtype == "*" || token(subtype))).then_some(range)
+}
+
+fn upload_types(key: &str, value: Value) -> Result<Option<Vec<String>>, String> {
+    let expected =
+        || format!("{}: expected a list of types such as image/*, or null", key);
+    let ranges = match value {
+        Value::Null => return Ok(None),
+        Value::Array(ranges) => ranges,
+        _ => return Err(expected()),
+    };
+    if ranges.is_empty() || ranges.len() > MAX_UPLOAD_TYPES {
+        return Err(format!("{}: expected 1 to {} types", key, MAX_UPLOAD_TYPES));
+    }
+
+    let mut types: Vec<String> = ranges
+        .iter()
+        .map(|range| range.as_str().and_then(mime_range).ok_or_else(expected))
+        .collect::<Result<_, _>>()?;
+    types.sort();
+    types.dedup();
+
+    Ok(Some(types))
+}
+
+impl Setting {
+    /// From one field of a `PATCH /api/rooms/{room}` body, `null` clears it
+    pub fn from_json(key: &str, value: Value) -> Result<Setting, String> {
+        Ok(match key {
+            "topic" => Setting::Topic(text_setting(key, value, MAX_TOPIC_LEN)?),
+            "welcome" => Setting::Welcome(text_setting(key, value, MAX_WELCOME_LEN)?),
+            "rules" => Setting::Rules(text_setting(key, value, MAX_RULES_LEN)?),
+            "language" => match text_setting(key, value, MAX_LANGUAGE_LEN)? {
+                Some(tag) => Setting::Language(Some(
+                    language_tag(tag.trim()).ok_or_else(|| {
+                        format!("{}: expected a language tag such as en or pt-BR", key)
+                    })?,
+                )),
+                None => Setting::Language(None),
+            },
+            "max_upload_size" => Setting::MaxUploadSize(
+                number_setting(key, value, config().max_attachment_size as u64)?
+                    .map(|n| n as usize),
+            ),
+            "upload_types" => Setting::UploadTypes(upload_types(key, value)?),
+            "slow_mode" => Setting::SlowMode(number_setting(key, value, 3600)?),
+            "digest" => Setting::Digest(number_setting(key, value, 7 * 24)?),
+            "capacity" => Setting::Capacity(
+                number_setting(key, value, u32::MAX.into())?.map(|n| n as usize),
+            ),
+            "retention" => Setting::Retention(
+                number_setting(key, value, config().history_depth as u64)?
+                    .map(|n| n as usize),
+            ),
+            "visibility" => {
+                Setting::Visibility(serde_json::from_value(value).map_err(|_| {
+                    format!("{}: expected public, members or private", key)
+                })?)
+            }
+            key => {
+                let flag = RoomFlag::from_name(key)
+                    .ok_or_else(|| format!("unknown setting: {}", key))?;
+
+                match value {
+                    Value::Bool(on) => Setting::Flag(flag, on),
+                    _ => return Err(format!("{}: expected true or false", key)),
+                }
+            }
+        })
+    }
+
+    /// From `/settings key value`. `off` clears a setting or turns a flag off,
+    /// `on` turns a flag on.
+    pub fn from_command(key: &str, value: &str) -> Result<Setting, String> {
+        let value = value.trim();
+
+        let json = match (key, value) {
+            (_, "off") if RoomFlag::from_name(key).is_some() => Value::Bool(false),
+            (_, "off") => Value::Null,
+            ("topic", _)
+            | ("welcome", _)
+            | ("rules", _)
+            | ("language", _)
+            | ("visibility", _) => Value::String(value.to_owned()),
+            ("upload_types", _) => value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|range| !range.is_empty())
+                .map(Value::from)
+                .collect(),
+            (_, "on") => Value::Bool(true),
+            (_, value) => value
+                .parse::<u64>()
+                .map(Value::from)
+                .map_err(|_| format!("{}: expected a number or off", key))?,
+        };
+
+        Setting::from_json(key, json)
+    }
+
+    /// The setting's name on both surfaces
+    pub fn key(&self) -> &'static str {
+        match self {
+            Setting::Topic(_) => "topic",
+            Setting::SlowMode(_) => "slow_mode",
+            Setting::Capacity(_) => "capacity",
+            Setting::Retention(_) => "retention",
+            Setting::Welcome(_) => "welcome",
+            Setting::Rules(_) => "rules",
+            Setting::Language(_) => "language",
+            Setting::MaxUploadSize(_) => "max_upload_size",
+            Setting::UploadTypes(_) => "upload_types",
+            Setting::Digest(_) => "digest",
+            Setting::Visibility(_) => "visibility",
+            Setting::Flag(flag, _) => flag.name(),
+        }
+    }
+
+    pub fn apply(self, settings: &mut RoomSettings) {
+        match self {
+            Setting::Topic(topic) => settings.topic = topic,
+            Setting::SlowMode(secs) => settings.slow_mode = secs,
+            Setting::Capacity(capacity) => settings.capacity = capacity,
+            Setting::Retention(retention) => settings.retention = retention,
+            Setting::Welcome(welcome) => settings.welcome = welcome,
+            Setting::Rules(rules) => settings.rules = rules,
+            Setting::Language(language) => settings.language = language,
+            Setting::MaxUploadSize(size) => settings.max_upload_size = size,
+            Setting::UploadTypes(types) => settings.upload_types = types,
+            Setting::Digest(hours) => settings.digest = hours,
+            Setting::Visibility(visibility) => settings.visibility = visibility,
+            Setting::Flag(flag, true) => {
+                settings.flags.insert(flag);
+            }
+            Setting::Flag(flag, false) => {
+                settings.flags.remove(&flag);
+            }
+        }
+    }
+}
+
+/// Parses a `PATCH /api/rooms/{room}` body, e.g.
+/// `{"topic":"standup at 10","slow_mode":null,"unlisted":true}`
+pub fn parse_patch(body: Map<String, Value>) -> Result<Vec<Setting>, String> {
+    body.into_iter()
+        .map(|(key, value)| Setting::from_json(&key, value))
+        .collect()
+}
+
+impl RoomSettings {
+    pub fn has_flag(&self, flag: RoomFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether `upload_types` lets attachments of type `mime` in
+    pub fn takes_upload_type(&self, mime: &str) -> bool {
+        let types = match &self.upload_types {
+            Some(types) => types,
+            None => return true,
+        };
+        let mime = mime.to_ascii_lowercase();
+        // parameters such as `codecs=opus` don't matter
+        let mime = mime.split(';').next().unwrap_or_default().trim();
+        let kind = mime.split('/').next().unwrap_or_default();
+
+        types.iter().any(|range| match range.strip_suffix("/*") {
+            Some(range_kind) => range_kind == kind,
+            None => range == mime,
+        })
+    }
+
+    /// The changes that give a room these settings from the defaults, only
+    /// the settings that differ from them
+    pub fn changes(&self) -> Vec<Setting> {
+        let defaults = RoomSettings::default();
+        let mut changes: Vec<_> = vec![
+            Setting::Topic(self.topic.clone()),
+            Setting::SlowMode(self.slow_mode),
+            Setting::Capacity(self.capacity),
+            Setting::Retention(self.retention),
+            Setting::Welcome(self.welcome.clone()),
+            Setting::Rules(self.rules.clone()),
+            Setting::Language(self.language.clone()),
+            Setting::MaxUploadSize(self.max_upload_size),
+            Setting::UploadTypes(self.upload_types.clone()),
+            Setting::Digest(self.digest),
+            Setting::Visibility(self.visibility),
+        ]
+        .into_iter()
+        .filter(|change| {
+            let mut settings = defaults.clone();
+            change.clone().apply(&mut settings);
+            settings != defaults
+        })
+        .collect();
+        changes.extend(self.flags.iter().map(|flag| Setting::Flag(*flag, true)));
+
+        changes
+    }
+
+    /// One `key: value` line per setting, for `/settings`
+    pub fn lines(&self) -> Vec<String> {
+        fn show<T: ToString>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "off".to_owned(), |value| value.to_string())
+        }
+
+        let mut lines = vec![
+            format!("topic: {}", show(&self.topic)),
+            format!("slow_mode: {}", show(&self.slow_mode)),
+            format!("capacity: {}", show(&self.capacity)),
+            format!("retention: {}", show(&self.retention)),
+            format!("welcome: {}", show(&self.welcome)),
+            format!("rules: {}", show(&self.rules)),
+            format!("language: {}", show(&self.language)),
+            format!("max_upload_size: {}", show(&self.max_upload_size)),
+            format!(
+                "upload_types: {}",
+                show(&self.upload_types.as_ref().map(|types| types.join(", ")))
+            ),
+            format!("digest: {}", show(&self.digest)),
+            format!("visibility: {}", self.visibility.name()),
+        ];
+
+        for flag in &RoomFlag::ALL {
+            let on = if self.has_flag(*flag) { "on" } else { "off" };
+            lines.push(format!("{}: {}", flag.name(), on));
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        assert_eq!(
+            Setting::from_command("topic", " standup at 10 "),
+            Ok(Setting::Topic(Some("standup at 10".to_owned())))
+        );
+        assert_eq!(
+            Setting::from_command("topic", "off"),
+            Ok(Setting::Topic(None))
+        );
+        assert_eq!(
+            Setting::from_command("slow_mode", "30"),
+            Ok(Setting::SlowMode(Some(30)))
+        );
+        assert_eq!(
+            Setting::from_command("unlisted", "on"),
+            Ok(Setting::Flag(RoomFlag::Unlisted, true))
+        );
+        assert_eq!(
+            Setting::from_command("no_attachments", "off"),
+            Ok(Setting::Flag(RoomFlag::NoAttachments, false))
+        );
+        assert!(Setting::from_command("slow_mode", "soon").is_err());
+        assert!(Setting::from_command("capacity", "0").is_err());
+        assert!(Setting::from_command("retention", "5000").is_err());
+        assert!(Setting::from_command("colour", "red").is_err());
+        assert_eq!(
+            Setting::from_command("visibility", "members"),
+            Ok(Setting::Visibility(Visibility::Members))
+        );
+        assert!(Setting::from_command("visibility", "secret").is_err());
+        assert_eq!(
+            Setting::from_command("language", "PT-br"),
+            Ok(Setting::Language(Some("pt-BR".to_owned())))
+        );
+        assert!(Setting::from_command("language", "portuguese!").is_err());
+        assert_eq!(language_tag("zh-hant-tw"), Some("zh-Hant-TW".to_owned()));
+        assert_eq!(language_tag("en--US"), None);
+        assert!(language_matches("pt-BR", "pt"));
+        assert!(!language_matches("pt", "pt-BR"));
+        assert!(!language_matches("ptx", "pt"));
+
+        let types = Setting::from_command("upload_types", "image/*, Application/PDF");
+        let types = match types {
+            Ok(Setting::UploadTypes(Some(types))) => types,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(types, ["application/pdf", "image/*"]);
+        assert!(Setting::from_command("upload_types", "images").is_err());
+        assert!(Setting::from_command("max_upload_size", "100000000000").is_err());
+
+        let settings = RoomSettings {
+            upload_types: Some(types),
+            ..Default::default()
+        };
+        assert!(settings.takes_upload_type("IMAGE/png"));
+        assert!(settings.takes_upload_type("application/pdf"));
+        assert!(!settings.takes_upload_type("audio/ogg; codecs=opus"));
+        assert!(RoomSettings::default().takes_upload_type("text/html"));
+
+        let body =
+            serde_json::json!({"capacity": 10, "welcome": null, "unlisted": true});
+        let mut settings = RoomSettings {
+            welcome: Some("hi".to_owned()),
+            ..Default::default()
+        };
+        for setting in parse_patch(body.as_object().unwrap().clone()).unwrap() {
+            setting.apply(&mut settings);
+        }
+        assert_eq!(settings.capacity, Some(10));
+        assert_eq!(settings.welcome, None);
+        assert!(settings.has_flag(RoomFlag::Unlisted));
+
+        let changes = settings.changes();
+        assert_eq!(changes.len(), 2);
+        let mut copy = RoomSettings::default();
+        for setting in changes {
+            setting.apply(&mut copy);
+        }
+        assert_eq!(copy, settings);
+
+        let body = serde_json::json!({"topic": 5});
+        assert!(parse_patch(body.as_object().unwrap().clone()).is_err());
+    }
+}
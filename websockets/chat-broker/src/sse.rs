@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use actix::fut;
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::warn;
+
+use crate::access::{authorize_history, history_response};
+use crate::journal::{Journal, RoomEvent};
+use crate::message::SubscribeEvents;
+
+/// How often an idle stream gets a comment line, so proxies keep it open and
+/// closed streams are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Relays a room's journal to one server-sent events response
+struct EventStream {
+    room_name: String,
+    /// journal sequence number of the last event written
+    last_seq: u64,
+    tx: mpsc::UnboundedSender<Bytes>,
+}
+
+impl EventStream {
+    fn write(&mut self, event: &RoomEvent, ctx: &mut Context<Self>) {
+        // the backlog and the live events may overlap
+        if event.seq <= self.last_seq {
+            return;
+        }
+        self.last_seq = event.seq;
+
+        let data = match serde_json::to_string(event) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("EventStream - can't serialize event: {}", err);
+                return;
+            }
+        };
+
+        let frame = format!("id: {}\ndata: {}\n\n", event.seq, data);
+
+        // the client went away
+        if self.tx.unbounded_send(Bytes::from(frame)).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for EventStream {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let msg = SubscribeEvents {
+            room_name: Some(self.room_name.clone()),
+            since: self.last_seq,
+            subscriber: ctx.address().recipient(),
+        };
+
+        // wait() holds back live events until the backlog is written
+        Journal::from_registry()
+            .send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(backlog) => {
+                        for event in &backlog {
+                            act.write(event, ctx);
+                        }
+                    }
+                    Err(_) => ctx.stop(),
+                }
+
+                fut::ready(())
+            })
+            .wait(ctx);
+
+        ctx.run_interval(KEEPALIVE_INTERVAL, |act, ctx| {
+            if act
+                .tx
+                .unbounded_send(Bytes::from(": keepalive\n\n"))
+                .is_err()
+            {
+                ctx.stop();
+            }
+        });
+    }
+}
+
+impl Handler<RoomEvent> for EventStream {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomEvent, ctx: &mut Self::Context) {
+        self.write(&msg, ctx);
+    }
+}
+
+/// Streams a room's events as server-sent events, for read-only consumers.
+/// Every event carries its journal sequence number as its id, and a
+/// reconnect sending it back as `Last-Event-ID` first gets whatever it missed.
+/// Only for whoever the room's `visibility` lets in.
+pub async fn room_events(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let indexable = match authorize_history(&req, &room_name).await {
+        Ok(indexable) => indexable,
+        Err(res) => return Ok(res),
+    };
+
+    let since = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok())
+        .unwrap_or(0);
+
+    let (tx, rx) = mpsc::unbounded();
+
+    EventStream {
+        room_name: room_name.into_inner(),
+        last_seq: since,
+        tx,
+    }
+    .start();
+
+    Ok(history_response(indexable)
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(rx.map(Ok::<_, Error>)))
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use actix::prelude::*;
+use serde::Serialize;
+
+use crate::journal::{Journal, RoomEvent};
+use crate::message::{FindEvents, ListStars, StarMessage, UnstarMessage};
+use crate::session::unix_millis;
+
+/// Messages an account may star, so the list stays a list
+const MAX_STARS_PER_ACCOUNT: usize = 500;
+
+#[derive(Debug, PartialEq)]
+pub enum StarError {
+    AlreadyStarred,
+    NotStarred,
+    TooMany,
+}
+
+impl fmt::Display for StarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StarError::AlreadyStarred => write!(f, "message is already starred"),
+            StarError::NotStarred => write!(f, "message isn't starred"),
+            StarError::TooMany => write!(
+                f,
+                "an account can star at most {} messages, /unstar some first",
+                MAX_STARS_PER_ACCOUNT
+            ),
+        }
+    }
+}
+
+/// A message an account saved, by its journal sequence number
+#[derive(Clone, Debug, Serialize)]
+pub struct Star {
+    pub seq: u64,
+    pub room_name: String,
+    /// unix milliseconds
+    pub starred: u64,
+}
+
+/// A star with the message it points at, `None` once the journal dropped it
+#[derive(Clone, Debug, Serialize)]
+pub struct StarredMessage {
+    #[serde(flatten)]
+    pub star: Star,
+    pub event: Option<RoomEvent>,
+}
+
+/// Starred messages by account, newest first. Stars only keep where the
+/// message is, it is looked up in the journal when listed, so like sequence
+/// numbers they are kept per node.
+#[derive(Default)]
+pub struct Stars {
+    stars: HashMap<String, Vec<Star>>,
+}
+
+impl Actor for Stars {
+    type Context = Context<Self>;
+}
+
+impl Handler<StarMessage> for Stars {
+    type Result = Result<(), StarError>;
+
+    fn handle(&mut self, msg: StarMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let StarMessage {
+            account,
+            room_name,
+            seq,
+        } = msg;
+
+        let stars = self.stars.entry(account).or_default();
+        if stars.iter().any(|star| star.seq == seq) {
+            return Err(StarError::AlreadyStarred);
+        }
+        if stars.len() >= MAX_STARS_PER_ACCOUNT {
+            return Err(StarError::TooMany);
+        }
+
+        stars.insert(
+            0,
+            Star {
+                seq,
+                room_name,
+                starred: unix_millis() as u64,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+impl Handler<UnstarMessage> for Stars {
+    type Result = Result<(), StarError>;
+
+    fn handle(&mut self, msg: UnstarMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let UnstarMessage { account, seq } = msg;
+
+        let stars = self.stars.get_mut(&account).ok_or(StarError::NotStarred)?;
+        let before = stars.len();
+        stars.retain(|star| star.seq != seq);
+        if stars.len() == before {
+            return Err(StarError::NotStarred);
+        }
+        if stars.is_empty() {
+            self.stars.remove(&account);
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<ListStars> for Stars {
+    type Result = MessageResult<ListStars>;
+
+    fn handle(&mut self, msg: ListStars, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.stars.get(&msg.0).cloned().unwrap_or_default())
+    }
+}
+
+impl SystemService for Stars {}
+impl Supervised for Stars {}
+
+/// The account's stars, newest first, with their messages from the journal
+pub async fn starred(account: String) -> Result<Vec<StarredMessage>, MailboxError> {
+    let stars = Stars::from_registry().send(ListStars(account)).await?;
+    let seqs = stars.iter().map(|star| star.seq).collect();
+
+    let mut events: HashMap<u64, RoomEvent> = Journal::from_registry()
+        .send(FindEvents(seqs))
+        .await?
+        .into_iter()
+        .map(|event| (event.seq, event))
+        .collect();
+
+    Ok(stars
+        .into_iter()
+        .map(|star| StarredMessage {
+            event: events.remove(&star.seq),
+            star,
+        })
+        .collect())
+}
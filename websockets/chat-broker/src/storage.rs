@@ -0,0 +1,354 @@
+use std::fmt;
+use std::sync::Mutex;
+
+use actix::prelude::*;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+
+use crate::config::config;
+use crate::drafts::Draft;
+use crate::journal::{EventKind, Journal, RoomEvent};
+use crate::message::{
+    ForgetRoom, ImportEvents, LoadDrafts, LoadRecent, SaveEvent, StoreDraft,
+};
+
+/// The store the journal writes to, if one is configured
+static STORE: OnceCell<Addr<Store>> = OnceCell::new();
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// `DATABASE_URL` names a database this build can't use
+    Unsupported(String),
+    Database(String),
+    /// a stored event that no longer parses
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    Corrupt(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Unsupported(url) => write!(
+                f,
+                "unsupported DATABASE_URL {:?}, use sqlite:path with the sqlite feature",
+                url
+            ),
+            StorageError::Database(err) => write!(f, "database error: {}", err),
+            StorageError::Corrupt(err) => {
+                write!(f, "stored event doesn't parse: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// A room event as stored, without the journal's sequence number, which
+/// starts over with every run
+pub struct StoredEvent {
+    pub room_name: String,
+    /// unix milliseconds
+    pub time: u64,
+    pub kind: EventKind,
+}
+
+/// Chat history, and drafts, that outlive the process. Calls block, `Store`
+/// makes them on a thread of its own.
+pub trait Storage: Send {
+    fn save(&mut self, event: &RoomEvent) -> Result<(), StorageError>;
+
+    /// Drops everything kept of the room, for rooms turning `no_log`
+    fn forget(&mut self, room_name: &str) -> Result<(), StorageError>;
+
+    /// The newest `per_room` events of every room, oldest first
+    fn recent(&mut self, per_room: usize) -> Result<Vec<StoredEvent>, StorageError>;
+
+    /// Keeps the account's draft for the room, or deletes it if `None`
+    fn save_draft(
+        &mut self,
+        account: &str,
+        room_name: &str,
+        draft: Option<&Draft>,
+    ) -> Result<(), StorageError>;
+
+    /// Every draft kept, as (account, room, draft)
+    fn drafts(&mut self) -> Result<Vec<(String, String, Draft)>, StorageError>;
+}
+
+/// Runs a `Storage` for the journal, which hands it every event it records
+pub struct Store(Box<dyn Storage>);
+
+impl Store {
+    /// Opens the database `DATABASE_URL` names, if it is set, and reloads the
+    /// journal from it before anything new is recorded
+    pub async fn from_env() -> Result<Option<Addr<Store>>, StorageError> {
+        let url = match std::env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let storage = open(&url)?;
+        info!("Store - keeping chat history in {}", &url);
+
+        // a single thread, so writes are made in order
+        let storage = Mutex::new(Some(storage));
+        let store = SyncArbiter::start(1, move || {
+            let storage = storage.lock().unwrap().take();
+            Store(storage.expect("Store runs on a single thread"))
+        });
+
+        restore(&store).await?;
+        STORE.set(store.clone()).ok();
+
+        Ok(Some(store))
+    }
+}
+
+impl Actor for Store {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<SaveEvent> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveEvent, _ctx: &mut Self::Context) {
+        let SaveEvent(event) = msg;
+
+        if let Err(err) = self.0.save(&event) {
+            warn!("Store - saving event {} failed: {}", event.seq, err);
+        }
+    }
+}
+
+impl Handler<ForgetRoom> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForgetRoom, _ctx: &mut Self::Context) {
+        let ForgetRoom(room_name) = msg;
+
+        if let Err(err) = self.0.forget(&room_name) {
+            warn!("Store - forgetting {} failed: {}", &room_name, err);
+        }
+    }
+}
+
+impl Handler<LoadRecent> for Store {
+    type Result = Result<Vec<StoredEvent>, StorageError>;
+
+    fn handle(&mut self, msg: LoadRecent, _ctx: &mut Self::Context) -> Self::Result {
+        let LoadRecent(per_room) = msg;
+        self.0.recent(per_room)
+    }
+}
+
+impl Handler<StoreDraft> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreDraft, _ctx: &mut Self::Context) {
+        let StoreDraft {
+            account,
+            room_name,
+            draft,
+        } = msg;
+
+        if let Err(err) = self.0.save_draft(&account, &room_name, draft.as_ref()) {
+            warn!("Store - saving a draft for {} failed: {}", &account, err);
+        }
+    }
+}
+
+impl Handler<LoadDrafts> for Store {
+    type Result = Result<Vec<(String, String, Draft)>, StorageError>;
+
+    fn handle(&mut self, _msg: LoadDrafts, _ctx: &mut Self::Context) -> Self::Result {
+        self.0.drafts()
+    }
+}
+
+/// The configured store, for the journal and drafts
+pub fn store() -> Option<&'static Addr<Store>> {
+    STORE.get()
+}
+
+/// Hands what the database kept to the journal, room by room
+async fn restore(store: &Addr<Store>) -> Result<(), StorageError> {
+    let events = store
+        .send(LoadRecent(config().history_depth))
+        .await
+        .map_err(|err| StorageError::Database(err.to_string()))??;
+    let count = events.len();
+
+    let mut rooms: Vec<ImportEvents> = Vec::new();
+    for event in events {
+        match rooms
+            .iter_mut()
+            .find(|room| room.room_name == event.room_name)
+        {
+            Some(room) => room.events.push((event.time, event.kind)),
+            None => rooms.push(ImportEvents {
+                room_name: event.room_name,
+                events: vec![(event.time, event.kind)],
+            }),
+        }
+    }
+
+    let journal = Journal::from_registry();
+    for room in rooms {
+        let room_name = room.room_name.clone();
+        if let Ok(Err(err)) = journal.send(room).await {
+            warn!("Store - restoring {} failed: {}", &room_name, err);
+        }
+    }
+
+    info!("Store - restored {} events", count);
+    Ok(())
+}
+
+fn open(url: &str) -> Result<Box<dyn Storage>, StorageError> {
+    match url.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        Some(path) => Ok(Box::new(sqlite::SqliteStorage::open(path)?)),
+        _ => Err(StorageError::Unsupported(url.to_owned())),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use rusqlite::{params, Connection};
+
+    use super::{Storage, StorageError, StoredEvent};
+    use crate::drafts::Draft;
+    use crate::journal::RoomEvent;
+
+    impl From<rusqlite::Error> for StorageError {
+        fn from(err: rusqlite::Error) -> Self {
+            StorageError::Database(err.to_string())
+        }
+    }
+
+    /// Events and drafts in a SQLite file. Every node journals the events of
+    /// all rooms it hears about, and keeps every draft, so each one keeps a
+    /// file of its own.
+    pub struct SqliteStorage {
+        conn: Connection,
+    }
+
+    impl SqliteStorage {
+        pub fn open(path: &str) -> Result<Self, StorageError> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS events (
+                    id INTEGER PRIMARY KEY,
+                    room TEXT NOT NULL,
+                    sender TEXT,
+                    time INTEGER NOT NULL,
+                    kind TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS events_by_room ON events (room, id);
+                CREATE TABLE IF NOT EXISTS drafts (
+                    account TEXT NOT NULL,
+                    room TEXT NOT NULL,
+                    text TEXT NOT NULL,
+                    updated INTEGER NOT NULL,
+                    PRIMARY KEY (account, room)
+                );",
+            )?;
+
+            Ok(SqliteStorage { conn })
+        }
+    }
+
+    impl Storage for SqliteStorage {
+        fn save(&mut self, event: &RoomEvent) -> Result<(), StorageError> {
+            let kind = serde_json::to_string(&event.kind)
+                .map_err(|err| StorageError::Corrupt(err.to_string()))?;
+
+            self.conn.execute(
+                "INSERT INTO events (room, sender, time, kind) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    event.room_name,
+                    event.kind.author(),
+                    event.time as i64,
+                    kind
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn forget(&mut self, room_name: &str) -> Result<(), StorageError> {
+            self.conn
+                .execute("DELETE FROM events WHERE room = ?1", params![room_name])?;
+            Ok(())
+        }
+
+        fn recent(&mut self, per_room: usize) -> Result<Vec<StoredEvent>, StorageError> {
+            let mut query = self.conn.prepare(
+                "SELECT room, time, kind FROM (
+                    SELECT id, room, time, kind,
+                        ROW_NUMBER() OVER (PARTITION BY room ORDER BY id DESC) AS newer
+                    FROM events
+                ) WHERE newer <= ?1 ORDER BY id",
+            )?;
+            let rows = query.query_map(params![per_room as i64], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+
+            let mut events = Vec::new();
+            for row in rows {
+                let (room_name, time, kind) = row?;
+                let kind = serde_json::from_str(&kind)
+                    .map_err(|err| StorageError::Corrupt(err.to_string()))?;
+                events.push(StoredEvent {
+                    room_name,
+                    time: time as u64,
+                    kind,
+                });
+            }
+
+            Ok(events)
+        }
+
+        fn save_draft(
+            &mut self,
+            account: &str,
+            room_name: &str,
+            draft: Option<&Draft>,
+        ) -> Result<(), StorageError> {
+            match draft {
+                Some(draft) => self.conn.execute(
+                    "INSERT OR REPLACE INTO drafts (account, room, text, updated)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    params![account, room_name, draft.text, draft.updated as i64],
+                )?,
+                None => self.conn.execute(
+                    "DELETE FROM drafts WHERE account = ?1 AND room = ?2",
+                    params![account, room_name],
+                )?,
+            };
+            Ok(())
+        }
+
+        fn drafts(&mut self) -> Result<Vec<(String, String, Draft)>, StorageError> {
+            let mut query = self
+                .conn
+                .prepare("SELECT account, room, text, updated FROM drafts")?;
+            let rows = query.query_map(params![], |row| {
+                let draft = Draft {
+                    text: row.get(2)?,
+                    updated: row.get::<_, i64>(3)? as u64,
+                };
+                Ok((row.get(0)?, row.get(1)?, draft))
+            })?;
+
+            let mut drafts = Vec::new();
+            for row in rows {
+                drafts.push(row?);
+            }
+
+            Ok(drafts)
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use actix::prelude::*;
+use log::info;
+use serde::Serialize;
+
+use crate::message::{ListTeams, ManageTeam, SetTeamRooms};
+use crate::server::WsChatServer;
+
+/// Teams an account may create, so names can't be squatted wholesale
+const MAX_TEAMS_PER_ACCOUNT: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum TeamError {
+    NotFound,
+    NameTaken,
+    NotAdmin,
+    NotMember(String),
+    /// the room already belongs to this or another team
+    RoomTaken(String),
+    /// the last admin can't leave or be removed
+    LastAdmin,
+    TooMany,
+}
+
+impl fmt::Display for TeamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TeamError::NotFound => write!(f, "no such team"),
+            TeamError::NameTaken => write!(f, "team name is already taken"),
+            TeamError::NotAdmin => write!(f, "only team admins can do that"),
+            TeamError::NotMember(name) => write!(f, "{} isn't in the team", name),
+            TeamError::RoomTaken(room) => {
+                write!(f, "{} already belongs to a team", room)
+            }
+            TeamError::LastAdmin => {
+                write!(f, "a team needs an admin, promote someone else first")
+            }
+            TeamError::TooMany => write!(
+                f,
+                "an account can create at most {} teams",
+                MAX_TEAMS_PER_ACCOUNT
+            ),
+        }
+    }
+}
+
+/// What `/team ...` does, on behalf of the account sending it
+#[derive(Clone, Debug)]
+pub enum TeamAction {
+    Create,
+    /// adds an account as a member, admins only
+    Add(String),
+    /// takes an account out of the team, admins only
+    Remove(String),
+    /// makes a member an admin, admins only
+    Promote(String),
+    /// claims a room that doesn't exist yet for the team, admins only
+    Room(String),
+    Leave,
+}
+
+/// A set of rooms only its members may join, run by its admins
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Team {
+    pub name: String,
+    /// accounts, admins included
+    pub members: BTreeSet<String>,
+    pub admins: BTreeSet<String>,
+    pub rooms: BTreeSet<String>,
+    /// the account that created it
+    #[serde(skip)]
+    creator: String,
+}
+
+/// Who may join a team's room, as `WsChatServer` checks it
+#[derive(Clone, Debug)]
+pub struct TeamRoom {
+    pub team: String,
+    pub members: BTreeSet<String>,
+}
+
+/// Teams by name. Like accounts they are kept per node, and the rooms they
+/// claim are pushed to `WsChatServer` on every change, which refuses joins
+/// by anyone else and groups `/list` by team.
+#[derive(Default)]
+pub struct Teams {
+    teams: BTreeMap<String, Team>,
+}
+
+impl Teams {
+    fn sync_rooms(&self) {
+        let rooms: HashMap<String, TeamRoom> = self
+            .teams
+            .values()
+            .flat_map(|team| {
+                team.rooms.iter().map(move |room_name| {
+                    let access = TeamRoom {
+                        team: team.name.clone(),
+                        members: team.members.clone(),
+                    };
+                    (room_name.clone(), access)
+                })
+            })
+            .collect();
+
+        WsChatServer::from_registry().do_send(SetTeamRooms(rooms));
+    }
+
+    fn apply(
+        &mut self,
+        account: String,
+        team_name: String,
+        action: TeamAction,
+    ) -> Result<String, TeamError> {
+        if let TeamAction::Create = action {
+            if self.teams.contains_key(&team_name) {
+                return Err(TeamError::NameTaken);
+            }
+            let created = self
+                .teams
+                .values()
+                .filter(|team| team.creator == account)
+                .count();
+            if created >= MAX_TEAMS_PER_ACCOUNT {
+                return Err(TeamError::TooMany);
+            }
+
+            let mut team = Team {
+                name: team_name.clone(),
+                creator: account.clone(),
+                ..Default::default()
+            };
+            team.members.insert(account.clone());
+            team.admins.insert(account);
+            self.teams.insert(team_name.clone(), team);
+
+            return Ok(format!("created team {}, you are its admin", team_name));
+        }
+
+        if let TeamAction::Room(room_name) = &action {
+            if self
+                .teams
+                .values()
+                .any(|team| team.rooms.contains(room_name))
+            {
+                return Err(TeamError::RoomTaken(room_name.clone()));
+            }
+        }
+
+        let team = self.teams.get_mut(&team_name).ok_or(TeamError::NotFound)?;
+        let is_admin = team.admins.contains(&account);
+
+        let reply = match action {
+            TeamAction::Create => unreachable!("handled above"),
+            TeamAction::Leave => {
+                if !team.members.contains(&account) {
+                    return Err(TeamError::NotMember(account));
+                }
+                if is_admin && team.admins.len() == 1 {
+                    return Err(TeamError::LastAdmin);
+                }
+                team.members.remove(&account);
+                team.admins.remove(&account);
+                format!("left team {}", team_name)
+            }
+            _ if !is_admin => return Err(TeamError::NotAdmin),
+            TeamAction::Add(name) => {
+                team.members.insert(name.clone());
+                format!("{} is now in team {}", name, team_name)
+            }
+            TeamAction::Remove(name) => {
+                if !team.members.contains(&name) {
+                    return Err(TeamError::NotMember(name));
+                }
+                if team.admins.contains(&name) && team.admins.len() == 1 {
+                    return Err(TeamError::LastAdmin);
+                }
+                team.members.remove(&name);
+                team.admins.remove(&name);
+                format!("{} is no longer in team {}", name, team_name)
+            }
+            TeamAction::Promote(name) => {
+                if !team.members.contains(&name) {
+                    return Err(TeamError::NotMember(name));
+                }
+                team.admins.insert(name.clone());
+                format!("{} is now an admin of team {}", name, team_name)
+            }
+            TeamAction::Room(room_name) => {
+                team.rooms.insert(room_name.clone());
+                format!(
+                    "{} now belongs to team {}, only its members may join",
+                    room_name, team_name
+                )
+            }
+        };
+
+        Ok(reply)
+    }
+}
+
+impl Actor for Teams {
+    type Context = Context<Self>;
+}
+
+impl Handler<ManageTeam> for Teams {
+    type Result = Result<String, TeamError>;
+
+    fn handle(&mut self, msg: ManageTeam, _ctx: &mut Self::Context) -> Self::Result {
+        let ManageTeam {
+            account,
+            team,
+            action,
+        } = msg;
+
+        info!("Teams - {} on {}: {:?}", &account, &team, &action);
+        let reply = self.apply(account, team, action)?;
+        self.sync_rooms();
+
+        Ok(reply)
+    }
+}
+
+impl Handler<ListTeams> for Teams {
+    type Result = MessageResult<ListTeams>;
+
+    fn handle(&mut self, msg: ListTeams, _ctx: &mut Self::Context) -> Self::Result {
+        let teams = self
+            .teams
+            .values()
+            .filter(|team| team.members.contains(&msg.0))
+            .cloned()
+            .collect();
+
+        MessageResult(teams)
+    }
+}
+
+impl SystemService for Teams {}
+impl Supervised for Teams {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_team_roles() {
+        let mut teams = Teams::default();
+        let act = |teams: &mut Teams, account: &str, action| {
+            teams.apply(account.to_owned(), "eng".to_owned(), action)
+        };
+
+        assert!(act(&mut teams, "alice", TeamAction::Create).is_ok());
+        assert_eq!(
+            act(&mut teams, "bob", TeamAction::Create),
+            Err(TeamError::NameTaken)
+        );
+        assert_eq!(
+            act(&mut teams, "bob", TeamAction::Add("carol".into())),
+            Err(TeamError::NotAdmin)
+        );
+        assert!(act(&mut teams, "alice", TeamAction::Add("bob".into())).is_ok());
+        assert!(act(&mut teams, "alice", TeamAction::Room("standup".into())).is_ok());
+        assert_eq!(
+            act(&mut teams, "alice", TeamAction::Room("standup".into())),
+            Err(TeamError::RoomTaken("standup".into()))
+        );
+        assert_eq!(
+            act(&mut teams, "alice", TeamAction::Leave),
+            Err(TeamError::LastAdmin)
+        );
+        assert!(act(&mut teams, "alice", TeamAction::Promote("bob".into())).is_ok());
+        assert!(act(&mut teams, "alice", TeamAction::Leave).is_ok());
+        assert!(act(&mut teams, "bob", TeamAction::Room("retro".into())).is_ok());
+
+        let team = &teams.teams["eng"];
+        assert_eq!(team.members.iter().collect::<Vec<_>>(), ["bob"]);
+        assert_eq!(team.rooms.len(), 2);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use actix::prelude::*;
+use log::{info, warn};
+use serde_json::{Map, Value};
+
+use crate::message::{GetTemplate, ListTemplates, PutTemplate, RemoveTemplate};
+use crate::settings::{parse_patch, Setting};
+
+/// Settings a template sets, in the `PATCH /api/rooms/{room}` format, e.g.
+/// `{"topic":"standup at 10","welcome":"hi","rules":"be kind","slow_mode":30}`
+pub type RoomTemplate = Map<String, Value>;
+
+/// Named sets of settings new rooms can be created with, see `/create`.
+/// Templates are read from the JSON file `ROOM_TEMPLATES` names, an object of
+/// templates by name, and the admin API's changes are saved back to it.
+/// Without the variable they only live as long as the node.
+#[derive(Default)]
+pub struct Templates {
+    path: Option<PathBuf>,
+    templates: BTreeMap<String, RoomTemplate>,
+}
+
+impl Templates {
+    fn load(&mut self) {
+        let path = match std::env::var("ROOM_TEMPLATES") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return,
+        };
+
+        let templates: BTreeMap<String, RoomTemplate> = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(templates) => templates,
+                Err(err) => {
+                    warn!("Templates - ignoring {}: {}", path.display(), err);
+                    return;
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+
+        for (name, template) in templates {
+            match parse_patch(template.clone()) {
+                Ok(_) => {
+                    self.templates.insert(name, template);
+                }
+                Err(err) => warn!("Templates - ignoring template {}: {}", name, err),
+            }
+        }
+
+        info!(
+            "Templates - loaded {} from {}",
+            self.templates.len(),
+            path.display()
+        );
+        self.path = Some(path);
+    }
+
+    /// Written next to the file and renamed over it, like reminders
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+
+        let res = serde_json::to_vec_pretty(&self.templates)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(err) = res {
+            warn!("Templates - can't save {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl Actor for Templates {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.load();
+    }
+}
+
+impl Handler<GetTemplate> for Templates {
+    type Result = Option<Vec<Setting>>;
+
+    fn handle(&mut self, msg: GetTemplate, _ctx: &mut Self::Context) -> Self::Result {
+        // checked when the template was added
+        self.templates
+            .get(&msg.0)
+            .and_then(|template| parse_patch(template.clone()).ok())
+    }
+}
+
+impl Handler<ListTemplates> for Templates {
+    type Result = MessageResult<ListTemplates>;
+
+    fn handle(&mut self, _: ListTemplates, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.templates.clone())
+    }
+}
+
+impl Handler<PutTemplate> for Templates {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: PutTemplate, _ctx: &mut Self::Context) -> Self::Result {
+        let PutTemplate { name, template } = msg;
+
+        parse_patch(template.clone())?;
+        self.templates.insert(name, template);
+        self.save();
+
+        Ok(())
+    }
+}
+
+impl Handler<RemoveTemplate> for Templates {
+    type Result = bool;
+
+    fn handle(&mut self, msg: RemoveTemplate, _ctx: &mut Self::Context) -> Self::Result {
+        let removed = self.templates.remove(&msg.0).is_some();
+        if removed {
+            self.save();
+        }
+
+        removed
+    }
+}
+
+impl SystemService for Templates {}
+impl Supervised for Templates {}
@@ -0,0 +1,150 @@
+//! Helpers for unit tests driving a `WsChatSession` as a client would, without
+//! an HTTP server or a socket. `run` gives each test an actix `System` of its
+//! own, so the `WsChatServer` its sessions talk to is a fresh one with no
+//! rooms or clients but theirs, standing in for the node's. `TestClient`
+//! starts a session on a `WebsocketContext` fed from a channel and decodes
+//! the frames it writes. `wait_for` waits for the frame a test is after,
+//! rather than for the session to go quiet, for answers that take the server
+//! a while.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{Codec, Frame, Message};
+use actix_web::error::PayloadError;
+use actix_web::Error;
+use actix_web_actors::ws::WebsocketContext;
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+
+use crate::session::{DefaultRooms, WsChatSession};
+
+/// How long the session gets to answer before `TestClient` decides it won't
+const QUIET: Duration = Duration::from_millis(200);
+
+/// How long `wait_for` waits for a frame the session should send
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Runs a test in a `System` of its own, and with it a chat server of its own
+pub fn run<F: Future + 'static>(test: F) -> F::Output {
+    System::new("test").block_on(test)
+}
+
+/// The client end of a session's websocket
+pub struct TestClient {
+    input: mpsc::UnboundedSender<Result<Bytes, PayloadError>>,
+    /// also what drives the session actor, so it only runs while read from
+    output: Pin<Box<dyn Stream<Item = Result<Bytes, Error>>>>,
+    codec: Codec,
+    buf: BytesMut,
+}
+
+impl TestClient {
+    /// A client connected to a new session, joining `default_rooms`
+    pub fn connect(default_rooms: &[&str]) -> Self {
+        let default_rooms =
+            DefaultRooms(default_rooms.iter().map(|room| room.to_string()).collect());
+
+        Self::start(WsChatSession::new(&default_rooms))
+    }
+
+    /// A client connected to `session`, which starts now
+    pub fn start(session: WsChatSession) -> Self {
+        let (input, frames) = mpsc::unbounded();
+        let (_addr, output) = WebsocketContext::create_with_addr(session, frames);
+
+        TestClient {
+            input,
+            output: Box::pin(output),
+            codec: Codec::new().client_mode(),
+            buf: BytesMut::new(),
+        }
+    }
+
+    pub fn send(&mut self, text: &str) {
+        self.send_message(Message::Text(text.to_owned()));
+    }
+
+    /// Sends any frame, masked as clients' are
+    pub fn send_message(&mut self, msg: Message) {
+        let mut frame = BytesMut::new();
+        self.codec
+            .encode(msg, &mut frame)
+            .expect("frames to encode");
+        self.input
+            .unbounded_send(Ok(frame.freeze()))
+            .expect("the session to be reading");
+    }
+
+    /// The next frame the session sends, `None` if it doesn't send one in time
+    /// or has stopped
+    pub async fn recv(&mut self) -> Option<Frame> {
+        self.recv_within(QUIET).await
+    }
+
+    async fn recv_within(&mut self, wait: Duration) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buf).expect("valid frames")
+            {
+                return Some(frame);
+            }
+
+            match actix_rt::time::timeout(wait, self.output.next()).await {
+                Ok(Some(Ok(bytes))) => self.buf.extend_from_slice(&bytes),
+                _ => return None,
+            }
+        }
+    }
+
+    /// The text frames the session sends until it goes quiet, skipping pings
+    /// and the like
+    pub async fn texts(&mut self) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Some(frame) = self.recv().await {
+            if let Frame::Text(text) = frame {
+                texts.push(String::from_utf8_lossy(&text).into_owned());
+            }
+        }
+
+        texts
+    }
+
+    /// Sends `text` and returns the text frames that come back
+    pub async fn ask(&mut self, text: &str) -> Vec<String> {
+        self.send(text);
+        self.texts().await
+    }
+
+    /// Skips text frames until one containing `wanted` arrives, and returns
+    /// it. Panics with the frames skipped if none does in time. Only this
+    /// client's session runs meanwhile, so what other clients sent should
+    /// have been read back with `ask` first.
+    pub async fn wait_for(&mut self, wanted: &str) -> String {
+        let deadline = Instant::now() + PATIENCE;
+        let mut skipped = Vec::new();
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match self.recv_within(wait).await {
+                Some(Frame::Text(text)) => {
+                    let text = String::from_utf8_lossy(&text).into_owned();
+                    if text.contains(wanted) {
+                        return text;
+                    }
+                    skipped.push(text);
+                }
+                Some(_) => {}
+                None => panic!("no frame with {:?} came, only {:?}", wanted, skipped),
+            }
+        }
+    }
+
+    /// Sends `text` and waits for the frame containing `wanted`
+    pub async fn ask_for(&mut self, text: &str, wanted: &str) -> String {
+        self.send(text);
+        self.wait_for(wanted).await
+    }
+}
@@ -0,0 +1,475 @@
+//! Credentials for integrations that only need a room or two. The admin API
+//! issues a token for one room, allowed to do some of reading its history,
+//! posting to it and managing its webhooks, and integrations send it as
+//! `Authorization: Bearer <token>` in place of the admin token.
+//!
+//! Also the QR codes of rooms' join links, for getting people at an event
+//! into the right room by pointing their phones at a screen.
+
+use std::collections::{BTreeSet, HashMap};
+
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{hash_token, random_token};
+use crate::admin;
+use crate::config::config;
+use crate::message::{
+    AddWebhook, CheckRoomToken, GetJoinCode, IssueRoomToken, ListRoomTokens,
+    ListWebhooks, RemoveWebhook, RevokeRoomToken, SendMessage,
+};
+use crate::qr::QrCode;
+use crate::server::{RoomError, WsChatServer};
+use crate::webhooks::Webhooks;
+
+/// Longest label of a token, which its posts are signed with
+pub const MAX_LABEL_LEN: usize = 32;
+
+/// Pixels per module of a join QR code, at most `MAX_QR_SCALE`
+const DEFAULT_QR_SCALE: usize = 8;
+const MAX_QR_SCALE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `GET /api/rooms/{name}/events` and `/questions`, whatever the visibility
+    Read,
+    /// `POST /api/rooms/{name}/messages`
+    Post,
+    /// `/api/rooms/{name}/webhooks`
+    Webhooks,
+    /// `GET /api/rooms/{name}/join-qr`
+    Invite,
+}
+
+/// A token as the admin API lists it, without the token itself
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenInfo {
+    pub id: String,
+    pub room_name: String,
+    pub label: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+/// Who is using a token that allows the request
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub label: String,
+    /// posts from the token count as one client's, for slow mode
+    pub client_id: usize,
+}
+
+struct RoomToken {
+    info: TokenInfo,
+    client_id: usize,
+}
+
+/// Issued tokens by their hash, kept per node like webhooks
+#[derive(Default)]
+pub struct RoomTokens {
+    tokens: HashMap<String, RoomToken>,
+}
+
+impl Actor for RoomTokens {
+    type Context = Context<Self>;
+}
+
+impl Handler<IssueRoomToken> for RoomTokens {
+    type Result = MessageResult<IssueRoomToken>;
+
+    fn handle(&mut self, msg: IssueRoomToken, _ctx: &mut Self::Context) -> Self::Result {
+        let IssueRoomToken {
+            room_name,
+            label,
+            scopes,
+        } = msg;
+
+        let info = TokenInfo {
+            id: hex::encode(rand::random::<[u8; 8]>()),
+            room_name,
+            label,
+            scopes,
+        };
+        info!(
+            "RoomTokens - issued {} for {}: {:?}",
+            &info.id, &info.room_name, &info.scopes
+        );
+
+        let token = random_token();
+        self.tokens.insert(
+            hash_token(&token),
+            RoomToken {
+                info: info.clone(),
+                client_id: rand::random(),
+            },
+        );
+
+        MessageResult((info, token))
+    }
+}
+
+impl Handler<ListRoomTokens> for RoomTokens {
+    type Result = MessageResult<ListRoomTokens>;
+
+    fn handle(&mut self, msg: ListRoomTokens, _ctx: &mut Self::Context) -> Self::Result {
+        let mut tokens: Vec<_> = self
+            .tokens
+            .values()
+            .filter(|token| token.info.room_name == msg.0)
+            .map(|token| token.info.clone())
+            .collect();
+        tokens.sort_by(|a, b| a.label.cmp(&b.label).then_with(|| a.id.cmp(&b.id)));
+
+        MessageResult(tokens)
+    }
+}
+
+impl Handler<RevokeRoomToken> for RoomTokens {
+    type Result = bool;
+
+    fn handle(
+        &mut self,
+        msg: RevokeRoomToken,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let before = self.tokens.len();
+        self.tokens.retain(|_, token| token.info.id != msg.0);
+
+        self.tokens.len() < before
+    }
+}
+
+impl Handler<CheckRoomToken> for RoomTokens {
+    type Result = Option<Grant>;
+
+    fn handle(&mut self, msg: CheckRoomToken, _ctx: &mut Self::Context) -> Self::Result {
+        let CheckRoomToken {
+            token,
+            room_name,
+            scope,
+        } = msg;
+
+        self.tokens
+            .get(&hash_token(&token))
+            .filter(|token| token.info.room_name == room_name)
+            .filter(|token| token.info.scopes.contains(&scope))
+            .map(|token| Grant {
+                label: token.info.label.clone(),
+                client_id: token.client_id,
+            })
+    }
+}
+
+impl SystemService for RoomTokens {}
+impl Supervised for RoomTokens {}
+
+fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// The token's grant if the request sends one allowing `scope` in the room
+pub async fn grant(req: &HttpRequest, room_name: &str, scope: Scope) -> Option<Grant> {
+    let token = bearer(req)?.trim().to_owned();
+
+    RoomTokens::from_registry()
+        .send(CheckRoomToken {
+            token,
+            room_name: room_name.to_owned(),
+            scope,
+        })
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Whether the request may do what `scope` allows in the room, with the admin
+/// token or a room token, answering with the response to send instead if
+/// not. Resolves to `None` for the admin token.
+pub async fn authorize(
+    req: &HttpRequest,
+    room_name: &str,
+    scope: Scope,
+) -> Result<Option<Grant>, HttpResponse> {
+    if admin::authorize(req.headers()).is_ok() {
+        return Ok(None);
+    }
+    if bearer(req).is_none() {
+        return Err(HttpResponse::Unauthorized().finish());
+    }
+
+    match grant(req, room_name, scope).await {
+        Some(grant) => Ok(Some(grant)),
+        None => Err(HttpResponse::Forbidden().finish()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PostForm {
+    content: String,
+    /// who the admin token posts as, room tokens post as their label
+    #[serde(default)]
+    from: Option<String>,
+}
+
+/// Posts to the room like a member would, so archived rooms, moderation and
+/// slow mode apply. Accepted rather than created, a refused post isn't
+/// reported back.
+pub async fn post_message(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+    form: web::Json<PostForm>,
+) -> Result<HttpResponse, Error> {
+    let grant = match authorize(&req, &room_name, Scope::Post).await {
+        Ok(grant) => grant,
+        Err(res) => return Ok(res),
+    };
+    let PostForm { content, from } = form.into_inner();
+
+    let max_len = config().max_message_len;
+    if content.trim().is_empty() || content.len() > max_len {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("content must be 1 to {} bytes", max_len)));
+    }
+
+    let (author, client_id) = match grant {
+        Some(grant) => (grant.label, grant.client_id),
+        None => match from.filter(|from| !from.trim().is_empty()) {
+            Some(from) => (from, rand::random()),
+            None => return Ok(HttpResponse::BadRequest().body("from is required")),
+        },
+    };
+
+    WsChatServer::from_registry().do_send(SendMessage(
+        room_name.into_inner(),
+        client_id,
+        format!("{}: {}", author, content),
+    ));
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Deserialize)]
+pub struct RoomWebhookForm {
+    url: String,
+}
+
+pub async fn list_webhooks(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    if let Err(res) = authorize(&req, &room_name, Scope::Webhooks).await {
+        return Ok(res);
+    }
+
+    let hooks: Vec<_> = Webhooks::from_registry()
+        .send(ListWebhooks)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .into_iter()
+        .filter(|hook| hook.room_name.as_deref() == Some(room_name.as_str()))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(hooks))
+}
+
+/// Like the admin API's, but always for this room only
+pub async fn add_webhook(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+    form: web::Json<RoomWebhookForm>,
+) -> Result<HttpResponse, Error> {
+    if let Err(res) = authorize(&req, &room_name, Scope::Webhooks).await {
+        return Ok(res);
+    }
+
+    let (info, secret) = Webhooks::from_registry()
+        .send(AddWebhook {
+            url: form.into_inner().url,
+            room_name: Some(room_name.into_inner()),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "webhook": info,
+        "secret": secret,
+    })))
+}
+
+/// 404 for hooks of other rooms, as if they didn't exist
+pub async fn remove_webhook(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (room_name, id) = path.into_inner();
+    if let Err(res) = authorize(&req, &room_name, Scope::Webhooks).await {
+        return Ok(res);
+    }
+
+    let webhooks = Webhooks::from_registry();
+    let hooks = webhooks
+        .send(ListWebhooks)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if !hooks.iter().any(|hook| {
+        hook.id == id && hook.room_name.as_deref() == Some(room_name.as_str())
+    }) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let removed = webhooks
+        .send(RemoveWebhook(id))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    })
+}
+
+#[derive(Deserialize)]
+pub struct JoinQrQuery {
+    #[serde(default)]
+    scale: Option<usize>,
+}
+
+/// Where the web client is served, `PUBLIC_URL` or else the host the request
+/// came to
+fn public_url(req: &HttpRequest) -> String {
+    match std::env::var("PUBLIC_URL") {
+        Ok(url) if !url.is_empty() => url.trim_end_matches('/').to_owned(),
+        _ => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    }
+}
+
+/// An SVG QR code of the link joining the room by its join code, creating
+/// the code if the room has none. The link itself is in `X-Join-Link`. While
+/// another node, the room's home, creates the code, answers 503 to be asked
+/// again.
+pub async fn join_qr(
+    req: HttpRequest,
+    room_name: web::Path<String>,
+    query: web::Query<JoinQrQuery>,
+) -> Result<HttpResponse, Error> {
+    if let Err(res) = authorize(&req, &room_name, Scope::Invite).await {
+        return Ok(res);
+    }
+    let scale = query.scale.unwrap_or(DEFAULT_QR_SCALE);
+    if scale == 0 || scale > MAX_QR_SCALE {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("scale must be 1 to {}", MAX_QR_SCALE)));
+    }
+
+    let res = WsChatServer::from_registry()
+        .send(GetJoinCode(room_name.into_inner()))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let code = match res {
+        Ok(Some(code)) => code,
+        Ok(None) => {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .header("Retry-After", "1")
+                .finish())
+        }
+        Err(RoomError::NotFound) => return Ok(HttpResponse::NotFound().finish()),
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+
+    let link = format!("{}/?join={}", public_url(&req), code);
+    let qr = match QrCode::encode(link.as_bytes()) {
+        Some(qr) => qr,
+        None => {
+            return Ok(HttpResponse::InternalServerError().body("PUBLIC_URL is too long"))
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .header("X-Join-Link", link)
+        .header("Cache-Control", "no-store")
+        .body(qr.to_svg(scale)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    use crate::testing::run;
+
+    async fn issue(room_name: &str, scopes: &[Scope]) -> (TokenInfo, String) {
+        RoomTokens::from_registry()
+            .send(IssueRoomToken {
+                room_name: room_name.to_owned(),
+                label: "bot".to_owned(),
+                scopes: scopes.iter().copied().collect(),
+            })
+            .await
+            .unwrap()
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .header("Authorization", format!("Bearer {}", token))
+            .to_http_request()
+    }
+
+    /// What `authorize` answers the token for `scope` in the room
+    async fn check(
+        token: &str,
+        room_name: &str,
+        scope: Scope,
+    ) -> Result<(), StatusCode> {
+        authorize(&bearer(token), room_name, scope)
+            .await
+            .map(|_| ())
+            .map_err(|res| res.status())
+    }
+
+    #[test]
+    fn test_room_tokens() {
+        run(async {
+            let (_, reader) = issue("a", &[Scope::Read]).await;
+            let (poster_info, poster) = issue("a", &[Scope::Read, Scope::Post]).await;
+            let forbidden = Err(StatusCode::FORBIDDEN);
+
+            assert_eq!(check(&reader, "a", Scope::Read).await, Ok(()));
+            assert_eq!(check(&reader, "a", Scope::Post).await, forbidden);
+            let form = PostForm {
+                content: "hi".to_owned(),
+                from: None,
+            };
+            let res =
+                post_message(bearer(&reader), "a".to_owned().into(), web::Json(form))
+                    .await
+                    .unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            assert_eq!(check(&poster, "a", Scope::Post).await, Ok(()));
+            assert_eq!(check(&poster, "b", Scope::Post).await, forbidden);
+            assert_eq!(check(&poster, "b", Scope::Read).await, forbidden);
+            assert_eq!(check("made up", "a", Scope::Read).await, forbidden);
+
+            let revoked = RoomTokens::from_registry()
+                .send(RevokeRoomToken(poster_info.id))
+                .await
+                .unwrap();
+            assert!(revoked);
+            assert_eq!(check(&poster, "a", Scope::Post).await, forbidden);
+            assert_eq!(check(&poster, "a", Scope::Read).await, forbidden);
+            assert_eq!(check(&reader, "a", Scope::Read).await, Ok(()));
+        });
+    }
+}
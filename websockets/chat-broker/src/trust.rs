@@ -0,0 +1,207 @@
+//! Abuse heuristics. Every client name gets a trust score from its account,
+//! if it has one, and the reports and content filter hits held against it.
+//! Capabilities that are easy to abuse are only open to clients whose score
+//! reaches the threshold configured for them.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::config::config;
+use crate::scheduler::format_delay;
+
+/// Points for having an account, and more once its email is verified
+const ACCOUNT_POINTS: i64 = 10;
+const VERIFIED_POINTS: i64 = 10;
+
+/// An account gains a point per day of age, up to this many
+const MAX_AGE_POINTS: i64 = 30;
+
+/// Lost for each client that reported the name
+const REPORT_PENALTY: i64 = 10;
+
+/// Lost for each message the content filter blocked
+const FILTER_HIT_PENALTY: i64 = 5;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Something only trusted enough clients may do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    /// messages with an `http://`, `https://` or `www.` link
+    Links,
+    /// attachments and voice notes
+    Uploads,
+    /// joining a room nobody created yet
+    CreateRoom,
+}
+
+impl Capability {
+    pub fn threshold(self) -> i64 {
+        match self {
+            Capability::Links => config().trust_links,
+            Capability::Uploads => config().trust_uploads,
+            Capability::CreateRoom => config().trust_create_room,
+        }
+    }
+
+    pub fn allowed(self, score: i64) -> bool {
+        score >= self.threshold()
+    }
+
+    /// Whether newcomers are kept from it whatever their score
+    pub fn closed_to_newcomers(self) -> bool {
+        matches!(self, Capability::Links | Capability::Uploads)
+    }
+
+    fn action(self) -> &'static str {
+        match self {
+            Capability::Links => "post links",
+            Capability::Uploads => "send attachments",
+            Capability::CreateRoom => "create rooms",
+        }
+    }
+
+    /// Why the capability was refused, e.g. for `!!! ...` replies
+    pub fn refusal(self) -> String {
+        format!(
+            "your trust score is too low to {} (needs {})",
+            self.action(),
+            self.threshold()
+        )
+    }
+}
+
+/// How long a client has been around, and how much it has said
+#[derive(Clone, Copy, Debug)]
+pub struct Experience {
+    /// of the account if logged in, otherwise of the session
+    pub age: Duration,
+    pub messages: u64,
+}
+
+impl Experience {
+    pub fn is_newcomer(self) -> bool {
+        self.age < config().newcomer_age() || self.messages < config().newcomer_messages
+    }
+
+    /// What it takes to stop being a newcomer, e.g. `wait 4m 10s and send 2
+    /// more messages`
+    pub fn waiting(self) -> String {
+        let mut waiting = Vec::new();
+        if self.age < config().newcomer_age() {
+            // rounded up, never "wait 0s"
+            let left = config().newcomer_age() - self.age;
+            let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+            waiting.push(format!("wait {}", format_delay(Duration::from_secs(secs))));
+        }
+        if self.messages < config().newcomer_messages {
+            let left = config().newcomer_messages - self.messages;
+            let plural = if left == 1 { "" } else { "s" };
+            waiting.push(format!("send {} more message{}", left, plural));
+        }
+
+        waiting.join(" and ")
+    }
+
+    /// Why a newcomer was refused the capability
+    pub fn refusal(self, capability: Capability) -> String {
+        format!(
+            "new users can't {} yet, {} first",
+            capability.action(),
+            self.waiting()
+        )
+    }
+}
+
+/// What `Accounts` knows of a client, see `GetTrust`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Standing {
+    pub score: i64,
+    /// unix milliseconds, for a logged in client
+    pub account_created: Option<u64>,
+    /// sent while logged in to the account, ever
+    pub account_messages: u64,
+}
+
+/// What has been held against a client name
+#[derive(Debug, Default)]
+pub struct TrustRecord {
+    /// accounts that reported the name
+    pub reporters: HashSet<String>,
+    pub filter_hits: u32,
+}
+
+/// The account behind a name, as far as trust goes
+pub struct AccountStanding {
+    /// unix milliseconds
+    pub created: u64,
+    pub email_verified: bool,
+}
+
+/// Trust score of a client name at `now` (unix milliseconds), 0 for a
+/// name without an account or anything held against it
+pub fn score(
+    account: Option<&AccountStanding>,
+    record: Option<&TrustRecord>,
+    now: u64,
+) -> i64 {
+    let mut score = 0;
+
+    if let Some(account) = account {
+        score += ACCOUNT_POINTS;
+        if account.email_verified {
+            score += VERIFIED_POINTS;
+        }
+
+        let age_days = now.saturating_sub(account.created) / DAY_MS;
+        score += (age_days as i64).min(MAX_AGE_POINTS);
+    }
+
+    if let Some(record) = record {
+        score -= record.reporters.len() as i64 * REPORT_PENALTY;
+        score -= i64::from(record.filter_hits) * FILTER_HIT_PENALTY;
+    }
+
+    score
+}
+
+pub fn has_link(msg: &str) -> bool {
+    let msg = msg.to_lowercase();
+    ["http://", "https://", "www."]
+        .iter()
+        .any(|prefix| msg.contains(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_score() {
+        let now = 100 * DAY_MS;
+        assert_eq!(score(None, None, now), 0);
+
+        let fresh = AccountStanding {
+            created: now,
+            email_verified: false,
+        };
+        assert_eq!(score(Some(&fresh), None, now), 10);
+
+        let old = AccountStanding {
+            created: 0,
+            email_verified: true,
+        };
+        assert_eq!(score(Some(&old), None, now), 50);
+
+        let mut record = TrustRecord::default();
+        record.reporters.insert("ann".to_owned());
+        record.reporters.insert("cat".to_owned());
+        record.filter_hits = 1;
+        assert_eq!(score(Some(&old), Some(&record), now), 25);
+        assert_eq!(score(None, Some(&record), now), -25);
+
+        assert!(has_link("see https://example.com"));
+        assert!(has_link("www.example.com"));
+        assert!(!has_link("no links here"));
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use log::{info, warn};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::accounts::random_token;
+use crate::breaker::{Circuit, CircuitBreaker, COOL_OFF};
+use crate::journal::{Journal, RoomEvent};
+use crate::message::{
+    AddWebhook, EnableWebhook, ListWebhooks, RemoveWebhook, SubscribeEvents,
+    WebhookDeliveries,
+};
+use crate::session::unix_millis;
+
+/// Attempts per event before the delivery counts as failed
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled for each one after
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Hooks are disabled after this many failed deliveries in a row
+const DISABLE_AFTER_FAILURES: u32 = 10;
+
+/// Delivery attempts kept per hook for the admin API
+const DELIVERY_LOG_SIZE: usize = 100;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deliveries taking longer count against the hook's circuit breaker
+const SLOW_DELIVERY: Duration = Duration::from_secs(3);
+
+/// Outgoing webhook, as listed by the admin API
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookInfo {
+    pub id: String,
+    pub url: String,
+    /// only this room's events, every room's if unset
+    pub room_name: Option<String>,
+    pub enabled: bool,
+    /// failed deliveries since the last successful one
+    pub failures: u32,
+    /// `open` while the hook is given a rest after failing or being slow
+    pub circuit: Circuit,
+}
+
+/// One delivery attempt
+#[derive(Clone, Debug, Serialize)]
+pub struct Delivery {
+    pub seq: u64,
+    pub attempt: u32,
+    /// unix milliseconds
+    pub time: u64,
+    /// HTTP status, if the hook answered at all
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+struct Webhook {
+    info: WebhookInfo,
+    secret: String,
+    deliveries: VecDeque<Delivery>,
+    breaker: CircuitBreaker,
+}
+
+/// What comes after a delivery attempt
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Delivered,
+    /// tried again after the backoff
+    Retry(Duration),
+    /// out of attempts
+    Failed,
+    /// out of attempts, and the hook failed too often in a row to keep it
+    Disabled,
+}
+
+impl Webhook {
+    /// Logs an attempt that took `took`, and counts it for the circuit breaker
+    /// and, once the event is out of attempts, for disabling the hook
+    fn record(&mut self, delivery: Delivery, took: Duration) -> Outcome {
+        let failed = delivery.error.is_some();
+        let attempt = delivery.attempt;
+
+        if self.breaker.record(!failed, took) {
+            warn!(
+                "Webhooks - {} keeps failing, pausing it for {}s",
+                &self.info.id,
+                COOL_OFF.as_secs()
+            );
+        }
+
+        self.deliveries.push_back(delivery);
+        if self.deliveries.len() > DELIVERY_LOG_SIZE {
+            self.deliveries.pop_front();
+        }
+
+        if !failed {
+            self.info.failures = 0;
+            return Outcome::Delivered;
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            return Outcome::Retry(FIRST_RETRY_DELAY * 2u32.pow(attempt - 1));
+        }
+
+        self.info.failures += 1;
+        if self.info.failures >= DISABLE_AFTER_FAILURES {
+            warn!(
+                "Webhooks - disabling {} after {} failed deliveries",
+                &self.info.id, self.info.failures
+            );
+            self.info.enabled = false;
+            return Outcome::Disabled;
+        }
+        Outcome::Failed
+    }
+}
+
+/// Posts room events to registered URLs. Every body is signed with the hook's
+/// secret as `X-Chat-Signature: sha256=<hex HMAC-SHA256 of the body>`.
+/// Failed attempts are retried with exponential backoff. A hook that keeps
+/// failing or answering slowly is given a rest, see `breaker`: its deliveries
+/// wait for the circuit to close, without using up their attempts.
+#[derive(Default)]
+pub struct Webhooks {
+    hooks: HashMap<String, Webhook>,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Webhooks {
+    fn deliver(
+        &mut self,
+        hook_id: String,
+        event: RoomEvent,
+        body: String,
+        attempt: u32,
+        ctx: &mut Context<Self>,
+    ) {
+        let hook = match self.hooks.get_mut(&hook_id) {
+            Some(hook) if hook.info.enabled => hook,
+            _ => return,
+        };
+
+        if !hook.breaker.allow() {
+            ctx.run_later(COOL_OFF, move |act, ctx| {
+                act.deliver(hook_id, event, body, attempt, ctx)
+            });
+            return;
+        }
+
+        let sent = Instant::now();
+        let request = awc::Client::new()
+            .post(&hook.info.url)
+            .timeout(REQUEST_TIMEOUT)
+            .content_type("application/json")
+            .header("X-Chat-Signature", sign(&hook.secret, body.as_bytes()))
+            .header("X-Chat-Event", event.seq.to_string())
+            .send_body(body.clone());
+
+        request
+            .into_actor(self)
+            .map(move |res, act, ctx| {
+                let (status, error) = match res {
+                    Ok(res) if res.status().is_success() => {
+                        (Some(res.status().as_u16()), None)
+                    }
+                    Ok(res) => {
+                        (Some(res.status().as_u16()), Some("not a 2xx".to_owned()))
+                    }
+                    Err(err) => (None, Some(err.to_string())),
+                };
+                let hook = match act.hooks.get_mut(&hook_id) {
+                    Some(hook) => hook,
+                    None => return,
+                };
+                let delivery = Delivery {
+                    seq: event.seq,
+                    attempt,
+                    time: unix_millis() as u64,
+                    status,
+                    error,
+                };
+
+                if let Outcome::Retry(delay) = hook.record(delivery, sent.elapsed()) {
+                    ctx.run_later(delay, move |act, ctx| {
+                        act.deliver(hook_id, event, body, attempt + 1, ctx)
+                    });
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Webhooks {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // only events from now on, the backlog isn't sent
+        Journal::from_registry().do_send(SubscribeEvents {
+            room_name: None,
+            since: u64::MAX,
+            subscriber: ctx.address().recipient(),
+        });
+    }
+}
+
+impl Handler<RoomEvent> for Webhooks {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomEvent, ctx: &mut Self::Context) {
+        let body = match serde_json::to_string(&msg) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Webhooks - can't serialize event: {}", err);
+                return;
+            }
+        };
+
+        let hook_ids: Vec<String> = self
+            .hooks
+            .values()
+            .filter(|hook| hook.info.enabled)
+            .filter(|hook| {
+                hook.info
+                    .room_name
+                    .as_ref()
+                    .is_none_or(|room| *room == msg.room_name)
+            })
+            .map(|hook| hook.info.id.clone())
+            .collect();
+
+        for hook_id in hook_ids {
+            self.deliver(hook_id, msg.clone(), body.clone(), 1, ctx);
+        }
+    }
+}
+
+impl Handler<AddWebhook> for Webhooks {
+    type Result = MessageResult<AddWebhook>;
+
+    fn handle(&mut self, msg: AddWebhook, _ctx: &mut Self::Context) -> Self::Result {
+        let AddWebhook { url, room_name } = msg;
+
+        let info = WebhookInfo {
+            id: hex::encode(rand::random::<[u8; 8]>()),
+            url,
+            room_name,
+            enabled: true,
+            failures: 0,
+            circuit: Circuit::Closed,
+        };
+        let secret = random_token();
+
+        info!("Webhooks - added {} for {}", &info.id, &info.url);
+        self.hooks.insert(
+            info.id.clone(),
+            Webhook {
+                info: info.clone(),
+                secret: secret.clone(),
+                deliveries: VecDeque::new(),
+                breaker: CircuitBreaker::new(SLOW_DELIVERY),
+            },
+        );
+
+        MessageResult((info, secret))
+    }
+}
+
+impl Handler<ListWebhooks> for Webhooks {
+    type Result = MessageResult<ListWebhooks>;
+
+    fn handle(&mut self, _: ListWebhooks, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.hooks
+                .values()
+                .map(|hook| WebhookInfo {
+                    circuit: hook.breaker.circuit(),
+                    ..hook.info.clone()
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Handler<RemoveWebhook> for Webhooks {
+    type Result = bool;
+
+    fn handle(&mut self, msg: RemoveWebhook, _ctx: &mut Self::Context) -> bool {
+        let RemoveWebhook(id) = msg;
+        self.hooks.remove(&id).is_some()
+    }
+}
+
+impl Handler<EnableWebhook> for Webhooks {
+    type Result = bool;
+
+    fn handle(&mut self, msg: EnableWebhook, _ctx: &mut Self::Context) -> bool {
+        let EnableWebhook(id) = msg;
+
+        match self.hooks.get_mut(&id) {
+            Some(hook) => {
+                hook.info.enabled = true;
+                hook.info.failures = 0;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<WebhookDeliveries> for Webhooks {
+    type Result = Option<Vec<Delivery>>;
+
+    fn handle(
+        &mut self,
+        msg: WebhookDeliveries,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let WebhookDeliveries(id) = msg;
+
+        self.hooks
+            .get(&id)
+            .map(|hook| hook.deliveries.iter().cloned().collect())
+    }
+}
+
+impl SystemService for Webhooks {}
+impl Supervised for Webhooks {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hook() -> Webhook {
+        Webhook {
+            info: WebhookInfo {
+                id: "h1".to_owned(),
+                url: "http://localhost/hook".to_owned(),
+                room_name: None,
+                enabled: true,
+                failures: 0,
+                circuit: Circuit::Closed,
+            },
+            secret: "Jefe".to_owned(),
+            deliveries: VecDeque::new(),
+            breaker: CircuitBreaker::new(SLOW_DELIVERY),
+        }
+    }
+
+    fn attempt(seq: u64, attempt: u32, failed: bool) -> Delivery {
+        Delivery {
+            seq,
+            attempt,
+            time: 0,
+            status: Some(if failed { 500 } else { 200 }),
+            error: Some("not a 2xx".to_owned()).filter(|_| failed),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_retries_and_disabling() {
+        let mut hook = hook();
+        let fast = Duration::from_millis(10);
+
+        let retries: Vec<Outcome> = (1..MAX_ATTEMPTS)
+            .map(|n| hook.record(attempt(1, n, true), fast))
+            .collect();
+        let secs = |secs| Outcome::Retry(Duration::from_secs(secs));
+        assert_eq!(retries, [secs(1), secs(2), secs(4), secs(8)]);
+        assert_eq!(
+            hook.record(attempt(1, MAX_ATTEMPTS, true), fast),
+            Outcome::Failed
+        );
+        assert_eq!(hook.info.failures, 1);
+
+        // a delivery that gets through starts the count again
+        assert_eq!(hook.record(attempt(2, 2, false), fast), Outcome::Delivered);
+        assert_eq!(hook.info.failures, 0);
+
+        for seq in 3..2 + u64::from(DISABLE_AFTER_FAILURES) {
+            let outcome = hook.record(attempt(seq, MAX_ATTEMPTS, true), fast);
+            assert_eq!(outcome, Outcome::Failed);
+        }
+        assert!(hook.info.enabled);
+        assert_eq!(
+            hook.record(attempt(99, MAX_ATTEMPTS, true), fast),
+            Outcome::Disabled
+        );
+        assert!(!hook.info.enabled);
+        assert_eq!(hook.deliveries.len(), 16);
+    }
+}